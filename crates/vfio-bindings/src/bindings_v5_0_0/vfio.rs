@@ -167,7 +167,7 @@ fn bindgen_test_layout___kernel_fd_set() {
         concat!("Alignment of ", stringify!(__kernel_fd_set))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<__kernel_fd_set>())).fds_bits as *const _ as usize },
+        ::std::mem::offset_of!(__kernel_fd_set, fds_bits),
         0usize,
         concat!(
             "Offset of field: ",
@@ -217,7 +217,7 @@ fn bindgen_test_layout___kernel_fsid_t() {
         concat!("Alignment of ", stringify!(__kernel_fsid_t))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<__kernel_fsid_t>())).val as *const _ as usize },
+        ::std::mem::offset_of!(__kernel_fsid_t, val),
         0usize,
         concat!(
             "Offset of field: ",
@@ -266,7 +266,7 @@ fn bindgen_test_layout_vfio_info_cap_header() {
         concat!("Alignment of ", stringify!(vfio_info_cap_header))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_info_cap_header>())).id as *const _ as usize },
+        ::std::mem::offset_of!(vfio_info_cap_header, id),
         0usize,
         concat!(
             "Offset of field: ",
@@ -276,7 +276,7 @@ fn bindgen_test_layout_vfio_info_cap_header() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_info_cap_header>())).version as *const _ as usize },
+        ::std::mem::offset_of!(vfio_info_cap_header, version),
         2usize,
         concat!(
             "Offset of field: ",
@@ -286,7 +286,7 @@ fn bindgen_test_layout_vfio_info_cap_header() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_info_cap_header>())).next as *const _ as usize },
+        ::std::mem::offset_of!(vfio_info_cap_header, next),
         4usize,
         concat!(
             "Offset of field: ",
@@ -322,7 +322,7 @@ fn bindgen_test_layout_vfio_group_status() {
         concat!("Alignment of ", stringify!(vfio_group_status))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_group_status>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_group_status, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -332,7 +332,7 @@ fn bindgen_test_layout_vfio_group_status() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_group_status>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_group_status, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -369,7 +369,7 @@ fn bindgen_test_layout_vfio_device_info() {
         concat!("Alignment of ", stringify!(vfio_device_info))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_info>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -379,7 +379,7 @@ fn bindgen_test_layout_vfio_device_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_info>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -389,7 +389,7 @@ fn bindgen_test_layout_vfio_device_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_info>())).num_regions as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_info, num_regions),
         8usize,
         concat!(
             "Offset of field: ",
@@ -399,7 +399,7 @@ fn bindgen_test_layout_vfio_device_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_info>())).num_irqs as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_info, num_irqs),
         12usize,
         concat!(
             "Offset of field: ",
@@ -442,7 +442,7 @@ fn bindgen_test_layout_vfio_region_info() {
         concat!("Alignment of ", stringify!(vfio_region_info))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -452,7 +452,7 @@ fn bindgen_test_layout_vfio_region_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -462,7 +462,7 @@ fn bindgen_test_layout_vfio_region_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).index as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, index),
         8usize,
         concat!(
             "Offset of field: ",
@@ -472,7 +472,7 @@ fn bindgen_test_layout_vfio_region_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).cap_offset as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, cap_offset),
         12usize,
         concat!(
             "Offset of field: ",
@@ -482,7 +482,7 @@ fn bindgen_test_layout_vfio_region_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).size as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, size),
         16usize,
         concat!(
             "Offset of field: ",
@@ -492,7 +492,7 @@ fn bindgen_test_layout_vfio_region_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info>())).offset as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info, offset),
         24usize,
         concat!(
             "Offset of field: ",
@@ -521,9 +521,7 @@ fn bindgen_test_layout_vfio_region_sparse_mmap_area() {
         concat!("Alignment of ", stringify!(vfio_region_sparse_mmap_area))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_sparse_mmap_area>())).offset as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_sparse_mmap_area, offset),
         0usize,
         concat!(
             "Offset of field: ",
@@ -533,9 +531,7 @@ fn bindgen_test_layout_vfio_region_sparse_mmap_area() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_sparse_mmap_area>())).size as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_sparse_mmap_area, size),
         8usize,
         concat!(
             "Offset of field: ",
@@ -589,9 +585,7 @@ fn bindgen_test_layout_vfio_region_info_cap_type() {
         concat!("Alignment of ", stringify!(vfio_region_info_cap_type))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_type>())).header as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_type, header),
         0usize,
         concat!(
             "Offset of field: ",
@@ -601,7 +595,7 @@ fn bindgen_test_layout_vfio_region_info_cap_type() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_info_cap_type>())).type_ as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_info_cap_type, type_),
         8usize,
         concat!(
             "Offset of field: ",
@@ -611,9 +605,7 @@ fn bindgen_test_layout_vfio_region_info_cap_type() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_type>())).subtype as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_type, subtype),
         12usize,
         concat!(
             "Offset of field: ",
@@ -680,9 +672,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         concat!("Alignment of ", stringify!(vfio_region_gfx_edid))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_gfx_edid>())).edid_offset as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, edid_offset),
         0usize,
         concat!(
             "Offset of field: ",
@@ -692,9 +682,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_gfx_edid>())).edid_max_size as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, edid_max_size),
         4usize,
         concat!(
             "Offset of field: ",
@@ -704,7 +692,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_gfx_edid>())).edid_size as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, edid_size),
         8usize,
         concat!(
             "Offset of field: ",
@@ -714,7 +702,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_gfx_edid>())).max_xres as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, max_xres),
         12usize,
         concat!(
             "Offset of field: ",
@@ -724,7 +712,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_gfx_edid>())).max_yres as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, max_yres),
         16usize,
         concat!(
             "Offset of field: ",
@@ -734,7 +722,7 @@ fn bindgen_test_layout_vfio_region_gfx_edid() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_region_gfx_edid>())).link_state as *const _ as usize },
+        ::std::mem::offset_of!(vfio_region_gfx_edid, link_state),
         20usize,
         concat!(
             "Offset of field: ",
@@ -766,10 +754,7 @@ fn bindgen_test_layout_vfio_region_info_cap_nvlink2_ssatgt() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_nvlink2_ssatgt>())).header as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_nvlink2_ssatgt, header),
         0usize,
         concat!(
             "Offset of field: ",
@@ -779,9 +764,7 @@ fn bindgen_test_layout_vfio_region_info_cap_nvlink2_ssatgt() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_nvlink2_ssatgt>())).tgt as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_nvlink2_ssatgt, tgt),
         8usize,
         concat!(
             "Offset of field: ",
@@ -814,10 +797,7 @@ fn bindgen_test_layout_vfio_region_info_cap_nvlink2_lnkspd() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_nvlink2_lnkspd>())).header as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_nvlink2_lnkspd, header),
         0usize,
         concat!(
             "Offset of field: ",
@@ -827,10 +807,7 @@ fn bindgen_test_layout_vfio_region_info_cap_nvlink2_lnkspd() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_nvlink2_lnkspd>())).link_speed as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_nvlink2_lnkspd, link_speed),
         8usize,
         concat!(
             "Offset of field: ",
@@ -840,10 +817,7 @@ fn bindgen_test_layout_vfio_region_info_cap_nvlink2_lnkspd() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_region_info_cap_nvlink2_lnkspd>())).__pad as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_region_info_cap_nvlink2_lnkspd, __pad),
         12usize,
         concat!(
             "Offset of field: ",
@@ -904,7 +878,7 @@ fn bindgen_test_layout_vfio_irq_info() {
         concat!("Alignment of ", stringify!(vfio_irq_info))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_irq_info>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_irq_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -914,7 +888,7 @@ fn bindgen_test_layout_vfio_irq_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_irq_info>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_irq_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -924,7 +898,7 @@ fn bindgen_test_layout_vfio_irq_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_irq_info>())).index as *const _ as usize },
+        ::std::mem::offset_of!(vfio_irq_info, index),
         8usize,
         concat!(
             "Offset of field: ",
@@ -934,7 +908,7 @@ fn bindgen_test_layout_vfio_irq_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_irq_info>())).count as *const _ as usize },
+        ::std::mem::offset_of!(vfio_irq_info, count),
         12usize,
         concat!(
             "Offset of field: ",
@@ -1053,9 +1027,7 @@ fn bindgen_test_layout_vfio_pci_dependent_device() {
         concat!("Alignment of ", stringify!(vfio_pci_dependent_device))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_pci_dependent_device>())).group_id as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_pci_dependent_device, group_id),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1065,9 +1037,7 @@ fn bindgen_test_layout_vfio_pci_dependent_device() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_pci_dependent_device>())).segment as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_pci_dependent_device, segment),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1077,7 +1047,7 @@ fn bindgen_test_layout_vfio_pci_dependent_device() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_pci_dependent_device>())).bus as *const _ as usize },
+        ::std::mem::offset_of!(vfio_pci_dependent_device, bus),
         6usize,
         concat!(
             "Offset of field: ",
@@ -1087,7 +1057,7 @@ fn bindgen_test_layout_vfio_pci_dependent_device() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_pci_dependent_device>())).devfn as *const _ as usize },
+        ::std::mem::offset_of!(vfio_pci_dependent_device, devfn),
         7usize,
         concat!(
             "Offset of field: ",
@@ -1210,10 +1180,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info__bindgen_ty_1>())).region_index
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info__bindgen_ty_1, region_index),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1223,10 +1190,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info__bindgen_ty_1>())).dmabuf_id
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info__bindgen_ty_1, dmabuf_id),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1254,9 +1218,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         concat!("Alignment of ", stringify!(vfio_device_gfx_plane_info))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).argsz as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1266,9 +1228,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).flags as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1278,10 +1238,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).drm_plane_type as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, drm_plane_type),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1291,9 +1248,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).drm_format as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, drm_format),
         12usize,
         concat!(
             "Offset of field: ",
@@ -1303,10 +1258,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).drm_format_mod as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, drm_format_mod),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1316,9 +1268,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).width as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, width),
         24usize,
         concat!(
             "Offset of field: ",
@@ -1328,9 +1278,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).height as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, height),
         28usize,
         concat!(
             "Offset of field: ",
@@ -1340,9 +1288,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).stride as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, stride),
         32usize,
         concat!(
             "Offset of field: ",
@@ -1352,7 +1298,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).size as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, size),
         36usize,
         concat!(
             "Offset of field: ",
@@ -1362,9 +1308,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).x_pos as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, x_pos),
         40usize,
         concat!(
             "Offset of field: ",
@@ -1374,9 +1318,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).y_pos as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, y_pos),
         44usize,
         concat!(
             "Offset of field: ",
@@ -1386,9 +1328,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).x_hot as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, x_hot),
         48usize,
         concat!(
             "Offset of field: ",
@@ -1398,9 +1338,7 @@ fn bindgen_test_layout_vfio_device_gfx_plane_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_device_gfx_plane_info>())).y_hot as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_device_gfx_plane_info, y_hot),
         52usize,
         concat!(
             "Offset of field: ",
@@ -1447,7 +1385,7 @@ fn bindgen_test_layout_vfio_device_ioeventfd() {
         concat!("Alignment of ", stringify!(vfio_device_ioeventfd))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_ioeventfd>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_ioeventfd, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1457,7 +1395,7 @@ fn bindgen_test_layout_vfio_device_ioeventfd() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_ioeventfd>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_ioeventfd, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1467,7 +1405,7 @@ fn bindgen_test_layout_vfio_device_ioeventfd() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_ioeventfd>())).offset as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_ioeventfd, offset),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1477,7 +1415,7 @@ fn bindgen_test_layout_vfio_device_ioeventfd() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_ioeventfd>())).data as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_ioeventfd, data),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1487,7 +1425,7 @@ fn bindgen_test_layout_vfio_device_ioeventfd() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_device_ioeventfd>())).fd as *const _ as usize },
+        ::std::mem::offset_of!(vfio_device_ioeventfd, fd),
         24usize,
         concat!(
             "Offset of field: ",
@@ -1523,7 +1461,7 @@ fn bindgen_test_layout_vfio_iommu_type1_info() {
         concat!("Alignment of ", stringify!(vfio_iommu_type1_info))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_info>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1533,7 +1471,7 @@ fn bindgen_test_layout_vfio_iommu_type1_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_info>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1543,9 +1481,7 @@ fn bindgen_test_layout_vfio_iommu_type1_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_type1_info>())).iova_pgsizes as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_type1_info, iova_pgsizes),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1581,7 +1517,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_map() {
         concat!("Alignment of ", stringify!(vfio_iommu_type1_dma_map))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_map>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_map, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1591,7 +1527,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_map() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_map>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_map, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1601,7 +1537,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_map() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_map>())).vaddr as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_map, vaddr),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1611,7 +1547,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_map() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_map>())).iova as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_map, iova),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1621,7 +1557,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_map() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_map>())).size as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_map, size),
         24usize,
         concat!(
             "Offset of field: ",
@@ -1660,9 +1596,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_unmap() {
         concat!("Alignment of ", stringify!(vfio_iommu_type1_dma_unmap))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_type1_dma_unmap>())).argsz as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_unmap, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1672,9 +1606,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_unmap() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_type1_dma_unmap>())).flags as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_unmap, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1684,7 +1616,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_unmap() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_unmap>())).iova as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_unmap, iova),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1694,7 +1626,7 @@ fn bindgen_test_layout_vfio_iommu_type1_dma_unmap() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_type1_dma_unmap>())).size as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_type1_dma_unmap, size),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1724,9 +1656,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_ddw_info() {
         concat!("Alignment of ", stringify!(vfio_iommu_spapr_tce_ddw_info))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_ddw_info>())).pgsizes as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_ddw_info, pgsizes),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1736,10 +1666,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_ddw_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_ddw_info>())).max_dynamic_windows_supported
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_ddw_info, max_dynamic_windows_supported),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1749,9 +1676,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_ddw_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_ddw_info>())).levels as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_ddw_info, levels),
         12usize,
         concat!(
             "Offset of field: ",
@@ -1783,7 +1708,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_info() {
         concat!("Alignment of ", stringify!(vfio_iommu_spapr_tce_info))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_spapr_tce_info>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_info, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1793,7 +1718,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_spapr_tce_info>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_info, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1803,10 +1728,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_info>())).dma32_window_start as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_info, dma32_window_start),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1816,10 +1738,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_info() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_info>())).dma32_window_size as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_info, dma32_window_size),
         12usize,
         concat!(
             "Offset of field: ",
@@ -1829,7 +1748,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_info() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_iommu_spapr_tce_info>())).ddw as *const _ as usize },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_info, ddw),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1860,7 +1779,7 @@ fn bindgen_test_layout_vfio_eeh_pe_err() {
         concat!("Alignment of ", stringify!(vfio_eeh_pe_err))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_err>())).type_ as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_err, type_),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1870,7 +1789,7 @@ fn bindgen_test_layout_vfio_eeh_pe_err() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_err>())).func as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_err, func),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1880,7 +1799,7 @@ fn bindgen_test_layout_vfio_eeh_pe_err() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_err>())).addr as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_err, addr),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1890,7 +1809,7 @@ fn bindgen_test_layout_vfio_eeh_pe_err() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_err>())).mask as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_err, mask),
         16usize,
         concat!(
             "Offset of field: ",
@@ -1927,9 +1846,7 @@ fn bindgen_test_layout_vfio_eeh_pe_op__bindgen_ty_1() {
         concat!("Alignment of ", stringify!(vfio_eeh_pe_op__bindgen_ty_1))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_eeh_pe_op__bindgen_ty_1>())).err as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_eeh_pe_op__bindgen_ty_1, err),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1957,7 +1874,7 @@ fn bindgen_test_layout_vfio_eeh_pe_op() {
         concat!("Alignment of ", stringify!(vfio_eeh_pe_op))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_op>())).argsz as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_op, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1967,7 +1884,7 @@ fn bindgen_test_layout_vfio_eeh_pe_op() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_op>())).flags as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_op, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1977,7 +1894,7 @@ fn bindgen_test_layout_vfio_eeh_pe_op() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<vfio_eeh_pe_op>())).op as *const _ as usize },
+        ::std::mem::offset_of!(vfio_eeh_pe_op, op),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2022,9 +1939,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_register_memory() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_register_memory>())).argsz as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_register_memory, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2034,9 +1949,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_register_memory() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_register_memory>())).flags as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_register_memory, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -2046,9 +1959,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_register_memory() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_register_memory>())).vaddr as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_register_memory, vaddr),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2058,9 +1969,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_register_memory() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_register_memory>())).size as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_register_memory, size),
         16usize,
         concat!(
             "Offset of field: ",
@@ -2102,9 +2011,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         concat!("Alignment of ", stringify!(vfio_iommu_spapr_tce_create))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).argsz as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2114,9 +2021,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).flags as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -2126,9 +2031,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).page_shift as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, page_shift),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2138,9 +2041,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).__resv1 as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, __resv1),
         12usize,
         concat!(
             "Offset of field: ",
@@ -2150,9 +2051,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).window_size as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, window_size),
         16usize,
         concat!(
             "Offset of field: ",
@@ -2162,9 +2061,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).levels as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, levels),
         24usize,
         concat!(
             "Offset of field: ",
@@ -2174,9 +2071,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).__resv2 as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, __resv2),
         28usize,
         concat!(
             "Offset of field: ",
@@ -2186,9 +2081,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_create() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_create>())).start_addr as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_create, start_addr),
         32usize,
         concat!(
             "Offset of field: ",
@@ -2222,9 +2115,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_remove() {
         concat!("Alignment of ", stringify!(vfio_iommu_spapr_tce_remove))
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_remove>())).argsz as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_remove, argsz),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2234,9 +2125,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_remove() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_remove>())).flags as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_remove, flags),
         4usize,
         concat!(
             "Offset of field: ",
@@ -2246,9 +2135,7 @@ fn bindgen_test_layout_vfio_iommu_spapr_tce_remove() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<vfio_iommu_spapr_tce_remove>())).start_addr as *const _ as usize
-        },
+        ::std::mem::offset_of!(vfio_iommu_spapr_tce_remove, start_addr),
         8usize,
         concat!(
             "Offset of field: ",
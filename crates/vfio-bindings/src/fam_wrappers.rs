@@ -29,7 +29,7 @@ mod tests {
     use std::mem;
 
     fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
-        let rounded_size = (size_in_bytes + mem::size_of::<T>() - 1) / mem::size_of::<T>();
+        let rounded_size = size_in_bytes.div_ceil(mem::size_of::<T>());
         let mut v = Vec::with_capacity(rounded_size);
         for _ in 0..rounded_size {
             v.push(T::default())
@@ -64,7 +64,7 @@ mod tests {
 
         // Build a FAM wrapper for this vfio_irq_set.
         let mut irq_set_wrapper = IrqSet::new(event_fds.len() * mem::size_of::<u32>()).unwrap();
-        let mut irq_set_fam = irq_set_wrapper.as_mut_fam_struct();
+        let irq_set_fam = irq_set_wrapper.as_mut_fam_struct();
 
         let fds_fam = irq_set_fam.as_mut_slice();
         for (index, event_fd) in event_fds.iter().enumerate() {
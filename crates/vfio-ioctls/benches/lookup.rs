@@ -0,0 +1,90 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Benchmarks of the region and interrupt lookups of a VFIO device.
+//!
+//! The interrupt lookups are compared with a `HashMap` indexed the same way, as the interrupts
+//! used to be stored. They need a PCI device bound to the `vfio-pci` driver:
+//!
+//! ```text
+//! VFIO_TEST_DEVICE=0000:03:00.0 cargo bench --bench lookup
+//! ```
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use vfio_bindings::bindings::vfio::*;
+use vfio_ioctls::{VfioContainer, VfioDevice, VfioIrq};
+
+const TEST_DEVICE_ENV: &str = "VFIO_TEST_DEVICE";
+const ITERATIONS: u32 = 1_000_000;
+
+// Run `f` ITERATIONS times and print the average duration of a call.
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up the caches first.
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>8.1} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+    );
+}
+
+fn main() {
+    let bdf = match std::env::var(TEST_DEVICE_ENV) {
+        Ok(bdf) => bdf,
+        Err(_) => {
+            eprintln!(
+                "Skipping the lookup benchmarks, {} isn't set to the address of a device bound \
+                 to vfio-pci",
+                TEST_DEVICE_ENV
+            );
+            return;
+        }
+    };
+    let path = PathBuf::from("/sys/bus/pci/devices").join(bdf);
+    let container = Arc::new(VfioContainer::new(None).unwrap());
+    let device = VfioDevice::new(&path, container).unwrap();
+
+    bench("get_region_size", || {
+        for index in 0..VFIO_PCI_NUM_REGIONS {
+            black_box(device.get_region_size(black_box(index)));
+        }
+    });
+    bench("get_region_flags", || {
+        for index in 0..VFIO_PCI_NUM_REGIONS {
+            black_box(device.get_region_flags(black_box(index)));
+        }
+    });
+    bench("region_class", || {
+        for index in 0..VFIO_PCI_NUM_REGIONS {
+            black_box(device.region_class(black_box(index)));
+        }
+    });
+
+    bench("irq_info", || {
+        for index in 0..VFIO_PCI_NUM_IRQS {
+            black_box(device.irq_info(black_box(index)));
+        }
+    });
+    let irqs: HashMap<u32, VfioIrq> = (0..VFIO_PCI_NUM_IRQS)
+        .filter_map(|index| device.irq_info(index).map(|irq| (index, irq)))
+        .collect();
+    bench("HashMap::get (baseline)", || {
+        for index in 0..VFIO_PCI_NUM_IRQS {
+            black_box(irqs.get(&black_box(index)));
+        }
+    });
+}
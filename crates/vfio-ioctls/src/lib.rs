@@ -45,6 +45,10 @@
 //! wrappers for:
 //! - [VFIO Container](struct.VfioContainer.html) using the `VfioContainer` structure
 //! - [VFIO Device](struct.VfioDevice.html) using the `VfioDevice` structure
+//! - [IOMMUFD IO address space](struct.IommufdContainer.html) using the `IommufdContainer`
+//!   structure
+//! - [VFIO interrupt dispatching](struct.VfioIrqDispatcher.html) using the `VfioIrqDispatcher`
+//!   structure, with the `irq-dispatcher` feature
 //!
 //! # Platform support
 //!
@@ -58,41 +62,146 @@
 extern crate vmm_sys_util;
 
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 use vmm_sys_util::errno::Error as SysError;
 
 mod fam;
+mod vfio_bar_plan;
 mod vfio_device;
+mod vfio_device_builder;
+mod vfio_dirty_bitmap;
+mod vfio_dma_addr;
+mod vfio_dma_ext;
+mod vfio_dma_fault;
+mod vfio_handover;
+mod vfio_host_caps;
 mod vfio_ioctls;
+mod vfio_iommufd;
+#[cfg(feature = "irq-dispatcher")]
+mod vfio_irq_dispatcher;
+mod vfio_irq_set;
+mod vfio_migration;
+mod vfio_msix;
+mod vfio_passthrough;
+mod vfio_paths;
+mod vfio_pci;
+mod vfio_quirks;
+mod vfio_reset;
+mod vfio_topology;
+#[cfg(feature = "vga-arbiter")]
+mod vfio_vga_arbiter;
 
+pub use vfio_bar_plan::{
+    plan_guest_bars, BarPlacementConstraints, GuestBar, PciBarInfo, PciBarKind,
+};
 pub use vfio_device::{
-    VfioContainer, VfioDevice, VfioDeviceFd, VfioGroup, VfioIrq, VfioRegion, VfioRegionInfoCap,
+    KnownVendorRegion, MappingUnmap, MultiGroupPolicy, PgsizeLimit, RegionPgsizeReport,
+    RegionUnmap, TagUnmapReport, UnmapOutcome, UnmapReport, VfioContainer, VfioContainerRef,
+    VfioDevice, VfioDeviceFd, VfioDeviceFlags, VfioDmaMapping, VfioGroup, VfioIovaRange, VfioIrq,
+    VfioRegion, VfioRegionAccessPolicy, VfioRegionClass, VfioRegionGuestMapping, VfioRegionInfoCap,
     VfioRegionInfoCapNvlink2Lnkspd, VfioRegionInfoCapNvlink2Ssatgt, VfioRegionInfoCapSparseMmap,
-    VfioRegionInfoCapType, VfioRegionSparseMmapArea,
+    VfioRegionInfoCapType, VfioRegionMmap, VfioRegionSparseMmapArea,
+};
+pub use vfio_device_builder::VfioDeviceBuilder;
+pub use vfio_dirty_bitmap::{DirtyBitmapChunk, DirtyBitmapIter};
+pub use vfio_dma_addr::{HostVa, Iova};
+pub use vfio_dma_ext::VfioDmaExt;
+pub use vfio_dma_fault::{parse_dma_fault, parse_dma_faults, DmaFaultIommu, DmaFaultRecord};
+pub use vfio_handover::{
+    ContainerStateSnapshot, DeviceStateSnapshot, GroupStateSnapshot, IrqStateSnapshot,
+    MappingTagSnapshot, VFIO_CONTAINER_STATE_VERSION,
+};
+pub use vfio_host_caps::{
+    diagnose_iommu, diagnose_iommu_with_paths, probe_host_capabilities,
+    probe_host_capabilities_with_paths, HostVfioCapabilities, IommuDiagnosis, OpenContainerHint,
+};
+pub use vfio_ioctls::{
+    VFIO_DMA_MAP_FLAG_VADDR, VFIO_DMA_UNMAP_FLAG_ALL, VFIO_DMA_UNMAP_FLAG_GET_DIRTY_BITMAP,
+    VFIO_DMA_UNMAP_FLAG_VADDR,
+};
+pub use vfio_iommufd::IommufdContainer;
+#[cfg(feature = "irq-dispatcher")]
+pub use vfio_irq_dispatcher::{VfioIrqDispatcher, VfioIrqHandler};
+pub use vfio_migration::VfioMigrationChunks;
+pub use vfio_msix::{MsixTableAccessPolicy, MsixTableAccessor};
+pub use vfio_passthrough::{validate_device_passthrough, PassthroughBlocker, PassthroughReport};
+pub use vfio_paths::{MdevInfo, VfioGroupDevice, VfioPaths, VfioReservedRegion};
+pub use vfio_pci::{
+    BufferedConfigWriter, MsiCapInfo, MsixInfo, MsixVectorStat, PciCommand, PciStatus,
+    SriovCapInfo, VfioPciCapability, VfioPciConfig, VfioPciConfigReadHook, VfioPciConfigWriteHook,
+    VfioPciExtCapability,
+};
+pub use vfio_quirks::{
+    detect_known_quirks, known_quirks, KnownQuirk, QuirkRemediation, VfioPciModuleParam,
+};
+pub use vfio_reset::{
+    VfioDeviceGroupReset, VfioPciAddress, VfioPciDependentDevice, VfioResetPlan, VfioResetStep,
+    VfioResetTarget,
+};
+pub use vfio_topology::{VfioIrqTopology, VfioRegionTopology, VfioTopology, VFIO_TOPOLOGY_VERSION};
+#[cfg(feature = "vga-arbiter")]
+pub use vfio_vga_arbiter::{
+    parse_vga_arbiter_status, vga_cards, VgaArbiter, VgaCard, VgaCardStatus, VgaResources,
 };
 
 /// Error codes for VFIO operations.
+///
+/// Use [`VfioError::kind()`] to branch on the category of an error rather than on its variant.
 #[derive(Debug, Error)]
 #[allow(missing_docs)]
+#[non_exhaustive]
 pub enum VfioError {
-    #[error("failed to open /dev/vfio/vfio container: {0}")]
-    OpenContainer(#[source] io::Error),
-    #[error("failed to open /dev/vfio/{1} group: {0}")]
+    #[error(
+        "failed to open vfio container: {0}{}",
+        .1.map(|hint| format!(", {}", hint)).unwrap_or_default()
+    )]
+    OpenContainer(#[source] io::Error, Option<OpenContainerHint>),
+    #[error("failed to open vfio group {1}: {0}")]
     OpenGroup(#[source] io::Error, String),
+    #[error("failed to open iommufd: {0}")]
+    OpenIommufd(#[source] io::Error),
     #[error("failed to get Group Status")]
     GetGroupStatus,
-    #[error("group is not viable")]
-    GroupViable,
+    #[error("vfio group {group_id} is not viable, devices bound to other drivers: {devices:?}")]
+    GroupViable { group_id: u32, devices: Vec<String> },
+    #[error(
+        "vfio group {group_id} is still not viable (status {status:#x}), devices bound to other \
+         drivers: {devices:?}"
+    )]
+    GroupViableTimeout {
+        group_id: u32,
+        status: u32,
+        devices: Vec<String>,
+    },
+    #[error("vfio device {device} still isn't ready {timeout:?} after its reset")]
+    DeviceResetTimeout { device: String, timeout: Duration },
     #[error("vfio API version doesn't match with VFIO_API_VERSION defined in vfio-bindings")]
     VfioApiVersion,
     #[error("failed to check VFIO extension")]
     VfioExtension,
+    #[error("the type1 IOMMU backend isn't available, the IOMMU may not be enabled: {diagnosis}")]
+    IommuNotEnabled { diagnosis: IommuDiagnosis },
     #[error("invalid VFIO type")]
     VfioInvalidType,
     #[error("container doesn't support VfioType1V2 IOMMU driver type")]
     VfioType1V2,
     #[error("failed to add vfio group into vfio container")]
     GroupSetContainer,
+    #[error(
+        "vfio group {group_id} is busy, it may still be attached to a container after a failed \
+         setup or be used by another process, retry later"
+    )]
+    GroupBusy { group_id: u32 },
+    #[error(
+        "vfio group {group_id} was refused by the container with EINVAL, the kernel may not \
+         support adding groups once the IOMMU is set"
+    )]
+    GroupSetContainerInvalid { group_id: u32 },
+    #[error("vfio group {0} isn't attached to the container")]
+    GroupNotAttached(u32),
+    #[error("vfio group {0} isn't detached from the container")]
+    GroupNotDetached(u32),
     #[error("failed to unset vfio container")]
     UnsetContainer,
     #[error("failed to set container's IOMMU driver type as VfioType1V2")]
@@ -105,12 +214,54 @@ pub enum VfioError {
     VfioDeviceGetInfo,
     #[error("failed to get vfio device's region info: {0}")]
     VfioDeviceGetRegionInfo(#[source] SysError),
+    #[error("invalid vfio device region index {0}")]
+    InvalidRegionIndex(u32),
+    #[error("vfio device region {0} doesn't support mmap")]
+    RegionNotMappable(u32),
+    #[error("vfio device region {0} isn't exposed, its type is unknown")]
+    RegionNotExposed(u32),
+    #[error("vfio device region {0} can only be mmap'ed through its sparse areas")]
+    RegionSparseMmapOnly(u32),
+    #[error("vfio device region {index} can't be mapped at guest address {gpa:#x}")]
+    InvalidRegionGuestAddress { index: u32, gpa: u64 },
     #[error("invalid file path")]
     InvalidPath,
+    #[error("failed to read {1}: {0}")]
+    ReadSysfs(#[source] io::Error, String),
+    #[error("failed to write {1}: {0}")]
+    WriteSysfs(#[source] io::Error, String),
+    #[error("invalid reserved region entry: {0}")]
+    InvalidReservedRegion(String),
+    #[error("invalid container state: {0}")]
+    InvalidContainerState(String),
+    #[error("dma mapping must grant read or write access")]
+    IommuDmaMapNoAccess,
+    #[error("dma mapping at {iova:#x} of size {size:#x} doesn't fit in a single iova window")]
+    IommuDmaMapCrossWindow { iova: u64, size: u64 },
+    #[error(
+        "dma mapping host address {vaddr:#x} isn't aligned to the iommu page size {page_size:#x}"
+    )]
+    IommuDmaMapUnalignedVaddr { vaddr: u64, page_size: u64 },
+    #[error("dma mapping size {size:#x} isn't a multiple of the page size {page_size:#x}")]
+    IommuDmaMapUnalignedSize { size: u64, page_size: u64 },
+    #[error("failed to get iommu info: {0}")]
+    IommuGetInfo(#[source] SysError),
     #[error("failed to add guest memory map into iommu table: {0}")]
     IommuDmaMap(#[source] SysError),
     #[error("failed to remove guest memory map from iommu table: {0}")]
     IommuDmaUnmap(#[source] SysError),
+    #[error("failed to allocate an iommufd IO address space: {0}")]
+    IommufdIoasAlloc(#[source] SysError),
+    #[error("failed to map memory into an iommufd IO address space: {0}")]
+    IommufdIoasMap(#[source] SysError),
+    #[error("failed to unmap memory from an iommufd IO address space: {0}")]
+    IommufdIoasUnmap(#[source] SysError),
+    #[error("failed to bind vfio device to iommufd: {0}")]
+    IommufdBindDevice(#[source] SysError),
+    #[error("failed to attach vfio device to an iommufd IO address space: {0}")]
+    IommufdAttachDevice(#[source] SysError),
+    #[error("failed to detach vfio device from an iommufd IO address space: {0}")]
+    IommufdDetachDevice(#[source] SysError),
     #[error("failed to get vfio device irq info")]
     VfioDeviceGetIrqInfo,
     #[error("failed to set vfio device irq")]
@@ -121,16 +272,288 @@ pub enum VfioError {
     VfioDeviceDisableIrq,
     #[error("failed to unmask vfio device irq")]
     VfioDeviceUnmaskIrq,
+    #[error("failed to mask vfio device irq")]
+    VfioDeviceMaskIrq,
     #[error("failed to trigger vfio device irq")]
     VfioDeviceTriggerIrq,
+    #[error("failed to drain vfio device irq: {0}")]
+    VfioDeviceDrainIrq(#[source] io::Error),
+    #[error("failed to read vfio device's msi-x pending bit array: {0}")]
+    ReadMsixPba(#[source] io::Error),
+    #[error("vfio device has no msi-x capability")]
+    NoMsixCapability,
+    #[error("failed to read the kernel log for dma faults: {0}")]
+    ReadDmaFaults(#[source] io::Error),
+    #[error("failed to reset vfio device: {0}")]
+    VfioDeviceReset(#[source] SysError),
+    #[error("failed to get vfio device's pci hot reset info: {0}")]
+    VfioDeviceHotResetInfo(#[source] SysError),
+    #[error("failed to pci hot reset vfio device: {0}")]
+    VfioDevicePciHotReset(#[source] SysError),
+    #[error("pci hot reset needs vfio group {0}, which isn't owned")]
+    HotResetGroupNotOwned(u32),
+    #[error("failed to access vfio device feature: {0}")]
+    VfioDeviceFeature(#[source] SysError),
+    #[error("failed to read vfio device migration data: {0}")]
+    ReadMigrationData(#[source] io::Error),
+    #[error("failed to access vfio device config space: {0}")]
+    VfioDeviceConfigAccess(#[source] io::Error),
+    #[error("failed to access vfio device region: {0}")]
+    VfioDeviceRegionAccess(#[source] io::Error),
+    #[error("failed to mmap vfio device region: {0}")]
+    VfioDeviceRegionMmap(#[source] io::Error),
+    #[error("mmap protection {prot:#x} isn't allowed by the flags of vfio device region {index}")]
+    RegionMmapProt { index: u32, prot: i32 },
+    #[error("invalid access of {size:#x} bytes at {addr:#x} of vfio device region {index}")]
+    InvalidRegionAccess { index: u32, addr: u64, size: u64 },
+    #[error("invalid maximum access width {max_width} of vfio device region {index}")]
+    InvalidRegionAccessWidth { index: u32, max_width: u64 },
+    #[error("vfio device is opened read-only")]
+    DeviceReadOnly,
+    #[error("device is gone, it couldn't be opened again")]
+    DeviceGone,
     #[error("failed to duplicate fd")]
     VfioDeviceDupFd,
     #[error("wrong device fd type")]
     VfioDeviceFdWrongType,
     #[error("failed to get host address")]
     GetHostAddress,
+    #[error(
+        "dma mapping of {requested:#x} bytes exceeds the budget of {budget:#x} bytes, \
+         {used:#x} bytes are already mapped"
+    )]
+    MappingBudgetExceeded {
+        requested: u64,
+        used: u64,
+        budget: u64,
+    },
+    #[error("region {index} reports capabilities of {argsz:#x} bytes, beyond the limit")]
+    RegionCapsTooLarge { index: u32, argsz: u32 },
+    #[error(
+        "dma mapping at {iova:#x} of {size:#x} bytes is beyond the dma mask {mask:#x} of device \
+         {device}"
+    )]
+    IommuDmaMapBeyondDmaMask {
+        iova: u64,
+        size: u64,
+        device: String,
+        mask: u64,
+    },
+    #[error("sparse mmap area at {offset:#x} of {size:#x} bytes is out of region {index}")]
+    InvalidSparseMmapArea { index: u32, offset: u64, size: u64 },
+    #[error("the kernel doesn't report the available dma mappings")]
+    DmaAvailUnsupported,
+    #[error("the kernel doesn't report the iommu page sizes")]
+    IommuPgsizesUnsupported,
+    #[error("no room in the guest window for bar {index} of {size:#x} bytes")]
+    GuestBarNoRoom { index: u32, size: u64 },
+    #[error("no dma mapping contains {iova:#x} of size {size:#x}")]
+    IommuDmaNoMapping { iova: u64, size: u64 },
+    #[error("failed to remap dma mapping: {error}, and to restore it: {restore}")]
+    IommuDmaRestore {
+        error: Box<VfioError>,
+        restore: Box<VfioError>,
+    },
     #[error("invalid dma unmap size")]
     InvalidDmaUnmapSize,
+    #[error("failed to remove guest memory from iommu table: {0}")]
+    GuestMemoryUnmap(UnmapReport),
+    #[error("failed to dispatch vfio device irq: {0}")]
+    IrqDispatcher(#[source] io::Error),
+    #[error("failed to access the iommu dirty pages tracking: {0}")]
+    IommuDirtyPages(#[source] SysError),
+    #[error(
+        "invalid dirty bitmap range of {size:#x} bytes at {iova:#x} with pages of {page_size:#x} \
+         bytes"
+    )]
+    InvalidDirtyBitmapRange {
+        iova: u64,
+        size: u64,
+        page_size: u64,
+    },
+    #[error("failed to access the vga arbiter: {0}")]
+    VgaArbiter(#[source] io::Error),
+    #[error("invalid vga arbiter status: {0}")]
+    InvalidVgaArbiterStatus(String),
+    #[error("legacy vga resources of {device} aren't locked")]
+    VgaResourcesNotLocked { device: String },
+    #[error("can't lock legacy vga resources of {device} while holding those of {holder}")]
+    VgaArbiterConflict { device: String, holder: String },
+}
+
+/// Categories of [`VfioError`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum VfioErrorKind {
+    /// The caller lacks the permissions or the ownership needed.
+    PermissionDenied,
+    /// A device, group, or VFIO file doesn't exist.
+    NotFound,
+    /// A resource is in use, retrying later may succeed.
+    Busy,
+    /// The kernel, IOMMU or device doesn't support the operation.
+    Unsupported,
+    /// The caller passed invalid arguments or used the API incorrectly.
+    InvalidInput,
+    /// A limit was reached.
+    ResourceExhausted,
+    /// Any other failure reported by the kernel or the device.
+    Other,
+}
+
+impl VfioErrorKind {
+    fn from_errno(errno: i32) -> Option<Self> {
+        match errno {
+            libc::EACCES | libc::EPERM => Some(VfioErrorKind::PermissionDenied),
+            libc::ENOENT | libc::ENODEV | libc::ENXIO => Some(VfioErrorKind::NotFound),
+            libc::EBUSY => Some(VfioErrorKind::Busy),
+            libc::ENOTTY | libc::EOPNOTSUPP | libc::ENOSYS => Some(VfioErrorKind::Unsupported),
+            libc::ENOMEM | libc::ENOSPC => Some(VfioErrorKind::ResourceExhausted),
+            _ => None,
+        }
+    }
+}
+
+impl VfioError {
+    /// Get the category of the error.
+    ///
+    /// Errors carrying an errno are categorized from it when it is meaningful, and from the
+    /// failed operation otherwise.
+    pub fn kind(&self) -> VfioErrorKind {
+        use VfioErrorKind::*;
+
+        let errno = match self {
+            VfioError::OpenContainer(e, _)
+            | VfioError::OpenGroup(e, _)
+            | VfioError::OpenIommufd(e)
+            | VfioError::ReadSysfs(e, _)
+            | VfioError::WriteSysfs(e, _)
+            | VfioError::VfioDeviceDrainIrq(e)
+            | VfioError::ReadMsixPba(e)
+            | VfioError::ReadDmaFaults(e)
+            | VfioError::ReadMigrationData(e)
+            | VfioError::VfioDeviceConfigAccess(e)
+            | VfioError::VfioDeviceRegionAccess(e)
+            | VfioError::VfioDeviceRegionMmap(e)
+            | VfioError::IrqDispatcher(e)
+            | VfioError::VgaArbiter(e) => e.raw_os_error(),
+            VfioError::SetDeviceAttr(e)
+            | VfioError::VfioDeviceGetRegionInfo(e)
+            | VfioError::IommuGetInfo(e)
+            | VfioError::IommuDmaMap(e)
+            | VfioError::IommuDmaUnmap(e)
+            | VfioError::IommufdIoasAlloc(e)
+            | VfioError::IommufdIoasMap(e)
+            | VfioError::IommufdIoasUnmap(e)
+            | VfioError::IommufdBindDevice(e)
+            | VfioError::IommufdAttachDevice(e)
+            | VfioError::IommufdDetachDevice(e)
+            | VfioError::VfioDeviceReset(e)
+            | VfioError::VfioDeviceHotResetInfo(e)
+            | VfioError::VfioDevicePciHotReset(e)
+            | VfioError::VfioDeviceFeature(e)
+            | VfioError::IommuDirtyPages(e) => Some(e.errno()),
+            _ => None,
+        };
+        if let Some(kind) = errno.and_then(VfioErrorKind::from_errno) {
+            return kind;
+        }
+
+        match self {
+            VfioError::GroupBusy { .. }
+            | VfioError::GroupViableTimeout { .. }
+            | VfioError::DeviceResetTimeout { .. }
+            | VfioError::VgaArbiterConflict { .. } => Busy,
+            VfioError::GroupGetDeviceFD
+            | VfioError::InvalidPath
+            | VfioError::GroupNotAttached(_)
+            | VfioError::GroupNotDetached(_)
+            | VfioError::DeviceGone => NotFound,
+            VfioError::HotResetGroupNotOwned(_)
+            | VfioError::DeviceReadOnly
+            | VfioError::RegionNotExposed(_) => PermissionDenied,
+            VfioError::VfioApiVersion
+            | VfioError::VfioExtension
+            | VfioError::IommuNotEnabled { .. }
+            | VfioError::VfioType1V2
+            | VfioError::GroupSetContainerInvalid { .. }
+            | VfioError::RegionNotMappable(_)
+            | VfioError::NoMsixCapability
+            | VfioError::DmaAvailUnsupported
+            | VfioError::IommuPgsizesUnsupported => Unsupported,
+            VfioError::VfioInvalidType
+            | VfioError::InvalidRegionIndex(_)
+            | VfioError::InvalidRegionAccess { .. }
+            | VfioError::InvalidRegionAccessWidth { .. }
+            | VfioError::RegionMmapProt { .. }
+            | VfioError::RegionSparseMmapOnly(_)
+            | VfioError::InvalidRegionGuestAddress { .. }
+            | VfioError::IommuDmaMapNoAccess
+            | VfioError::IommuDmaMapCrossWindow { .. }
+            | VfioError::IommuDmaMapUnalignedVaddr { .. }
+            | VfioError::IommuDmaMapUnalignedSize { .. }
+            | VfioError::IommuDmaNoMapping { .. }
+            | VfioError::IommuDmaMapBeyondDmaMask { .. }
+            | VfioError::InvalidSparseMmapArea { .. }
+            | VfioError::VfioDeviceFdWrongType
+            | VfioError::InvalidContainerState(_)
+            | VfioError::VgaResourcesNotLocked { .. }
+            | VfioError::InvalidDirtyBitmapRange { .. }
+            | VfioError::GetHostAddress => InvalidInput,
+            VfioError::MappingBudgetExceeded { .. }
+            | VfioError::RegionCapsTooLarge { .. }
+            | VfioError::GuestBarNoRoom { .. } => ResourceExhausted,
+            VfioError::IommuDmaRestore { error, .. } => error.kind(),
+            VfioError::OpenContainer(_, _)
+            | VfioError::OpenGroup(_, _)
+            | VfioError::OpenIommufd(_)
+            | VfioError::GetGroupStatus
+            | VfioError::GroupViable { .. }
+            | VfioError::GroupSetContainer
+            | VfioError::UnsetContainer
+            | VfioError::ContainerSetIOMMU
+            | VfioError::SetDeviceAttr(_)
+            | VfioError::VfioDeviceGetInfo
+            | VfioError::VfioDeviceGetRegionInfo(_)
+            | VfioError::ReadSysfs(_, _)
+            | VfioError::WriteSysfs(_, _)
+            | VfioError::InvalidReservedRegion(_)
+            | VfioError::IommuGetInfo(_)
+            | VfioError::IommuDmaMap(_)
+            | VfioError::IommuDmaUnmap(_)
+            | VfioError::IommufdIoasAlloc(_)
+            | VfioError::IommufdIoasMap(_)
+            | VfioError::IommufdIoasUnmap(_)
+            | VfioError::IommufdBindDevice(_)
+            | VfioError::IommufdAttachDevice(_)
+            | VfioError::IommufdDetachDevice(_)
+            | VfioError::VfioDeviceGetIrqInfo
+            | VfioError::VfioDeviceSetIrq
+            | VfioError::VfioDeviceEnableIrq
+            | VfioError::VfioDeviceDisableIrq
+            | VfioError::VfioDeviceUnmaskIrq
+            | VfioError::VfioDeviceMaskIrq
+            | VfioError::VfioDeviceTriggerIrq
+            | VfioError::VfioDeviceDrainIrq(_)
+            | VfioError::ReadMsixPba(_)
+            | VfioError::ReadDmaFaults(_)
+            | VfioError::VfioDeviceReset(_)
+            | VfioError::VfioDeviceHotResetInfo(_)
+            | VfioError::VfioDevicePciHotReset(_)
+            | VfioError::VfioDeviceFeature(_)
+            | VfioError::ReadMigrationData(_)
+            | VfioError::VfioDeviceConfigAccess(_)
+            | VfioError::VfioDeviceRegionAccess(_)
+            | VfioError::VfioDeviceRegionMmap(_)
+            | VfioError::VfioDeviceDupFd
+            | VfioError::InvalidDmaUnmapSize
+            | VfioError::GuestMemoryUnmap(_)
+            | VfioError::IrqDispatcher(_)
+            | VfioError::IommuDirtyPages(_)
+            | VfioError::VgaArbiter(_)
+            | VfioError::InvalidVgaArbiterStatus(_) => Other,
+        }
+    }
 }
 
 /// Specialized version of `Result` for VFIO subsystem.
@@ -144,11 +567,275 @@ mod tests {
     #[test]
     fn test_vfio_error_fmt() {
         let e = VfioError::GetGroupStatus;
-        let e2 = VfioError::OpenContainer(std::io::Error::from(std::io::ErrorKind::Other));
+        let e2 = VfioError::OpenContainer(std::io::Error::from(std::io::ErrorKind::Other), None);
         let str = format!("{}", e);
 
         assert_eq!(&str, "failed to get Group Status");
         assert!(e2.source().is_some());
         assert!(e.source().is_none());
+        assert_eq!(
+            format!(
+                "{}",
+                VfioError::OpenContainer(
+                    std::io::Error::from_raw_os_error(libc::ENOENT),
+                    Some(OpenContainerHint::ModuleNotLoaded)
+                )
+            ),
+            "failed to open vfio container: No such file or directory (os error 2), the vfio \
+             module may not be loaded, try `modprobe vfio-pci`"
+        );
+    }
+
+    #[test]
+    fn test_vfio_error_kind() {
+        use VfioErrorKind::*;
+
+        let io = || io::Error::from_raw_os_error(libc::EIO);
+        let sys = || SysError::new(libc::EIO);
+        let boxed = |e| Box::new(e);
+        let table = vec![
+            (VfioError::OpenContainer(io(), None), Other),
+            (VfioError::OpenGroup(io(), String::new()), Other),
+            (VfioError::OpenIommufd(io()), Other),
+            (VfioError::GetGroupStatus, Other),
+            (
+                VfioError::GroupViable {
+                    group_id: 1,
+                    devices: Vec::new(),
+                },
+                Other,
+            ),
+            (
+                VfioError::GroupViableTimeout {
+                    group_id: 1,
+                    status: 0,
+                    devices: Vec::new(),
+                },
+                Busy,
+            ),
+            (VfioError::VfioApiVersion, Unsupported),
+            (VfioError::VfioExtension, Unsupported),
+            (
+                VfioError::IommuNotEnabled {
+                    diagnosis: IommuDiagnosis::default(),
+                },
+                Unsupported,
+            ),
+            (VfioError::VfioInvalidType, InvalidInput),
+            (VfioError::VfioType1V2, Unsupported),
+            (VfioError::GroupSetContainer, Other),
+            (VfioError::GroupBusy { group_id: 1 }, Busy),
+            (
+                VfioError::DeviceResetTimeout {
+                    device: String::new(),
+                    timeout: Duration::from_secs(1),
+                },
+                Busy,
+            ),
+            (
+                VfioError::GroupSetContainerInvalid { group_id: 1 },
+                Unsupported,
+            ),
+            (VfioError::GroupNotAttached(1), NotFound),
+            (VfioError::GroupNotDetached(1), NotFound),
+            (VfioError::UnsetContainer, Other),
+            (VfioError::ContainerSetIOMMU, Other),
+            (VfioError::GroupGetDeviceFD, NotFound),
+            (VfioError::SetDeviceAttr(sys()), Other),
+            (VfioError::VfioDeviceGetInfo, Other),
+            (VfioError::VfioDeviceGetRegionInfo(sys()), Other),
+            (VfioError::InvalidRegionIndex(9), InvalidInput),
+            (
+                VfioError::InvalidRegionAccess {
+                    index: 0,
+                    addr: 0,
+                    size: 0,
+                },
+                InvalidInput,
+            ),
+            (
+                VfioError::InvalidRegionAccessWidth {
+                    index: 0,
+                    max_width: 3,
+                },
+                InvalidInput,
+            ),
+            (VfioError::RegionNotMappable(0), Unsupported),
+            (VfioError::RegionNotExposed(0), PermissionDenied),
+            (VfioError::RegionSparseMmapOnly(0), InvalidInput),
+            (
+                VfioError::InvalidRegionGuestAddress { index: 0, gpa: 0 },
+                InvalidInput,
+            ),
+            (VfioError::InvalidPath, NotFound),
+            (VfioError::ReadSysfs(io(), String::new()), Other),
+            (VfioError::WriteSysfs(io(), String::new()), Other),
+            (VfioError::InvalidReservedRegion(String::new()), Other),
+            (
+                VfioError::InvalidContainerState(String::new()),
+                InvalidInput,
+            ),
+            (VfioError::IommuDmaMapNoAccess, InvalidInput),
+            (
+                VfioError::IommuDmaMapCrossWindow { iova: 0, size: 0 },
+                InvalidInput,
+            ),
+            (
+                VfioError::IommuDmaMapUnalignedVaddr {
+                    vaddr: 1,
+                    page_size: 0x1000,
+                },
+                InvalidInput,
+            ),
+            (
+                VfioError::IommuDmaMapUnalignedSize {
+                    size: 0x800,
+                    page_size: 0x1000,
+                },
+                InvalidInput,
+            ),
+            (VfioError::IommuGetInfo(sys()), Other),
+            (VfioError::IommuDmaMap(sys()), Other),
+            (VfioError::IommuDmaUnmap(sys()), Other),
+            (VfioError::IommufdIoasAlloc(sys()), Other),
+            (VfioError::IommufdIoasMap(sys()), Other),
+            (VfioError::IommufdIoasUnmap(sys()), Other),
+            (VfioError::IommufdBindDevice(sys()), Other),
+            (VfioError::IommufdAttachDevice(sys()), Other),
+            (VfioError::IommufdDetachDevice(sys()), Other),
+            (VfioError::VfioDeviceGetIrqInfo, Other),
+            (VfioError::VfioDeviceSetIrq, Other),
+            (VfioError::VfioDeviceEnableIrq, Other),
+            (VfioError::VfioDeviceDisableIrq, Other),
+            (VfioError::VfioDeviceUnmaskIrq, Other),
+            (VfioError::VfioDeviceMaskIrq, Other),
+            (VfioError::VfioDeviceTriggerIrq, Other),
+            (VfioError::VfioDeviceDrainIrq(io()), Other),
+            (VfioError::ReadMsixPba(io()), Other),
+            (VfioError::NoMsixCapability, Unsupported),
+            (VfioError::ReadDmaFaults(io()), Other),
+            (VfioError::VfioDeviceReset(sys()), Other),
+            (VfioError::VfioDeviceHotResetInfo(sys()), Other),
+            (VfioError::VfioDevicePciHotReset(sys()), Other),
+            (VfioError::HotResetGroupNotOwned(2), PermissionDenied),
+            (VfioError::VfioDeviceFeature(sys()), Other),
+            (VfioError::ReadMigrationData(io()), Other),
+            (VfioError::VfioDeviceConfigAccess(io()), Other),
+            (VfioError::VfioDeviceRegionAccess(io()), Other),
+            (VfioError::VfioDeviceRegionMmap(io()), Other),
+            (
+                VfioError::RegionMmapProt {
+                    index: 0,
+                    prot: libc::PROT_WRITE,
+                },
+                InvalidInput,
+            ),
+            (VfioError::DeviceReadOnly, PermissionDenied),
+            (VfioError::DeviceGone, NotFound),
+            (VfioError::DmaAvailUnsupported, Unsupported),
+            (VfioError::IommuPgsizesUnsupported, Unsupported),
+            (VfioError::VfioDeviceDupFd, Other),
+            (VfioError::VfioDeviceFdWrongType, InvalidInput),
+            (VfioError::GetHostAddress, InvalidInput),
+            (
+                VfioError::MappingBudgetExceeded {
+                    requested: 1,
+                    used: 0,
+                    budget: 0,
+                },
+                ResourceExhausted,
+            ),
+            (
+                VfioError::RegionCapsTooLarge {
+                    index: 1,
+                    argsz: u32::MAX,
+                },
+                ResourceExhausted,
+            ),
+            (
+                VfioError::InvalidSparseMmapArea {
+                    index: 1,
+                    offset: 0x1000,
+                    size: 0x1000,
+                },
+                InvalidInput,
+            ),
+            (
+                VfioError::GuestBarNoRoom {
+                    index: 0,
+                    size: 0x1000,
+                },
+                ResourceExhausted,
+            ),
+            (
+                VfioError::IommuDmaNoMapping { iova: 0, size: 0 },
+                InvalidInput,
+            ),
+            (
+                VfioError::IommuDmaMapBeyondDmaMask {
+                    iova: 1 << 32,
+                    size: 0x1000,
+                    device: "0000:00:01.0".to_string(),
+                    mask: u32::MAX.into(),
+                },
+                InvalidInput,
+            ),
+            (
+                VfioError::IommuDmaRestore {
+                    error: boxed(VfioError::GroupBusy { group_id: 1 }),
+                    restore: boxed(VfioError::IommuDmaMap(sys())),
+                },
+                Busy,
+            ),
+            (VfioError::InvalidDmaUnmapSize, Other),
+            (VfioError::GuestMemoryUnmap(UnmapReport::default()), Other),
+            (VfioError::IrqDispatcher(io()), Other),
+            (VfioError::IommuDirtyPages(sys()), Other),
+            (
+                VfioError::InvalidDirtyBitmapRange {
+                    iova: 0,
+                    size: 0,
+                    page_size: 0,
+                },
+                InvalidInput,
+            ),
+            (VfioError::VgaArbiter(io()), Other),
+            (VfioError::InvalidVgaArbiterStatus(String::new()), Other),
+            (
+                VfioError::VgaResourcesNotLocked {
+                    device: String::new(),
+                },
+                InvalidInput,
+            ),
+            (
+                VfioError::VgaArbiterConflict {
+                    device: String::new(),
+                    holder: String::new(),
+                },
+                Busy,
+            ),
+        ];
+        for (error, kind) in table {
+            assert_eq!(error.kind(), kind, "{:?}", error);
+        }
+
+        // Meaningful errnos take precedence over the failed operation.
+        let errno_table = vec![
+            (libc::EACCES, PermissionDenied),
+            (libc::EPERM, PermissionDenied),
+            (libc::ENOENT, NotFound),
+            (libc::ENODEV, NotFound),
+            (libc::EBUSY, Busy),
+            (libc::ENOTTY, Unsupported),
+            (libc::ENOSPC, ResourceExhausted),
+            (libc::EINVAL, Other),
+        ];
+        for (errno, kind) in errno_table {
+            assert_eq!(VfioError::IommuDmaMap(SysError::new(errno)).kind(), kind);
+            assert_eq!(
+                VfioError::OpenContainer(io::Error::from_raw_os_error(errno), None).kind(),
+                kind
+            );
+        }
     }
 }
@@ -0,0 +1,359 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::cmp::Reverse;
+
+use vfio_bindings::bindings::vfio::VFIO_PCI_BAR0_REGION_INDEX;
+
+use crate::{Result, VfioDevice, VfioError};
+
+// Offset of the first BAR register in the PCI configuration space, and number of BARs.
+const PCI_BASE_ADDRESS_0: u64 = 0x10;
+const PCI_NUM_BARS: u32 = 6;
+
+// Bits of the BAR registers.
+const PCI_BASE_ADDRESS_SPACE_IO: u32 = 1 << 0;
+const PCI_BASE_ADDRESS_MEM_TYPE_MASK: u32 = 0x6;
+const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0x4;
+const PCI_BASE_ADDRESS_MEM_PREFETCH: u32 = 1 << 3;
+
+/// Address space decoded by a PCI BAR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PciBarKind {
+    /// I/O port space.
+    Io,
+    /// Memory space, with a 32 bits address.
+    Mem32,
+    /// Memory space, with a 64 bits address spanning two BAR registers.
+    Mem64,
+}
+
+/// A BAR implemented by a PCI device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PciBarInfo {
+    /// Index of the BAR, the lower one for 64 bits BARs.
+    pub index: u32,
+    /// Address space decoded by the BAR.
+    pub kind: PciBarKind,
+    /// The memory behind the BAR is prefetchable.
+    pub prefetchable: bool,
+    /// Size of the BAR in bytes.
+    pub size: u64,
+}
+
+/// Guest address windows BARs are placed in by [`plan_guest_bars()`].
+///
+/// Windows are given as a base and a size, a zero size window receives no BAR.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BarPlacementConstraints {
+    /// Base of the I/O port window.
+    pub io_base: u64,
+    /// Size of the I/O port window.
+    pub io_size: u64,
+    /// Base of the memory window below 4 GiB.
+    pub mmio32_base: u64,
+    /// Size of the memory window below 4 GiB.
+    pub mmio32_size: u64,
+    /// Base of the 64 bits memory window.
+    pub mmio64_base: u64,
+    /// Size of the 64 bits memory window, 64 bits BARs go below 4 GiB when zero.
+    pub mmio64_size: u64,
+    /// Smallest size of the memory BARs in the guest, such as the host page size for the BARs
+    /// to be mapped into the guest, zero to keep the sizes of the device.
+    pub mmio_min_size: u64,
+}
+
+/// A BAR placed in the guest address space by [`plan_guest_bars()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GuestBar {
+    /// Index of the BAR, the lower one for 64 bits BARs.
+    pub index: u32,
+    /// Index of the VFIO region backing the BAR.
+    pub region_index: u32,
+    /// Address space decoded by the BAR.
+    pub kind: PciBarKind,
+    /// The memory behind the BAR is prefetchable.
+    pub prefetchable: bool,
+    /// Guest address of the BAR, naturally aligned.
+    pub address: u64,
+    /// Size of the BAR in the guest, a power of two at least as large as the BAR of the device.
+    pub size: u64,
+}
+
+// Bump allocator over a window, for naturally aligned power of two sizes.
+struct Window {
+    next: u64,
+    end: u64,
+}
+
+impl Window {
+    fn new(base: u64, size: u64) -> Self {
+        Window {
+            next: base,
+            end: base.saturating_add(size),
+        }
+    }
+
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let address = self.next.checked_add(size - 1)? & !(size - 1);
+        let end = address.checked_add(size)?;
+        if end > self.end {
+            return None;
+        }
+        self.next = end;
+        Some(address)
+    }
+}
+
+/// Place the BARs of a device in the guest address space.
+///
+/// Each BAR gets a naturally aligned address in its window: I/O BARs in the I/O window, 32 bits
+/// memory BARs below 4 GiB and 64 bits memory BARs in the 64 bits window. Within a window, the
+/// prefetchable BARs are placed together after the other ones, largest first so that alignment
+/// wastes no space. Sizes are rounded up to a power of two, and memory BARs to
+/// `mmio_min_size`. The plan is returned in BAR order.
+///
+/// Fails with `VfioError::GuestBarNoRoom` if a window is too small for its BARs.
+///
+/// # Parameters
+/// * bars: BARs of the device, such as returned by `VfioDevice::pci_bars()`.
+/// * constraints: guest windows to place the BARs in.
+pub fn plan_guest_bars(
+    bars: &[PciBarInfo],
+    constraints: &BarPlacementConstraints,
+) -> Result<Vec<GuestBar>> {
+    let mut io = Window::new(constraints.io_base, constraints.io_size);
+    let mut mmio32 = Window::new(constraints.mmio32_base, constraints.mmio32_size);
+    let mut mmio64 = Window::new(constraints.mmio64_base, constraints.mmio64_size);
+
+    let mut plan: Vec<GuestBar> = bars
+        .iter()
+        .map(|bar| {
+            let mut size = bar.size.max(1);
+            if bar.kind != PciBarKind::Io {
+                size = size.max(constraints.mmio_min_size);
+            }
+            GuestBar {
+                index: bar.index,
+                region_index: VFIO_PCI_BAR0_REGION_INDEX + bar.index,
+                kind: bar.kind,
+                prefetchable: bar.prefetchable,
+                address: 0,
+                size: size.checked_next_power_of_two().unwrap_or(0),
+            }
+        })
+        .collect();
+    plan.sort_by_key(|bar| (bar.prefetchable, Reverse(bar.size), bar.index));
+
+    for bar in plan.iter_mut() {
+        let window = match bar.kind {
+            PciBarKind::Io => &mut io,
+            PciBarKind::Mem64 if constraints.mmio64_size != 0 => &mut mmio64,
+            PciBarKind::Mem32 | PciBarKind::Mem64 => &mut mmio32,
+        };
+        bar.address = match bar.size {
+            0 => None,
+            size => window.allocate(size),
+        }
+        .ok_or(VfioError::GuestBarNoRoom {
+            index: bar.index,
+            size: bar.size,
+        })?;
+    }
+    plan.sort_by_key(|bar| bar.index);
+
+    Ok(plan)
+}
+
+impl VfioDevice {
+    /// Get the BARs implemented by the PCI device.
+    ///
+    /// The kind of each BAR is read from its register in the configuration space, and its size
+    /// from the VFIO region backing it. Unimplemented BARs, and the upper halves of 64 bits
+    /// BARs, aren't listed. The expansion ROM isn't a BAR here.
+    pub fn pci_bars(&self) -> Result<Vec<PciBarInfo>> {
+        let config = self.config();
+        let mut bars = Vec::new();
+        let mut index = 0;
+
+        while index < PCI_NUM_BARS {
+            let size = self.get_region_size(VFIO_PCI_BAR0_REGION_INDEX + index);
+            let reg = config.read_u32(PCI_BASE_ADDRESS_0 + u64::from(index) * 4)?;
+            let (kind, prefetchable) = if reg & PCI_BASE_ADDRESS_SPACE_IO != 0 {
+                (PciBarKind::Io, false)
+            } else if reg & PCI_BASE_ADDRESS_MEM_TYPE_MASK == PCI_BASE_ADDRESS_MEM_TYPE_64 {
+                (PciBarKind::Mem64, reg & PCI_BASE_ADDRESS_MEM_PREFETCH != 0)
+            } else {
+                (PciBarKind::Mem32, reg & PCI_BASE_ADDRESS_MEM_PREFETCH != 0)
+            };
+            if size != 0 {
+                bars.push(PciBarInfo {
+                    index,
+                    kind,
+                    prefetchable,
+                    size,
+                });
+            }
+            index += if kind == PciBarKind::Mem64 { 2 } else { 1 };
+        }
+
+        Ok(bars)
+    }
+
+    /// Place the BARs of the PCI device in the guest address space.
+    ///
+    /// This is [`plan_guest_bars()`] applied to [`pci_bars()`](Self::pci_bars). The plan is only
+    /// a proposal, nothing is programmed into the device.
+    ///
+    /// # Parameters
+    /// * constraints: guest windows to place the BARs in.
+    pub fn guest_bar_plan(&self, constraints: BarPlacementConstraints) -> Result<Vec<GuestBar>> {
+        plan_guest_bars(&self.pci_bars()?, &constraints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_pci::tests::create_vfio_device;
+
+    const GIB: u64 = 1 << 30;
+
+    fn bar(index: u32, kind: PciBarKind, prefetchable: bool, size: u64) -> PciBarInfo {
+        PciBarInfo {
+            index,
+            kind,
+            prefetchable,
+            size,
+        }
+    }
+
+    fn constraints() -> BarPlacementConstraints {
+        BarPlacementConstraints {
+            io_base: 0x1000,
+            io_size: 0x1000,
+            mmio32_base: 3 * GIB,
+            mmio32_size: GIB,
+            mmio64_base: 64 * GIB,
+            mmio64_size: 64 * GIB,
+            mmio_min_size: 0x1000,
+        }
+    }
+
+    fn placements(plan: &[GuestBar]) -> Vec<(u32, u64, u64)> {
+        plan.iter().map(|b| (b.index, b.address, b.size)).collect()
+    }
+
+    #[test]
+    fn test_vfio_bar_plan_mixed() {
+        let bars = [
+            bar(0, PciBarKind::Mem32, false, 0x100),
+            bar(1, PciBarKind::Mem64, true, 16 * GIB),
+            bar(3, PciBarKind::Mem32, false, 0x4000),
+            bar(4, PciBarKind::Io, false, 0x20),
+            bar(5, PciBarKind::Mem32, true, 0x10_0000),
+        ];
+        let plan = plan_guest_bars(&bars, &constraints()).unwrap();
+        assert_eq!(
+            placements(&plan),
+            [
+                // Rounded up to a page, after the larger non-prefetchable BAR.
+                (0, 3 * GIB + 0x4000, 0x1000),
+                (1, 64 * GIB, 16 * GIB),
+                (3, 3 * GIB, 0x4000),
+                (4, 0x1000, 0x20),
+                // Prefetchable BARs come after the other ones, naturally aligned.
+                (5, 3 * GIB + 0x10_0000, 0x10_0000),
+            ]
+        );
+        assert_eq!(
+            plan.iter().map(|b| b.region_index).collect::<Vec<_>>(),
+            [0, 1, 3, 4, 5]
+        );
+        assert_eq!(plan[1].kind, PciBarKind::Mem64);
+        assert!(plan[1].prefetchable);
+    }
+
+    #[test]
+    fn test_vfio_bar_plan_windows() {
+        let bars = [
+            bar(0, PciBarKind::Mem64, false, 0x3000),
+            bar(2, PciBarKind::Mem64, true, 0x8000),
+        ];
+
+        // Without a 64 bits window, 64 bits BARs go below 4 GiB, the size is rounded up.
+        let mut constraints = constraints();
+        constraints.mmio64_size = 0;
+        let plan = plan_guest_bars(&bars, &constraints).unwrap();
+        assert_eq!(
+            placements(&plan),
+            [(0, 3 * GIB, 0x4000), (2, 3 * GIB + 0x8000, 0x8000)]
+        );
+
+        // Windows too small for the BARs, or an unaligned base the BAR doesn't fit after.
+        constraints.mmio32_size = 0x8000;
+        assert!(matches!(
+            plan_guest_bars(&bars, &constraints),
+            Err(VfioError::GuestBarNoRoom {
+                index: 2,
+                size: 0x8000
+            })
+        ));
+        constraints.mmio64_base = u64::MAX - 0x1_0000;
+        constraints.mmio64_size = 0x1_0000;
+        assert!(matches!(
+            plan_guest_bars(&bars, &constraints),
+            Err(VfioError::GuestBarNoRoom { .. })
+        ));
+        let io = [bar(0, PciBarKind::Io, false, 0x100)];
+        constraints.io_size = 0;
+        assert!(matches!(
+            plan_guest_bars(&io, &constraints),
+            Err(VfioError::GuestBarNoRoom {
+                index: 0,
+                size: 0x100
+            })
+        ));
+
+        assert!(plan_guest_bars(&[], &constraints).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_bar_plan_device() {
+        // BAR0 is 32 bits, BAR1 I/O and BAR2 with BAR3 a 64 bits prefetchable BAR. BAR4 and BAR5
+        // read as 32 bits BARs.
+        let mut config = vec![0u8; 0x100];
+        config[0x10..0x14].copy_from_slice(&0u32.to_le_bytes());
+        config[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+        config[0x18..0x1c].copy_from_slice(&0xcu32.to_le_bytes());
+        config[0x20..0x24].copy_from_slice(&0u32.to_le_bytes());
+        let device = create_vfio_device(&config);
+
+        // The regions of BAR0 to BAR5 are 0x1000 to 0x6000 bytes long.
+        assert_eq!(
+            device.pci_bars().unwrap(),
+            [
+                bar(0, PciBarKind::Mem32, false, 0x1000),
+                bar(1, PciBarKind::Io, false, 0x2000),
+                bar(2, PciBarKind::Mem64, true, 0x3000),
+                bar(4, PciBarKind::Mem32, false, 0x5000),
+                bar(5, PciBarKind::Mem32, false, 0x6000),
+            ]
+        );
+
+        let mut constraints = constraints();
+        constraints.io_size = 0x1_0000;
+        let plan = device.guest_bar_plan(constraints).unwrap();
+        assert_eq!(
+            placements(&plan),
+            [
+                (0, 3 * GIB + 0x1_0000, 0x1000),
+                (1, 0x2000, 0x2000),
+                (2, 64 * GIB, 0x4000),
+                (4, 3 * GIB, 0x8000),
+                (5, 3 * GIB + 0x8000, 0x8000),
+            ]
+        );
+    }
+}
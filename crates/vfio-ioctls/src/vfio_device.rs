@@ -3,26 +3,40 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::CString;
-use std::fs::{File, OpenOptions};
-use std::mem::{self, ManuallyDrop};
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::OwnedFd;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::prelude::FileExt;
-use std::path::Path;
-#[cfg(not(test))]
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
-use byteorder::{ByteOrder, LittleEndian};
 use log::{debug, error, warn};
+use once_cell::sync::OnceCell;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use vfio_bindings::bindings::vfio::*;
-use vm_memory::{Address, GuestMemory, GuestMemoryRegion, MemoryRegionAddress};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryRegion, MemoryRegionAddress, VolatileSlice,
+};
+use vmm_sys_util::errno::Error as SysError;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::fam::vec_with_array_field;
 use crate::vfio_ioctls::*;
-use crate::{Result, VfioError};
+use crate::vfio_irq_set::{IrqSetAction, IrqSetData, VfioIrqSet};
+use crate::vfio_pci::PciConfigState;
+use crate::{diagnose_iommu_with_paths, HostVa, Iova, MdevInfo, Result, VfioError, VfioPaths};
 #[cfg(all(feature = "kvm", not(test)))]
 use kvm_bindings::{
     kvm_device_attr, KVM_DEV_VFIO_GROUP, KVM_DEV_VFIO_GROUP_ADD, KVM_DEV_VFIO_GROUP_DEL,
@@ -51,6 +65,9 @@ enum DeviceFdInner {
 
 #[derive(Debug)]
 /// A wrapper for a device fd from either KVM or MSHV.
+///
+/// Both hypervisors may be enabled at build time, the one used by a container is then
+/// selected at runtime by the constructor used for its device fd.
 pub struct VfioDeviceFd(DeviceFdInner);
 
 impl VfioDeviceFd {
@@ -153,6 +170,295 @@ impl vfio_region_info_with_cap {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Default)]
+// A VFIO IOMMU info structure with an incomplete array for the IOMMU
+// capabilities information, fetched the same way as vfio_region_info_with_cap.
+pub struct vfio_iommu_info_with_cap {
+    pub info: vfio_iommu_type1_info_v2,
+    cap_info: __IncompleteArrayField<u8>,
+}
+
+impl vfio_iommu_info_with_cap {
+    fn with_argsz(argsz: u32) -> Vec<Self> {
+        let info_size = mem::size_of::<vfio_iommu_type1_info_v2>();
+        let cap_len = (argsz as usize).saturating_sub(info_size);
+
+        let mut info_with_cap = vec_with_array_field::<Self, u8>(cap_len);
+        info_with_cap[0].info.argsz = argsz.max(info_size as u32);
+
+        info_with_cap
+    }
+}
+
+// Threshold of available DMA mappings below which an EventFd is signaled, see
+// VfioContainer::set_dma_avail_watermark().
+pub(crate) struct DmaAvailWatermark {
+    threshold: u32,
+    notify: EventFd,
+    // The available mappings are below the threshold, as of the last query.
+    below: bool,
+    // Mappings which can still be created before the threshold may be crossed.
+    maps_until_query: u32,
+}
+
+impl DmaAvailWatermark {
+    // Update the state with the available mappings, signaling the EventFd when crossing the
+    // threshold downward.
+    fn update(&mut self, avail: u32) {
+        let below = avail < self.threshold;
+        if below && !self.below {
+            if let Err(e) = self.notify.write(1) {
+                warn!("Failed to signal the dma avail watermark: {}", e);
+            }
+        }
+        self.below = below;
+        // Each mapping consumes one entry and unmapping only makes more available, so the
+        // threshold can't be crossed before that many mappings. Once below it, query on every
+        // mapping to notice when it is crossed again.
+        self.maps_until_query = avail.saturating_sub(self.threshold).saturating_add(1);
+    }
+}
+
+/// A range of IO virtual addresses usable for DMA mappings, as reported by the IOMMU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VfioIovaRange {
+    /// First IO virtual address of the range.
+    pub start: u64,
+    /// Last IO virtual address of the range, inclusive.
+    pub end: u64,
+}
+
+// IOMMU properties of a container, fixed once its IOMMU backend is set.
+#[derive(Clone, Debug)]
+pub(crate) struct VfioIommuInfo {
+    // Bitmap of the supported IOMMU page sizes, 0 when not reported.
+    pgsizes: u64,
+    // Dirty page tracking is supported.
+    dirty_bitmap: bool,
+    iova_ranges: Vec<VfioIovaRange>,
+}
+
+/// A DMA mapping established in the IOMMU table of a VFIO container.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VfioDmaMapping {
+    /// IO virtual address of the mapping.
+    pub iova: Iova,
+    /// Size of the mapping.
+    pub size: u64,
+    /// Host virtual address backing the mapping.
+    pub user_addr: HostVa,
+    /// `VFIO_DMA_MAP_FLAG_*` flags the mapping was created with.
+    pub flags: u32,
+}
+
+impl VfioDmaMapping {
+    fn dma_map(&self) -> vfio_iommu_type1_dma_map {
+        vfio_iommu_type1_dma_map {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
+            flags: self.flags,
+            vaddr: self.user_addr.raw_value(),
+            iova: self.iova.raw_value(),
+            size: self.size,
+        }
+    }
+
+    fn dma_unmap(&self) -> vfio_iommu_type1_dma_unmap {
+        vfio_iommu_type1_dma_unmap {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
+            flags: 0,
+            iova: self.iova.raw_value(),
+            size: self.size,
+        }
+    }
+}
+
+/// Alignment constraint preventing the IOMMU from using a larger page size for a region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PgsizeLimit {
+    /// The IO virtual address of the region isn't aligned to the larger page size.
+    IovaMisaligned,
+    /// The host virtual address backing the region isn't aligned to the larger page size.
+    VaddrMisaligned,
+    /// The size of the region isn't a multiple of the larger page size.
+    SizeRemainder,
+}
+
+/// The largest IOMMU page size a guest memory region can be mapped with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegionPgsizeReport {
+    /// Guest physical address of the region.
+    pub guest_addr: u64,
+    /// IO virtual address of the region.
+    pub iova: u64,
+    /// Host virtual address backing the region.
+    pub host_addr: u64,
+    /// Size of the region.
+    pub size: u64,
+    /// Largest supported IOMMU page size the region is aligned to, 0 if none.
+    pub pgsize: u64,
+    /// Why the next supported page size can't be used, `None` if the region is mapped with
+    /// the largest page size it is big enough for.
+    pub limited_by: Option<PgsizeLimit>,
+}
+
+impl RegionPgsizeReport {
+    fn new(pgsizes: u64, guest_addr: u64, iova: u64, host_addr: u64, size: u64) -> Self {
+        let mut report = RegionPgsizeReport {
+            guest_addr,
+            iova,
+            host_addr,
+            size,
+            pgsize: 0,
+            limited_by: None,
+        };
+
+        // Supported page sizes are powers of two, so alignment to one implies alignment to
+        // all the smaller ones.
+        for shift in 0..u64::BITS {
+            let pgsize = 1u64 << shift;
+            if pgsizes & pgsize == 0 {
+                continue;
+            }
+            let mask = pgsize - 1;
+            let limit = if iova & mask != 0 {
+                Some(PgsizeLimit::IovaMisaligned)
+            } else if host_addr & mask != 0 {
+                Some(PgsizeLimit::VaddrMisaligned)
+            } else if size & mask != 0 {
+                Some(PgsizeLimit::SizeRemainder)
+            } else {
+                None
+            };
+            match limit {
+                None => report.pgsize = pgsize,
+                Some(limit) => {
+                    // A region smaller than the page size can't use it whatever its alignment.
+                    if size >= pgsize {
+                        report.limited_by = Some(limit);
+                    }
+                    break;
+                }
+            }
+        }
+
+        report
+    }
+}
+
+/// Outcome of unmapping a guest memory region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnmapOutcome {
+    /// The region was unmapped.
+    Unmapped,
+    /// No DMA mapping of the container covered the region.
+    NotMapped,
+    /// Unmapping the region failed with this errno.
+    Failed(i32),
+}
+
+/// Outcome of unmapping one guest memory region with
+/// [`VfioContainer::vfio_unmap_guest_memory_best_effort()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegionUnmap {
+    /// Guest physical address of the region.
+    pub guest_addr: u64,
+    /// Size of the region.
+    pub size: u64,
+    /// What happened to the region.
+    pub outcome: UnmapOutcome,
+}
+
+/// Outcomes of unmapping the regions of a guest memory, in the order of the regions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnmapReport {
+    /// Outcome of each region.
+    pub regions: Vec<RegionUnmap>,
+}
+
+impl UnmapReport {
+    fn count(&self, pred: impl Fn(&UnmapOutcome) -> bool) -> usize {
+        self.regions.iter().filter(|r| pred(&r.outcome)).count()
+    }
+
+    /// Get the number of regions which failed to be unmapped.
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, UnmapOutcome::Failed(_)))
+    }
+}
+
+/// Outcome of unmapping one DMA mapping with [`VfioContainer::unmap_by_tag()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MappingUnmap {
+    /// The DMA mapping.
+    pub mapping: VfioDmaMapping,
+    /// What happened to the mapping.
+    pub outcome: UnmapOutcome,
+}
+
+/// Outcomes of unmapping the DMA mappings of a tag, sorted by IOVA.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagUnmapReport {
+    /// Outcome of each mapping.
+    pub mappings: Vec<MappingUnmap>,
+}
+
+impl TagUnmapReport {
+    /// Get the number of mappings which failed to be unmapped.
+    pub fn failed(&self) -> usize {
+        self.mappings
+            .iter()
+            .filter(|m| matches!(m.outcome, UnmapOutcome::Failed(_)))
+            .count()
+    }
+}
+
+impl fmt::Display for UnmapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} unmapped, {} not mapped, {} failed",
+            self.count(|o| *o == UnmapOutcome::Unmapped),
+            self.count(|o| *o == UnmapOutcome::NotMapped),
+            self.failed()
+        )?;
+        for region in self.regions.iter() {
+            if let UnmapOutcome::Failed(errno) = region.outcome {
+                write!(
+                    f,
+                    "; {:#x}+{:#x}: {}",
+                    region.guest_addr,
+                    region.size,
+                    io::Error::from_raw_os_error(errno)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a container binds the groups of a kernel refusing to add groups to a container once its
+/// IOMMU is set.
+///
+/// Such kernels fail `VFIO_GROUP_SET_CONTAINER` with `EINVAL` for any group but the first one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MultiGroupPolicy {
+    /// All the groups share the container, failing with `VfioError::GroupSetContainerInvalid`.
+    #[default]
+    Shared,
+    /// Bind the groups refused by the kernel to a container of their own, see
+    /// [`VfioContainer::set_multi_group_policy()`].
+    SharedOrFallbackToPerGroup,
+}
+
+// Interval between two checks of the readiness of a device after its reset.
+const RESET_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Maximum number of buffers of a preadv() or pwritev() call.
+const IOV_MAX: usize = 1024;
+
 /// A safe wrapper over a VFIO container object.
 ///
 /// A VFIO container represents an IOMMU domain, or a set of IO virtual address translation tables.
@@ -163,37 +469,185 @@ impl vfio_region_info_with_cap {
 ///
 /// Multiple VFIO groups may be associated with the same VFIO container to share the underline
 /// address translation mapping tables.
+///
+/// A container is shared between subsystems through an `Arc`, see [`share()`](Self::share).
+/// All the handles operate on the same IOMMU domain: the DMA mappings and the group bindings
+/// are serialized by the container locks, so they can be changed concurrently from any handle.
+///
+/// Query methods which don't modify the IOMMU state are also offered by the read only view
+/// returned by [`read_only()`](Self::read_only).
 pub struct VfioContainer {
-    pub(crate) container: File,
+    pub(crate) container: OwnedFd,
     #[allow(dead_code)]
     pub(crate) device_fd: Option<VfioContainerDeviceHandle>,
     pub(crate) groups: Mutex<HashMap<u32, Arc<VfioGroup>>>,
+    // Thread holding the groups lock through lock_groups(), to detect re-entrant use when
+    // dropping a device.
+    pub(crate) groups_owner: Mutex<Option<ThreadId>>,
+    // IDs of the groups bound to the container, excluding the ones bound to a container of
+    // their own. Held while running VFIO_GROUP_SET_CONTAINER and VFIO_GROUP_UNSET_CONTAINER, to
+    // know which group sets the IOMMU backend. Never locked before `groups`.
+    pub(crate) bound_groups: Mutex<HashSet<u32>>,
+    // Groups detached with detach_group() while their devices are still alive.
+    pub(crate) detached_groups: Mutex<HashMap<u32, Arc<VfioGroup>>>,
+    pub(crate) iova_base: AtomicU64,
+    pub(crate) paths: VfioPaths,
+    // DMA mappings established through this container, indexed by IOVA.
+    pub(crate) mappings: Mutex<BTreeMap<u64, VfioDmaMapping>>,
+    // Tags of the DMA mappings which have one, indexed by IOVA. Locked after `mappings`.
+    pub(crate) mapping_tags: Mutex<HashMap<u64, Cow<'static, str>>>,
+    // Maximum number of bytes mapped through this container.
+    pub(crate) mapping_budget: Mutex<Option<u64>>,
+    // Largest region info, capabilities included, fetched from the kernel for the devices.
+    pub(crate) region_caps_max_size: Mutex<u32>,
+    // Notification of the available DMA mappings running low, checked after each mapping.
+    pub(crate) dma_avail_watermark: Mutex<Option<DmaAvailWatermark>>,
+    // Names and DMA masks of the devices of the container which set one, indexed by device ID.
+    pub(crate) dma_masks: Mutex<HashMap<u64, (String, u64)>>,
+    // IOMMU properties, queried on first use. The cell is replaced to query them again.
+    pub(crate) iommu_info: RwLock<OnceCell<Arc<VfioIommuInfo>>>,
+    // Timeout and poll interval of the wait for new groups to become viable.
+    pub(crate) group_viable_wait: Mutex<Option<(Duration, Duration)>>,
+    // IDs of the groups being opened or released, which is done without holding the groups
+    // lock.
+    pub(crate) group_transitions: Mutex<HashSet<u32>>,
+    pub(crate) group_transition_done: Condvar,
+    pub(crate) multi_group_policy: Mutex<MultiGroupPolicy>,
+    // Containers of the groups the kernel refused to add to this one, indexed by group ID, which
+    // mirror its DMA mappings.
+    pub(crate) group_containers: Mutex<HashMap<u32, VfioContainer>>,
 }
 
 impl VfioContainer {
+    #[cfg(not(test))]
+    fn open_container_file(paths: &VfioPaths) -> Result<OwnedFd> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(paths.container_path())
+            .map(OwnedFd::from)
+            .map_err(|e| {
+                let hint = crate::OpenContainerHint::from_io_error(&e);
+                VfioError::OpenContainer(e, hint)
+            })
+    }
+
     /// Create a container wrapper object.
     ///
     /// # Arguments
     /// * `device_fd`: An optional file handle of the hypervisor VFIO device.
     pub fn new(device_fd: Option<VfioContainerDeviceHandle>) -> Result<Self> {
-        let container = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/vfio/vfio")
-            .map_err(VfioError::OpenContainer)?;
+        Self::new_with_paths(device_fd, VfioPaths::default())
+    }
 
-        let container = VfioContainer {
-            container,
-            device_fd,
-            groups: Mutex::new(HashMap::new()),
-        };
+    /// Create a container wrapper object using non-standard VFIO device and sysfs locations.
+    ///
+    /// The common setup mistakes are reported with their likely cause: a container device which
+    /// can't be opened carries an [`OpenContainerHint`](crate::OpenContainerHint), and a missing
+    /// type1 IOMMU backend fails with [`VfioError::IommuNotEnabled`], diagnosed by
+    /// [`diagnose_iommu_with_paths()`].
+    ///
+    /// # Arguments
+    /// * `device_fd`: An optional file handle of the hypervisor VFIO device.
+    /// * `paths`: Locations of the VFIO character devices and of the sysfs tree.
+    pub fn new_with_paths(
+        device_fd: Option<VfioContainerDeviceHandle>,
+        paths: VfioPaths,
+    ) -> Result<Self> {
+        let container = Self::open_unchecked(device_fd, paths)?;
         container.check_api_version()?;
-        container.check_extension(VFIO_TYPE1v2_IOMMU)?;
+        match container.check_extension(VFIO_TYPE1v2_IOMMU) {
+            Err(VfioError::VfioExtension) => {
+                return Err(VfioError::IommuNotEnabled {
+                    diagnosis: diagnose_iommu_with_paths(&container.paths),
+                })
+            }
+            result => result?,
+        }
 
         Ok(container)
     }
 
-    fn check_api_version(&self) -> Result<()> {
+    // Open a container without checking the API version and the IOMMU backends it supports.
+    pub(crate) fn open_unchecked(
+        device_fd: Option<VfioContainerDeviceHandle>,
+        paths: VfioPaths,
+    ) -> Result<Self> {
+        Ok(VfioContainer {
+            container: Self::open_container_file(&paths)?,
+            device_fd,
+            groups: Mutex::new(HashMap::new()),
+            groups_owner: Mutex::new(None),
+            bound_groups: Mutex::new(HashSet::new()),
+            detached_groups: Mutex::new(HashMap::new()),
+            iova_base: AtomicU64::new(0),
+            paths,
+            mappings: Mutex::new(BTreeMap::new()),
+            mapping_tags: Mutex::new(HashMap::new()),
+            mapping_budget: Mutex::new(None),
+            region_caps_max_size: Mutex::new(VFIO_REGION_INFO_MAX_SIZE),
+            dma_avail_watermark: Mutex::new(None),
+            dma_masks: Mutex::new(HashMap::new()),
+            iommu_info: RwLock::new(OnceCell::new()),
+            group_viable_wait: Mutex::new(None),
+            group_transitions: Mutex::new(HashSet::new()),
+            group_transition_done: Condvar::new(),
+            multi_group_policy: Mutex::new(MultiGroupPolicy::default()),
+            group_containers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the locations of the VFIO character devices and of the sysfs tree.
+    pub fn paths(&self) -> &VfioPaths {
+        &self.paths
+    }
+
+    /// Get a read only view of the container, limited to the queries of its state.
+    pub fn read_only(self: &Arc<Self>) -> VfioContainerRef {
+        VfioContainerRef(self.clone())
+    }
+
+    /// Get another handle to the container, for another subsystem to use the same IOMMU domain.
+    ///
+    /// The handles are equivalent: mappings made through one of them are visible through all
+    /// of them, and the container is closed when the last handle is dropped.
+    pub fn share(self: &Arc<Self>) -> Arc<Self> {
+        Arc::clone(self)
+    }
+
+    /// Get the ids of the groups attached to the container, in ascending order.
+    pub fn group_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = lock_or_recover(&self.groups).keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Get the number of groups attached to the container.
+    pub fn group_count(&self) -> usize {
+        lock_or_recover(&self.groups).len()
+    }
+
+    // Lock the groups map for an operation running ioctls or dropping groups, recording the
+    // thread holding it.
+    pub(crate) fn lock_groups(&self) -> GroupsGuard<'_> {
+        let hash = lock_or_recover(&self.groups);
+        *lock_or_recover(&self.groups_owner) = Some(thread::current().id());
+
+        GroupsGuard {
+            owner: &self.groups_owner,
+            hash,
+        }
+    }
+
+    /// Check whether the container supports a VFIO extension.
+    ///
+    /// # Parameters
+    /// * extension: a `VFIO_CHECK_EXTENSION` argument such as `VFIO_TYPE1v2_IOMMU`.
+    pub fn supports_extension(&self, extension: u32) -> bool {
+        matches!(vfio_syscall::check_extension(self, extension), Ok(ret) if ret > 0)
+    }
+
+    pub(crate) fn check_api_version(&self) -> Result<()> {
         let version = vfio_syscall::check_api_version(self);
         if version as u32 != VFIO_API_VERSION {
             return Err(VfioError::VfioApiVersion);
@@ -222,134 +676,1363 @@ impl VfioContainer {
         vfio_syscall::set_iommu(self, val)
     }
 
-    fn get_group(&self, group_id: u32) -> Result<Arc<VfioGroup>> {
-        // Safe because there's no legal way to break the lock.
-        let mut hash = self.groups.lock().unwrap();
-        if let Some(entry) = hash.get(&group_id) {
+    // Get a group of the container, opening it if needed. The group is waited for as set by
+    // `viable_wait`, or by set_group_viable_wait() if `None`.
+    pub(crate) fn get_group(
+        &self,
+        group_id: u32,
+        viable_wait: Option<(Duration, Duration)>,
+    ) -> Result<Arc<VfioGroup>> {
+        if let Some(entry) = lock_or_recover(&self.groups).get(&group_id) {
             return Ok(entry.clone());
         }
 
-        let group = Arc::new(VfioGroup::new(group_id)?);
+        // The group is opened, waited for and bound without the groups lock, which would keep
+        // the other groups from being opened or released meanwhile. The transition keeps another
+        // thread from opening or releasing the same group.
+        let _transition = self.begin_group_transition(group_id);
+        if let Some(entry) = lock_or_recover(&self.groups).get(&group_id) {
+            return Ok(entry.clone());
+        }
+        let viable_wait = viable_wait.or(*lock_or_recover(&self.group_viable_wait));
+        let group = match viable_wait {
+            Some((timeout, poll_interval)) => {
+                let group = VfioGroup::open(group_id, &self.paths)?;
+                group.wait_viable(timeout, poll_interval)?;
+                Arc::new(group)
+            }
+            None => Arc::new(VfioGroup::new(group_id, &self.paths)?),
+        };
 
         // Bind the new group object to the container.
-        vfio_syscall::set_group_container(&group, self)?;
-
-        // Initialize the IOMMU backend driver after binding the first group object.
-        if hash.len() == 0 {
-            if let Err(e) = self.set_iommu(VFIO_TYPE1v2_IOMMU) {
+        match self.bind_group(&group) {
+            Err(VfioError::GroupBusy { .. }) => {
+                // A previous failed attempt may have left the group attached for a short while,
+                // so try to detach it and bind it again once.
+                warn!("VFIO group {} is busy, trying to rebind it", group_id);
                 let _ = vfio_syscall::unset_group_container(&group, self);
-                return Err(e);
+                self.bind_group(&group)?;
             }
+            r => r?,
         }
+        self.lock_groups().insert(group_id, group.clone());
 
-        // Add the new group object to the hypervisor driver.
-        #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
-        if let Err(e) = self.device_add_group(&group) {
-            let _ = vfio_syscall::unset_group_container(&group, self);
-            return Err(e);
+        Ok(group)
+    }
+
+    // Mark a group as being opened or released until the returned guard is dropped, once it
+    // isn't anymore by another thread.
+    fn begin_group_transition(&self, group_id: u32) -> GroupTransition<'_> {
+        let mut transitions = lock_or_recover(&self.group_transitions);
+        while transitions.contains(&group_id) {
+            transitions = self
+                .group_transition_done
+                .wait(transitions)
+                .unwrap_or_else(PoisonError::into_inner);
         }
+        transitions.insert(group_id);
 
-        hash.insert(group_id, group.clone());
+        GroupTransition {
+            container: self,
+            group_id,
+        }
+    }
 
-        Ok(group)
+    // Bind a group to the container, or to a container of its own if the kernel refuses to add
+    // it to this one and the policy allows it, and finish its setup.
+    fn bind_group(&self, group: &VfioGroup) -> Result<()> {
+        let mut bound = lock_or_recover(&self.bound_groups);
+        match vfio_syscall::set_group_container(group, self) {
+            Ok(()) => self.register_group(Some(&mut bound), group, true),
+            Err(VfioError::GroupSetContainerInvalid { group_id })
+                if !bound.is_empty()
+                    && self.multi_group_policy()
+                        == MultiGroupPolicy::SharedOrFallbackToPerGroup =>
+            {
+                warn!(
+                    "VFIO group {} can't share the container, binding it to its own container",
+                    group_id
+                );
+                self.bind_group_to_own_container(group)?;
+                self.register_group(None, group, true)
+            }
+            Err(e) => Err(e),
+        }
     }
 
-    fn put_group(&self, group: Arc<VfioGroup>) {
-        // Safe because there's no legal way to break the lock.
-        let mut hash = self.groups.lock().unwrap();
+    // Unbind a group from the container, or from its own container. A group bound by the
+    // process which opened it is only forgotten, unless `force` is set.
+    fn unbind_group(&self, group: &VfioGroup, force: bool) -> Result<()> {
+        let mut bound = lock_or_recover(&self.bound_groups);
+        if force || group.owned.load(Ordering::Acquire) {
+            vfio_syscall::unset_group_container(group, self)?;
+        } else {
+            debug!("Leaving VFIO group {} bound to the container", group.id());
+        }
+        bound.remove(&group.id());
+        self.drop_group_container(group.id());
 
-        // Clean up the group when the last user releases reference to the group, three reference
-        // count for:
-        // - one reference held by the last device object
-        // - one reference cloned in VfioDevice.drop() and passed into here
-        // - one reference held by the groups hashmap
-        if Arc::strong_count(&group) == 3 {
-            #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
-            match self.device_del_group(&group) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Could not delete VFIO group: {:?}", e);
-                    return;
-                }
-            }
-            if vfio_syscall::unset_group_container(&group, self).is_err() {
-                error!("Could not unbind VFIO group: {:?}", group.id());
-                return;
-            }
-            hash.remove(&group.id());
+        Ok(())
+    }
+
+    fn bind_group_to_own_container(&self, group: &VfioGroup) -> Result<()> {
+        let container = VfioContainer::new_with_paths(None, self.paths.clone())?;
+        vfio_syscall::set_group_container(group, &container)?;
+        let unbind = |e: VfioError| {
+            let _ = vfio_syscall::unset_group_container(group, &container);
+            e
+        };
+        container.set_iommu(VFIO_TYPE1v2_IOMMU).map_err(unbind)?;
+
+        // The mappings of this container are replayed, and kept locked until the new container
+        // is registered so that no mapping is missed.
+        let mappings = lock_or_recover(&self.mappings);
+        for mapping in mappings.values() {
+            vfio_syscall::map_dma(&container, &mapping.dma_map()).map_err(unbind)?;
         }
+        lock_or_recover(&self.group_containers).insert(group.id(), container);
+
+        Ok(())
     }
 
-    /// Map a region of guest memory regions into the vfio container's iommu table.
+    /// Set how groups are bound when the kernel refuses to add them to this container.
+    ///
+    /// Some older kernels only accept groups added before the IOMMU backend is set, which this
+    /// container does when binding its first group. With
+    /// `MultiGroupPolicy::SharedOrFallbackToPerGroup`, the groups they refuse are bound to a
+    /// new container of their own instead. The DMA mappings of this container are then
+    /// replicated into each of these containers, multiplying the `VFIO_IOMMU_MAP_DMA` and
+    /// `VFIO_IOMMU_UNMAP_DMA` calls and the memory pinned by the kernel accordingly. The groups
+    /// using a container of their own are listed by `fallback_group_ids()`.
     ///
     /// # Parameters
-    /// * iova: IO virtual address to mapping the memory.
-    /// * size: size of the memory region.
-    /// * user_addr: host virtual address for the guest memory region to map.
-    pub fn vfio_dma_map(&self, iova: u64, size: u64, user_addr: u64) -> Result<()> {
-        let dma_map = vfio_iommu_type1_dma_map {
-            argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
-            flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
-            vaddr: user_addr,
-            iova,
-            size,
-        };
+    /// * policy: binding of the groups refused by the kernel.
+    pub fn set_multi_group_policy(&self, policy: MultiGroupPolicy) {
+        *lock_or_recover(&self.multi_group_policy) = policy;
+    }
 
-        vfio_syscall::map_dma(self, &dma_map)
+    /// Get how groups are bound when the kernel refuses to add them to this container.
+    pub fn multi_group_policy(&self) -> MultiGroupPolicy {
+        *lock_or_recover(&self.multi_group_policy)
     }
 
-    /// Unmap a region of guest memory regions into the vfio container's iommu table.
+    /// Get the ids of the groups bound to a container of their own, in ascending order.
     ///
-    /// # Parameters
-    /// * iova: IO virtual address to mapping the memory.
-    /// * size: size of the memory region.
-    pub fn vfio_dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
-        let mut dma_unmap = vfio_iommu_type1_dma_unmap {
-            argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
-            flags: 0,
-            iova,
-            size,
+    /// See [`set_multi_group_policy()`](Self::set_multi_group_policy).
+    pub fn fallback_group_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = lock_or_recover(&self.group_containers)
+            .keys()
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    // Forget the container of its own a group was bound to.
+    fn drop_group_container(&self, group_id: u32) {
+        if lock_or_recover(&self.group_containers)
+            .remove(&group_id)
+            .is_some()
+        {
+            debug!("Released the container of VFIO group {}", group_id);
+        }
+    }
+
+    // Finish the setup of a group bound to the container, recording it in `bound`, or bound to
+    // its own container if `bound` is `None`. On failure, the group is unbound from the
+    // container only if `owned` is set.
+    fn register_group(
+        &self,
+        bound: Option<&mut HashSet<u32>>,
+        group: &VfioGroup,
+        owned: bool,
+    ) -> Result<()> {
+        let unbind = |e: VfioError| {
+            if owned {
+                let _ = vfio_syscall::unset_group_container(group, self);
+                self.drop_group_container(group.id());
+            }
+            e
         };
 
-        vfio_syscall::unmap_dma(self, &mut dma_unmap)?;
-        if dma_unmap.size != size {
-            return Err(VfioError::InvalidDmaUnmapSize);
+        // Initialize the IOMMU backend driver after binding the first group object.
+        if matches!(&bound, Some(bound) if bound.is_empty()) {
+            self.set_iommu(VFIO_TYPE1v2_IOMMU).map_err(unbind)?;
+        }
+
+        // Add the new group object to the hypervisor driver.
+        #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
+        self.device_add_group(group).map_err(unbind)?;
+
+        if let Some(bound) = bound {
+            bound.insert(group.id());
         }
 
         Ok(())
     }
 
-    /// Add all guest memory regions into the vfio container's iommu table.
+    /// Attach a VFIO group opened outside of this container.
+    ///
+    /// This is meant for groups opened by a privileged process and passed over as a file
+    /// descriptor, see [`VfioGroup::from_fd()`]. That process may already have bound the group
+    /// to this container, in which case `VFIO_GROUP_SET_CONTAINER` fails with `EBUSY` and the
+    /// group status is checked to report a container as set. The kernel doesn't tell which
+    /// container the group is bound to, so the caller must make sure it is this one.
+    ///
+    /// Devices of the group can then be opened with `VfioDevice::new_in_group()`, and the group
+    /// is detached when its last device is dropped. A group already bound by that process is
+    /// left bound to the container then, the binding being owned by that process. Attaching a
+    /// group whose ID is already known to the container is a no-op. Once attached, the group
+    /// uses the [`paths()`](Self::paths) of the container.
     ///
     /// # Parameters
-    /// * mem: pinned guest memory which could be accessed by devices binding to the container.
-    pub fn vfio_map_guest_memory<M: GuestMemory>(&self, mem: &M) -> Result<()> {
-        mem.iter().try_for_each(|region| {
-            let host_addr = region
-                .get_host_address(MemoryRegionAddress(0))
-                .map_err(|_| VfioError::GetHostAddress)?;
-            self.vfio_dma_map(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                host_addr as u64,
-            )
-        })
+    /// * `group`: the VFIO group to attach.
+    pub fn attach_group(&self, mut group: VfioGroup) -> Result<()> {
+        group.paths = self.paths.clone();
+        let mut hash = self.lock_groups();
+        if hash.contains_key(&group.id()) {
+            return Ok(());
+        }
+
+        let mut bound = lock_or_recover(&self.bound_groups);
+        let owned = match vfio_syscall::set_group_container(&group, self) {
+            Ok(()) => true,
+            Err(VfioError::GroupBusy { group_id }) => {
+                if group.status()? & VFIO_GROUP_FLAGS_CONTAINER_SET == 0 {
+                    return Err(VfioError::GroupBusy { group_id });
+                }
+                debug!("VFIO group {} is already bound to a container", group_id);
+                false
+            }
+            Err(e) => return Err(e),
+        };
+        self.register_group(Some(&mut bound), &group, owned)?;
+        drop(bound);
+        group.owned.store(owned, Ordering::Release);
+        hash.insert(group.id(), Arc::new(group));
+
+        Ok(())
     }
 
-    /// Remove all guest memory regions from the vfio container's iommu table.
+    /// Detach a group from the container, keeping it around for `reattach_group()`.
     ///
-    /// The vfio kernel driver and device hardware couldn't access this guest memory after
-    /// returning from the function.
+    /// The group is removed from the hypervisor VFIO device and unbound from the container with
+    /// `VFIO_GROUP_UNSET_CONTAINER`. This is meant for error recovery, where the binding has to
+    /// be rebuilt without dropping the devices of the group.
+    ///
+    /// Devices of a detached group lose their DMA access, and must not be used until the group
+    /// is reattached: in-flight DMA faults, and the device may be left in an unknown state.
+    /// Detaching the last group of the container also tears down its IOMMU context, dropping
+    /// all the DMA mappings, which must be established again after reattaching.
     ///
     /// # Parameters
-    /// * mem: pinned guest memory which could be accessed by devices binding to the container.
-    pub fn vfio_unmap_guest_memory<M: GuestMemory>(&self, mem: &M) -> Result<()> {
-        mem.iter().try_for_each(|region| {
-            self.vfio_dma_unmap(region.start_addr().raw_value(), region.len() as u64)
-        })
+    /// * `group_id`: ID of the group to detach.
+    pub fn detach_group(&self, group_id: u32) -> Result<()> {
+        let mut hash = self.lock_groups();
+        let group = hash
+            .get(&group_id)
+            .cloned()
+            .ok_or(VfioError::GroupNotAttached(group_id))?;
+
+        #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
+        self.device_del_group(&group)?;
+        let add_back = |e: VfioError| {
+            #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
+            if let Err(e) = self.device_add_group(&group) {
+                error!("Could not add back VFIO group {}: {:?}", group_id, e);
+            }
+            e
+        };
+        self.unbind_group(&group, true).map_err(add_back)?;
+
+        hash.remove(&group_id);
+        if hash.is_empty() {
+            let mut mappings = lock_or_recover(&self.mappings);
+            mappings.clear();
+            lock_or_recover(&self.mapping_tags).clear();
+        }
+        lock_or_recover(&self.detached_groups).insert(group_id, group);
+
+        Ok(())
     }
 
-    #[cfg(all(
+    /// Bind a group detached with `detach_group()` to the container again.
+    ///
+    /// # Parameters
+    /// * `group_id`: ID of the group to reattach.
+    pub fn reattach_group(&self, group_id: u32) -> Result<()> {
+        let mut hash = self.lock_groups();
+        let mut detached = lock_or_recover(&self.detached_groups);
+        let group = detached
+            .get(&group_id)
+            .cloned()
+            .ok_or(VfioError::GroupNotDetached(group_id))?;
+
+        self.bind_group(&group)?;
+        group.owned.store(true, Ordering::Release);
+        hash.insert(group_id, group);
+        detached.remove(&group_id);
+
+        Ok(())
+    }
+
+    fn put_group(&self, group: Arc<VfioGroup>) {
+        // A device dropped while the groups lock is held by the same thread would deadlock.
+        if *lock_or_recover(&self.groups_owner) == Some(thread::current().id()) {
+            error!(
+                "Re-entrant container use during device drop, VFIO group {} is left bound to \
+                 the container",
+                group.id()
+            );
+            return;
+        }
+
+        // The transition keeps the group from being opened again until it is unbound.
+        let _transition = self.begin_group_transition(group.id());
+        let mut hash = lock_or_recover(&self.groups);
+
+        // A detached group is already unbound, only forget it with its last device.
+        let mut detached = lock_or_recover(&self.detached_groups);
+        if matches!(detached.get(&group.id()), Some(g) if Arc::ptr_eq(g, &group)) {
+            if Arc::strong_count(&group) == 3 {
+                detached.remove(&group.id());
+            }
+            return;
+        }
+        drop(detached);
+
+        // Clean up the group when the last user releases reference to the group, three reference
+        // count for:
+        // - one reference held by the last device object
+        // - one reference cloned in VfioGroupBinding.drop() and passed into here
+        // - one reference held by the groups hashmap
+        if Arc::strong_count(&group) != 3 {
+            return;
+        }
+
+        // The lock is only held to take the group out of the map, it is unbound outside of it
+        // and put back if that fails.
+        hash.remove(&group.id());
+        drop(hash);
+        if !self.release_group(&group) {
+            lock_or_recover(&self.groups).insert(group.id(), group);
+        }
+    }
+
+    // Remove a group from the hypervisor VFIO device and unbind it from the container.
+    fn release_group(&self, group: &VfioGroup) -> bool {
+        #[cfg(any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")))]
+        if let Err(e) = self.device_del_group(group) {
+            error!("Could not delete VFIO group: {:?}", e);
+            return false;
+        }
+        if self.unbind_group(group, false).is_err() {
+            error!("Could not unbind VFIO group: {:?}", group.id());
+            return false;
+        }
+
+        true
+    }
+
+    /// Map a region of host memory into the vfio container's iommu table, readable and
+    /// writable by the device.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to map the memory at.
+    /// * size: size of the memory region.
+    /// * host_va: host virtual address of the memory region to map.
+    pub fn dma_map(&self, iova: Iova, size: usize, host_va: HostVa) -> Result<()> {
+        self.dma_map_checked(
+            VfioDmaMapping {
+                iova,
+                size: size as u64,
+                user_addr: host_va,
+                flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+            },
+            false,
+            None,
+        )
+    }
+
+    /// Map a region of host memory like [`dma_map()`](Self::dma_map), recording which component
+    /// created the mapping.
+    ///
+    /// The tag is kept until the mapping is unmapped, and is inherited by the parts of the
+    /// mapping left by `vfio_dma_punch_hole()`. Tagged mappings are listed by
+    /// [`mappings_by_tag()`](Self::mappings_by_tag) and unmapped together by
+    /// [`unmap_by_tag()`](Self::unmap_by_tag). `VfioDevice::dma_map()` tags the mappings with
+    /// the name of the device.
+    ///
+    /// Tags aren't part of the handover state, so imported mappings have none.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to map the memory at.
+    /// * size: size of the memory region.
+    /// * host_va: host virtual address of the memory region to map.
+    /// * tag: component creating the mapping.
+    pub fn dma_map_tagged(
+        &self,
+        iova: Iova,
+        size: usize,
+        host_va: HostVa,
+        tag: impl Into<Cow<'static, str>>,
+    ) -> Result<()> {
+        self.dma_map_checked(
+            VfioDmaMapping {
+                iova,
+                size: size as u64,
+                user_addr: host_va,
+                flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+            },
+            false,
+            Some(tag.into()),
+        )
+    }
+
+    /// Map a region of guest memory regions into the vfio container's iommu table.
+    ///
+    /// Prefer [`dma_map()`](Self::dma_map), whose typed addresses can't be swapped by
+    /// mistake. This function is kept for existing callers and may be deprecated.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to mapping the memory.
+    /// * size: size of the memory region.
+    /// * user_addr: host virtual address for the guest memory region to map.
+    pub fn vfio_dma_map(&self, iova: u64, size: u64, user_addr: u64) -> Result<()> {
+        self.vfio_dma_map_raw(
+            iova,
+            size,
+            user_addr,
+            VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+            false,
+        )
+    }
+
+    /// Map a userspace buffer into the vfio container's iommu table for device DMA.
+    ///
+    /// The buffer address and length must be aligned to the IOMMU page size, or to the host
+    /// page size when the IOMMU doesn't report it. `mmap()`ed buffers satisfy this as long as
+    /// their length is a multiple of the page size.
+    ///
+    /// The buffer must stay alive, and must not be moved or reallocated, until it is unmapped
+    /// with `vfio_dma_unmap()`. This is the caller's responsibility: the device may access the
+    /// memory at any time while the mapping exists.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to mapping the buffer.
+    /// * buf: the buffer to map, readable and writable by the device.
+    pub fn vfio_dma_map_buffer(&self, iova: u64, buf: &[u8]) -> Result<()> {
+        let vaddr = buf.as_ptr() as u64;
+        let size = buf.len() as u64;
+        let page_size = match self.iommu_page_size()? {
+            Some(page_size) => page_size,
+            // SAFETY: sysconf() has no memory safety requirements.
+            None => unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 },
+        };
+        if vaddr & (page_size - 1) != 0 {
+            return Err(VfioError::IommuDmaMapUnalignedVaddr { vaddr, page_size });
+        }
+        if size == 0 || size & (page_size - 1) != 0 {
+            return Err(VfioError::IommuDmaMapUnalignedSize { size, page_size });
+        }
+
+        self.vfio_dma_map(iova, size, vaddr)
+    }
+
+    /// Map a region of memory into the vfio container's iommu table with caller defined flags.
+    ///
+    /// The flags are passed verbatim to `VFIO_IOMMU_MAP_DMA`, so flags unknown to the running
+    /// kernel make the mapping fail, and flags changing the meaning of the other fields (such as
+    /// `VFIO_DMA_MAP_FLAG_VADDR`) are the caller's responsibility.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to mapping the memory.
+    /// * size: size of the memory region.
+    /// * user_addr: host virtual address for the memory region to map.
+    /// * flags: `VFIO_DMA_MAP_FLAG_*` flags of the mapping.
+    /// * allow_no_access: accept flags granting neither read nor write access.
+    pub fn vfio_dma_map_raw(
+        &self,
+        iova: u64,
+        size: u64,
+        user_addr: u64,
+        flags: u32,
+        allow_no_access: bool,
+    ) -> Result<()> {
+        self.dma_map_checked(
+            VfioDmaMapping {
+                iova: Iova(iova),
+                size,
+                user_addr: HostVa(user_addr),
+                flags,
+            },
+            allow_no_access,
+            None,
+        )
+    }
+
+    fn dma_map_checked(
+        &self,
+        mapping: VfioDmaMapping,
+        allow_no_access: bool,
+        tag: Option<Cow<'static, str>>,
+    ) -> Result<()> {
+        let size = mapping.size;
+        if !allow_no_access
+            && mapping.flags & (VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE) == 0
+        {
+            return Err(VfioError::IommuDmaMapNoAccess);
+        }
+        self.check_vaddr_alignment(mapping.user_addr.raw_value())?;
+        self.check_iova_window(mapping.iova.raw_value(), size)?;
+        self.check_dma_masks(mapping.iova.raw_value(), size)?;
+
+        let mut mappings = lock_or_recover(&self.mappings);
+        if let Some(budget) = *lock_or_recover(&self.mapping_budget) {
+            let used = Self::mapped_bytes_locked(&mappings);
+            match used.checked_add(size) {
+                Some(total) if total <= budget => {}
+                _ => {
+                    return Err(VfioError::MappingBudgetExceeded {
+                        requested: size,
+                        used,
+                        budget,
+                    })
+                }
+            }
+        }
+        self.dma_map_locked(&mut mappings, mapping)?;
+        self.set_mapping_tag(mapping.iova.raw_value(), tag);
+        drop(mappings);
+        self.check_dma_avail_watermark(1);
+
+        Ok(())
+    }
+
+    // Tag the mapping at iova, the mappings lock must be held.
+    fn set_mapping_tag(&self, iova: u64, tag: Option<Cow<'static, str>>) {
+        if let Some(tag) = tag {
+            lock_or_recover(&self.mapping_tags).insert(iova, tag);
+        }
+    }
+
+    fn dma_map_locked(
+        &self,
+        mappings: &mut BTreeMap<u64, VfioDmaMapping>,
+        mapping: VfioDmaMapping,
+    ) -> Result<()> {
+        let dma_map = mapping.dma_map();
+        vfio_syscall::map_dma(self, &dma_map)?;
+
+        let group_containers = lock_or_recover(&self.group_containers);
+        for (i, container) in group_containers.values().enumerate() {
+            if let Err(e) = vfio_syscall::map_dma(container, &dma_map) {
+                for container in group_containers.values().take(i).chain([self]) {
+                    let mut dma_unmap = mapping.dma_unmap();
+                    let _ = vfio_syscall::unmap_dma(container, &mut dma_unmap);
+                }
+                return Err(e);
+            }
+        }
+        mappings.insert(mapping.iova.raw_value(), mapping);
+
+        Ok(())
+    }
+
+    // Count new mappings against the watermark, querying the available mappings when the
+    // threshold may have been crossed. The mappings lock mustn't be held, so that the query
+    // doesn't delay the other mappings.
+    fn check_dma_avail_watermark(&self, maps: u32) {
+        let mut watermark = lock_or_recover(&self.dma_avail_watermark);
+        let watermark = match watermark.as_mut() {
+            Some(watermark) => watermark,
+            None => return,
+        };
+        watermark.maps_until_query = watermark.maps_until_query.saturating_sub(maps);
+        if watermark.maps_until_query > 0 {
+            return;
+        }
+        match self.dma_avail() {
+            Ok(Some(avail)) => watermark.update(avail),
+            Ok(None) => watermark.maps_until_query = 1,
+            Err(e) => {
+                warn!("Failed to query the available dma mappings: {}", e);
+                watermark.maps_until_query = 1;
+            }
+        }
+    }
+
+    // Unmap [iova, iova + size) and return the size actually unmapped by the kernel.
+    fn dma_unmap_locked(
+        &self,
+        mappings: &mut BTreeMap<u64, VfioDmaMapping>,
+        iova: u64,
+        size: u64,
+    ) -> Result<u64> {
+        let mut dma_unmap = vfio_iommu_type1_dma_unmap {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
+            flags: 0,
+            iova,
+            size,
+        };
+
+        vfio_syscall::unmap_dma(self, &mut dma_unmap)?;
+        // The kernel reports the size it actually unmapped, made of whole mappings.
+        let end = iova.saturating_add(dma_unmap.size);
+        mappings.retain(|_, m| {
+            let m_iova = m.iova.raw_value();
+            m_iova < iova || m_iova.saturating_add(m.size) > end
+        });
+        let mut tags = lock_or_recover(&self.mapping_tags);
+        if !tags.is_empty() {
+            tags.retain(|iova, _| mappings.contains_key(iova));
+        }
+        drop(tags);
+
+        // The containers of their own of the groups hold the same mappings.
+        let mut result = Ok(dma_unmap.size);
+        for (group_id, container) in lock_or_recover(&self.group_containers).iter() {
+            let mut group_unmap = vfio_iommu_type1_dma_unmap {
+                size: dma_unmap.size,
+                ..dma_unmap
+            };
+            if let Err(e) = vfio_syscall::unmap_dma(container, &mut group_unmap) {
+                error!(
+                    "Failed to unmap {:#x}+{:#x} from the container of VFIO group {}: {}",
+                    iova, dma_unmap.size, group_id, e
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Get the IOVA windows usable for DMA mappings in this container.
+    ///
+    /// An empty list is returned when the kernel doesn't report IOVA windows, which
+    /// happens before Linux v5.4.
+    ///
+    /// The first call queries the IOMMU and may block on concurrent callers doing the same.
+    pub fn valid_iova_ranges(&self) -> Result<Vec<VfioIovaRange>> {
+        Ok(self.iommu_info()?.iova_ranges.clone())
+    }
+
+    /// Get the smallest page size supported by the container's IOMMU.
+    ///
+    /// Returns `None` if the IOMMU doesn't report its supported page sizes.
+    ///
+    /// The first call queries the IOMMU and may block on concurrent callers doing the same.
+    pub fn iommu_page_size(&self) -> Result<Option<u64>> {
+        match self.iommu_info()?.pgsizes {
+            0 => Ok(None),
+            pgsizes => Ok(Some(1 << pgsizes.trailing_zeros())),
+        }
+    }
+
+    /// Query the IOMMU properties again, dropping the cached ones.
+    ///
+    /// The IOMMU properties only change when the IOMMU backend of the container changes.
+    pub fn refresh_iommu_info(&self) -> Result<()> {
+        *write_or_recover(&self.iommu_info) = OnceCell::new();
+        self.iommu_info().map(|_| ())
+    }
+
+    // Errors aren't cached, so the query is retried until the IOMMU has been set.
+    fn iommu_info(&self) -> Result<Arc<VfioIommuInfo>> {
+        read_or_recover(&self.iommu_info)
+            .get_or_try_init(|| self.query_iommu_info().map(Arc::new))
+            .cloned()
+    }
+
+    // Query the IOMMU info with its capability chain, calling `visit` with the ID of each
+    // capability and the bytes from its start to the end of the chain. Returns the supported
+    // page sizes, 0 when not reported.
+    fn walk_iommu_info<F: FnMut(u32, &[u8])>(&self, mut visit: F) -> Result<u64> {
+        let info_size = mem::size_of::<vfio_iommu_type1_info_v2>() as u32;
+        let mut info = vfio_iommu_info_with_cap::with_argsz(info_size);
+        vfio_syscall::get_iommu_info(self, &mut info)?;
+
+        let pgsizes = if info[0].info.flags & VFIO_IOMMU_INFO_PGSIZES == 0 {
+            0
+        } else {
+            info[0].info.iova_pgsizes
+        };
+
+        let argsz = info[0].info.argsz;
+        if info[0].info.flags & VFIO_IOMMU_INFO_CAPS == 0 || argsz <= info_size {
+            return Ok(pgsizes);
+        }
+
+        // Fetch the capability chain with the size hinted by the kernel.
+        let mut info = vfio_iommu_info_with_cap::with_argsz(argsz);
+        vfio_syscall::get_iommu_info(self, &mut info)?;
+
+        // SAFETY: the buffer is made of plain data initialized when allocated, and the slice
+        // covers the whole allocation.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                info.as_ptr() as *const u8,
+                info.len() * mem::size_of::<vfio_iommu_info_with_cap>(),
+            )
+        };
+        // The kernel may report a larger size than the one it was given.
+        let end = bytes.len().min(info[0].info.argsz as usize);
+        let header_size = mem::size_of::<vfio_info_cap_header>();
+        // Capabilities are chained in increasing offsets, which also stops a looping chain.
+        let mut min_offset = info_size as usize;
+        let mut offset = info[0].info.cap_offset as usize;
+        while offset != 0 {
+            if offset < min_offset || offset + header_size > end {
+                warn!(
+                    "Ignoring IOMMU info capabilities from invalid offset {:#x}",
+                    offset
+                );
+                break;
+            }
+            let cap = &bytes[offset..end];
+            // SAFETY: the header lies within the chain, as checked above.
+            let cap_header =
+                unsafe { std::ptr::read_unaligned(cap.as_ptr() as *const vfio_info_cap_header) };
+            visit(u32::from(cap_header.id), cap);
+
+            min_offset = offset + header_size;
+            offset = cap_header.next as usize;
+        }
+
+        Ok(pgsizes)
+    }
+
+    fn query_iommu_info(&self) -> Result<VfioIommuInfo> {
+        let mut iova_ranges = Vec::new();
+        let mut dirty_bitmap = false;
+        let pgsizes = self.walk_iommu_info(|id, cap| {
+            if id == VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE {
+                let cap_size = mem::size_of::<vfio_iommu_type1_info_cap_iova_range>();
+                if cap.len() < cap_size {
+                    warn!("Ignoring truncated IOMMU IOVA ranges capability");
+                    return;
+                }
+                // SAFETY: the capability lies within the chain, as checked above.
+                let nr_iovas = unsafe {
+                    std::ptr::read_unaligned(
+                        cap.as_ptr() as *const vfio_iommu_type1_info_cap_iova_range
+                    )
+                }
+                .nr_iovas as usize;
+                let range_size = mem::size_of::<vfio_iova_range>();
+                let ranges = match nr_iovas
+                    .checked_mul(range_size)
+                    .and_then(|size| cap.get(cap_size..cap_size.checked_add(size)?))
+                {
+                    Some(ranges) => ranges,
+                    None => {
+                        warn!("Ignoring {} IOMMU IOVA ranges beyond the chain", nr_iovas);
+                        return;
+                    }
+                };
+                iova_ranges.extend(ranges.chunks_exact(range_size).map(|range| {
+                    // SAFETY: the range lies within the chain, as checked above.
+                    let range = unsafe {
+                        std::ptr::read_unaligned(range.as_ptr() as *const vfio_iova_range)
+                    };
+                    VfioIovaRange {
+                        start: range.start,
+                        end: range.end,
+                    }
+                }));
+            } else if id == VFIO_IOMMU_TYPE1_INFO_CAP_MIGRATION {
+                dirty_bitmap = true;
+            }
+        })?;
+
+        Ok(VfioIommuInfo {
+            pgsizes,
+            dirty_bitmap,
+            iova_ranges,
+        })
+    }
+
+    /// Get the number of DMA mappings the container may still create.
+    ///
+    /// The kernel limits the number of DMA mappings of a container, 65535 by default with the
+    /// `dma_entry_limit` parameter of `vfio_iommu_type1`. Mappings beyond it fail with `ENOSPC`.
+    /// Unlike the other IOMMU properties, this is queried from the kernel on every call.
+    ///
+    /// Returns `None` if the kernel doesn't report it, which happens before Linux v5.10.
+    pub fn dma_avail(&self) -> Result<Option<u32>> {
+        let mut avail = None;
+        self.walk_iommu_info(|id, cap| {
+            if id == VFIO_IOMMU_TYPE1_INFO_DMA_AVAIL {
+                if cap.len() < mem::size_of::<vfio_iommu_type1_info_dma_avail>() {
+                    warn!("Ignoring truncated IOMMU DMA_AVAIL capability");
+                    return;
+                }
+                // SAFETY: the capability lies within the chain, as checked above.
+                let cap = unsafe {
+                    std::ptr::read_unaligned(cap.as_ptr() as *const vfio_iommu_type1_info_dma_avail)
+                };
+                avail = Some(cap.avail);
+            }
+        })?;
+
+        Ok(avail)
+    }
+
+    /// Check whether the container supports a `VFIO_DMA_MAP_FLAG_*` flag.
+    ///
+    /// Support depends on the IOMMU backend, so `false` is returned until a group is added to
+    /// the container.
+    ///
+    /// # Parameters
+    /// * flag: a single map flag.
+    pub fn supports_dma_flag(&self, flag: u32) -> bool {
+        if self.iommu_info().is_err() {
+            return false;
+        }
+        match flag {
+            VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE => true,
+            VFIO_DMA_MAP_FLAG_VADDR => self.supports_extension(VFIO_UPDATE_VADDR),
+            _ => false,
+        }
+    }
+
+    /// Check whether the container supports a `VFIO_DMA_UNMAP_FLAG_*` flag.
+    ///
+    /// Support depends on the IOMMU backend, so `false` is returned until a group is added to
+    /// the container.
+    ///
+    /// # Parameters
+    /// * flag: a single unmap flag.
+    pub fn supports_dma_unmap_flag(&self, flag: u32) -> bool {
+        let info = match self.iommu_info() {
+            Ok(info) => info,
+            Err(_) => return false,
+        };
+        match flag {
+            VFIO_DMA_UNMAP_FLAG_GET_DIRTY_BITMAP => info.dirty_bitmap,
+            VFIO_DMA_UNMAP_FLAG_ALL => self.supports_extension(VFIO_UNMAP_ALL),
+            VFIO_DMA_UNMAP_FLAG_VADDR => self.supports_extension(VFIO_UPDATE_VADDR),
+            _ => false,
+        }
+    }
+
+    // The kernel refuses host addresses which aren't IOMMU page aligned with a bare EINVAL.
+    fn check_vaddr_alignment(&self, vaddr: u64) -> Result<()> {
+        match self.iommu_page_size()? {
+            Some(page_size) if vaddr & (page_size - 1) != 0 => {
+                Err(VfioError::IommuDmaMapUnalignedVaddr { vaddr, page_size })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Check that [iova, iova + size) fits in a single IOVA window, when windows are reported.
+    fn check_iova_window(&self, iova: u64, size: u64) -> Result<()> {
+        if size == 0 {
+            // Let the kernel reject the mapping.
+            return Ok(());
+        }
+        let last = iova
+            .checked_add(size - 1)
+            .ok_or_else(|| VfioError::IommuDmaMap(SysError::new(libc::EOVERFLOW)))?;
+
+        let ranges = self.valid_iova_ranges()?;
+        if ranges.is_empty() || ranges.iter().any(|r| r.start <= iova && last <= r.end) {
+            Ok(())
+        } else {
+            Err(VfioError::IommuDmaMapCrossWindow { iova, size })
+        }
+    }
+
+    // Check that [iova, iova + size) is reachable by all the devices which set a DMA mask.
+    fn check_dma_masks(&self, iova: u64, size: u64) -> Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+        // check_iova_window() refused overflowing ranges.
+        let last = iova + (size - 1);
+        let masks = lock_or_recover(&self.dma_masks);
+        match masks
+            .values()
+            .filter(|(_, mask)| last > *mask)
+            .min_by_key(|(_, mask)| *mask)
+        {
+            Some((device, mask)) => Err(VfioError::IommuDmaMapBeyondDmaMask {
+                iova,
+                size,
+                device: device.clone(),
+                mask: *mask,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Limit the number of bytes which may be mapped through this container.
+    ///
+    /// Mappings exceeding the budget are refused with `VfioError::MappingBudgetExceeded`.
+    /// Lowering the budget below `mapped_bytes()` doesn't unmap anything, but refuses any new
+    /// mapping until enough memory is unmapped.
+    ///
+    /// # Parameters
+    /// * budget: maximum number of mapped bytes, `None` to remove the limit.
+    pub fn set_mapping_budget(&self, budget: Option<u64>) {
+        *lock_or_recover(&self.mapping_budget) = budget;
+    }
+
+    /// Limit the size of the region capabilities fetched for the devices opened afterwards.
+    ///
+    /// The kernel hints the size of the capability chain of a region, which is allocated before
+    /// fetching it. Regions hinting a larger size are kept without their capabilities by
+    /// `VfioDevice::new()`, with a warning, and lose `VFIO_REGION_INFO_FLAG_MMAP` as the parts
+    /// which may be mmap'ed are unknown. `VfioDevice::refresh_region()` fails with
+    /// `VfioError::RegionCapsTooLarge` instead. Defaults to 4 MiB.
+    ///
+    /// # Parameters
+    /// * size: maximum size in bytes of the region info, capabilities included.
+    pub fn set_region_caps_max_size(&self, size: u32) {
+        *lock_or_recover(&self.region_caps_max_size) = size;
+    }
+
+    pub(crate) fn region_caps_max_size(&self) -> u32 {
+        *lock_or_recover(&self.region_caps_max_size)
+    }
+
+    /// Signal an EventFd when the number of DMA mappings the container may still create drops
+    /// below a threshold.
+    ///
+    /// This lets users such as virtio-iommu devices start coalescing mappings before the
+    /// kernel refuses new ones with `ENOSPC`. The available mappings reported by
+    /// [`dma_avail()`](Self::dma_avail) are checked after the mappings created through the
+    /// container. As each mapping consumes one entry, they are only queried again once enough
+    /// mappings were created to reach the threshold, then on every mapping while below it.
+    /// `notify` is written once each time the threshold is crossed downward, including right
+    /// away if the available mappings are already below it. Replaces the previous watermark.
+    ///
+    /// Fails with `VfioError::DmaAvailUnsupported` if the kernel doesn't report the available
+    /// mappings.
+    ///
+    /// # Parameters
+    /// * threshold: number of available mappings below which `notify` is signaled.
+    /// * notify: EventFd to signal.
+    pub fn set_dma_avail_watermark(&self, threshold: u32, notify: EventFd) -> Result<()> {
+        let avail = self.dma_avail()?.ok_or(VfioError::DmaAvailUnsupported)?;
+        let mut watermark = DmaAvailWatermark {
+            threshold,
+            notify,
+            below: false,
+            maps_until_query: 0,
+        };
+        watermark.update(avail);
+        *lock_or_recover(&self.dma_avail_watermark) = Some(watermark);
+
+        Ok(())
+    }
+
+    /// Stop signaling the EventFd set by
+    /// [`set_dma_avail_watermark()`](Self::set_dma_avail_watermark).
+    pub fn clear_dma_avail_watermark(&self) {
+        *lock_or_recover(&self.dma_avail_watermark) = None;
+    }
+
+    /// Wait for the groups opened by the devices created afterwards to become viable.
+    ///
+    /// By default `VfioDevice::new()` fails with `VfioError::GroupViable`, listing the devices
+    /// of the group bound to other drivers, if the group of the device isn't viable yet. See
+    /// [`VfioGroup::wait_viable()`] for the behavior once set.
+    ///
+    /// # Parameters
+    /// * wait: timeout and poll interval of the wait, `None` to fail immediately.
+    pub fn set_group_viable_wait(&self, wait: Option<(Duration, Duration)>) {
+        *lock_or_recover(&self.group_viable_wait) = wait;
+    }
+
+    /// Get the number of bytes mapped through this container.
+    pub fn mapped_bytes(&self) -> u64 {
+        Self::mapped_bytes_locked(&lock_or_recover(&self.mappings))
+    }
+
+    fn mapped_bytes_locked(mappings: &BTreeMap<u64, VfioDmaMapping>) -> u64 {
+        mappings
+            .values()
+            .fold(0u64, |total, m| total.saturating_add(m.size))
+    }
+
+    /// Get the DMA mappings established through this container, sorted by IOVA.
+    pub fn mappings(&self) -> Vec<VfioDmaMapping> {
+        lock_or_recover(&self.mappings).values().copied().collect()
+    }
+
+    /// Get the DMA mappings created with a tag by
+    /// [`dma_map_tagged()`](Self::dma_map_tagged), sorted by IOVA.
+    ///
+    /// # Parameters
+    /// * tag: component which created the mappings.
+    pub fn mappings_by_tag(&self, tag: &str) -> Vec<VfioDmaMapping> {
+        let mappings = lock_or_recover(&self.mappings);
+        let tags = lock_or_recover(&self.mapping_tags);
+        mappings
+            .values()
+            .filter(|m| tags.get(&m.iova.raw_value()).is_some_and(|t| t == tag))
+            .copied()
+            .collect()
+    }
+
+    /// Unmap all the DMA mappings created with a tag, carrying on after failures.
+    ///
+    /// Every mapping of the tag is attempted, and the outcome of each one is reported in IOVA
+    /// order. Mappings which failed to be unmapped keep their tag.
+    ///
+    /// # Parameters
+    /// * tag: component which created the mappings.
+    pub fn unmap_by_tag(&self, tag: &str) -> TagUnmapReport {
+        let mut mappings = lock_or_recover(&self.mappings);
+        let tagged: Vec<VfioDmaMapping> = {
+            let tags = lock_or_recover(&self.mapping_tags);
+            mappings
+                .values()
+                .filter(|m| tags.get(&m.iova.raw_value()).is_some_and(|t| t == tag))
+                .copied()
+                .collect()
+        };
+
+        let mut report = TagUnmapReport::default();
+        for mapping in tagged {
+            let outcome = match self.dma_unmap_locked(
+                &mut mappings,
+                mapping.iova.raw_value(),
+                mapping.size,
+            ) {
+                Ok(size) if size == mapping.size => UnmapOutcome::Unmapped,
+                Ok(_) => UnmapOutcome::Failed(libc::EINVAL),
+                Err(VfioError::IommuDmaUnmap(e)) => UnmapOutcome::Failed(e.errno()),
+                Err(_) => UnmapOutcome::Failed(libc::EIO),
+            };
+            report.mappings.push(MappingUnmap { mapping, outcome });
+        }
+
+        report
+    }
+
+    /// Unmap a region of memory from the vfio container's iommu table.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address of the memory region.
+    /// * size: size of the memory region.
+    pub fn dma_unmap(&self, iova: Iova, size: usize) -> Result<()> {
+        self.dma_unmap_exact(iova, size as u64)
+    }
+
+    /// Unmap a region of guest memory regions into the vfio container's iommu table.
+    ///
+    /// Prefer [`dma_unmap()`](Self::dma_unmap), taking a typed IO virtual address. This
+    /// function is kept for existing callers and may be deprecated.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to mapping the memory.
+    /// * size: size of the memory region.
+    pub fn vfio_dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
+        self.dma_unmap_exact(Iova(iova), size)
+    }
+
+    // Unmap [iova, iova + size), which must be made of whole mappings.
+    fn dma_unmap_exact(&self, iova: Iova, size: u64) -> Result<()> {
+        let mut mappings = lock_or_recover(&self.mappings);
+        if self.dma_unmap_locked(&mut mappings, iova.raw_value(), size)? != size {
+            return Err(VfioError::InvalidDmaUnmapSize);
+        }
+
+        Ok(())
+    }
+
+    /// Unmap a sub-range of an existing DMA mapping, keeping the rest of it mapped.
+    ///
+    /// The type1v2 IOMMU only unmaps whole mappings, so the mapping containing
+    /// [iova, iova + size) is unmapped, and the parts before and after the hole are mapped
+    /// again with the same host address and flags. DMA to the remaining parts faults while
+    /// they are being remapped.
+    ///
+    /// If remapping fails, the original mapping is restored and the remapping error is
+    /// returned. If restoring fails too, `VfioError::IommuDmaRestore` reports both errors and
+    /// the range is left unmapped.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address of the hole.
+    /// * size: size of the hole.
+    pub fn vfio_dma_punch_hole(&self, iova: u64, size: u64) -> Result<()> {
+        let hole_end = iova
+            .checked_add(size)
+            .ok_or_else(|| VfioError::IommuDmaUnmap(SysError::new(libc::EOVERFLOW)))?;
+
+        let mut mappings = lock_or_recover(&self.mappings);
+        let orig = mappings
+            .range(..=iova)
+            .next_back()
+            .map(|(_, m)| *m)
+            .filter(|m| size > 0 && hole_end <= m.iova.raw_value() + m.size)
+            .ok_or(VfioError::IommuDmaNoMapping { iova, size })?;
+        let orig_iova = orig.iova.raw_value();
+        let orig_end = orig_iova + orig.size;
+        let tag = lock_or_recover(&self.mapping_tags).get(&orig_iova).cloned();
+
+        if self.dma_unmap_locked(&mut mappings, orig_iova, orig.size)? != orig.size {
+            return Err(VfioError::InvalidDmaUnmapSize);
+        }
+
+        let mut remainders = Vec::new();
+        if iova > orig_iova {
+            remainders.push(VfioDmaMapping {
+                size: iova - orig_iova,
+                ..orig
+            });
+        }
+        if hole_end < orig_end {
+            remainders.push(VfioDmaMapping {
+                iova: Iova(hole_end),
+                size: orig_end - hole_end,
+                user_addr: HostVa(orig.user_addr.raw_value() + (hole_end - orig_iova)),
+                flags: orig.flags,
+            });
+        }
+
+        for (i, remainder) in remainders.iter().enumerate() {
+            if let Err(e) = self.dma_map_locked(&mut mappings, *remainder) {
+                for mapped in remainders[..i].iter() {
+                    let _ =
+                        self.dma_unmap_locked(&mut mappings, mapped.iova.raw_value(), mapped.size);
+                }
+                if let Err(restore) = self.dma_map_locked(&mut mappings, orig) {
+                    return Err(VfioError::IommuDmaRestore {
+                        error: Box::new(e),
+                        restore: Box::new(restore),
+                    });
+                }
+                self.set_mapping_tag(orig_iova, tag);
+                return Err(e);
+            }
+        }
+        for remainder in remainders.iter() {
+            self.set_mapping_tag(remainder.iova.raw_value(), tag.clone());
+        }
+        drop(mappings);
+        self.check_dma_avail_watermark(remainders.len() as u32);
+
+        Ok(())
+    }
+
+    /// Set the base IO virtual address of the DMA window.
+    ///
+    /// On IOMMUs with a fixed DMA window not starting at 0 (SPAPR for instance), guest memory is
+    /// mapped at `iova_base + guest physical address` by `vfio_map_guest_memory()` and
+    /// `vfio_unmap_guest_memory()`. It defaults to 0, and must be set before mapping guest
+    /// memory, whose mappings aren't moved.
+    ///
+    /// # Parameters
+    /// * iova_base: IO virtual address of the start of the DMA window.
+    pub fn set_iova_base(&self, iova_base: u64) {
+        self.iova_base.store(iova_base, Ordering::Relaxed);
+    }
+
+    /// Get the base IO virtual address of the DMA window.
+    pub fn iova_base(&self) -> u64 {
+        self.iova_base.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn guest_iova(&self, gpa: GuestAddress) -> Option<u64> {
+        self.iova_base().checked_add(gpa.raw_value())
+    }
+
+    /// Add all guest memory regions into the vfio container's iommu table.
+    ///
+    /// # Parameters
+    /// * mem: pinned guest memory which could be accessed by devices binding to the container.
+    pub fn vfio_map_guest_memory<M: GuestMemory>(&self, mem: &M) -> Result<()> {
+        self.vfio_map_guest_memory_with_progress(mem, |_, _| {})
+    }
+
+    /// Add all guest memory regions into the vfio container's iommu table, reporting progress.
+    ///
+    /// `progress` is called after each region is mapped with the number of bytes mapped so
+    /// far and the total size of the guest memory. It isn't called for the failing region.
+    ///
+    /// # Parameters
+    /// * mem: pinned guest memory which could be accessed by devices binding to the container.
+    /// * progress: callback receiving `(mapped_bytes, total_bytes)`.
+    pub fn vfio_map_guest_memory_with_progress<M, F>(&self, mem: &M, mut progress: F) -> Result<()>
+    where
+        M: GuestMemory,
+        F: FnMut(u64, u64),
+    {
+        let total: u64 = mem.iter().map(|region| region.len()).sum();
+        let mut mapped = 0;
+        mem.iter().try_for_each(|region| {
+            let host_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(|_| VfioError::GetHostAddress)?;
+            self.vfio_dma_map(
+                self.guest_iova(region.start_addr())
+                    .ok_or_else(|| VfioError::IommuDmaMap(SysError::new(libc::EOVERFLOW)))?,
+                region.len(),
+                host_addr as u64,
+            )?;
+            mapped += region.len();
+            progress(mapped, total);
+            Ok(())
+        })
+    }
+
+    /// Predict the IOMMU page size each guest memory region is mapped with.
+    ///
+    /// The kernel doesn't report the page sizes used by a mapping, but it can only use a page
+    /// size when the IO virtual address, host virtual address and size of the mapping are all
+    /// aligned to it. Regions with `limited_by` set get smaller IOTLB entries than their size
+    /// permits.
+    ///
+    /// Fails with `VfioError::IommuPgsizesUnsupported` if the kernel doesn't report the page
+    /// sizes supported by the IOMMU.
+    ///
+    /// # Parameters
+    /// * mem: guest memory mapped, or to be mapped, with `vfio_map_guest_memory()`.
+    pub fn mapping_pgsize_report<M: GuestMemory>(
+        &self,
+        mem: &M,
+    ) -> Result<Vec<RegionPgsizeReport>> {
+        let pgsizes = match self.iommu_info()?.pgsizes {
+            0 => return Err(VfioError::IommuPgsizesUnsupported),
+            pgsizes => pgsizes,
+        };
+
+        mem.iter()
+            .map(|region| {
+                let host_addr = region
+                    .get_host_address(MemoryRegionAddress(0))
+                    .map_err(|_| VfioError::GetHostAddress)?;
+                let iova = self
+                    .guest_iova(region.start_addr())
+                    .ok_or_else(|| VfioError::IommuDmaMap(SysError::new(libc::EOVERFLOW)))?;
+                Ok(RegionPgsizeReport::new(
+                    pgsizes,
+                    region.start_addr().raw_value(),
+                    iova,
+                    host_addr as u64,
+                    region.len(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Remove all guest memory regions from the vfio container's iommu table.
+    ///
+    /// The vfio kernel driver and device hardware couldn't access this guest memory after
+    /// returning from the function.
+    ///
+    /// A region mapped as several smaller DMA mappings is unmapped one mapping at a time, with
+    /// the boundaries recorded when it was mapped.
+    ///
+    /// # Parameters
+    /// * mem: pinned guest memory which could be accessed by devices binding to the container.
+    pub fn vfio_unmap_guest_memory<M: GuestMemory>(&self, mem: &M) -> Result<()> {
+        mem.iter().try_for_each(|region| {
+            let iova = self
+                .guest_iova(region.start_addr())
+                .ok_or_else(|| VfioError::IommuDmaUnmap(SysError::new(libc::EOVERFLOW)))?;
+            self.vfio_unmap_chunks(iova, region.len())
+        })
+    }
+
+    /// Remove all guest memory regions from the vfio container's iommu table, carrying on
+    /// after failures.
+    ///
+    /// Unlike [`vfio_unmap_guest_memory()`](Self::vfio_unmap_guest_memory), every region is
+    /// attempted, which suits cleanup paths after a partial `vfio_map_guest_memory()`. Regions
+    /// not covered by any mapping of the container are reported as not mapped rather than
+    /// failing. `VfioError::GuestMemoryUnmap` is only returned if some region failed to be
+    /// unmapped, and carries the report.
+    ///
+    /// # Parameters
+    /// * mem: guest memory mapped, or partially mapped, with `vfio_map_guest_memory()`.
+    pub fn vfio_unmap_guest_memory_best_effort<M: GuestMemory>(
+        &self,
+        mem: &M,
+    ) -> Result<UnmapReport> {
+        let report = UnmapReport {
+            regions: mem
+                .iter()
+                .map(|region| {
+                    // An IOVA overflowing can't have been mapped.
+                    let outcome = match self.guest_iova(region.start_addr()) {
+                        Some(iova) => self.unmap_region_best_effort(iova, region.len()),
+                        None => UnmapOutcome::NotMapped,
+                    };
+                    RegionUnmap {
+                        guest_addr: region.start_addr().raw_value(),
+                        size: region.len(),
+                        outcome,
+                    }
+                })
+                .collect(),
+        };
+
+        if report.failed() > 0 {
+            return Err(VfioError::GuestMemoryUnmap(report));
+        }
+        Ok(report)
+    }
+
+    fn unmap_region_best_effort(&self, iova: u64, size: u64) -> UnmapOutcome {
+        let end = iova.saturating_add(size);
+        // Mappings don't overlap, so only the last one starting before the end may overlap.
+        let mapped = lock_or_recover(&self.mappings)
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, m)| m.iova.raw_value().saturating_add(m.size) > iova);
+        if !mapped {
+            return UnmapOutcome::NotMapped;
+        }
+
+        match self.vfio_unmap_chunks(iova, size) {
+            Ok(()) => UnmapOutcome::Unmapped,
+            Err(VfioError::IommuDmaUnmap(e)) => UnmapOutcome::Failed(e.errno()),
+            // Part of the region is still mapped.
+            Err(_) => UnmapOutcome::Failed(libc::EINVAL),
+        }
+    }
+
+    // Unmap [iova, iova + size), replaying the mappings covering it when they tile it exactly.
+    fn vfio_unmap_chunks(&self, iova: u64, size: u64) -> Result<()> {
+        let mut mappings = lock_or_recover(&self.mappings);
+        let end = iova.saturating_add(size);
+        let chunks: Vec<(u64, u64)> = mappings
+            .range(iova..end)
+            .map(|(_, m)| (m.iova.raw_value(), m.size))
+            .collect();
+        let tiled = chunks
+            .iter()
+            .try_fold(iova, |next, (chunk_iova, chunk_size)| {
+                if *chunk_iova == next {
+                    chunk_iova.checked_add(*chunk_size)
+                } else {
+                    None
+                }
+            })
+            == Some(end);
+
+        if chunks.len() > 1 && tiled {
+            for (chunk_iova, chunk_size) in chunks {
+                if self.dma_unmap_locked(&mut mappings, chunk_iova, chunk_size)? != chunk_size {
+                    return Err(VfioError::InvalidDmaUnmapSize);
+                }
+            }
+        } else if self.dma_unmap_locked(&mut mappings, iova, size)? != size {
+            return Err(VfioError::InvalidDmaUnmapSize);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(
         any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")),
         not(test)
     ))]
@@ -387,8 +2070,9 @@ impl VfioContainer {
                         attr: u64::from(flag),
                         addr: group_fd_ptr as u64,
                     };
+                    // mshv-ioctls reports errors with a vmm-sys-util release of its own.
                     fd.set_device_attr(&dev_attr)
-                        .map_err(VfioError::SetDeviceAttr)
+                        .map_err(|e| VfioError::SetDeviceAttr(SysError::new(e.errno())))
                 }
             }
         } else {
@@ -410,34 +2094,146 @@ impl VfioContainer {
         self.device_set_group(group, true)
     }
 
-    /// Delete a device from a VFIO group
-    ///
-    /// The VFIO device fd should have been set.
-    ///
-    /// # Parameters
-    /// * group: target VFIO group
-    #[cfg(all(
-        any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")),
-        not(test)
-    ))]
-    fn device_del_group(&self, group: &VfioGroup) -> Result<()> {
-        self.device_set_group(group, false)
+    /// Delete a device from a VFIO group
+    ///
+    /// The VFIO device fd should have been set.
+    ///
+    /// # Parameters
+    /// * group: target VFIO group
+    #[cfg(all(
+        any(feature = "kvm", all(feature = "mshv", target_arch = "x86_64")),
+        not(test)
+    ))]
+    fn device_del_group(&self, group: &VfioGroup) -> Result<()> {
+        self.device_set_group(group, false)
+    }
+
+    #[cfg(test)]
+    fn device_add_group(&self, _group: &VfioGroup) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn device_del_group(&self, _group: &VfioGroup) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for VfioContainer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.container.as_raw_fd()
+    }
+}
+
+/// A read only view of a [`VfioContainer`], for components which must not change its state.
+///
+/// The view only offers the container queries, and the notification of the available DMA
+/// mappings: it can't map or unmap memory nor attach groups, and doesn't give access to the
+/// container file descriptor. Cloning it is cheap.
+#[derive(Clone)]
+pub struct VfioContainerRef(Arc<VfioContainer>);
+
+impl VfioContainerRef {
+    /// See [`VfioContainer::paths()`].
+    pub fn paths(&self) -> &VfioPaths {
+        self.0.paths()
+    }
+
+    /// See [`VfioContainer::group_ids()`].
+    pub fn group_ids(&self) -> Vec<u32> {
+        self.0.group_ids()
+    }
+
+    /// See [`VfioContainer::group_count()`].
+    pub fn group_count(&self) -> usize {
+        self.0.group_count()
+    }
+
+    /// See [`VfioContainer::supports_extension()`].
+    pub fn supports_extension(&self, extension: u32) -> bool {
+        self.0.supports_extension(extension)
+    }
+
+    /// See [`VfioContainer::supports_dma_flag()`].
+    pub fn supports_dma_flag(&self, flag: u32) -> bool {
+        self.0.supports_dma_flag(flag)
+    }
+
+    /// See [`VfioContainer::supports_dma_unmap_flag()`].
+    pub fn supports_dma_unmap_flag(&self, flag: u32) -> bool {
+        self.0.supports_dma_unmap_flag(flag)
+    }
+
+    /// See [`VfioContainer::valid_iova_ranges()`].
+    pub fn valid_iova_ranges(&self) -> Result<Vec<VfioIovaRange>> {
+        self.0.valid_iova_ranges()
+    }
+
+    /// See [`VfioContainer::iommu_page_size()`].
+    pub fn iommu_page_size(&self) -> Result<Option<u64>> {
+        self.0.iommu_page_size()
+    }
+
+    /// See [`VfioContainer::iova_base()`].
+    pub fn iova_base(&self) -> u64 {
+        self.0.iova_base()
+    }
+
+    /// See [`VfioContainer::mapped_bytes()`].
+    pub fn mapped_bytes(&self) -> u64 {
+        self.0.mapped_bytes()
+    }
+
+    /// See [`VfioContainer::mappings()`].
+    pub fn mappings(&self) -> Vec<VfioDmaMapping> {
+        self.0.mappings()
+    }
+
+    /// See [`VfioContainer::mappings_by_tag()`].
+    pub fn mappings_by_tag(&self, tag: &str) -> Vec<VfioDmaMapping> {
+        self.0.mappings_by_tag(tag)
+    }
+
+    /// See [`VfioContainer::mapping_pgsize_report()`].
+    pub fn mapping_pgsize_report<M: GuestMemory>(
+        &self,
+        mem: &M,
+    ) -> Result<Vec<RegionPgsizeReport>> {
+        self.0.mapping_pgsize_report(mem)
+    }
+
+    /// See [`VfioContainer::multi_group_policy()`].
+    pub fn multi_group_policy(&self) -> MultiGroupPolicy {
+        self.0.multi_group_policy()
+    }
+
+    /// See [`VfioContainer::fallback_group_ids()`].
+    pub fn fallback_group_ids(&self) -> Vec<u32> {
+        self.0.fallback_group_ids()
+    }
+
+    /// See [`VfioContainer::dma_avail()`].
+    pub fn dma_avail(&self) -> Result<Option<u32>> {
+        self.0.dma_avail()
     }
 
-    #[cfg(test)]
-    fn device_add_group(&self, _group: &VfioGroup) -> Result<()> {
-        Ok(())
+    /// See [`VfioContainer::set_dma_avail_watermark()`].
+    pub fn set_dma_avail_watermark(&self, threshold: u32, notify: EventFd) -> Result<()> {
+        self.0.set_dma_avail_watermark(threshold, notify)
     }
 
-    #[cfg(test)]
-    fn device_del_group(&self, _group: &VfioGroup) -> Result<()> {
-        Ok(())
+    /// See [`VfioContainer::clear_dma_avail_watermark()`].
+    pub fn clear_dma_avail_watermark(&self) {
+        self.0.clear_dma_avail_watermark()
     }
 }
 
-impl AsRawFd for VfioContainer {
-    fn as_raw_fd(&self) -> RawFd {
-        self.container.as_raw_fd()
+impl fmt::Debug for VfioContainerRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VfioContainerRef")
+            .field("groups", &self.group_ids())
+            .field("mapped_bytes", &self.mapped_bytes())
+            .finish()
     }
 }
 
@@ -449,17 +2245,22 @@ impl AsRawFd for VfioContainer {
 /// details.
 pub struct VfioGroup {
     pub(crate) id: u32,
-    pub(crate) group: File,
+    pub(crate) group: OwnedFd,
+    // Locations used to list the devices of the group.
+    pub(crate) paths: VfioPaths,
+    // Cleared for a group bound to the container by the process which opened it, which is
+    // left bound when released.
+    pub(crate) owned: AtomicBool,
 }
 
 impl VfioGroup {
     #[cfg(not(test))]
-    fn open_group_file(id: u32) -> Result<File> {
-        let group_path = Path::new("/dev/vfio").join(id.to_string());
+    fn open_group_file(id: u32, paths: &VfioPaths) -> Result<OwnedFd> {
         OpenOptions::new()
             .read(true)
             .write(true)
-            .open(&group_path)
+            .open(paths.group_path(id))
+            .map(OwnedFd::from)
             .map_err(|e| VfioError::OpenGroup(e, id.to_string()))
     }
 
@@ -467,45 +2268,135 @@ impl VfioGroup {
     ///
     /// # Parameters
     /// * `id`: ID(index) of the VFIO group file.
-    fn new(id: u32) -> Result<Self> {
-        let group = Self::open_group_file(id)?;
+    /// * `paths`: Locations of the VFIO character devices.
+    fn new(id: u32, paths: &VfioPaths) -> Result<Self> {
+        let group = Self::open(id, paths)?;
+        if group.status()? != VFIO_GROUP_FLAGS_VIABLE {
+            return Err(group.not_viable_error());
+        }
+
+        Ok(group)
+    }
+
+    pub(crate) fn open(id: u32, paths: &VfioPaths) -> Result<Self> {
+        Ok(VfioGroup {
+            id,
+            group: Self::open_group_file(id, paths)?,
+            paths: paths.clone(),
+            owned: AtomicBool::new(true),
+        })
+    }
+
+    /// Create a VfioGroup object from an already opened VFIO group file.
+    ///
+    /// The group may already be bound to a container, see `VfioContainer::attach_group()`.
+    ///
+    /// # Parameters
+    /// * `id`: ID(index) of the VFIO group.
+    /// * `group`: the opened `/dev/vfio/<id>` file.
+    pub fn from_fd(id: u32, group: File) -> Result<Self> {
+        Self::from_fd_with_paths(id, group, &VfioPaths::default())
+    }
+
+    /// Create a VfioGroup object from an already opened VFIO group file, under non-standard
+    /// VFIO and sysfs directories.
+    ///
+    /// # Parameters
+    /// * `id`: ID(index) of the VFIO group.
+    /// * `group`: the opened VFIO group file.
+    /// * `paths`: Locations of the VFIO character devices and of the sysfs tree, used to list
+    ///   the devices of the group.
+    pub fn from_fd_with_paths(id: u32, group: File, paths: &VfioPaths) -> Result<Self> {
+        let group = VfioGroup {
+            id,
+            group: group.into(),
+            paths: paths.clone(),
+            owned: AtomicBool::new(true),
+        };
+        if group.status()? & VFIO_GROUP_FLAGS_VIABLE == 0 {
+            return Err(group.not_viable_error());
+        }
+
+        Ok(group)
+    }
+
+    pub(crate) fn status(&self) -> Result<u32> {
         let mut group_status = vfio_group_status {
             argsz: mem::size_of::<vfio_group_status>() as u32,
             flags: 0,
         };
-        vfio_syscall::get_group_status(&group, &mut group_status)?;
-        if group_status.flags != VFIO_GROUP_FLAGS_VIABLE {
-            return Err(VfioError::GroupViable);
-        }
+        vfio_syscall::get_group_status(&self.group, &mut group_status)?;
 
-        Ok(VfioGroup { id, group })
+        Ok(group_status.flags)
     }
 
-    fn id(&self) -> u32 {
+    pub(crate) fn id(&self) -> u32 {
         self.id
     }
 
-    fn get_device(&self, name: &Path) -> Result<VfioDeviceInfo> {
+    // List the devices of the group bound to drivers preventing it from being viable.
+    fn blocking_devices(&self) -> Vec<String> {
+        match self.paths.iommu_group_devices(self.id) {
+            Ok(devices) => devices
+                .into_iter()
+                .filter(|d| d.blocks_viability())
+                .map(|d| d.name)
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to list the devices of VFIO group {}: {}",
+                    self.id, e
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    fn not_viable_error(&self) -> VfioError {
+        VfioError::GroupViable {
+            group_id: self.id,
+            devices: self.blocking_devices(),
+        }
+    }
+
+    /// Wait for the group to become viable.
+    ///
+    /// Right after binding the last device of a group to a VFIO driver, the group may still be
+    /// reported as not viable for a short while. The group status is polled every
+    /// `poll_interval` until it is viable, or `timeout` expires in which case
+    /// `VfioError::GroupViableTimeout` reports the last status along with the devices of the
+    /// group still bound to other drivers.
+    ///
+    /// # Parameters
+    /// * `timeout`: maximum time to wait for the group.
+    /// * `poll_interval`: time between two status queries.
+    pub fn wait_viable(&self, timeout: Duration, poll_interval: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = self.status()?;
+            if status & VFIO_GROUP_FLAGS_VIABLE != 0 {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(VfioError::GroupViableTimeout {
+                    group_id: self.id,
+                    status,
+                    devices: self.blocking_devices(),
+                });
+            }
+            thread::sleep(poll_interval.min(deadline - now));
+        }
+    }
+
+    pub(crate) fn get_device(&self, name: &Path) -> Result<VfioDeviceInfo> {
         let uuid_osstr = name.file_name().ok_or(VfioError::InvalidPath)?;
         let uuid_str = uuid_osstr.to_str().ok_or(VfioError::InvalidPath)?;
         let path: CString = CString::new(uuid_str.as_bytes()).expect("CString::new() failed");
         let device = vfio_syscall::get_group_device_fd(self, &path)?;
 
-        let mut dev_info = vfio_device_info {
-            argsz: mem::size_of::<vfio_device_info>() as u32,
-            flags: 0,
-            num_regions: 0,
-            num_irqs: 0,
-        };
-        vfio_syscall::get_device_info(&device, &mut dev_info)?;
-        if (dev_info.flags & VFIO_DEVICE_FLAGS_PCI) == 0
-            || dev_info.num_regions < VFIO_PCI_CONFIG_REGION_INDEX + 1
-            || dev_info.num_irqs < VFIO_PCI_MSIX_IRQ_INDEX + 1
-        {
-            return Err(VfioError::VfioDeviceGetInfo);
-        }
-
-        Ok(VfioDeviceInfo::new(device, &dev_info))
+        VfioDeviceInfo::from_device(device)
     }
 }
 
@@ -517,6 +2408,7 @@ impl AsRawFd for VfioGroup {
 
 /// Represent one area of the sparse mmap
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VfioRegionSparseMmapArea {
     /// Offset of mmap'able area within region
     pub offset: u64,
@@ -524,15 +2416,169 @@ pub struct VfioRegionSparseMmapArea {
     pub size: u64,
 }
 
+impl From<vfio_region_sparse_mmap_area> for VfioRegionSparseMmapArea {
+    fn from(area: vfio_region_sparse_mmap_area) -> Self {
+        VfioRegionSparseMmapArea {
+            offset: area.offset,
+            size: area.size,
+        }
+    }
+}
+
+impl From<VfioRegionSparseMmapArea> for vfio_region_sparse_mmap_area {
+    fn from(area: VfioRegionSparseMmapArea) -> Self {
+        vfio_region_sparse_mmap_area {
+            offset: area.offset,
+            size: area.size,
+        }
+    }
+}
+
+// Version of the region capabilities defined by the kernel ABI.
+const VFIO_REGION_INFO_CAP_VERSION: u16 = 1;
+
+// Default largest region info, capabilities included, fetched from the kernel. Capability chains
+// take a few hundred bytes, sparse mmap areas of large BARs a few KiB, a larger hint is bogus and
+// isn't worth allocating for.
+pub(crate) const VFIO_REGION_INFO_MAX_SIZE: u32 = 4 << 20;
+
+fn region_cap_header(id: u32) -> vfio_info_cap_header {
+    vfio_info_cap_header {
+        id: id as u16,
+        version: VFIO_REGION_INFO_CAP_VERSION,
+        next: 0,
+    }
+}
+
+// Read a fixed size region capability from the start of `cap`, or None if it is truncated.
+fn read_region_cap<T: Copy>(cap: &[u8]) -> Option<T> {
+    if cap.len() < mem::size_of::<T>() {
+        return None;
+    }
+    // SAFETY: `cap` holds a whole T, which is plain data and read without alignment constraint.
+    Some(unsafe { std::ptr::read_unaligned(cap.as_ptr() as *const T) })
+}
+
 /// List of sparse mmap areas
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VfioRegionInfoCapSparseMmap {
     /// List of areas
     pub areas: Vec<VfioRegionSparseMmapArea>,
 }
 
+impl VfioRegionInfoCapSparseMmap {
+    /// Build the kernel `vfio_region_info_cap_sparse_mmap` structure of the capability.
+    ///
+    /// The structure is followed by its areas, so it is stored in a vector of which only the
+    /// first element is a valid `vfio_region_info_cap_sparse_mmap`. The `next` field of the
+    /// header is left to 0.
+    pub fn to_raw(&self) -> Vec<vfio_region_info_cap_sparse_mmap> {
+        let mut raw = vec_with_array_field::<
+            vfio_region_info_cap_sparse_mmap,
+            vfio_region_sparse_mmap_area,
+        >(self.areas.len());
+        raw[0].header = region_cap_header(VFIO_REGION_INFO_CAP_SPARSE_MMAP);
+        raw[0].nr_areas = self.areas.len() as u32;
+        // SAFETY: the vector has been allocated with room for nr_areas areas after the header.
+        let areas = unsafe { raw[0].areas.as_mut_slice(self.areas.len()) };
+        for (dst, src) in areas.iter_mut().zip(self.areas.iter()) {
+            *dst = (*src).into();
+        }
+
+        raw
+    }
+
+    /// Decode a kernel `vfio_region_info_cap_sparse_mmap` structure followed by its areas.
+    ///
+    /// Returns `None` if `raw` isn't a sparse mmap capability or is too small to hold the
+    /// `nr_areas` areas it advertises.
+    pub fn from_raw(raw: &[vfio_region_info_cap_sparse_mmap]) -> Option<Self> {
+        let cap = raw.first()?;
+        if u32::from(cap.header.id) != VFIO_REGION_INFO_CAP_SPARSE_MMAP {
+            return None;
+        }
+        let nr_areas = cap.nr_areas as usize;
+        let needed = nr_areas
+            .checked_mul(mem::size_of::<vfio_region_sparse_mmap_area>())?
+            .checked_add(mem::size_of::<vfio_region_info_cap_sparse_mmap>())?;
+        if mem::size_of_val(raw) < needed {
+            return None;
+        }
+
+        // SAFETY: the slice holds nr_areas areas after the header, as checked above.
+        let areas = unsafe { cap.areas.as_slice(nr_areas) };
+        Some(VfioRegionInfoCapSparseMmap {
+            areas: areas.iter().map(|a| (*a).into()).collect(),
+        })
+    }
+
+    // Decode a sparse mmap capability from the bytes left in the capability chain, without
+    // trusting its `nr_areas` to fit in them.
+    fn from_bytes(cap: &[u8]) -> Option<Self> {
+        let cap_size = mem::size_of::<vfio_region_info_cap_sparse_mmap>();
+        if cap.len() < cap_size {
+            return None;
+        }
+        let nr_areas = (cap.len() - cap_size) / mem::size_of::<vfio_region_sparse_mmap_area>();
+        let mut raw = vec_with_array_field::<
+            vfio_region_info_cap_sparse_mmap,
+            vfio_region_sparse_mmap_area,
+        >(nr_areas);
+        let len = cap.len().min(mem::size_of_val(raw.as_slice()));
+        // SAFETY: both buffers hold at least `len` bytes, and raw is made of plain data.
+        unsafe {
+            std::ptr::copy_nonoverlapping(cap.as_ptr(), raw.as_mut_ptr() as *mut u8, len);
+        }
+
+        Self::from_raw(&raw)
+    }
+
+    // Check the areas reported for the region `index` of `region_size` bytes, and sort them
+    // without overlaps so that mapping them can't reach outside of the region. Areas out of the
+    // region are refused, empty ones are dropped and overlapping ones merged.
+    fn normalize(&mut self, index: u32, region_size: u64) -> Result<()> {
+        for area in self.areas.iter() {
+            match area.offset.checked_add(area.size) {
+                Some(end) if end <= region_size => {}
+                _ => {
+                    return Err(VfioError::InvalidSparseMmapArea {
+                        index,
+                        offset: area.offset,
+                        size: area.size,
+                    })
+                }
+            }
+        }
+        self.areas.retain(|area| area.size != 0);
+        if !self.areas.windows(2).all(|w| w[0].offset <= w[1].offset) {
+            warn!("Region #{} reports unordered sparse mmap areas", index);
+            self.areas.sort_by_key(|area| area.offset);
+        }
+
+        let mut areas: Vec<VfioRegionSparseMmapArea> = Vec::with_capacity(self.areas.len());
+        for area in self.areas.iter() {
+            match areas.last_mut() {
+                Some(last) if area.offset < last.offset + last.size => {
+                    warn!(
+                        "Region #{} reports overlapping sparse mmap areas at {:#x} and {:#x}",
+                        index, last.offset, area.offset
+                    );
+                    let end = (last.offset + last.size).max(area.offset + area.size);
+                    last.size = end - last.offset;
+                }
+                _ => areas.push(*area),
+            }
+        }
+        self.areas = areas;
+
+        Ok(())
+    }
+}
+
 /// Represent a specific device by providing type and subtype
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VfioRegionInfoCapType {
     /// Device type
     pub type_: u32,
@@ -540,22 +2586,77 @@ pub struct VfioRegionInfoCapType {
     pub subtype: u32,
 }
 
+impl From<vfio_region_info_cap_type> for VfioRegionInfoCapType {
+    fn from(cap: vfio_region_info_cap_type) -> Self {
+        VfioRegionInfoCapType {
+            type_: cap.type_,
+            subtype: cap.subtype,
+        }
+    }
+}
+
+impl From<VfioRegionInfoCapType> for vfio_region_info_cap_type {
+    fn from(cap: VfioRegionInfoCapType) -> Self {
+        vfio_region_info_cap_type {
+            header: region_cap_header(VFIO_REGION_INFO_CAP_TYPE),
+            type_: cap.type_,
+            subtype: cap.subtype,
+        }
+    }
+}
+
 /// Carry NVLink SSA TGT information
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VfioRegionInfoCapNvlink2Ssatgt {
     /// TGT value
     pub tgt: u64,
 }
 
+impl From<vfio_region_info_cap_nvlink2_ssatgt> for VfioRegionInfoCapNvlink2Ssatgt {
+    fn from(cap: vfio_region_info_cap_nvlink2_ssatgt) -> Self {
+        VfioRegionInfoCapNvlink2Ssatgt { tgt: cap.tgt }
+    }
+}
+
+impl From<VfioRegionInfoCapNvlink2Ssatgt> for vfio_region_info_cap_nvlink2_ssatgt {
+    fn from(cap: VfioRegionInfoCapNvlink2Ssatgt) -> Self {
+        vfio_region_info_cap_nvlink2_ssatgt {
+            header: region_cap_header(VFIO_REGION_INFO_CAP_NVLINK2_SSATGT),
+            tgt: cap.tgt,
+        }
+    }
+}
+
 /// Carry NVLink link speed information
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VfioRegionInfoCapNvlink2Lnkspd {
     /// Link speed value
     pub link_speed: u32,
 }
 
+impl From<vfio_region_info_cap_nvlink2_lnkspd> for VfioRegionInfoCapNvlink2Lnkspd {
+    fn from(cap: vfio_region_info_cap_nvlink2_lnkspd) -> Self {
+        VfioRegionInfoCapNvlink2Lnkspd {
+            link_speed: cap.link_speed,
+        }
+    }
+}
+
+impl From<VfioRegionInfoCapNvlink2Lnkspd> for vfio_region_info_cap_nvlink2_lnkspd {
+    fn from(cap: VfioRegionInfoCapNvlink2Lnkspd) -> Self {
+        vfio_region_info_cap_nvlink2_lnkspd {
+            header: region_cap_header(VFIO_REGION_INFO_CAP_NVLINK2_LNKSPD),
+            link_speed: cap.link_speed,
+            __pad: 0,
+        }
+    }
+}
+
 /// List of capabilities that can be related to a region.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum VfioRegionInfoCap {
     /// Sparse memory mapping type
     SparseMmap(VfioRegionInfoCapSparseMmap),
@@ -569,17 +2670,418 @@ pub enum VfioRegionInfoCap {
     Nvlink2Lnkspd(VfioRegionInfoCapNvlink2Lnkspd),
 }
 
-/// Information about VFIO MMIO region.
-#[derive(Clone, Debug)]
-pub struct VfioRegion {
-    pub(crate) flags: u32,
-    pub(crate) size: u64,
-    pub(crate) offset: u64,
-    pub(crate) caps: Vec<VfioRegionInfoCap>,
+const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+const PCI_VENDOR_ID_NVIDIA: u16 = 0x10de;
+const PCI_VENDOR_ID_IBM: u16 = 0x1014;
+
+/// Well known vendor specific regions (`VFIO_REGION_TYPE_PCI_VENDOR_TYPE`).
+///
+/// The subtype namespace of vendor typed regions is defined by each vendor, so a region is only
+/// identified once the PCI vendor ID of the device is known. Combinations not listed here are
+/// still available as raw numbers through [`VfioRegionInfoCap::Type`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KnownVendorRegion {
+    /// Intel IGD OpRegion.
+    IntelIgdOpRegion,
+    /// Intel IGD host bridge config space.
+    IntelIgdHostCfg,
+    /// Intel IGD LPC bridge config space.
+    IntelIgdLpcCfg,
+    /// NVIDIA GPU RAM exposed through NVLink2.
+    NvidiaNvlink2Ram,
+    /// IBM NPU Address Translation Shootdown register.
+    IbmNvlink2Atsd,
+}
+
+impl KnownVendorRegion {
+    /// Look up a vendor region from the PCI vendor ID of the device and the region type.
+    ///
+    /// # Arguments
+    /// * `vendor_id` - PCI vendor ID read from the device config space.
+    /// * `cap` - Type capability of the region.
+    pub fn from_type(vendor_id: u16, cap: &VfioRegionInfoCapType) -> Option<Self> {
+        if cap.type_ & VFIO_REGION_TYPE_PCI_VENDOR_TYPE == 0
+            || (cap.type_ & VFIO_REGION_TYPE_PCI_VENDOR_MASK) as u16 != vendor_id
+        {
+            return None;
+        }
+
+        match (vendor_id, cap.subtype) {
+            (PCI_VENDOR_ID_INTEL, VFIO_REGION_SUBTYPE_INTEL_IGD_OPREGION) => {
+                Some(KnownVendorRegion::IntelIgdOpRegion)
+            }
+            (PCI_VENDOR_ID_INTEL, VFIO_REGION_SUBTYPE_INTEL_IGD_HOST_CFG) => {
+                Some(KnownVendorRegion::IntelIgdHostCfg)
+            }
+            (PCI_VENDOR_ID_INTEL, VFIO_REGION_SUBTYPE_INTEL_IGD_LPC_CFG) => {
+                Some(KnownVendorRegion::IntelIgdLpcCfg)
+            }
+            (PCI_VENDOR_ID_NVIDIA, VFIO_REGION_SUBTYPE_NVIDIA_NVLINK2_RAM) => {
+                Some(KnownVendorRegion::NvidiaNvlink2Ram)
+            }
+            (PCI_VENDOR_ID_IBM, VFIO_REGION_SUBTYPE_IBM_NVLINK2_ATSD) => {
+                Some(KnownVendorRegion::IbmNvlink2Atsd)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Device specific region types, as (type, subtype), known to be safe to expose to a guest.
+const KNOWN_REGION_TYPES: [(u32, u32); 4] = [
+    (VFIO_REGION_TYPE_GFX, VFIO_REGION_SUBTYPE_GFX_EDID),
+    (
+        VFIO_REGION_TYPE_PCI_VENDOR_TYPE | PCI_VENDOR_ID_NVIDIA as u32,
+        VFIO_REGION_SUBTYPE_NVIDIA_NVLINK2_RAM,
+    ),
+    (
+        VFIO_REGION_TYPE_PCI_VENDOR_TYPE | PCI_VENDOR_ID_IBM as u32,
+        VFIO_REGION_SUBTYPE_IBM_NVLINK2_ATSD,
+    ),
+    (VFIO_REGION_TYPE_MIGRATION, VFIO_REGION_SUBTYPE_MIGRATION),
+];
+
+/// Classification of a device region, to decide whether it is safe to expose it to a guest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VfioRegionClass {
+    /// Standard PCI region: BARs, expansion ROM, config space or VGA.
+    Standard,
+    /// Device specific region of a well known type.
+    KnownDeviceSpecific,
+    /// Device specific region without type, or of a type unknown to this crate.
+    ///
+    /// Such regions are often quirk regions, e.g. mirrors of the config space, which cause
+    /// subtle misbehavior when exposed to a guest.
+    UnknownDeviceSpecific,
+}
+
+impl fmt::Display for VfioRegionInfoCap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VfioRegionInfoCap::SparseMmap(m) => write!(f, "sparse-mmap({})", m.areas.len()),
+            VfioRegionInfoCap::Type(t) => write!(f, "type({:#x}:{:#x})", t.type_, t.subtype),
+            VfioRegionInfoCap::MsixMappable => write!(f, "msix-mappable"),
+            VfioRegionInfoCap::Nvlink2Ssatgt(_) => write!(f, "nvlink2-ssatgt"),
+            VfioRegionInfoCap::Nvlink2Lnkspd(_) => write!(f, "nvlink2-lnkspd"),
+        }
+    }
+}
+
+// Format a size using the largest binary unit dividing it exactly.
+fn human_size(size: u64) -> String {
+    const UNITS: [(u32, &str); 4] = [(40, "TiB"), (30, "GiB"), (20, "MiB"), (10, "KiB")];
+    for (shift, name) in UNITS.iter() {
+        if size >> shift != 0 && size.trailing_zeros() >= *shift {
+            return format!("{} {}", size >> shift, name);
+        }
+    }
+    format!("{} B", size)
+}
+
+// Format the set bits of `flags` using `names`, or "-" if none is set.
+fn flag_names(flags: u32, names: &[(u32, &str)]) -> String {
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        "-".to_string()
+    } else {
+        set.join("|")
+    }
+}
+
+/// `VFIO_DEVICE_FLAGS_*` reported by `VFIO_DEVICE_GET_INFO`, see `VfioDevice::flags_decoded()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VfioDeviceFlags {
+    /// The device supports `VFIO_DEVICE_RESET`.
+    pub reset: bool,
+    /// PCI device.
+    pub pci: bool,
+    /// Platform device.
+    pub platform: bool,
+    /// ARM AMBA device.
+    pub amba: bool,
+    /// s390 channel I/O device.
+    pub ccw: bool,
+    /// s390 adjunct processor device.
+    pub ap: bool,
+    /// NXP fsl-mc bus device.
+    pub fsl_mc: bool,
+    /// The device information has a capability chain.
+    pub caps: bool,
+    /// AMD CDX bus device.
+    pub cdx: bool,
+    /// Set bits this crate doesn't know about, reported by newer kernels.
+    pub unknown_bits: u32,
+}
+
+impl VfioDeviceFlags {
+    const KNOWN: u32 = VFIO_DEVICE_FLAGS_RESET
+        | VFIO_DEVICE_FLAGS_PCI
+        | VFIO_DEVICE_FLAGS_PLATFORM
+        | VFIO_DEVICE_FLAGS_AMBA
+        | VFIO_DEVICE_FLAGS_CCW
+        | VFIO_DEVICE_FLAGS_AP
+        | VFIO_DEVICE_FLAGS_FSL_MC
+        | VFIO_DEVICE_FLAGS_CAPS
+        | VFIO_DEVICE_FLAGS_CDX;
+
+    /// Get the positions of the unknown bits, from the least significant one.
+    pub fn unknown_bit_positions(&self) -> Vec<u32> {
+        (0..u32::BITS)
+            .filter(|bit| self.unknown_bits & (1 << bit) != 0)
+            .collect()
+    }
+}
+
+impl From<u32> for VfioDeviceFlags {
+    fn from(flags: u32) -> Self {
+        VfioDeviceFlags {
+            reset: flags & VFIO_DEVICE_FLAGS_RESET != 0,
+            pci: flags & VFIO_DEVICE_FLAGS_PCI != 0,
+            platform: flags & VFIO_DEVICE_FLAGS_PLATFORM != 0,
+            amba: flags & VFIO_DEVICE_FLAGS_AMBA != 0,
+            ccw: flags & VFIO_DEVICE_FLAGS_CCW != 0,
+            ap: flags & VFIO_DEVICE_FLAGS_AP != 0,
+            fsl_mc: flags & VFIO_DEVICE_FLAGS_FSL_MC != 0,
+            caps: flags & VFIO_DEVICE_FLAGS_CAPS != 0,
+            cdx: flags & VFIO_DEVICE_FLAGS_CDX != 0,
+            unknown_bits: flags & !Self::KNOWN,
+        }
+    }
+}
+
+/// Access widths accepted by a region, checked by `VfioDevice::region_read()` and
+/// `VfioDevice::region_write()`.
+///
+/// VFIO doesn't report such constraints, they come from the knowledge of the device.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum VfioRegionAccessPolicy {
+    /// Accesses of any width and alignment.
+    #[default]
+    Permissive,
+    /// Naturally aligned accesses of 1, 2, 4, ... up to `max_width` bytes.
+    Aligned {
+        /// Largest accepted access width, in bytes.
+        max_width: u64,
+    },
+}
+
+impl VfioRegionAccessPolicy {
+    /// Policy of the PCI configuration space: naturally aligned 1, 2 or 4 bytes accesses.
+    pub const CONFIG_SPACE: Self = VfioRegionAccessPolicy::Aligned { max_width: 4 };
+
+    /// Check whether an access of `size` bytes at `addr` is accepted.
+    pub fn allows(&self, addr: u64, size: u64) -> bool {
+        match *self {
+            VfioRegionAccessPolicy::Permissive => true,
+            VfioRegionAccessPolicy::Aligned { max_width } => {
+                size.is_power_of_two() && size <= max_width && addr & (size - 1) == 0
+            }
+        }
+    }
+}
+
+/// A VFIO device region mapped in the process address space, unmapped when dropped.
+///
+/// The mapping is shared with the device: accesses go straight to the device memory, so they
+/// are the caller's responsibility, like with any pointer to MMIO.
+#[derive(Debug)]
+pub struct VfioRegionMmap {
+    addr: *mut u8,
+    len: usize,
+    prot: i32,
+}
+
+// SAFETY: the mapping is owned by the object and stays valid until it is dropped. Accesses
+// through the pointer are the responsibility of the caller.
+unsafe impl Send for VfioRegionMmap {}
+// SAFETY: see above, the object itself has no interior mutability.
+unsafe impl Sync for VfioRegionMmap {}
+
+impl VfioRegionMmap {
+    /// Get the address the region is mapped at.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Get the size of the mapping, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the mapping is empty, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the `PROT_*` protection flags of the mapping.
+    pub fn prot(&self) -> i32 {
+        self.prot
+    }
+}
+
+impl Drop for VfioRegionMmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by mmap() with this address and length, and isn't
+        // used anymore.
+        if unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) } < 0 {
+            error!(
+                "Failed to munmap VFIO region at {:p}: {}",
+                self.addr,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// A part of a device region to map in the guest physical address space, as computed by
+/// [`VfioDevice::region_guest_mappings()`].
+///
+/// The VMM mmaps `size` bytes of the device fd at `fd_offset`, and registers the mapping as a
+/// memory slot of the guest at `gpa`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VfioRegionGuestMapping {
+    /// Guest physical address to map the part at.
+    pub gpa: GuestAddress,
+    /// Offset of the part in the device fd, to pass to mmap().
+    pub fd_offset: u64,
+    /// Size of the part, in bytes.
+    pub size: u64,
+}
+
+// Transfers from this size on go through the mmap fast path of region_read() and region_write().
+const MMAP_FASTPATH_MIN_SIZE: usize = 0x1000;
+
+// A mapped part of a region used by the mmap fast path.
+struct FastpathMapping {
+    // Offset of the part in the region.
+    start: u64,
+    mmap: VfioRegionMmap,
+}
+
+impl FastpathMapping {
+    // Get `len` bytes at `addr` of the region if they lie within the part.
+    fn slice(&self, addr: u64, len: usize) -> Option<VolatileSlice<'_>> {
+        let offset = addr.checked_sub(self.start)?;
+        if offset.checked_add(len as u64)? > self.mmap.len() as u64 {
+            return None;
+        }
+
+        // SAFETY: the range lies within the mapping, which outlives the returned slice.
+        Some(unsafe { VolatileSlice::new(self.mmap.as_ptr().add(offset as usize), len) })
+    }
+}
+
+/// Information about VFIO MMIO region.
+#[derive(Clone)]
+pub struct VfioRegion {
+    pub(crate) index: u32,
+    pub(crate) flags: u32,
+    pub(crate) size: u64,
+    pub(crate) offset: u64,
+    pub(crate) caps: RegionCaps,
+}
+
+// Capabilities of a region. The capabilities of all the regions of a device are stored in a
+// single slice shared by them, instead of a list of their own for each region, as deployments
+// open hundreds of devices. Each region refers to its part of the slice.
+#[derive(Clone, Default)]
+pub(crate) struct RegionCaps {
+    // None until the capabilities of the device are stored, and for devices without any.
+    pub(crate) arena: Option<Arc<[VfioRegionInfoCap]>>,
+    start: usize,
+    len: usize,
+}
+
+impl RegionCaps {
+    // Store the capabilities of `regions`, each referring to its part of `caps` as recorded by
+    // VfioDeviceInfo::get_region_map().
+    fn share(regions: &mut [VfioRegion], caps: Vec<VfioRegionInfoCap>) {
+        if caps.is_empty() {
+            return;
+        }
+        let arena: Arc<[VfioRegionInfoCap]> = caps.into();
+        for region in regions.iter_mut().filter(|r| r.caps.len > 0) {
+            region.caps.arena = Some(arena.clone());
+        }
+    }
+}
+
+// Capabilities of a single region, stored apart from the ones of other regions.
+impl From<Vec<VfioRegionInfoCap>> for RegionCaps {
+    fn from(caps: Vec<VfioRegionInfoCap>) -> Self {
+        let len = caps.len();
+        RegionCaps {
+            arena: (len > 0).then(|| caps.into()),
+            start: 0,
+            len,
+        }
+    }
+}
+
+impl Deref for RegionCaps {
+    type Target = [VfioRegionInfoCap];
+
+    fn deref(&self) -> &[VfioRegionInfoCap] {
+        match &self.arena {
+            Some(arena) => &arena[self.start..self.start + self.len],
+            None => &[],
+        }
+    }
+}
+
+impl VfioRegion {
+    fn flag_names(&self) -> String {
+        flag_names(
+            self.flags,
+            &[
+                (VFIO_REGION_INFO_FLAG_READ, "R"),
+                (VFIO_REGION_INFO_FLAG_WRITE, "W"),
+                (VFIO_REGION_INFO_FLAG_MMAP, "M"),
+            ],
+        )
+    }
+
+    fn cap_names(&self) -> String {
+        let caps: Vec<String> = self.caps.iter().map(|c| c.to_string()).collect();
+        format!("[{}]", caps.join(", "))
+    }
+}
+
+impl fmt::Debug for VfioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VfioRegion")
+            .field("index", &self.index)
+            .field(
+                "size",
+                &format_args!("{:#x} ({})", self.size, human_size(self.size)),
+            )
+            .field("offset", &format_args!("{:#x}", self.offset))
+            .field("flags", &format_args!("{}", self.flag_names()))
+            .field("caps", &format_args!("{}", self.cap_names()))
+            .finish()
+    }
+}
+
+impl fmt::Display for VfioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "region {}: {:#x} ({}) {} {}",
+            self.index,
+            self.size,
+            human_size(self.size),
+            self.flag_names(),
+            self.cap_names()
+        )
+    }
 }
 
 /// Information about VFIO interrupts.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct VfioIrq {
     /// Flags for irq.
     pub flags: u32,
@@ -589,15 +3091,130 @@ pub struct VfioIrq {
     pub count: u32,
 }
 
+impl VfioIrq {
+    fn index_name(&self) -> String {
+        match self.index {
+            VFIO_PCI_INTX_IRQ_INDEX => "INTX".to_string(),
+            VFIO_PCI_MSI_IRQ_INDEX => "MSI".to_string(),
+            VFIO_PCI_MSIX_IRQ_INDEX => "MSI-X".to_string(),
+            VFIO_PCI_ERR_IRQ_INDEX => "ERR".to_string(),
+            VFIO_PCI_REQ_IRQ_INDEX => "REQ".to_string(),
+            index => format!("#{}", index),
+        }
+    }
+
+    fn flag_names(&self) -> String {
+        flag_names(
+            self.flags,
+            &[
+                (VFIO_IRQ_INFO_EVENTFD, "EVENTFD"),
+                (VFIO_IRQ_INFO_MASKABLE, "MASKABLE"),
+                (VFIO_IRQ_INFO_AUTOMASKED, "AUTOMASKED"),
+                (VFIO_IRQ_INFO_NORESIZE, "NORESIZE"),
+            ],
+        )
+    }
+}
+
+impl fmt::Debug for VfioIrq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VfioIrq")
+            .field("index", &format_args!("{}", self.index_name()))
+            .field("count", &self.count)
+            .field("flags", &format_args!("{}", self.flag_names()))
+            .finish()
+    }
+}
+
+impl fmt::Display for VfioIrq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} vector(s) {}",
+            self.index_name(),
+            self.count,
+            self.flag_names()
+        )
+    }
+}
+
+// Interrupts of a device, sorted by index. Devices have a handful of indexes, rarely updated
+// once the device is opened, which a boxed slice holds with less memory than a map and searches
+// as fast.
+#[derive(Clone, Default)]
+pub(crate) struct VfioIrqs(Box<[VfioIrq]>);
+
+impl VfioIrqs {
+    fn new(mut irqs: Vec<VfioIrq>) -> Self {
+        irqs.sort_unstable_by_key(|irq| irq.index);
+        VfioIrqs(irqs.into_boxed_slice())
+    }
+
+    fn position(&self, index: u32) -> std::result::Result<usize, usize> {
+        self.0.binary_search_by_key(&index, |irq| irq.index)
+    }
+
+    pub(crate) fn get(&self, index: &u32) -> Option<&VfioIrq> {
+        self.position(*index).ok().map(|pos| &self.0[pos])
+    }
+
+    // Iterate over the interrupts, in ascending index order.
+    pub(crate) fn values(&self) -> std::slice::Iter<'_, VfioIrq> {
+        self.0.iter()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_mut(&mut self, index: &u32) -> Option<&mut VfioIrq> {
+        self.position(*index).ok().map(move |pos| &mut self.0[pos])
+    }
+
+    pub(crate) fn insert(&mut self, index: u32, irq: VfioIrq) {
+        let pos = self.position(index);
+        let mut irqs = mem::take(&mut self.0).into_vec();
+        match pos {
+            Ok(pos) => irqs[pos] = irq,
+            Err(pos) => irqs.insert(pos, irq),
+        }
+        self.0 = irqs.into_boxed_slice();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains_key(&self, index: &u32) -> bool {
+        self.get(index).is_some()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn remove(&mut self, index: &u32) -> Option<VfioIrq> {
+        let pos = self.position(*index).ok()?;
+        let mut irqs = mem::take(&mut self.0).into_vec();
+        let irq = irqs.remove(pos);
+        self.0 = irqs.into_boxed_slice();
+        Some(irq)
+    }
+}
+
+impl std::ops::Index<&u32> for VfioIrqs {
+    type Output = VfioIrq;
+
+    fn index(&self, index: &u32) -> &VfioIrq {
+        self.get(index).expect("no interrupts at this index")
+    }
+}
+
 pub(crate) struct VfioDeviceInfo {
-    device: File,
+    device: OwnedFd,
     flags: u32,
     num_regions: u32,
     num_irqs: u32,
 }
 
 impl VfioDeviceInfo {
-    fn new(device: File, dev_info: &vfio_device_info) -> Self {
+    fn new(device: OwnedFd, dev_info: &vfio_device_info) -> Self {
         VfioDeviceInfo {
             device,
             flags: dev_info.flags,
@@ -606,8 +3223,31 @@ impl VfioDeviceInfo {
         }
     }
 
-    fn get_irqs(&self) -> Result<HashMap<u32, VfioIrq>> {
-        let mut irqs: HashMap<u32, VfioIrq> = HashMap::new();
+    // Query the information of an opened device and check that it is usable.
+    pub(crate) fn from_device(device: OwnedFd) -> Result<Self> {
+        let mut dev_info = vfio_device_info {
+            argsz: mem::size_of::<vfio_device_info>() as u32,
+            flags: 0,
+            num_regions: 0,
+            num_irqs: 0,
+        };
+        vfio_syscall::get_device_info(&device, &mut dev_info)?;
+        // PCI devices have fixed region and irq indexes, other devices only expose what they have.
+        let valid = if dev_info.flags & VFIO_DEVICE_FLAGS_PCI != 0 {
+            dev_info.num_regions > VFIO_PCI_CONFIG_REGION_INDEX
+                && dev_info.num_irqs > VFIO_PCI_MSIX_IRQ_INDEX
+        } else {
+            dev_info.flags & VFIO_DEVICE_FLAGS_PLATFORM != 0
+        };
+        if !valid {
+            return Err(VfioError::VfioDeviceGetInfo);
+        }
+
+        Ok(VfioDeviceInfo::new(device, &dev_info))
+    }
+
+    fn get_irqs(&self) -> Result<VfioIrqs> {
+        let mut irqs: Vec<VfioIrq> = Vec::with_capacity(self.num_irqs as usize);
 
         for index in 0..self.num_irqs {
             let mut irq_info = vfio_irq_info {
@@ -622,6 +3262,9 @@ impl VfioDeviceInfo {
                 continue;
             }
 
+            // Indexes the device doesn't use, e.g. VFIO_PCI_ERR_IRQ_INDEX on a
+            // conventional PCI device, are reported with a count of zero. Record
+            // them anyway, refresh_irq_info() picks up vectors showing up later.
             let irq = VfioIrq {
                 flags: irq_info.flags,
                 index,
@@ -632,157 +3275,239 @@ impl VfioDeviceInfo {
             debug!("\tflag 0x{:x}", irq.flags);
             debug!("\tindex {}", irq.index);
             debug!("\tcount {}", irq.count);
-            irqs.insert(index, irq);
+            irqs.push(irq);
         }
 
-        Ok(irqs)
+        Ok(VfioIrqs::new(irqs))
     }
 
-    fn get_region_map(
-        &self,
+    // Fetch the capabilities of a region, refusing to allocate more than `max_size` bytes for
+    // them or sparse mmap areas out of the region. They are appended to `caps`, which the region
+    // refers to once stored by RegionCaps::share(). Regions whose capabilities can't be fetched
+    // are kept without them, with a warning, rather than being dropped, but lose
+    // VFIO_REGION_INFO_FLAG_MMAP as the parts which may be mmap'ed are then unknown.
+    fn get_region_map<F: AsRawFd>(
+        device: &F,
         region: &mut VfioRegion,
         region_info: &vfio_region_info,
+        max_size: u32,
+        caps: &mut Vec<VfioRegionInfoCap>,
     ) -> Result<()> {
         let region_info_size: u32 = mem::size_of::<vfio_region_info>() as u32;
 
-        if region_info.flags & VFIO_REGION_INFO_FLAG_CAPS == 0
-            || region_info.argsz <= region_info_size
-        {
+        if region_info.flags & VFIO_REGION_INFO_FLAG_CAPS == 0 {
             // There is not capabilities information for that region, we can just return.
             return Ok(());
         }
 
+        // Some devices set the CAPS flag but hint a size that can't hold a single capability
+        // header, there is nothing to fetch in that case.
+        if (region_info.argsz as usize)
+            < mem::size_of::<vfio_region_info>() + mem::size_of::<vfio_info_cap_header>()
+        {
+            debug!(
+                "Region #{} reports capabilities without room for them (argsz {})",
+                region_info.index, region_info.argsz
+            );
+            return Ok(());
+        }
+        if region_info.argsz > max_size {
+            return Err(VfioError::RegionCapsTooLarge {
+                index: region_info.index,
+                argsz: region_info.argsz,
+            });
+        }
+
         // There is a capability information for that region, we have to call
         // VFIO_DEVICE_GET_REGION_INFO with a vfio_region_with_cap structure and the hinted size.
         let mut region_with_cap = vfio_region_info_with_cap::from_region_info(region_info);
-        vfio_syscall::get_device_region_info_cap(self, &mut region_with_cap)?;
-
-        // region_with_cap[0] may contain different types of structure depending on the capability
-        // type, but all of them begin with vfio_info_cap_header in order to identify the capability
-        // type, version and if there's another capability after this one.
-        // It is safe to convert region_with_cap[0] with an offset of cap_offset into
-        // vfio_info_cap_header pointer and access its elements, as long as cap_offset is greater
-        // than region_info_size.
-        //
-        // Safety: following code is safe because we trust data returned by the kernel.
-        if region_with_cap[0].region_info.cap_offset >= region_info_size {
-            let mut next_cap_offset = region_with_cap[0].region_info.cap_offset;
-            let info_ptr = &region_with_cap[0] as *const vfio_region_info_with_cap as *const u8;
-
-            while next_cap_offset >= region_info_size {
-                // SAFETY: data structure returned by kernel is trusted.
-                let cap_header = unsafe {
-                    *(info_ptr.offset(next_cap_offset as isize) as *const vfio_info_cap_header)
-                };
+        if let Err(e) = vfio_syscall::get_device_region_info_cap(device, &mut region_with_cap) {
+            warn!(
+                "Could not get region #{} capabilities, ignoring them: {}",
+                region_info.index, e
+            );
+            region.flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+            return Ok(());
+        }
+        if region_with_cap[0].region_info.cap_offset < region_info_size {
+            debug!(
+                "Region #{} reports capabilities with an empty capability chain",
+                region_info.index
+            );
+            return Ok(());
+        }
 
-                match u32::from(cap_header.id) {
-                    VFIO_REGION_INFO_CAP_SPARSE_MMAP => {
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let sparse_mmap = unsafe {
-                            info_ptr.offset(next_cap_offset as isize)
-                                as *const vfio_region_info_cap_sparse_mmap
-                        };
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let nr_areas = unsafe { (*sparse_mmap).nr_areas };
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let areas = unsafe { (*sparse_mmap).areas.as_slice(nr_areas as usize) };
-
-                        let cap = VfioRegionInfoCapSparseMmap {
-                            areas: areas
-                                .iter()
-                                .map(|a| VfioRegionSparseMmapArea {
-                                    offset: a.offset,
-                                    size: a.size,
-                                })
-                                .collect(),
-                        };
-                        region.caps.push(VfioRegionInfoCap::SparseMmap(cap));
-                    }
-                    VFIO_REGION_INFO_CAP_TYPE => {
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let type_ = unsafe {
-                            *(info_ptr.offset(next_cap_offset as isize)
-                                as *const vfio_region_info_cap_type)
-                        };
-                        let cap = VfioRegionInfoCapType {
-                            type_: type_.type_,
-                            subtype: type_.subtype,
-                        };
-                        region.caps.push(VfioRegionInfoCap::Type(cap));
+        // SAFETY: the buffer is made of plain data initialized when allocated, and the slice
+        // covers the whole allocation.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                region_with_cap.as_ptr() as *const u8,
+                region_with_cap.len() * mem::size_of::<vfio_region_info_with_cap>(),
+            )
+        };
+        // The capabilities may come from a vendor driver, the chain is only trusted once checked
+        // to lie within the buffer. The kernel may also report a larger size than it was given.
+        let end = bytes
+            .len()
+            .min(region_with_cap[0].region_info.argsz as usize);
+        let header_size = mem::size_of::<vfio_info_cap_header>();
+        // Capabilities are chained in increasing offsets, which also stops a looping chain.
+        let mut min_offset = region_info_size as usize;
+        let mut offset = region_with_cap[0].region_info.cap_offset as usize;
+        let start = caps.len();
+        while offset >= region_info_size as usize {
+            if offset < min_offset || offset + header_size > end {
+                warn!(
+                    "Ignoring region #{} capabilities from invalid offset {:#x}",
+                    region_info.index, offset
+                );
+                region.flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+                break;
+            }
+            let cap = &bytes[offset..end];
+            // SAFETY: the header lies within the chain, as checked above.
+            let cap_header =
+                unsafe { std::ptr::read_unaligned(cap.as_ptr() as *const vfio_info_cap_header) };
+
+            match u32::from(cap_header.id) {
+                VFIO_REGION_INFO_CAP_SPARSE_MMAP => {
+                    match VfioRegionInfoCapSparseMmap::from_bytes(cap) {
+                        Some(mut cap) => {
+                            cap.normalize(region_info.index, region_info.size)?;
+                            caps.push(VfioRegionInfoCap::SparseMmap(cap));
+                        }
+                        None => {
+                            warn!(
+                                "Ignoring truncated sparse mmap capability of region #{}",
+                                region_info.index
+                            );
+                            region.flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+                        }
                     }
-                    VFIO_REGION_INFO_CAP_MSIX_MAPPABLE => {
-                        region.caps.push(VfioRegionInfoCap::MsixMappable);
+                }
+                VFIO_REGION_INFO_CAP_TYPE => {
+                    match read_region_cap::<vfio_region_info_cap_type>(cap) {
+                        Some(type_) => caps.push(VfioRegionInfoCap::Type(type_.into())),
+                        None => warn!(
+                            "Ignoring truncated type capability of region #{}",
+                            region_info.index
+                        ),
                     }
-                    VFIO_REGION_INFO_CAP_NVLINK2_SSATGT => {
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let nvlink2_ssatgt = unsafe {
-                            *(info_ptr.offset(next_cap_offset as isize)
-                                as *const vfio_region_info_cap_nvlink2_ssatgt)
-                        };
-                        let cap = VfioRegionInfoCapNvlink2Ssatgt {
-                            tgt: nvlink2_ssatgt.tgt,
-                        };
-                        region.caps.push(VfioRegionInfoCap::Nvlink2Ssatgt(cap));
+                }
+                VFIO_REGION_INFO_CAP_MSIX_MAPPABLE => {
+                    caps.push(VfioRegionInfoCap::MsixMappable);
+                }
+                VFIO_REGION_INFO_CAP_NVLINK2_SSATGT => {
+                    match read_region_cap::<vfio_region_info_cap_nvlink2_ssatgt>(cap) {
+                        Some(ssatgt) => caps.push(VfioRegionInfoCap::Nvlink2Ssatgt(ssatgt.into())),
+                        None => warn!(
+                            "Ignoring truncated NVLink2 SSATGT capability of region #{}",
+                            region_info.index
+                        ),
                     }
-                    VFIO_REGION_INFO_CAP_NVLINK2_LNKSPD => {
-                        // SAFETY: data structure returned by kernel is trusted.
-                        let nvlink2_lnkspd = unsafe {
-                            *(info_ptr.offset(next_cap_offset as isize)
-                                as *const vfio_region_info_cap_nvlink2_lnkspd)
-                        };
-                        let cap = VfioRegionInfoCapNvlink2Lnkspd {
-                            link_speed: nvlink2_lnkspd.link_speed,
-                        };
-                        region.caps.push(VfioRegionInfoCap::Nvlink2Lnkspd(cap));
+                }
+                VFIO_REGION_INFO_CAP_NVLINK2_LNKSPD => {
+                    match read_region_cap::<vfio_region_info_cap_nvlink2_lnkspd>(cap) {
+                        Some(lnkspd) => caps.push(VfioRegionInfoCap::Nvlink2Lnkspd(lnkspd.into())),
+                        None => warn!(
+                            "Ignoring truncated NVLink2 link speed capability of region #{}",
+                            region_info.index
+                        ),
                     }
-                    _ => {}
                 }
-
-                next_cap_offset = cap_header.next;
+                _ => {}
             }
+
+            min_offset = offset + header_size;
+            offset = cap_header.next as usize;
         }
+        region.caps = RegionCaps {
+            arena: None,
+            start,
+            len: caps.len() - start,
+        };
 
         Ok(())
     }
 
-    fn get_regions(&self) -> Result<Vec<VfioRegion>> {
-        let mut regions: Vec<VfioRegion> = Vec::new();
+    // Query the info of a single region of `device`, without its capabilities.
+    fn get_region_info<F: AsRawFd>(
+        device: &F,
+        index: u32,
+    ) -> Result<(VfioRegion, vfio_region_info)> {
+        let argsz: u32 = mem::size_of::<vfio_region_info>() as u32;
+        let mut reg_info = vfio_region_info {
+            argsz,
+            flags: 0,
+            index,
+            cap_offset: 0,
+            size: 0,
+            offset: 0,
+        };
+        vfio_syscall::get_device_region_info(device, &mut reg_info)?;
+
+        let region = VfioRegion {
+            index,
+            flags: reg_info.flags,
+            size: reg_info.size,
+            offset: reg_info.offset,
+            caps: RegionCaps::default(),
+        };
 
-        for i in VFIO_PCI_BAR0_REGION_INDEX..self.num_regions {
-            let argsz: u32 = mem::size_of::<vfio_region_info>() as u32;
-            let mut reg_info = vfio_region_info {
-                argsz,
-                flags: 0,
-                index: i,
-                cap_offset: 0,
-                size: 0,
-                offset: 0,
-            };
+        debug!("Region #{}", index);
+        debug!("\tflag 0x{:x}", region.flags);
+        debug!("\tsize 0x{:x}", region.size);
+        debug!("\toffset 0x{:x}", region.offset);
 
-            if vfio_syscall::get_device_region_info(self, &mut reg_info).is_err() {
-                error!("Could not get region #{} info", i);
-                continue;
-            }
+        Ok((region, reg_info))
+    }
 
-            let mut region = VfioRegion {
-                flags: reg_info.flags,
-                size: reg_info.size,
-                offset: reg_info.offset,
-                caps: Vec::new(),
-            };
-            if let Err(e) = self.get_region_map(&mut region, &reg_info) {
-                error!("Could not get region #{} map {}", i, e);
-                continue;
-            }
+    // Query the info and capabilities of a single region of `device`, with at most
+    // `caps_max_size` bytes of region info.
+    fn get_region<F: AsRawFd>(device: &F, index: u32, caps_max_size: u32) -> Result<VfioRegion> {
+        let (mut region, reg_info) = Self::get_region_info(device, index)?;
+        let mut caps = Vec::new();
+        Self::get_region_map(device, &mut region, &reg_info, caps_max_size, &mut caps)?;
+        RegionCaps::share(std::slice::from_mut(&mut region), caps);
+
+        Ok(region)
+    }
 
-            debug!("Region #{}", i);
-            debug!("\tflag 0x{:x}", region.flags);
-            debug!("\tsize 0x{:x}", region.size);
-            debug!("\toffset 0x{:x}", region.offset);
-            regions.push(region);
+    // Regions are numbered from 0 for every device type, PCI devices have their BARs first.
+    // Regions whose capabilities exceed `caps_max_size` are kept without them, and can't be
+    // mmap'ed as their sparse mmap areas and MSI-X restrictions are unknown.
+    fn get_regions(&self, caps_max_size: u32) -> Result<Box<[VfioRegion]>> {
+        let mut regions: Vec<VfioRegion> = Vec::with_capacity(self.num_regions as usize);
+        let mut caps = Vec::new();
+
+        for i in 0..self.num_regions {
+            match Self::get_region_info(self, i) {
+                Ok((mut region, reg_info)) => {
+                    match Self::get_region_map(
+                        self,
+                        &mut region,
+                        &reg_info,
+                        caps_max_size,
+                        &mut caps,
+                    ) {
+                        Ok(()) => {}
+                        Err(e @ VfioError::RegionCapsTooLarge { .. }) => {
+                            warn!(
+                                "Ignoring region #{} capabilities, not mmap'ing it: {}",
+                                i, e
+                            );
+                            region.flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    regions.push(region);
+                }
+                Err(e) => error!("Could not get region #{} info {}", i, e),
+            }
         }
+        RegionCaps::share(&mut regions, caps);
 
-        Ok(regions)
+        Ok(regions.into_boxed_slice())
     }
 }
 
@@ -792,23 +3517,56 @@ impl AsRawFd for VfioDeviceInfo {
     }
 }
 
+// Identifier of the next VfioDevice object.
+static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(0);
+
 /// A safe wrapper over a Vfio device to access underlying hardware device.
 ///
 /// The VFIO device API includes ioctls for describing the device, the I/O regions and their
 /// read/write/mmap offsets on the device descriptor, as well as mechanisms for describing and
 /// registering interrupt notifications.
 pub struct VfioDevice {
-    pub(crate) device: ManuallyDrop<File>,
+    // Identifier of this object, unlike the name shared by all the objects opened for a device.
+    pub(crate) id: u64,
+    pub(crate) device: OwnedFd,
+    pub(crate) sysfspath: PathBuf,
     pub(crate) flags: u32,
-    pub(crate) regions: Vec<VfioRegion>,
-    pub(crate) irqs: HashMap<u32, VfioIrq>,
-    pub(crate) group: Arc<VfioGroup>,
-    pub(crate) container: Arc<VfioContainer>,
+    // Regions and interrupts are read once the device is opened, and stored without spare
+    // capacity as deployments open hundreds of devices. They can be queried again by
+    // refresh_region() and refresh_irq_info() while the device is shared, hence the locks.
+    // Regions are replaced as a whole, readers keep using the slice they got meanwhile.
+    pub(crate) regions: RwLock<Arc<[VfioRegion]>>,
+    pub(crate) irqs: RwLock<Arc<VfioIrqs>>,
+    // EventFds currently registered for each irq index, either duplicates of the ones passed to
+    // enable_irq() or the ones handed over to enable_irq_owned(). Declared after `device`, so
+    // that they are closed once closing the device fd has torn down the interrupts.
+    pub(crate) irq_fds: Mutex<HashMap<u32, Vec<EventFd>>>,
+    // Capability cache and interception hooks of the PCI configuration space.
+    pub(crate) pci_config: Mutex<PciConfigState>,
+    // Vendor and device IDs, read from the configuration space on first use. The cell is
+    // replaced to read them again.
+    pub(crate) pci_ids: RwLock<OnceCell<(u16, u16)>>,
+    // Refuse operations modifying the device state.
+    pub(crate) read_only: bool,
+    // The device couldn't be opened again by reopen(), its regions and interrupts are gone.
+    pub(crate) gone: bool,
+    // Consider regions of unknown types safe to expose.
+    pub(crate) expose_unknown_regions: AtomicBool,
+    // Access widths accepted by region_read() and region_write(), permissive if absent.
+    pub(crate) region_access_policies: HashMap<u32, VfioRegionAccessPolicy>,
+    // Mappings of the regions used by region_read() and region_write() for large transfers,
+    // created on first use. None unless enabled with set_mmap_fastpath().
+    mmap_fastpath: Option<Mutex<HashMap<u32, Arc<[FastpathMapping]>>>>,
+    // Properties of mediated devices opened with new_mdev().
+    pub(crate) mdev_info: Option<MdevInfo>,
+    pub(crate) vendor_regions: RwLock<Vec<(u32, KnownVendorRegion)>>,
+    // Declared after `device` so that the device fd is closed before the group is released.
+    pub(crate) binding: VfioGroupBinding,
 }
 
 impl VfioDevice {
     #[cfg(not(test))]
-    fn get_group_id_from_path(sysfspath: &Path) -> Result<u32> {
+    pub(crate) fn get_group_id_from_path(sysfspath: &Path) -> Result<u32> {
         let uuid_path: PathBuf = [sysfspath, Path::new("iommu_group")].iter().collect();
         let group_path = uuid_path.read_link().map_err(|_| VfioError::InvalidPath)?;
         let group_osstr = group_path.file_name().ok_or(VfioError::InvalidPath)?;
@@ -823,35 +3581,525 @@ impl VfioDevice {
     /// * `sysfspath`: specify the vfio device path in sys file system.
     /// * `container`: the new VFIO device object will bind to this container object.
     pub fn new(sysfspath: &Path, container: Arc<VfioContainer>) -> Result<Self> {
-        let group_id = Self::get_group_id_from_path(sysfspath)?;
-        let group = container.get_group(group_id)?;
-        let device_info = group.get_device(sysfspath)?;
-        let regions = device_info.get_regions()?;
+        Self::builder(sysfspath, container).build()
+    }
+
+    /// Create a new vfio device from an already known VFIO group id.
+    ///
+    /// Unlike [`new()`](Self::new), the `iommu_group` symlink under `sysfspath` is not resolved,
+    /// which is useful when the caller has already enumerated the groups. The supplied group id
+    /// is still validated by the kernel: `VFIO_GROUP_GET_DEVICE_FD` fails if the device doesn't
+    /// belong to the group.
+    ///
+    /// # Parameters
+    /// * `sysfspath`: specify the vfio device path in sys file system.
+    /// * `group_id`: ID of the VFIO group the device belongs to.
+    /// * `container`: the new VFIO device object will bind to this container object.
+    pub fn new_in_group(
+        sysfspath: &Path,
+        group_id: u32,
+        container: Arc<VfioContainer>,
+    ) -> Result<Self> {
+        Self::builder(sysfspath, container)
+            .group_id(group_id)
+            .build()
+    }
+
+    // Build the device object around a device fd of `group`, already bound to `container`.
+    pub(crate) fn from_device_info(
+        sysfspath: &Path,
+        device_info: VfioDeviceInfo,
+        group: Arc<VfioGroup>,
+        container: Arc<VfioContainer>,
+    ) -> Result<Self> {
+        let regions = device_info.get_regions(container.region_caps_max_size())?;
         let irqs = device_info.get_irqs()?;
 
-        Ok(VfioDevice {
-            device: ManuallyDrop::new(device_info.device),
+        let device = VfioDevice {
+            id: NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed),
+            device: device_info.device,
+            sysfspath: sysfspath.to_path_buf(),
             flags: device_info.flags,
-            regions,
-            irqs,
-            group,
-            container,
-        })
+            regions: RwLock::new(regions.into()),
+            irqs: RwLock::new(Arc::new(irqs)),
+            irq_fds: Mutex::new(HashMap::new()),
+            pci_config: Mutex::new(PciConfigState::default()),
+            pci_ids: RwLock::new(OnceCell::new()),
+            read_only: false,
+            gone: false,
+            expose_unknown_regions: AtomicBool::new(false),
+            region_access_policies: HashMap::new(),
+            mmap_fastpath: None,
+            mdev_info: None,
+            vendor_regions: RwLock::new(Vec::new()),
+            binding: VfioGroupBinding { group, container },
+        };
+        device.update_vendor_regions();
+
+        Ok(device)
+    }
+
+    /// Create a vfio device for read-only inspection.
+    ///
+    /// The device information, regions and interrupts are queried as usual, but operations
+    /// modifying the device state (region and config space writes, writable mappings, DMA
+    /// mappings, interrupt configuration and reset) are refused with
+    /// `VfioError::DeviceReadOnly`, and `region_write()` warns and writes nothing. This suits
+    /// devices opened only to read their configuration space, such as the upstream ports of a
+    /// PCIe switch whose link and slot capabilities are inspected. The mode is kept across
+    /// [`VfioContainer::export_state()`] and [`VfioContainer::import_state()`].
+    ///
+    /// This is enforced by this crate only: the kernel hands out read-write device fds, and it
+    /// still requires the group to be viable and bound to a container with an IOMMU set, as
+    /// done when opening any VFIO device.
+    ///
+    /// # Parameters
+    /// * `sysfspath`: specify the vfio device path in sys file system.
+    /// * `container`: the new VFIO device object will bind to this container object.
+    pub fn new_read_only(sysfspath: &Path, container: Arc<VfioContainer>) -> Result<Self> {
+        let mut device = Self::new(sysfspath, container)?;
+        device.read_only = true;
+
+        Ok(device)
+    }
+
+    /// Create a new vfio device for a mediated device.
+    ///
+    /// On top of what [`new()`](Self::new) does, the type and parent of the mediated device
+    /// are read from sysfs and reported by [`mdev_info()`](Self::mdev_info).
+    ///
+    /// # Parameters
+    /// * `sysfspath`: specify the mdev device path in sys file system, such as
+    ///   `/sys/bus/mdev/devices/<uuid>`.
+    /// * `container`: the new VFIO device object will bind to this container object.
+    pub fn new_mdev(sysfspath: &Path, container: Arc<VfioContainer>) -> Result<Self> {
+        let mdev_info = MdevInfo::from_sysfs(sysfspath)?;
+        let mut device = Self::new(sysfspath, container)?;
+        device.mdev_info = Some(mdev_info);
+
+        Ok(device)
+    }
+
+    /// Open the device again, after it was unbound and bound again to its VFIO driver.
+    ///
+    /// The device fd of a device unbound from its driver is stale, every operation fails with
+    /// `ENODEV`, but the group and container can still be used. The device is opened again
+    /// through its group and its regions and interrupts are queried again, before replacing
+    /// the ones of this object, whose stale device fd is then closed. The interrupts enabled
+    /// through `enable_irq()` are enabled again with the same EventFds, and the command
+    /// register, bus master enable included, is restored as last written through `config()`.
+    /// As after a reset, all of them are restored even if one fails, and the first error is
+    /// returned. The DMA mask set with `set_dma_mask()` is kept, and applied again.
+    ///
+    /// If the device can't be opened or queried, it is left gone: it has no region nor
+    /// interrupt anymore, and the operations modifying its state fail with
+    /// `VfioError::DeviceGone`. The stale device fd is kept until the object is dropped or
+    /// reopened. Calling `reopen()` again retries, and restores the interrupts which were
+    /// enabled before the device was gone, whose EventFds are kept meanwhile.
+    pub fn reopen(&mut self) -> Result<()> {
+        let command = self.written_command();
+
+        let caps_max_size = self.binding.container.region_caps_max_size();
+        let reopened = self
+            .binding
+            .group
+            .get_device(&self.sysfspath)
+            .and_then(|device_info| {
+                let regions = device_info.get_regions(caps_max_size)?;
+                let irqs = device_info.get_irqs()?;
+                Ok((device_info, regions, irqs))
+            });
+        let (device_info, regions, irqs) = match reopened {
+            Ok(reopened) => reopened,
+            Err(e) => {
+                error!("Failed to reopen device {}: {}", self.name(), e);
+                self.set_gone();
+                return Err(e);
+            }
+        };
+
+        self.device = device_info.device;
+        self.flags = device_info.flags;
+        *self
+            .regions
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = regions.into();
+        *self.irqs.get_mut().unwrap_or_else(PoisonError::into_inner) = Arc::new(irqs);
+        *self
+            .pci_ids
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = OnceCell::new();
+        self.gone = false;
+        self.config().invalidate_capabilities();
+        self.update_vendor_regions();
+        self.invalidate_mmap_fastpath();
+        // Warn again about the mappings the device can't reach.
+        if let Some(mask) = self.dma_mask() {
+            self.set_dma_mask(Some(mask));
+        }
+
+        let mut irq_fds: Vec<(u32, Vec<EventFd>)> = self
+            .irq_fds
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain()
+            .collect();
+        irq_fds.sort_by_key(|(irq_index, _)| *irq_index);
+        let mut result = Ok(());
+        if let Some(command) = command {
+            if let Err(e) = self.restore_command(command) {
+                error!("Failed to restore the command register after reopen: {}", e);
+                result = Err(e);
+            }
+        }
+        for (irq_index, fds) in irq_fds.into_iter().filter(|(_, fds)| !fds.is_empty()) {
+            if let Err(e) = self.enable_irq_owned(irq_index, fds) {
+                error!(
+                    "Failed to restore irq index {} after reopen: {}",
+                    irq_index, e
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    // Drop the regions and interrupts of a device which couldn't be opened again.
+    fn set_gone(&mut self) {
+        self.gone = true;
+        *self
+            .regions
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new([]);
+        *self.irqs.get_mut().unwrap_or_else(PoisonError::into_inner) = Arc::default();
+        self.config().invalidate_capabilities();
+        self.vendor_regions
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.invalidate_mmap_fastpath();
+    }
+
+    /// Check whether the device is gone, after [`reopen()`](Self::reopen) failed.
+    pub fn is_gone(&self) -> bool {
+        self.gone
+    }
+
+    /// Get the properties of a mediated device opened with [`new_mdev()`](Self::new_mdev).
+    ///
+    /// The number of available instances is the one read when the device was opened.
+    pub fn mdev_info(&self) -> Option<&MdevInfo> {
+        self.mdev_info.as_ref()
+    }
+
+    /// Get the NUMA node the device is attached to, read from its `numa_node` sysfs attribute.
+    ///
+    /// Placing the vCPUs and the memory of the guest on this node avoids crossing the socket
+    /// interconnect for DMA. Mediated devices report the node of their parent device. `None` is
+    /// returned if the attribute can't be read, and on hosts without NUMA affinity for the
+    /// device, for which the kernel reports -1.
+    pub fn numa_node(&self) -> Option<i32> {
+        let device_dir = if self.mdev_info.is_some() {
+            self.sysfspath.join("..")
+        } else {
+            self.sysfspath.clone()
+        };
+        let node = fs::read_to_string(device_dir.join("numa_node")).ok()?;
+
+        node.trim().parse().ok().filter(|node| *node >= 0)
+    }
+
+    /// Get the host IRQ numbers of the MSI and MSI-X vectors of the device, in ascending order.
+    ///
+    /// The numbers are listed by the kernel in the `msi_irqs` sysfs directory of the device
+    /// while MSI or MSI-X is enabled. They allow an affinity manager to pin the interrupts of
+    /// the device near the vCPUs they are routed to, through `/proc/irq/{n}/smp_affinity`. An
+    /// empty list is returned if the directory can't be read, like when no vector is enabled.
+    pub fn host_irq_numbers(&self) -> Vec<u32> {
+        let entries = match fs::read_dir(self.sysfspath.join("msi_irqs")) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut irqs: Vec<u32> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        irqs.sort_unstable();
+
+        irqs
+    }
+
+    /// Return whether the device was opened for read-only inspection.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.gone {
+            Err(VfioError::DeviceGone)
+        } else if self.read_only {
+            Err(VfioError::DeviceReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_vendor_regions(&self) -> Vec<(u32, KnownVendorRegion)> {
+        let mut vendor_regions = Vec::new();
+        let vendor_id = self.pci_ids().ok().map(|(vendor_id, _)| vendor_id);
+
+        for region in self.regions().iter() {
+            for cap in region.caps.iter() {
+                if let VfioRegionInfoCap::Type(t) = cap {
+                    // Without config space access, trust the vendor ID encoded in the type.
+                    let vendor_id =
+                        vendor_id.unwrap_or((t.type_ & VFIO_REGION_TYPE_PCI_VENDOR_MASK) as u16);
+                    if let Some(known) = KnownVendorRegion::from_type(vendor_id, t) {
+                        debug!("Region #{} is {:?}", region.index, known);
+                        vendor_regions.push((region.index, known));
+                    }
+                }
+            }
+        }
+
+        vendor_regions
+    }
+
+    fn update_vendor_regions(&self) {
+        let vendor_regions = self.get_vendor_regions();
+        *write_or_recover(&self.vendor_regions) = vendor_regions;
+    }
+
+    /// Get the well known vendor specific regions exposed by the device.
+    ///
+    /// Each entry is made of the region index and the identified vendor region.
+    pub fn known_vendor_regions(&self) -> Vec<(u32, KnownVendorRegion)> {
+        read_or_recover(&self.vendor_regions).clone()
+    }
+
+    /// Classify a region of the device.
+    ///
+    /// Regions past the standard PCI ones are known when they are well known vendor regions,
+    /// NVLink2 regions, graphics EDID regions or migration regions. Non PCI devices only have
+    /// standard regions.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_class(&self, index: u32) -> Option<VfioRegionClass> {
+        let region = self.region(index)?;
+        if self.flags & VFIO_DEVICE_FLAGS_PCI == 0 || index < VFIO_PCI_NUM_REGIONS {
+            return Some(VfioRegionClass::Standard);
+        }
+
+        let known_vendor = read_or_recover(&self.vendor_regions)
+            .iter()
+            .any(|(i, _)| *i == index);
+        let known_type = region.caps.iter().any(|cap| match cap {
+            VfioRegionInfoCap::Type(t) => KNOWN_REGION_TYPES.contains(&(t.type_, t.subtype)),
+            VfioRegionInfoCap::Nvlink2Ssatgt(_) | VfioRegionInfoCap::Nvlink2Lnkspd(_) => true,
+            _ => false,
+        });
+        if known_vendor || known_type {
+            Some(VfioRegionClass::KnownDeviceSpecific)
+        } else {
+            Some(VfioRegionClass::UnknownDeviceSpecific)
+        }
+    }
+
+    /// Opt in, or out, of exposing regions of unknown types to the guest.
+    ///
+    /// Unknown device specific regions aren't exposable by default: `region_read()` and
+    /// `region_write()` ignore them, while the vectored accesses, mmap and guest mappings fail
+    /// with `VfioError::RegionNotExposed`.
+    pub fn set_expose_unknown_regions(&self, expose: bool) {
+        self.expose_unknown_regions.store(expose, Ordering::Relaxed);
+    }
+
+    /// Check whether a region may be exposed to the guest, or mapped for it.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_exposable(&self, index: u32) -> bool {
+        match self.region_class(index) {
+            Some(VfioRegionClass::UnknownDeviceSpecific) => {
+                self.expose_unknown_regions.load(Ordering::Relaxed)
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    // Refuse the accesses to a region which isn't exposable.
+    fn check_region_exposed(&self, index: u32) -> Result<()> {
+        if self.region_exposable(index) {
+            Ok(())
+        } else {
+            Err(VfioError::RegionNotExposed(index))
+        }
+    }
+
+    /// Get the indexes of the regions which may be exposed to the guest.
+    pub fn exposable_regions(&self) -> Vec<u32> {
+        self.regions()
+            .iter()
+            .map(|r| r.index)
+            .filter(|&index| self.region_exposable(index))
+            .collect()
     }
 
     /// VFIO device reset only if the device supports being reset.
-    pub fn reset(&self) {
+    ///
+    /// Devices the kernel can't reset are reset with `flr_reset()` if they support FLR, other
+    /// devices are left as they are.
+    ///
+    /// The cached PCI capabilities and region mappings are invalidated. Read-only devices are
+    /// refused with `VfioError::DeviceReadOnly`, and devices which couldn't be reopened with
+    /// `VfioError::DeviceGone`. Failures of `VFIO_DEVICE_RESET` or of the FLR are returned.
+    pub fn reset(&self) -> Result<()> {
+        if let Err(e) = self.check_writable() {
+            warn!("Refusing reset of device {}: {}", self.name(), e);
+            return Err(e);
+        }
         if self.flags & VFIO_DEVICE_FLAGS_RESET != 0 {
-            vfio_syscall::reset(self);
+            self.function_reset()?;
+        } else if let Ok(Some(_)) = self.flr_capability() {
+            self.flr_reset()?;
+        }
+        self.config().invalidate_capabilities();
+        self.invalidate_pci_ids();
+        self.invalidate_mmap_fastpath();
+
+        Ok(())
+    }
+
+    /// Reset the VFIO device and restore the interrupts enabled through `enable_irq()`.
+    ///
+    /// The reset disables the device interrupts, which are then enabled again with the same
+    /// EventFds. Interrupts signaled by the device between the reset and their re-enabling are
+    /// lost, so callers should make sure the device is quiesced before calling this.
+    ///
+    /// All the interrupt indexes are restored even if one of them fails, and the first error
+    /// is returned.
+    pub fn reset_preserving_irqs(&self) -> Result<()> {
+        self.check_writable()?;
+        let mut irq_fds: Vec<(u32, Vec<EventFd>)> =
+            lock_or_recover(&self.irq_fds).drain().collect();
+        irq_fds.sort_by_key(|(irq_index, _)| *irq_index);
+
+        self.reset()?;
+
+        let mut result = Ok(());
+        for (irq_index, fds) in irq_fds.iter().filter(|(_, fds)| !fds.is_empty()) {
+            if let Err(e) = self.enable_irq(*irq_index, fds.iter().collect()) {
+                error!(
+                    "Failed to restore irq index {} after reset: {}",
+                    irq_index, e
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Reset the VFIO device and wait for it to be ready.
+    ///
+    /// Devices signal the end of their reset differently, so readiness is left to `predicate`,
+    /// which typically reads a configuration space or BAR register with `config()` or
+    /// `region_read()`. After `reset()`, the predicate is called every 10ms until it returns
+    /// true, or `timeout` expires in which case `VfioError::DeviceResetTimeout` is returned.
+    /// It is called at least once, even with a zero timeout.
+    ///
+    /// # Arguments
+    /// * `predicate` - Check whether the device is ready.
+    /// * `timeout` - Maximum time to wait for the device after the reset.
+    pub fn reset_and_wait<F: Fn(&VfioDevice) -> bool>(
+        &self,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<()> {
+        self.check_writable()?;
+        self.reset()?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if predicate(self) {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(VfioError::DeviceResetTimeout {
+                    device: self.name(),
+                    timeout,
+                });
+            }
+            thread::sleep(RESET_POLL_INTERVAL.min(deadline - now));
         }
     }
 
     /// Get information about VFIO IRQs.
     ///
+    /// Returns `None` for indexes the device doesn't have.
+    ///
     /// # Arguments
-    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to enable.
-    pub fn get_irq_info(&self, irq_index: u32) -> Option<&VfioIrq> {
-        self.irqs.get(&irq_index)
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts.
+    #[deprecated(note = "use irq_info(), of which this is now an alias")]
+    pub fn get_irq_info(&self, irq_index: u32) -> Option<VfioIrq> {
+        self.irq_info(irq_index)
+    }
+
+    /// Get a copy of the current information about VFIO IRQs.
+    ///
+    /// Returns `None` for indexes the device doesn't have. The entry isn't borrowed from the
+    /// device, so it stays valid while `refresh_irq_info()` updates it.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts.
+    pub fn irq_info(&self, irq_index: u32) -> Option<VfioIrq> {
+        self.irqs().get(&irq_index).copied()
+    }
+
+    /// Query the information about VFIO IRQs again, and update the cached entry.
+    ///
+    /// Some devices change the number of vectors they advertise after firmware configuration
+    /// writes, such as a new MSI-X table size, leaving the information read when the device was
+    /// opened stale. Concurrent readers see either the previous or the refreshed entry. Indexes
+    /// which no longer have interrupts keep their entry, with a zero count.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts.
+    pub fn refresh_irq_info(&self, irq_index: u32) -> Result<VfioIrq> {
+        let mut irq_info = vfio_irq_info {
+            argsz: mem::size_of::<vfio_irq_info>() as u32,
+            flags: 0,
+            index: irq_index,
+            count: 0,
+        };
+        vfio_syscall::get_device_irq_info(self, &mut irq_info)?;
+        let irq = VfioIrq {
+            flags: irq_info.flags,
+            index: irq_index,
+            count: irq_info.count,
+        };
+
+        let mut irqs = write_or_recover(&self.irqs);
+        if irqs.get(&irq_index) != Some(&irq) {
+            let mut updated = VfioIrqs::clone(&irqs);
+            updated.insert(irq_index, irq);
+            *irqs = Arc::new(updated);
+        }
+
+        Ok(irq)
+    }
+
+    // Get the cached interrupts of the device.
+    pub(crate) fn irqs(&self) -> Arc<VfioIrqs> {
+        read_or_recover(&self.irqs).clone()
     }
 
     /// Trigger a VFIO device IRQ from userspace.
@@ -864,21 +4112,34 @@ impl VfioDevice {
     /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to enable.
     /// * `vector` - The sub-index into the interrupt group of `irq_index`.
     pub fn trigger_irq(&self, irq_index: u32, vector: u32) -> Result<()> {
+        self.trigger_irq_range(irq_index, vector, 1)
+    }
+
+    /// Trigger a contiguous range of VFIO device IRQs from userspace, with a single
+    /// VFIO_DEVICE_SET_IRQS call.
+    ///
+    /// See [`trigger_irq()`](Self::trigger_irq), the vectors `start..start + count` must all be
+    /// within the interrupts reported for `irq_index`. A zero `count` is refused, since the kernel
+    /// would take it as a request to disable the whole index.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to trigger.
+    /// * `start` - The first sub-index into the interrupt group of `irq_index`.
+    /// * `count` - The number of vectors to trigger.
+    pub fn trigger_irq_range(&self, irq_index: u32, start: u32, count: u32) -> Result<()> {
+        self.check_writable()?;
         let irq = self
-            .irqs
-            .get(&irq_index)
+            .irq_info(irq_index)
             .ok_or(VfioError::VfioDeviceTriggerIrq)?;
-        if irq.count <= vector {
-            return Err(VfioError::VfioDeviceTriggerIrq);
+        match start.checked_add(count) {
+            Some(end) if count > 0 && end <= irq.count => {}
+            _ => return Err(VfioError::VfioDeviceTriggerIrq),
         }
 
-        let mut irq_set = vec_with_array_field::<vfio_irq_set, u32>(0);
-        irq_set[0].argsz = mem::size_of::<vfio_irq_set>() as u32;
-        irq_set[0].flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER;
-        irq_set[0].index = irq_index;
-        irq_set[0].start = vector;
-        irq_set[0].count = 1;
-
+        let irq_set = VfioIrqSet::new()
+            .index(irq_index)
+            .range(start, count)
+            .build();
         vfio_syscall::set_device_irqs(self, irq_set.as_slice())
             .map_err(|_| VfioError::VfioDeviceTriggerIrq)
     }
@@ -888,73 +4149,225 @@ impl VfioDevice {
     /// tells VFIO which EventFd to write into whenever one of the device interrupt vector
     /// is triggered.
     ///
+    /// Any index reported by the device can be enabled, not only the PCI ones `enable_msi()` and
+    /// `enable_msix()` wrap: platform and mdev devices expose their device-specific interrupts,
+    /// such as a reset completion notification, at indexes of their own. Indexes the device
+    /// doesn't have fail with `VfioError::VfioDeviceEnableIrq`.
+    ///
     /// # Arguments
-    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to enable.
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts to
+    ///   enable.
     /// * `event_fds` - The EventFds vector that matches all the supported VFIO interrupts.
     pub fn enable_irq(&self, irq_index: u32, event_fds: Vec<&EventFd>) -> Result<()> {
-        let irq = self
-            .irqs
-            .get(&irq_index)
+        let mut retained_fds = Vec::with_capacity(event_fds.len());
+        for event_fd in event_fds.iter() {
+            retained_fds.push(
+                event_fd
+                    .try_clone()
+                    .map_err(|_| VfioError::VfioDeviceEnableIrq)?,
+            );
+        }
+
+        self.enable_irq_owned(irq_index, retained_fds)
+    }
+
+    /// Enables a VFIO device IRQs with EventFds owned by the device.
+    ///
+    /// Like `enable_irq()`, but the device takes ownership of the EventFds, so the caller doesn't
+    /// have to keep them alive while the interrupts are enabled. They can be retrieved with
+    /// `irq_eventfd()`, and are released once the interrupts are successfully disabled, enabled
+    /// again with other EventFds, or the device is dropped. The EventFds are dropped if enabling
+    /// fails, the previously registered ones staying in place.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts to
+    ///   enable.
+    /// * `event_fds` - The EventFds vector that matches all the supported VFIO interrupts.
+    pub fn enable_irq_owned(&self, irq_index: u32, event_fds: Vec<EventFd>) -> Result<()> {
+        self.check_writable()?;
+        let mut irq = self
+            .irq_info(irq_index)
             .ok_or(VfioError::VfioDeviceEnableIrq)?;
+        // The device may advertise more vectors than it did when they were last queried.
+        if (irq.count as usize) < event_fds.len() {
+            irq = self
+                .refresh_irq_info(irq_index)
+                .map_err(|_| VfioError::VfioDeviceEnableIrq)?;
+        }
         if irq.count == 0 || (irq.count as usize) < event_fds.len() {
             return Err(VfioError::VfioDeviceEnableIrq);
         }
 
-        let mut irq_set = vec_with_array_field::<vfio_irq_set, u32>(event_fds.len());
-        irq_set[0].argsz = mem::size_of::<vfio_irq_set>() as u32
-            + (event_fds.len() * mem::size_of::<u32>()) as u32;
-        irq_set[0].flags = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
-        irq_set[0].index = irq_index;
-        irq_set[0].start = 0;
-        irq_set[0].count = event_fds.len() as u32;
+        let fds: Vec<RawFd> = event_fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let irq_set = Self::eventfd_irq_set(irq_index, 0, &fds);
+        vfio_syscall::set_device_irqs(self, irq_set.as_slice())
+            .map_err(|_| VfioError::VfioDeviceEnableIrq)?;
 
-        {
-            // irq_set.data could be none, bool or fd according to flags, so irq_set.data
-            // is u8 default, here irq_set.data is a vector of fds as u32, so 4 default u8
-            // are combined together as u32 for each fd.
-            // SAFETY: It is safe as enough space is reserved through
-            // vec_with_array_field(u32)<event_fds.len()>.
-            let fds = unsafe {
-                irq_set[0]
-                    .data
-                    .as_mut_slice(event_fds.len() * mem::size_of::<u32>())
-            };
-            for (index, event_fd) in event_fds.iter().enumerate() {
-                let fds_offset = index * mem::size_of::<u32>();
-                let fd = &mut fds[fds_offset..fds_offset + mem::size_of::<u32>()];
-                LittleEndian::write_u32(fd, event_fd.as_raw_fd() as u32);
-            }
+        lock_or_recover(&self.irq_fds).insert(irq_index, event_fds);
+
+        Ok(())
+    }
+
+    /// Get the EventFd registered for a VFIO device irq vector.
+    ///
+    /// The EventFds are kept behind a lock, so a duplicate sharing the same counter is returned,
+    /// which stays valid after the vector is disabled. Returns `None` if the vector isn't enabled.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts.
+    /// * `vector` - The sub-index into the interrupt group of `irq_index`.
+    pub fn irq_eventfd(&self, irq_index: u32, vector: u32) -> Result<Option<EventFd>> {
+        let irq_fds = lock_or_recover(&self.irq_fds);
+        irq_fds
+            .get(&irq_index)
+            .and_then(|fds| fds.get(vector as usize))
+            .map(|fd| fd.try_clone().map_err(|_| VfioError::VfioDeviceDupFd))
+            .transpose()
+    }
+
+    // Build a VFIO_DEVICE_SET_IRQS argument binding `fds` to the vectors of `irq_index` starting
+    // at `start`.
+    fn eventfd_irq_set(irq_index: u32, start: u32, fds: &[RawFd]) -> Vec<vfio_irq_set> {
+        VfioIrqSet::new()
+            .index(irq_index)
+            .range(start, fds.len() as u32)
+            .data(IrqSetData::Eventfd(fds))
+            .build()
+    }
+
+    /// Bind a new EventFd to a single vector of a VFIO device IRQ.
+    ///
+    /// This is meant for retargeting a vector already enabled through `enable_irq()`, or for
+    /// enabling the vector right after the last enabled one. The EventFd is replaced by a single
+    /// `VFIO_DEVICE_SET_IRQS` call, so an interrupt raised by the device during the update is
+    /// signaled on either the old or the new EventFd but never lost: the old EventFd is never
+    /// detached before the new one is attached. Interrupts which can't be resized once enabled
+    /// (`VFIO_IRQ_INFO_NORESIZE`) are updated by sending the whole table of vectors, including
+    /// the new EventFd, in that same single call. The kernel refuses to change their number of
+    /// vectors while enabled, so appending a vector to them fails: they must be disabled and
+    /// enabled again with all their vectors.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to update.
+    /// * `vector` - The sub-index into the interrupt group of `irq_index`.
+    /// * `event_fd` - The EventFd to signal when the vector is triggered.
+    pub fn assign_vector(&self, irq_index: u32, vector: u32, event_fd: &EventFd) -> Result<()> {
+        self.check_writable()?;
+        let irq = self
+            .irq_info(irq_index)
+            .ok_or(VfioError::VfioDeviceEnableIrq)?;
+        if irq.count <= vector {
+            return Err(VfioError::VfioDeviceEnableIrq);
         }
 
+        let mut irq_fds = lock_or_recover(&self.irq_fds);
+        let enabled = irq_fds
+            .get(&irq_index)
+            .map_or(&[][..], |fds| fds.as_slice());
+        if enabled.len() < vector as usize {
+            return Err(VfioError::VfioDeviceEnableIrq);
+        }
+        let retained_fd = event_fd
+            .try_clone()
+            .map_err(|_| VfioError::VfioDeviceEnableIrq)?;
+
+        let irq_set = if irq.flags & VFIO_IRQ_INFO_NORESIZE != 0 {
+            if !enabled.is_empty() && enabled.len() == vector as usize {
+                error!(
+                    "Can't add vector {} to irq index {} which can't be resized",
+                    vector, irq_index
+                );
+                return Err(VfioError::VfioDeviceEnableIrq);
+            }
+            let mut raw_fds: Vec<RawFd> = enabled.iter().map(|fd| fd.as_raw_fd()).collect();
+            match raw_fds.get_mut(vector as usize) {
+                Some(fd) => *fd = event_fd.as_raw_fd(),
+                None => raw_fds.push(event_fd.as_raw_fd()),
+            }
+            Self::eventfd_irq_set(irq_index, 0, &raw_fds)
+        } else {
+            Self::eventfd_irq_set(irq_index, vector, &[event_fd.as_raw_fd()])
+        };
         vfio_syscall::set_device_irqs(self, irq_set.as_slice())
-            .map_err(|_| VfioError::VfioDeviceEnableIrq)
+            .map_err(|_| VfioError::VfioDeviceEnableIrq)?;
+
+        let fds = irq_fds.entry(irq_index).or_default();
+        match fds.get_mut(vector as usize) {
+            Some(fd) => *fd = retained_fd,
+            None => fds.push(retained_fd),
+        }
+
+        Ok(())
     }
 
     /// Disables a VFIO device IRQs
     ///
+    /// Disabling interrupts which aren't enabled through this device object, or were already
+    /// disabled, is a no-op, so cleanup paths may call this unconditionally. This includes
+    /// read-only devices, which can't enable interrupts. Only interrupt indexes the device
+    /// doesn't have are reported as errors.
+    ///
     /// # Arguments
-    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to disable.
+    /// * `irq_index` - The type (INTX, MSI, MSI-X or a device-specific index) of interrupts to
+    ///   disable.
     pub fn disable_irq(&self, irq_index: u32) -> Result<()> {
         let irq = self
-            .irqs
-            .get(&irq_index)
+            .irq_info(irq_index)
             .ok_or(VfioError::VfioDeviceDisableIrq)?;
         // Currently the VFIO driver only support MASK/UNMASK INTX, so count is hard-coded to 1.
         if irq.count == 0 {
             return Err(VfioError::VfioDeviceDisableIrq);
         }
 
+        let mut irq_fds = lock_or_recover(&self.irq_fds);
+        if !irq_fds.contains_key(&irq_index) {
+            debug!("irq index {} isn't enabled, nothing to disable", irq_index);
+            return Ok(());
+        }
+        self.check_writable()?;
+
         // Individual subindex interrupts can be disabled using the -1 value for DATA_EVENTFD or
         // the index can be disabled as a whole with: flags = (DATA_NONE|ACTION_TRIGGER), count = 0.
-        let mut irq_set = vec_with_array_field::<vfio_irq_set, u32>(0);
-        irq_set[0].argsz = mem::size_of::<vfio_irq_set>() as u32;
-        irq_set[0].flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER;
-        irq_set[0].index = irq_index;
-        irq_set[0].start = 0;
-        irq_set[0].count = 0;
-
+        let irq_set = VfioIrqSet::new().index(irq_index).build();
         vfio_syscall::set_device_irqs(self, irq_set.as_slice())
-            .map_err(|_| VfioError::VfioDeviceDisableIrq)
+            .map_err(|_| VfioError::VfioDeviceDisableIrq)?;
+        irq_fds.remove(&irq_index);
+
+        Ok(())
+    }
+
+    /// Drain the interrupts signaled on a VFIO device irq vector.
+    ///
+    /// Read the EventFd registered for `vector` by `enable_irq()` and return the number of
+    /// interrupts accumulated since the last read, resetting the counter. The call blocks until
+    /// an interrupt is signaled unless the EventFd is non-blocking, in which case `None` is
+    /// returned when no interrupt is pending.
+    ///
+    /// This is meant for polling based interrupt handling, as it consumes the interrupts which
+    /// would otherwise be seen by any other reader of the EventFd.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to drain.
+    /// * `vector` - The sub-index into the interrupt group of `irq_index`.
+    pub fn drain_irq(&self, irq_index: u32, vector: u32) -> Result<Option<u64>> {
+        // Don't block the other irq operations while waiting for an interrupt.
+        let event_fd = {
+            let irq_fds = lock_or_recover(&self.irq_fds);
+            irq_fds
+                .get(&irq_index)
+                .and_then(|fds| fds.get(vector as usize))
+                .ok_or_else(|| {
+                    VfioError::VfioDeviceDrainIrq(io::Error::from(io::ErrorKind::NotFound))
+                })?
+                .try_clone()
+                .map_err(VfioError::VfioDeviceDrainIrq)?
+        };
+
+        match event_fd.read() {
+            Ok(count) => Ok(Some(count)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(VfioError::VfioDeviceDrainIrq(e)),
+        }
     }
 
     /// Unmask IRQ
@@ -962,24 +4375,39 @@ impl VfioDevice {
     /// # Arguments
     /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to unmask.
     pub fn unmask_irq(&self, irq_index: u32) -> Result<()> {
+        self.check_writable()?;
+        self.set_irq_mask(irq_index, IrqSetAction::Unmask)
+            .map_err(|_| VfioError::VfioDeviceUnmaskIrq)
+    }
+
+    /// Mask IRQ
+    ///
+    /// The interrupt stays masked until unmasked with `unmask_irq()`.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to mask.
+    pub fn mask_irq(&self, irq_index: u32) -> Result<()> {
+        self.check_writable()?;
+        self.set_irq_mask(irq_index, IrqSetAction::Mask)
+            .map_err(|_| VfioError::VfioDeviceMaskIrq)
+    }
+
+    // Mask or unmask an interrupt index.
+    fn set_irq_mask(&self, irq_index: u32, action: IrqSetAction) -> Result<()> {
         let irq = self
-            .irqs
-            .get(&irq_index)
-            .ok_or(VfioError::VfioDeviceUnmaskIrq)?;
+            .irq_info(irq_index)
+            .ok_or(VfioError::VfioDeviceSetIrq)?;
         // Currently the VFIO driver only support MASK/UNMASK INTX, so count is hard-coded to 1.
         if irq.count == 0 || irq.count != 1 || irq.index != VFIO_PCI_INTX_IRQ_INDEX {
-            return Err(VfioError::VfioDeviceUnmaskIrq);
+            return Err(VfioError::VfioDeviceSetIrq);
         }
 
-        let mut irq_set = vec_with_array_field::<vfio_irq_set, u32>(0);
-        irq_set[0].argsz = mem::size_of::<vfio_irq_set>() as u32;
-        irq_set[0].flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_UNMASK;
-        irq_set[0].index = irq_index;
-        irq_set[0].start = 0;
-        irq_set[0].count = 1;
-
+        let irq_set = VfioIrqSet::new()
+            .index(irq_index)
+            .range(0, 1)
+            .action(action)
+            .build();
         vfio_syscall::set_device_irqs(self, irq_set.as_slice())
-            .map_err(|_| VfioError::VfioDeviceUnmaskIrq)
     }
 
     /// Wrapper to enable MSI IRQs.
@@ -987,6 +4415,11 @@ impl VfioDevice {
         self.enable_irq(VFIO_PCI_MSI_IRQ_INDEX, fds)
     }
 
+    /// Wrapper to enable a single MSI vector.
+    pub fn enable_msi_single(&self, fd: &EventFd) -> Result<()> {
+        self.enable_msi(vec![fd])
+    }
+
     /// Wrapper to disable MSI IRQs.
     pub fn disable_msi(&self) -> Result<()> {
         self.disable_irq(VFIO_PCI_MSI_IRQ_INDEX)
@@ -997,17 +4430,38 @@ impl VfioDevice {
         self.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, fds)
     }
 
+    /// Wrapper to enable a single MSI-X vector.
+    pub fn enable_msix_single(&self, fd: &EventFd) -> Result<()> {
+        self.enable_msix(vec![fd])
+    }
+
     /// Wrapper to disable MSI-X IRQs.
     pub fn disable_msix(&self) -> Result<()> {
         self.disable_irq(VFIO_PCI_MSIX_IRQ_INDEX)
     }
 
+    // Get the current regions, which refresh_region() doesn't modify but replaces.
+    pub(crate) fn regions(&self) -> Arc<[VfioRegion]> {
+        read_or_recover(&self.regions).clone()
+    }
+
+    // Regions failing to be queried are missing from the list, so it can't be indexed directly.
+    pub(crate) fn region(&self, index: u32) -> Option<RegionRef> {
+        let regions = self.regions();
+        let pos = regions.iter().position(|r| r.index == index)?;
+
+        Some(RegionRef { regions, pos })
+    }
+
     /// Get a region's flag.
     ///
+    /// Regions are identified by their VFIO index, which for PCI devices is the BAR number for
+    /// the BARs, followed by the ROM, config and VGA regions.
+    ///
     /// # Arguments
     /// * `index` - The index of memory region.
     pub fn get_region_flags(&self, index: u32) -> u32 {
-        match self.regions.get(index as usize) {
+        match self.region(index) {
             Some(v) => v.flags,
             None => 0,
         }
@@ -1018,18 +4472,30 @@ impl VfioDevice {
     /// # Arguments
     /// * `index` - The index of memory region.
     pub fn get_region_offset(&self, index: u32) -> u64 {
-        match self.regions.get(index as usize) {
+        match self.region(index) {
             Some(v) => v.offset,
             None => 0,
         }
     }
 
+    /// Get the offset of a region's data in the device file descriptor.
+    ///
+    /// Unlike `get_region_offset()`, an invalid index is reported as `None`. External code can
+    /// access the region with pread()/pwrite() on `as_raw_fd()` at this offset plus the
+    /// offset in the region.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_fd_offset(&self, index: u32) -> Option<u64> {
+        self.region(index).map(|r| r.offset)
+    }
+
     /// Get a region's size.
     ///
     /// # Arguments
     /// * `index` - The index of memory region.
     pub fn get_region_size(&self, index: u32) -> u64 {
-        match self.regions.get(index as usize) {
+        match self.region(index) {
             Some(v) => v.size,
             None => {
                 warn!("get_region_size with invalid index: {}", index);
@@ -1038,34 +4504,484 @@ impl VfioDevice {
         }
     }
 
-    /// Get region's list of capabilities
-    ///
-    /// # Arguments
-    /// * `index` - The index of memory region.
-    pub fn get_region_caps(&self, index: u32) -> Vec<VfioRegionInfoCap> {
-        match self.regions.get(index as usize) {
-            Some(v) => v.caps.clone(),
-            None => {
-                warn!("get_region_caps with invalid index: {}", index);
-                Vec::new()
+    /// Get region's list of capabilities
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn get_region_caps(&self, index: u32) -> Vec<VfioRegionInfoCap> {
+        match self.region(index) {
+            Some(v) => v.caps.to_vec(),
+            None => {
+                warn!("get_region_caps with invalid index: {}", index);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Check whether a region can be mmap'ed even though it contains the MSI-X table.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn msix_table_mappable(&self, index: u32) -> bool {
+        match self.region(index) {
+            Some(v) => v.caps.contains(&VfioRegionInfoCap::MsixMappable),
+            None => false,
+        }
+    }
+
+    /// Query the info and capabilities of a region again and update its cached copy.
+    ///
+    /// Useful after operations which may resize a region, without enumerating all of them.
+    /// Accesses to the region running concurrently complete with its previous info.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn refresh_region(&self, index: u32) -> Result<()> {
+        if self.region(index).is_none() {
+            return Err(VfioError::InvalidRegionIndex(index));
+        }
+        let caps_max_size = self.binding.container.region_caps_max_size();
+        let region = VfioDeviceInfo::get_region(&self.device, index, caps_max_size)?;
+
+        // Concurrent refreshes of other regions are kept.
+        {
+            let mut regions = write_or_recover(&self.regions);
+            let mut updated = regions.to_vec();
+            if let Some(entry) = updated.iter_mut().find(|r| r.index == index) {
+                *entry = region;
+            }
+            *regions = updated.into();
+        }
+        self.update_vendor_regions();
+        self.invalidate_mmap_fastpath();
+
+        Ok(())
+    }
+
+    /// Check whether only the sparse mmap areas of a region may be mmap'ed.
+    ///
+    /// When this returns true, callers must mmap each area advertised by the
+    /// region's `VfioRegionInfoCap::SparseMmap` capability at
+    /// `get_region_offset(index) + area.offset` instead of the whole region.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_requires_sparse_mmap(&self, index: u32) -> bool {
+        match self.region(index) {
+            Some(v) => v
+                .caps
+                .iter()
+                .any(|cap| matches!(cap, VfioRegionInfoCap::SparseMmap(_))),
+            None => false,
+        }
+    }
+
+    /// Get the offset to pass to mmap() on the device fd to map a whole region.
+    ///
+    /// Unlike `get_region_offset()`, an invalid index, a region without
+    /// `VFIO_REGION_INFO_FLAG_MMAP` and a region restricted to sparse mmap areas
+    /// are all reported as errors rather than as a zero offset.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_mmap_offset(&self, index: u32) -> Result<u64> {
+        let region = self
+            .region(index)
+            .ok_or(VfioError::InvalidRegionIndex(index))?;
+        self.check_region_exposed(index)?;
+        if region.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 {
+            return Err(VfioError::RegionNotMappable(index));
+        }
+        if self.region_requires_sparse_mmap(index) {
+            return Err(VfioError::RegionSparseMmapOnly(index));
+        }
+
+        Ok(region.offset)
+    }
+
+    /// Map a whole region in the process address space.
+    ///
+    /// The protection is derived from the region flags: `PROT_READ` for
+    /// `VFIO_REGION_INFO_FLAG_READ` and `PROT_WRITE` for `VFIO_REGION_INFO_FLAG_WRITE`, the
+    /// latter being left out for read-only devices. See `mmap_region_prot()` for the errors.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn mmap_region(&self, index: u32) -> Result<VfioRegionMmap> {
+        let flags = self.get_region_flags(index);
+        let mut prot = libc::PROT_NONE;
+        if flags & VFIO_REGION_INFO_FLAG_READ != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if flags & VFIO_REGION_INFO_FLAG_WRITE != 0 && !self.read_only {
+            prot |= libc::PROT_WRITE;
+        }
+
+        self.mmap_region_prot(index, prot)
+    }
+
+    /// Map a whole region in the process address space with the given protection.
+    ///
+    /// `prot` is a combination of `PROT_READ` and `PROT_WRITE`, each of which must be allowed
+    /// by the region flags, otherwise `VfioError::RegionMmapProt` is returned. `PROT_WRITE` is
+    /// refused with `VfioError::DeviceReadOnly` on read-only devices. The region must support
+    /// mmap as a whole, see `region_mmap_offset()`.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    /// * `prot` - `PROT_*` protection flags of the mapping.
+    pub fn mmap_region_prot(&self, index: u32, prot: i32) -> Result<VfioRegionMmap> {
+        if prot & libc::PROT_WRITE != 0 {
+            self.check_writable()?;
+        }
+        let offset = self.region_mmap_offset(index)?;
+        let flags = self.get_region_flags(index);
+        let invalid = prot & !(libc::PROT_READ | libc::PROT_WRITE) != 0
+            || (prot & libc::PROT_READ != 0 && flags & VFIO_REGION_INFO_FLAG_READ == 0)
+            || (prot & libc::PROT_WRITE != 0 && flags & VFIO_REGION_INFO_FLAG_WRITE == 0);
+        if invalid {
+            return Err(VfioError::RegionMmapProt { index, prot });
+        }
+
+        self.mmap_range(offset, self.get_region_size(index) as usize, prot)
+    }
+
+    // Map `len` bytes of the device fd at `offset` with the given protection.
+    fn mmap_range(&self, offset: u64, len: usize, prot: i32) -> Result<VfioRegionMmap> {
+        // SAFETY: a new mapping is created at an address picked by the kernel, so no existing
+        // memory is affected, and the result is checked.
+        let addr = unsafe {
+            libc::mmap64(
+                std::ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                self.device.as_raw_fd(),
+                offset as libc::off64_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(VfioError::VfioDeviceRegionMmap(io::Error::last_os_error()));
+        }
+
+        Ok(VfioRegionMmap {
+            addr: addr as *mut u8,
+            len,
+            prot,
+        })
+    }
+
+    /// Compute the mappings backing a region at the guest physical address the guest assigned.
+    ///
+    /// When the guest programs a BAR, the VMM maps the mmap'able parts of the region at the BAR
+    /// address so that the guest accesses the device memory directly. A region without sparse
+    /// mmap areas is mapped as a whole, otherwise each area is mapped at its offset from `gpa`,
+    /// leaving the rest of the region, such as the MSI-X table, to be trapped.
+    ///
+    /// `VfioError::RegionNotMappable` is returned for a region without
+    /// `VFIO_REGION_INFO_FLAG_MMAP`, and `VfioError::InvalidRegionGuestAddress` if `gpa` isn't
+    /// aligned to the host page size or the region doesn't fit above it.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    /// * `gpa` - Guest physical address the guest assigned to the region.
+    pub fn region_guest_mappings(
+        &self,
+        index: u32,
+        gpa: GuestAddress,
+    ) -> Result<Vec<VfioRegionGuestMapping>> {
+        let region = self
+            .region(index)
+            .ok_or(VfioError::InvalidRegionIndex(index))?;
+        self.check_region_exposed(index)?;
+        if region.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 {
+            return Err(VfioError::RegionNotMappable(index));
+        }
+        // SAFETY: sysconf() has no memory safety requirements.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        if gpa.raw_value() & (page_size - 1) != 0 || gpa.checked_add(region.size).is_none() {
+            return Err(VfioError::InvalidRegionGuestAddress {
+                index,
+                gpa: gpa.raw_value(),
+            });
+        }
+
+        let sparse_areas = region.caps.iter().find_map(|cap| match cap {
+            VfioRegionInfoCap::SparseMmap(sparse) => Some(&sparse.areas),
+            _ => None,
+        });
+        let mappings = match sparse_areas {
+            Some(areas) => areas
+                .iter()
+                // Areas outside of the region are ignored rather than mapped over its neighbours.
+                .filter(|area| {
+                    area.size != 0
+                        && area
+                            .offset
+                            .checked_add(area.size)
+                            .is_some_and(|end| end <= region.size)
+                })
+                .map(|area| VfioRegionGuestMapping {
+                    gpa: gpa.unchecked_add(area.offset),
+                    fd_offset: region.offset + area.offset,
+                    size: area.size,
+                })
+                .collect(),
+            None => vec![VfioRegionGuestMapping {
+                gpa,
+                fd_offset: region.offset,
+                size: region.size,
+            }],
+        };
+
+        Ok(mappings)
+    }
+
+    /// Restrict the access widths accepted by `region_read()` and `region_write()` on a region.
+    ///
+    /// The config space of PCI devices defaults to
+    /// [`CONFIG_SPACE`](VfioRegionAccessPolicy::CONFIG_SPACE), the other regions are permissive
+    /// by default. The policy is kept across [`refresh_region()`](Self::refresh_region). A
+    /// `max_width` which isn't a power of two is refused with
+    /// `VfioError::InvalidRegionAccessWidth`.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    /// * `policy` - Access widths accepted by the region.
+    pub fn set_region_access_policy(
+        &mut self,
+        index: u32,
+        policy: VfioRegionAccessPolicy,
+    ) -> Result<()> {
+        if self.region(index).is_none() {
+            return Err(VfioError::InvalidRegionIndex(index));
+        }
+        if let VfioRegionAccessPolicy::Aligned { max_width } = policy {
+            if !max_width.is_power_of_two() {
+                return Err(VfioError::InvalidRegionAccessWidth { index, max_width });
+            }
+        }
+        self.region_access_policies.insert(index, policy);
+
+        Ok(())
+    }
+
+    /// Get the access widths accepted by a region.
+    ///
+    /// # Arguments
+    /// * `index` - The index of memory region.
+    pub fn region_access_policy(&self, index: u32) -> VfioRegionAccessPolicy {
+        match self.region_access_policies.get(&index) {
+            Some(policy) => *policy,
+            None if self.flags & VFIO_DEVICE_FLAGS_PCI != 0
+                && index == VFIO_PCI_CONFIG_REGION_INDEX =>
+            {
+                VfioRegionAccessPolicy::CONFIG_SPACE
+            }
+            None => VfioRegionAccessPolicy::Permissive,
+        }
+    }
+
+    /// Opt in, or out, of copying large transfers through mappings of the regions.
+    ///
+    /// When enabled, `region_read()` and `region_write()` copy transfers of at least a page
+    /// from or to a mapping of the region, created on first use, instead of going through
+    /// `pread()` and `pwrite()`, which is much faster for large BARs or device RAM. Regions
+    /// without `VFIO_REGION_INFO_FLAG_MMAP` and the BAR holding the MSI-X table are always
+    /// accessed through the device fd, as are transfers crossing the sparse mmap areas of a
+    /// region. The mappings are dropped when the device is reset and when its region is
+    /// refreshed. The fast path is disabled by default, devices shared behind an `Arc` enable it
+    /// when opened with [`enable_mmap_fastpath()`](crate::VfioDeviceBuilder::enable_mmap_fastpath).
+    pub fn set_mmap_fastpath(&mut self, enable: bool) {
+        self.mmap_fastpath = if enable {
+            Some(Mutex::new(HashMap::new()))
+        } else {
+            None
+        };
+    }
+
+    // Drop the mappings of the fast path, to be created again on the next large transfer.
+    pub(crate) fn invalidate_mmap_fastpath(&self) {
+        if let Some(mappings) = &self.mmap_fastpath {
+            lock_or_recover(mappings).clear();
+        }
+    }
+
+    // Map the parts of a region which the fast path may access, none for trap-only regions.
+    fn fastpath_mappings(&self, region: &VfioRegion) -> Vec<FastpathMapping> {
+        if region.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 {
+            return Vec::new();
+        }
+        // Accesses to the MSI-X table must be trapped by the kernel.
+        if self.flags & VFIO_DEVICE_FLAGS_PCI != 0 {
+            if let Ok(Some(info)) = self.msix_info() {
+                if info.table_bir == region.index {
+                    return Vec::new();
+                }
+            }
+        }
+
+        let mut prot = libc::PROT_NONE;
+        if region.flags & VFIO_REGION_INFO_FLAG_READ != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if region.flags & VFIO_REGION_INFO_FLAG_WRITE != 0 && !self.read_only {
+            prot |= libc::PROT_WRITE;
+        }
+        let areas = match region.caps.iter().find_map(|cap| match cap {
+            VfioRegionInfoCap::SparseMmap(sparse) => Some(sparse.areas.clone()),
+            _ => None,
+        }) {
+            Some(areas) => areas,
+            None => vec![VfioRegionSparseMmapArea {
+                offset: 0,
+                size: region.size,
+            }],
+        };
+
+        areas
+            .into_iter()
+            .filter(|area| {
+                area.size != 0
+                    && area
+                        .offset
+                        .checked_add(area.size)
+                        .is_some_and(|end| end <= region.size)
+            })
+            .filter_map(|area| {
+                match self.mmap_range(region.offset + area.offset, area.size as usize, prot) {
+                    Ok(mmap) => Some(FastpathMapping {
+                        start: area.offset,
+                        mmap,
+                    }),
+                    Err(e) => {
+                        warn!(
+                            "Failed to map region {} of {} for fast accesses: {}",
+                            region.index,
+                            self.name(),
+                            e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Copy `len` bytes at `addr` of a region through the fast path if possible, returning
+    // whether `copy` was called.
+    fn region_fastpath<F>(
+        &self,
+        region: &VfioRegion,
+        addr: u64,
+        len: usize,
+        prot: i32,
+        copy: F,
+    ) -> bool
+    where
+        F: FnOnce(VolatileSlice),
+    {
+        let cache = match &self.mmap_fastpath {
+            Some(cache) if len >= MMAP_FASTPATH_MIN_SIZE => cache,
+            _ => return false,
+        };
+        // The mappings stay alive during the copy even if they are invalidated meanwhile.
+        let mappings = lock_or_recover(cache)
+            .entry(region.index)
+            .or_insert_with(|| self.fastpath_mappings(region).into())
+            .clone();
+        let slice = mappings
+            .iter()
+            .filter(|mapping| mapping.mmap.prot() & prot != 0)
+            .find_map(|mapping| mapping.slice(addr, len));
+        match slice {
+            Some(slice) => {
+                copy(slice);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Read exactly `buf.len()` bytes of the device fd at `offset`, like `FileExt::read_exact_at()`.
+    pub(crate) fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            // SAFETY: we own the device fd and buf is valid for writes of buf.len() bytes.
+            let ret = unsafe {
+                libc::pread64(
+                    self.device.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    offset as libc::off64_t,
+                )
+            };
+            match ret {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                n => {
+                    buf = &mut buf[n as usize..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Write all of `buf` to the device fd at `offset`, like `FileExt::write_all_at()`.
+    pub(crate) fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            // SAFETY: we own the device fd and buf is valid for reads of buf.len() bytes.
+            let ret = unsafe {
+                libc::pwrite64(
+                    self.device.as_raw_fd(),
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    offset as libc::off64_t,
+                )
+            };
+            match ret {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                n => {
+                    buf = &buf[n as usize..];
+                    offset += n as u64;
+                }
             }
         }
+        Ok(())
     }
 
     /// Read region's data from VFIO device into buf
     ///
+    /// Large reads are copied from a mapping of the region if enabled with
+    /// [`set_mmap_fastpath()`](Self::set_mmap_fastpath).
+    ///
     /// # Arguments
     /// * `index`: region num
     /// * `buf`: data destination and buf length is read size
     /// * `addr`: offset in the region
     pub fn region_read(&self, index: u32, buf: &mut [u8], addr: u64) {
-        let region: &VfioRegion = match self.regions.get(index as usize) {
+        let region = match self.region(index) {
             Some(v) => v,
             None => {
                 warn!("region read with invalid index: {}", index);
                 return;
             }
         };
+        if !self.region_exposable(index) {
+            warn!("region read of unexposed region, index: {}", index);
+            return;
+        }
 
         let size = buf.len() as u64;
         if size > region.size || addr + size > region.size {
@@ -1075,8 +4991,20 @@ impl VfioDevice {
             );
             return;
         }
+        if !self.region_access_policy(index).allows(addr, size) {
+            warn!(
+                "region read with illegal width, index: {}, addr: {}, size: {}",
+                index, addr, size
+            );
+            return;
+        }
 
-        if let Err(e) = self.device.read_exact_at(buf, region.offset + addr) {
+        if self.region_fastpath(&region, addr, buf.len(), libc::PROT_READ, |slice| {
+            slice.copy_to(buf);
+        }) {
+            return;
+        }
+        if let Err(e) = self.read_exact_at(buf, region.offset + addr) {
             warn!(
                 "Failed to read region in index: {}, addr: {}, error: {}",
                 index, addr, e
@@ -1086,18 +5014,33 @@ impl VfioDevice {
 
     /// Write the data from buf into a vfio device region
     ///
+    /// Large writes are copied to a mapping of the region if enabled with
+    /// [`set_mmap_fastpath()`](Self::set_mmap_fastpath).
+    ///
     /// # Arguments
     /// * `index`: region num
     /// * `buf`: data src and buf length is write size
     /// * `addr`: offset in the region
     pub fn region_write(&self, index: u32, buf: &[u8], addr: u64) {
-        let stub: &VfioRegion = match self.regions.get(index as usize) {
+        if self.read_only {
+            warn!(
+                "Ignoring region write to read-only device {}, index: {}",
+                self.name(),
+                index
+            );
+            return;
+        }
+        let stub = match self.region(index) {
             Some(v) => v,
             None => {
                 warn!("region write with invalid index: {}", index);
                 return;
             }
         };
+        if !self.region_exposable(index) {
+            warn!("region write to unexposed region, index: {}", index);
+            return;
+        }
 
         let size = buf.len() as u64;
         if size > stub.size
@@ -1110,8 +5053,20 @@ impl VfioDevice {
             );
             return;
         }
+        if !self.region_access_policy(index).allows(addr, size) {
+            warn!(
+                "region write with illegal width, index: {}, addr: {}, size: {}",
+                index, addr, size
+            );
+            return;
+        }
 
-        if let Err(e) = self.device.write_all_at(buf, stub.offset + addr) {
+        if self.region_fastpath(&stub, addr, buf.len(), libc::PROT_WRITE, |slice| {
+            slice.copy_from(buf)
+        }) {
+            return;
+        }
+        if let Err(e) = self.write_all_at(buf, stub.offset + addr) {
             warn!(
                 "Failed to write region in index: {}, addr: {}, error: {}",
                 index, addr, e
@@ -1119,6 +5074,302 @@ impl VfioDevice {
         }
     }
 
+    // Check the accesses of `ranges` against the bounds and access policy of region `index`.
+    fn check_region_ranges<I>(&self, index: u32, ranges: I) -> Result<RegionRef>
+    where
+        I: Iterator<Item = (u64, u64)>,
+    {
+        let region = self
+            .region(index)
+            .ok_or(VfioError::InvalidRegionIndex(index))?;
+        self.check_region_exposed(index)?;
+        let policy = self.region_access_policy(index);
+        for (addr, size) in ranges {
+            match addr.checked_add(size) {
+                Some(end) if end <= region.size && policy.allows(addr, size) => {}
+                _ => return Err(VfioError::InvalidRegionAccess { index, addr, size }),
+            }
+        }
+
+        Ok(region)
+    }
+
+    // Split `addrs` into runs of contiguous accesses, as (start, end) index pairs.
+    fn contiguous_runs(addrs: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for (i, (addr, _)) in addrs.iter().enumerate() {
+            match runs.last_mut() {
+                Some((start, end))
+                    if *end - *start < IOV_MAX
+                        && addrs[i - 1].0 + addrs[i - 1].1 as u64 == *addr =>
+                {
+                    *end = i + 1
+                }
+                _ => runs.push((i, i + 1)),
+            }
+        }
+        runs
+    }
+
+    // Read contiguous buffers from the device fd at `offset` with a single preadv(), completing
+    // short reads buffer by buffer.
+    fn read_vectored_at(&self, bufs: &mut [&mut [u8]], offset: u64) -> io::Result<()> {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: we own the device fd and each iovec points to a buffer valid for writes of
+        // its length.
+        let ret = unsafe {
+            libc::preadv64(
+                self.device.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off64_t,
+            )
+        };
+        let mut done = match ret {
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+                0
+            }
+            n => n as usize,
+        };
+
+        let mut offset = offset;
+        for buf in bufs.iter_mut() {
+            let skip = done.min(buf.len());
+            done -= skip;
+            if skip < buf.len() {
+                self.read_exact_at(&mut buf[skip..], offset + skip as u64)?;
+            }
+            offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    // Write contiguous buffers to the device fd at `offset` with a single pwritev(), completing
+    // short writes buffer by buffer.
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: we own the device fd and each iovec points to a buffer valid for reads of its
+        // length, which pwritev() doesn't write to.
+        let ret = unsafe {
+            libc::pwritev64(
+                self.device.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off64_t,
+            )
+        };
+        let mut done = match ret {
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+                0
+            }
+            n => n as usize,
+        };
+
+        let mut offset = offset;
+        for buf in bufs.iter() {
+            let skip = done.min(buf.len());
+            done -= skip;
+            if skip < buf.len() {
+                self.write_all_at(&buf[skip..], offset + skip as u64)?;
+            }
+            offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Read several ranges of a vfio device region.
+    ///
+    /// Each entry of `iovecs` is filled from its offset in the region. All the ranges are
+    /// checked against the region bounds and access policy before anything is read. Runs of
+    /// ranges following each other, such as a register dump split by register, are read with a
+    /// single `preadv()`; discontiguous ranges need one syscall each.
+    ///
+    /// # Arguments
+    /// * `index`: region num
+    /// * `iovecs`: offsets in the region and the buffers to read them into
+    pub fn region_readv(&self, index: u32, iovecs: &mut [(u64, &mut [u8])]) -> Result<()> {
+        let region = self.check_region_ranges(
+            index,
+            iovecs.iter().map(|(addr, buf)| (*addr, buf.len() as u64)),
+        )?;
+        if region.flags & VFIO_REGION_INFO_FLAG_READ == 0 {
+            return Err(VfioError::InvalidRegionAccess {
+                index,
+                addr: 0,
+                size: region.size,
+            });
+        }
+        let addrs: Vec<(u64, usize)> = iovecs.iter().map(|(a, b)| (*a, b.len())).collect();
+
+        for (start, end) in Self::contiguous_runs(&addrs) {
+            let offset = region.offset + iovecs[start].0;
+            let mut bufs: Vec<&mut [u8]> = iovecs[start..end]
+                .iter_mut()
+                .map(|(_, buf)| &mut **buf)
+                .collect();
+            self.read_vectored_at(&mut bufs, offset)
+                .map_err(VfioError::VfioDeviceRegionAccess)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write several ranges of a vfio device region.
+    ///
+    /// Each entry of `iovecs` is written at its offset in the region. All the ranges are checked
+    /// against the region bounds and access policy before anything is written. Runs of ranges
+    /// following each other are written with a single `pwritev()`; discontiguous ranges need
+    /// one syscall each.
+    ///
+    /// # Arguments
+    /// * `index`: region num
+    /// * `iovecs`: offsets in the region and the data to write there
+    pub fn region_writev(&self, index: u32, iovecs: &[(u64, &[u8])]) -> Result<()> {
+        self.check_writable()?;
+        let region = self.check_region_ranges(
+            index,
+            iovecs.iter().map(|(addr, buf)| (*addr, buf.len() as u64)),
+        )?;
+        if region.flags & VFIO_REGION_INFO_FLAG_WRITE == 0 {
+            return Err(VfioError::InvalidRegionAccess {
+                index,
+                addr: 0,
+                size: region.size,
+            });
+        }
+        let addrs: Vec<(u64, usize)> = iovecs.iter().map(|(a, b)| (*a, b.len())).collect();
+
+        for (start, end) in Self::contiguous_runs(&addrs) {
+            let bufs: Vec<&[u8]> = iovecs[start..end].iter().map(|(_, buf)| *buf).collect();
+            self.write_vectored_at(&bufs, region.offset + iovecs[start].0)
+                .map_err(VfioError::VfioDeviceRegionAccess)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read from the PCI configuration space of the device.
+    ///
+    /// This is a shortcut for `config().read()`, going through the interception hooks.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data destination and buf length is read size
+    pub fn read_config(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.config().read(offset, buf)
+    }
+
+    /// Write to the PCI configuration space of the device.
+    ///
+    /// This is a shortcut for `config().write()`, going through the interception hooks.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data source and buf length is write size
+    pub fn write_config(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.config().write(offset, buf)
+    }
+
+    /// Get the raw `VFIO_DEVICE_FLAGS_*` reported by `VFIO_DEVICE_GET_INFO`.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Get the device flags decoded, including the bits unknown to this crate.
+    pub fn flags_decoded(&self) -> VfioDeviceFlags {
+        VfioDeviceFlags::from(self.flags)
+    }
+
+    /// Check whether DMA of the device is cache coherent.
+    ///
+    /// This is the `VFIO_DMA_CC_IOMMU` extension of the container, set when the IOMMU enforces
+    /// cache coherency for the DMA of all the groups of the container. When it isn't, the VMM
+    /// may have to do cache maintenance on the guest memory the device accesses, such as on ARM
+    /// platforms with non-coherent interconnects.
+    pub fn is_dma_coherent(&self) -> bool {
+        self.binding.container.supports_extension(VFIO_DMA_CC_IOMMU)
+    }
+
+    /// Map a region of host memory for DMA into the container of the device.
+    ///
+    /// The mapping is shared by all the devices of the container, and is tagged with the name
+    /// of the device, see [`VfioContainer::dma_map_tagged()`]. The mappings of the device are
+    /// then listed and unmapped with `mappings_by_tag()` and `unmap_by_tag()` of the container.
+    /// Read-only devices are refused with `VfioError::DeviceReadOnly`.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to map the memory at.
+    /// * size: size of the memory region.
+    /// * host_va: host virtual address of the memory region to map.
+    pub fn dma_map(&self, iova: Iova, size: usize, host_va: HostVa) -> Result<()> {
+        self.check_writable()?;
+        self.binding
+            .container
+            .dma_map_tagged(iova, size, host_va, self.name())
+    }
+
+    /// Record the range of IOVAs the device is able to address for DMA.
+    ///
+    /// Devices with a narrow DMA mask, such as legacy devices limited to 32 bits, silently fail
+    /// to reach memory mapped above it. Once set, mappings of the container, created by any of
+    /// its devices, which end beyond the mask are refused with
+    /// `VfioError::IommuDmaMapBeyondDmaMask`. The mappings already beyond the mask are kept, with
+    /// a warning. The configuration space doesn't report the mask, it comes from the driver of
+    /// the device.
+    ///
+    /// # Parameters
+    /// * mask: highest IOVA the device can address, e.g. `u32::MAX.into()` for 32 bits, `None`
+    ///   to remove the limit.
+    pub fn set_dma_mask(&self, mask: Option<u64>) {
+        let container = &self.binding.container;
+        match mask {
+            Some(mask) => {
+                for m in container.mappings() {
+                    let last = m.iova.raw_value().saturating_add(m.size.saturating_sub(1));
+                    if last > mask {
+                        warn!(
+                            "{}: dma mapping at {:#x} of {:#x} bytes is beyond the dma mask {:#x}",
+                            self.name(),
+                            m.iova.raw_value(),
+                            m.size,
+                            mask
+                        );
+                    }
+                }
+                lock_or_recover(&container.dma_masks).insert(self.id, (self.name(), mask));
+            }
+            None => {
+                lock_or_recover(&container.dma_masks).remove(&self.id);
+            }
+        }
+    }
+
+    /// Get the DMA mask set with [`set_dma_mask()`](Self::set_dma_mask), if any.
+    pub fn dma_mask(&self) -> Option<u64> {
+        let masks = lock_or_recover(&self.binding.container.dma_masks);
+        masks.get(&self.id).map(|(_, mask)| *mask)
+    }
+
     /// Return the maximum numner of interrupts a VFIO device can request.
     pub fn max_interrupts(&self) -> u32 {
         let mut max_interrupts = 0;
@@ -1129,7 +5380,7 @@ impl VfioDevice {
         ];
 
         for index in irq_indexes {
-            if let Some(irq_info) = self.irqs.get(&index) {
+            if let Some(irq_info) = self.irq_info(index) {
                 if irq_info.count > max_interrupts {
                     max_interrupts = irq_info.count;
                 }
@@ -1138,6 +5389,136 @@ impl VfioDevice {
 
         max_interrupts
     }
+
+    /// Describe the device, its regions and its interrupts in a human readable form.
+    ///
+    /// The description spans multiple lines, with each region and irq index followed by
+    /// the details of its capabilities. It is meant for diagnostics, not for parsing.
+    pub fn describe(&self) -> String {
+        let mut out = format!("{}\n", self);
+
+        let all_regions = self.regions();
+        let mut regions: Vec<&VfioRegion> = all_regions.iter().collect();
+        regions.sort_by_key(|r| r.index);
+        for region in regions {
+            let _ = writeln!(out, "  {} at {:#x}", region, region.offset);
+            for cap in region.caps.iter() {
+                match cap {
+                    VfioRegionInfoCap::SparseMmap(m) => {
+                        for area in m.areas.iter() {
+                            let _ = writeln!(
+                                out,
+                                "    sparse-mmap area {:#x} ({})",
+                                area.offset,
+                                human_size(area.size)
+                            );
+                        }
+                    }
+                    VfioRegionInfoCap::Type(t) => {
+                        let _ = writeln!(out, "    type {:#x} subtype {:#x}", t.type_, t.subtype);
+                    }
+                    VfioRegionInfoCap::MsixMappable => {
+                        let _ = writeln!(out, "    msix-mappable");
+                    }
+                    VfioRegionInfoCap::Nvlink2Ssatgt(t) => {
+                        let _ = writeln!(out, "    nvlink2 ssatgt {:#x}", t.tgt);
+                    }
+                    VfioRegionInfoCap::Nvlink2Lnkspd(l) => {
+                        let _ = writeln!(out, "    nvlink2 link speed {}", l.link_speed);
+                    }
+                }
+            }
+        }
+
+        for irq in self.irqs().values() {
+            let _ = writeln!(out, "  {}", irq);
+        }
+
+        out
+    }
+}
+
+impl VfioDevice {
+    #[cfg(test)]
+    pub(crate) fn push_region(&mut self, region: VfioRegion) {
+        let regions = self.regions.get_mut().unwrap();
+        let mut updated = regions.to_vec();
+        updated.push(region);
+        *regions = updated.into();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn regions_mut(&mut self) -> &mut [VfioRegion] {
+        Arc::get_mut(self.regions.get_mut().unwrap()).unwrap()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn irqs_mut(&mut self) -> &mut VfioIrqs {
+        Arc::make_mut(self.irqs.get_mut().unwrap())
+    }
+
+    // Number of parts of a region mapped by the fast path, None if not mapped yet.
+    #[cfg(test)]
+    pub(crate) fn mmap_fastpath_parts(&self, index: u32) -> Option<usize> {
+        let cache = lock_or_recover(self.mmap_fastpath.as_ref()?);
+        cache.get(&index).map(|mappings| mappings.len())
+    }
+
+    pub(crate) fn name(&self) -> String {
+        self.sysfspath
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.sysfspath.to_string_lossy().into_owned())
+    }
+
+    fn type_name(&self) -> &'static str {
+        if self.flags & VFIO_DEVICE_FLAGS_PCI != 0 {
+            "pci"
+        } else if self.flags & VFIO_DEVICE_FLAGS_PLATFORM != 0 {
+            "platform"
+        } else if self.flags & VFIO_DEVICE_FLAGS_AMBA != 0 {
+            "amba"
+        } else if self.flags & VFIO_DEVICE_FLAGS_CCW != 0 {
+            "ccw"
+        } else if self.flags & VFIO_DEVICE_FLAGS_AP != 0 {
+            "ap"
+        } else {
+            "unknown"
+        }
+    }
+
+    fn irq_summary(&self) -> String {
+        let irqs: Vec<String> = self
+            .irqs()
+            .values()
+            .map(|irq| format!("{}:{}", irq.index_name(), irq.count))
+            .collect();
+        irqs.join(" ")
+    }
+}
+
+impl fmt::Debug for VfioDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VfioDevice")
+            .field("path", &self.sysfspath)
+            .field("type", &format_args!("{}", self.type_name()))
+            .field("regions", &self.regions().len())
+            .field("irqs", &format_args!("[{}]", self.irq_summary()))
+            .finish()
+    }
+}
+
+impl fmt::Display for VfioDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {} regions, irqs {})",
+            self.name(),
+            self.type_name(),
+            self.regions().len(),
+            self.irq_summary()
+        )
+    }
 }
 
 impl AsRawFd for VfioDevice {
@@ -1146,34 +5527,196 @@ impl AsRawFd for VfioDevice {
     }
 }
 
-impl Drop for VfioDevice {
+// Lock a mutex of a container, a device or an interrupt dispatcher, recovering it when a thread
+// panicked while holding it. The state they guard is only updated by single inserts, removals and
+// replacements which a panic can't leave half done, so a panicked operation mustn't wedge all the
+// following ones.
+pub(crate) fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| recover_poisoned(mutex, e))
+}
+
+// Read and write a lock of a container or a device, recovering it when a thread panicked while
+// holding it. The values they guard are replaced as a whole, which a panic can't leave half done.
+pub(crate) fn read_or_recover<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| {
+        warn!("Recovering VFIO lock poisoned by a panic");
+        lock.clear_poison();
+        e.into_inner()
+    })
+}
+
+pub(crate) fn write_or_recover<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| {
+        warn!("Recovering VFIO lock poisoned by a panic");
+        lock.clear_poison();
+        e.into_inner()
+    })
+}
+
+fn recover_poisoned<'a, T>(
+    mutex: &Mutex<T>,
+    e: PoisonError<MutexGuard<'a, T>>,
+) -> MutexGuard<'a, T> {
+    warn!("Recovering VFIO lock poisoned by a panic");
+    mutex.clear_poison();
+    e.into_inner()
+}
+
+// Guard of the groups lock of a container, clearing the thread recorded by lock_groups() before
+// releasing the lock.
+pub(crate) struct GroupsGuard<'a> {
+    owner: &'a Mutex<Option<ThreadId>>,
+    hash: MutexGuard<'a, HashMap<u32, Arc<VfioGroup>>>,
+}
+
+impl Deref for GroupsGuard<'_> {
+    type Target = HashMap<u32, Arc<VfioGroup>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.hash
+    }
+}
+
+impl DerefMut for GroupsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.hash
+    }
+}
+
+impl Drop for GroupsGuard<'_> {
     fn drop(&mut self) {
-        // ManuallyDrop is needed here because we need to ensure that VfioDevice::device is closed
-        // before dropping VfioDevice::group, otherwise it will cause EBUSY when putting the
-        // group object.
+        *lock_or_recover(self.owner) = None;
+    }
+}
 
-        // SAFETY: we own the File object.
-        unsafe {
-            ManuallyDrop::drop(&mut self.device);
+// A region of a device, along with the regions it was read with, which refresh_region() may
+// replace meanwhile.
+pub(crate) struct RegionRef {
+    regions: Arc<[VfioRegion]>,
+    pos: usize,
+}
+
+impl Deref for RegionRef {
+    type Target = VfioRegion;
+
+    fn deref(&self) -> &Self::Target {
+        &self.regions[self.pos]
+    }
+}
+
+// Guard of a group being opened or released, see VfioContainer::begin_group_transition().
+pub(crate) struct GroupTransition<'a> {
+    container: &'a VfioContainer,
+    group_id: u32,
+}
+
+impl Drop for GroupTransition<'_> {
+    fn drop(&mut self) {
+        lock_or_recover(&self.container.group_transitions).remove(&self.group_id);
+        self.container.group_transition_done.notify_all();
+    }
+}
+
+/// The reference of a device on its group and container.
+///
+/// The group is released from the container when dropped. Devices must be closed before, otherwise
+/// unbinding the group from the container fails with EBUSY, so the binding is the last field of
+/// `VfioDevice` to be dropped.
+pub(crate) struct VfioGroupBinding {
+    pub(crate) group: Arc<VfioGroup>,
+    pub(crate) container: Arc<VfioContainer>,
+}
+
+impl Drop for VfioDevice {
+    fn drop(&mut self) {
+        let mut masks = lock_or_recover(&self.binding.container.dma_masks);
+        if !masks.is_empty() {
+            masks.remove(&self.id);
         }
+    }
+}
+
+impl Drop for VfioGroupBinding {
+    fn drop(&mut self) {
         self.container.put_group(self.group.clone());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem::size_of;
-    use vm_memory::{GuestAddress, GuestMemoryMmap};
-    use vmm_sys_util::tempfile::TempFile;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::PciCommand;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::mem::size_of;
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Allocator tracking the heap blocks held by each thread.
+    struct CountingAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    thread_local! {
+        static HEAP_BLOCKS: Cell<isize> = const { Cell::new(0) };
+    }
+
+    fn count_heap_blocks(blocks: isize) {
+        // Allocations made while the thread is torn down aren't counted.
+        let _ = HEAP_BLOCKS.try_with(|c| c.set(c.get() + blocks));
+    }
+
+    // SAFETY: the allocations are forwarded to the system allocator.
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_heap_blocks(1);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count_heap_blocks(-1);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    // Run `f` and return its result with the number of heap blocks it left allocated.
+    fn heap_blocks<T>(f: impl FnOnce() -> T) -> (T, isize) {
+        let blocks = HEAP_BLOCKS.with(Cell::get);
+        let value = f();
+        (value, HEAP_BLOCKS.with(Cell::get) - blocks)
+    }
+
+    // Fetch the capabilities of a single region, as VfioDeviceInfo::get_region() does.
+    fn get_region_map(
+        file: &File,
+        region: &mut VfioRegion,
+        region_info: &vfio_region_info,
+        max_size: u32,
+    ) -> Result<()> {
+        let mut caps = Vec::new();
+        VfioDeviceInfo::get_region_map(file, region, region_info, max_size, &mut caps)?;
+        RegionCaps::share(std::slice::from_mut(region), caps);
+        Ok(())
+    }
+
+    impl VfioContainer {
+        pub(crate) fn open_container_file(_paths: &VfioPaths) -> Result<OwnedFd> {
+            let tmp_file = TempFile::new().unwrap();
+            Ok(File::open(tmp_file.as_path()).unwrap().into())
+        }
+    }
 
     impl VfioGroup {
-        pub(crate) fn open_group_file(id: u32) -> Result<File> {
+        pub(crate) fn open_group_file(id: u32, _paths: &VfioPaths) -> Result<OwnedFd> {
             let tmp_file = TempFile::new().unwrap();
             OpenOptions::new()
                 .read(true)
                 .write(true)
                 .open(tmp_file.as_path())
+                .map(OwnedFd::from)
                 .map_err(|e| VfioError::OpenGroup(e, id.to_string()))
         }
     }
@@ -1206,12 +5749,409 @@ mod tests {
         assert_eq!(reg.region_info.argsz, 0);
     }
 
+    #[test]
+    fn test_vfio_region_sparse_mmap_normalize() {
+        let sparse = |areas: &[(u64, u64)]| VfioRegionInfoCapSparseMmap {
+            areas: areas
+                .iter()
+                .map(|&(offset, size)| VfioRegionSparseMmapArea { offset, size })
+                .collect(),
+        };
+
+        // Valid areas are left untouched, up to the end of the region.
+        let mut cap = sparse(&[(0, 0x1000), (0x1000, 0x1000), (0x3000, 0x1000)]);
+        cap.normalize(0, 0x4000).unwrap();
+        assert_eq!(
+            cap,
+            sparse(&[(0, 0x1000), (0x1000, 0x1000), (0x3000, 0x1000)])
+        );
+
+        // Unordered areas are sorted, empty ones dropped and overlapping ones merged.
+        let mut cap = sparse(&[(0x3000, 0x1000), (0x2000, 0), (0x800, 0x1000), (0, 0x1000)]);
+        cap.normalize(0, 0x4000).unwrap();
+        assert_eq!(cap, sparse(&[(0, 0x1800), (0x3000, 0x1000)]));
+        let mut cap = sparse(&[(0, 0x4000), (0x1000, 0x1000)]);
+        cap.normalize(0, 0x4000).unwrap();
+        assert_eq!(cap, sparse(&[(0, 0x4000)]));
+
+        // Areas out of the region are refused, even empty ones.
+        for (offset, size) in [(0x3000, 0x1001), (0x5000, 0), (u64::MAX, 2)] {
+            let mut cap = sparse(&[(0, 0x1000), (offset, size)]);
+            match cap.normalize(3, 0x4000) {
+                Err(VfioError::InvalidSparseMmapArea {
+                    index: 3,
+                    offset: o,
+                    size: s,
+                }) => assert_eq!((o, s), (offset, size)),
+                _ => panic!("expect VfioError::InvalidSparseMmapArea"),
+            }
+        }
+
+        // The areas reported by the kernel are checked against the size of their region.
+        let file = TempFile::new().unwrap().into_file();
+        let base = size_of::<vfio_region_info>() as u32;
+        let reg_info = vfio_region_info {
+            argsz: base + 64,
+            flags: VFIO_REGION_INFO_FLAG_CAPS,
+            index: 1,
+            cap_offset: 0,
+            size: 0x6,
+            offset: 0,
+        };
+        let mut region = VfioRegion {
+            index: 1,
+            flags: reg_info.flags,
+            size: reg_info.size,
+            offset: reg_info.offset,
+            caps: RegionCaps::default(),
+        };
+        assert!(matches!(
+            get_region_map(&file, &mut region, &reg_info, base + 64),
+            Err(VfioError::InvalidSparseMmapArea {
+                index: 1,
+                offset: 0x4,
+                size: 0x3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_vfio_region_sparse_mmap_invalid_device() {
+        fn sparse_chain(nr_areas: u32, offset: u64, size: u64) -> Vec<u8> {
+            let mut chain = (VFIO_REGION_INFO_CAP_SPARSE_MMAP as u16)
+                .to_le_bytes()
+                .to_vec();
+            chain.extend_from_slice(&1u16.to_le_bytes());
+            chain.extend_from_slice(&0u32.to_le_bytes());
+            chain.extend_from_slice(&nr_areas.to_le_bytes());
+            chain.extend_from_slice(&0u32.to_le_bytes());
+            chain.extend_from_slice(&offset.to_le_bytes());
+            chain.extend_from_slice(&size.to_le_bytes());
+            chain
+        }
+
+        // Region 1 is 0x2000 bytes long and may be mmap'ed.
+        vfio_syscall::inject_region_caps_flags(
+            VFIO_REGION_INFO_FLAG_MMAP | VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+        );
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+
+        // A sparse mmap area out of the region fails the device open rather than exposing the
+        // whole region.
+        vfio_syscall::inject_region_cap_chain(sparse_chain(1, 0x1000, 0x2000));
+        assert!(matches!(
+            VfioDevice::new(tmp_file.as_path(), container.clone()),
+            Err(VfioError::InvalidSparseMmapArea {
+                index: 1,
+                offset: 0x1000,
+                size: 0x2000
+            })
+        ));
+
+        // Areas within the region restrict the mappings to them.
+        vfio_syscall::inject_region_cap_chain(sparse_chain(1, 0x1000, 0x1000));
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert!(device.region_requires_sparse_mmap(1));
+        assert_eq!(
+            device
+                .region_guest_mappings(1, GuestAddress(0x1_0000_0000))
+                .unwrap(),
+            vec![VfioRegionGuestMapping {
+                gpa: GuestAddress(0x1_0000_1000),
+                fd_offset: 0x21000,
+                size: 0x1000,
+            }]
+        );
+
+        // Sparse mmap areas which can't be read, or capabilities which can't be fetched, leave
+        // the region without mmap support.
+        vfio_syscall::inject_region_cap_chain(sparse_chain(4, 0x1000, 0x1000));
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        vfio_syscall::inject_region_cap_chain(Vec::new());
+        vfio_syscall::inject_region_cap_query_failure(true);
+        let other = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        vfio_syscall::inject_region_cap_query_failure(false);
+        vfio_syscall::inject_region_caps_flags(0);
+        for device in [device, other] {
+            assert!(device.get_region_caps(1).is_empty());
+            assert!(!device.region_requires_sparse_mmap(1));
+            assert!(matches!(
+                device.region_mmap_offset(1),
+                Err(VfioError::RegionNotMappable(1))
+            ));
+            assert!(matches!(
+                device.region_guest_mappings(1, GuestAddress(0x1_0000_0000)),
+                Err(VfioError::RegionNotMappable(1))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_vfio_region_caps_degenerate() {
+        let file = TempFile::new().unwrap().into_file();
+        let base = size_of::<vfio_region_info>() as u32;
+        let mut reg_info = vfio_region_info {
+            argsz: base,
+            flags: VFIO_REGION_INFO_FLAG_CAPS,
+            index: 2,
+            cap_offset: 0,
+            size: 0x1000,
+            offset: 0,
+        };
+        let mut region = VfioRegion {
+            index: 2,
+            flags: reg_info.flags,
+            size: reg_info.size,
+            offset: reg_info.offset,
+            caps: RegionCaps::default(),
+        };
+        vfio_syscall::take_region_cap_queries();
+
+        // The hinted size is the bare region info, or too small for one header.
+        for argsz in [base, base + 4] {
+            reg_info.argsz = argsz;
+            get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+            assert!(region.caps.is_empty());
+            assert_eq!(vfio_syscall::take_region_cap_queries(), 0);
+        }
+
+        // Room for capabilities, right at the limit, but the kernel returns an empty chain.
+        reg_info.argsz = base + 64;
+        get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+        assert!(region.caps.is_empty());
+        assert_eq!(vfio_syscall::take_region_cap_queries(), 1);
+
+        // A hint beyond the limit isn't allocated for.
+        for (argsz, max_size) in [
+            (base + 65, base + 64),
+            (u32::MAX, VFIO_REGION_INFO_MAX_SIZE),
+        ] {
+            reg_info.argsz = argsz;
+            let err = get_region_map(&file, &mut region, &reg_info, max_size);
+            assert!(matches!(
+                err,
+                Err(VfioError::RegionCapsTooLarge { index: 2, argsz: a }) if a == argsz
+            ));
+            assert!(region.caps.is_empty());
+            assert_eq!(vfio_syscall::take_region_cap_queries(), 0);
+        }
+    }
+
+    #[test]
+    fn test_vfio_region_caps_invalid_chain() {
+        fn cap_header(id: u32, next: u32) -> Vec<u8> {
+            let mut header = (id as u16).to_le_bytes().to_vec();
+            header.extend_from_slice(&1u16.to_le_bytes());
+            header.extend_from_slice(&next.to_le_bytes());
+            header
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        let base = size_of::<vfio_region_info>() as u32;
+        let reg_info = vfio_region_info {
+            argsz: base + 64,
+            flags: VFIO_REGION_INFO_FLAG_CAPS,
+            index: 2,
+            cap_offset: 0,
+            size: 0x1000,
+            offset: 0,
+        };
+        let mut region = VfioRegion {
+            index: 2,
+            flags: reg_info.flags,
+            size: reg_info.size,
+            offset: reg_info.offset,
+            caps: RegionCaps::default(),
+        };
+
+        // A chain looping back to its first capability stops after one pass.
+        let mut chain = cap_header(VFIO_REGION_INFO_CAP_MSIX_MAPPABLE, 40);
+        chain.extend(cap_header(VFIO_REGION_INFO_CAP_TYPE, 32));
+        chain.extend_from_slice(&5u32.to_le_bytes());
+        chain.extend_from_slice(&6u32.to_le_bytes());
+        vfio_syscall::inject_region_cap_chain(chain);
+        get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+        assert_eq!(
+            &*region.caps,
+            &[
+                VfioRegionInfoCap::MsixMappable,
+                VfioRegionInfoCap::Type(VfioRegionInfoCapType {
+                    type_: 5,
+                    subtype: 6
+                }),
+            ]
+        );
+
+        // A capability pointing to itself, or out of the buffer.
+        for next in [32, 92, 0x1000] {
+            vfio_syscall::inject_region_cap_chain(cap_header(
+                VFIO_REGION_INFO_CAP_MSIX_MAPPABLE,
+                next,
+            ));
+            get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+            assert_eq!(&*region.caps, &[VfioRegionInfoCap::MsixMappable]);
+        }
+
+        // Sparse mmap areas beyond the end of the buffer aren't read.
+        for nr_areas in [4u32, 1000, u32::MAX] {
+            let mut chain = cap_header(VFIO_REGION_INFO_CAP_SPARSE_MMAP, 0);
+            chain.extend_from_slice(&nr_areas.to_le_bytes());
+            chain.extend_from_slice(&0u32.to_le_bytes());
+            chain.extend_from_slice(&0u64.to_le_bytes());
+            chain.extend_from_slice(&0x1000u64.to_le_bytes());
+            vfio_syscall::inject_region_cap_chain(chain);
+            get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+            assert!(region.caps.is_empty());
+        }
+
+        // The same capability within bounds is decoded.
+        let mut chain = cap_header(VFIO_REGION_INFO_CAP_SPARSE_MMAP, 0);
+        chain.extend_from_slice(&1u32.to_le_bytes());
+        chain.extend_from_slice(&0u32.to_le_bytes());
+        chain.extend_from_slice(&0u64.to_le_bytes());
+        chain.extend_from_slice(&0x1000u64.to_le_bytes());
+        vfio_syscall::inject_region_cap_chain(chain);
+        get_region_map(&file, &mut region, &reg_info, base + 64).unwrap();
+        assert_eq!(
+            &*region.caps,
+            &[VfioRegionInfoCap::SparseMmap(VfioRegionInfoCapSparseMmap {
+                areas: vec![VfioRegionSparseMmapArea {
+                    offset: 0,
+                    size: 0x1000
+                }]
+            })]
+        );
+    }
+
+    #[test]
+    fn test_vfio_region_caps_shared() {
+        vfio_syscall::inject_region_caps_count(3);
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        let other = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        // The regions of a device share the storage of their capabilities, each seeing its own.
+        let (regions, other_regions) = (device.regions(), other.regions());
+        let arena = regions[1].caps.arena.clone().unwrap();
+        assert_eq!(arena.len(), 9);
+        for region in &regions[1..4] {
+            assert_eq!(&*region.caps, &arena[..3]);
+            assert!(Arc::ptr_eq(region.caps.arena.as_ref().unwrap(), &arena));
+        }
+        assert!(regions[0].caps.arena.is_none());
+        assert!(regions[4].caps.is_empty());
+
+        // Devices store them on their own.
+        let other_arena = other_regions[1].caps.arena.as_ref().unwrap();
+        assert!(!Arc::ptr_eq(&arena, other_arena));
+        assert_eq!(arena, *other_arena);
+
+        // A refreshed region stores its capabilities apart from the other ones.
+        device.refresh_region(2).unwrap();
+        vfio_syscall::inject_region_caps_count(1);
+        let regions = device.regions();
+        assert_eq!(regions[2].caps.arena.as_ref().unwrap().len(), 3);
+        assert_eq!(&*regions[2].caps, &*regions[1].caps);
+        assert!(Arc::ptr_eq(regions[1].caps.arena.as_ref().unwrap(), &arena));
+    }
+
+    #[test]
+    fn test_vfio_region_caps_allocations() {
+        // Regions 0 to 6 report capabilities, region 7 can't be queried.
+        vfio_syscall::inject_region_layout(0, 7);
+        vfio_syscall::inject_region_caps_count(7);
+        let mut dev_info = vfio_syscall::create_dev_info_for_test();
+        dev_info.num_regions = 8;
+        let tmp_file = TempFile::new().unwrap();
+        let device = File::open(tmp_file.as_path()).unwrap();
+        let device_info = VfioDeviceInfo::new(device.into(), &dev_info);
+        let (regions, blocks) =
+            heap_blocks(|| device_info.get_regions(VFIO_REGION_INFO_MAX_SIZE).unwrap());
+        vfio_syscall::inject_region_caps_count(1);
+        vfio_syscall::inject_region_layout(1, 7);
+
+        // The regions hold the slice of regions, the capabilities of all of them, and the
+        // sparse mmap areas of each, rather than a capability list per region on top of that.
+        assert_eq!(regions.len(), 7);
+        assert!(regions.iter().all(|r| r.caps.len() == 3));
+        assert_eq!(blocks, 2 + regions.len() as isize);
+        let ((), blocks) = heap_blocks(|| drop(regions));
+        assert_eq!(blocks, -9);
+    }
+
+    #[test]
+    fn test_vfio_region_caps_max_size() {
+        // Region 1 hints 88 bytes of region info, the limit applies to the devices opened next.
+        let mmap_flags =
+            VFIO_REGION_INFO_FLAG_MMAP | VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE;
+        vfio_syscall::inject_region_caps_flags(mmap_flags);
+        let container = Arc::new(create_vfio_container());
+        container.set_region_caps_max_size(87);
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(device.regions().len(), 7);
+        assert!(device.get_region_caps(1).is_empty());
+        // Without its capabilities, the region is still accessible but can't be mmap'ed.
+        assert_eq!(
+            device.get_region_flags(1),
+            VFIO_REGION_INFO_FLAG_CAPS | VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE
+        );
+        assert!(matches!(
+            device.region_mmap_offset(1),
+            Err(VfioError::RegionNotMappable(1))
+        ));
+        assert!(matches!(
+            device.region_guest_mappings(1, GuestAddress(0x1_0000_0000)),
+            Err(VfioError::RegionNotMappable(1))
+        ));
+
+        container.set_region_caps_max_size(88);
+        let tmp_file = TempFile::new().unwrap();
+        let device2 = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        vfio_syscall::inject_region_caps_flags(0);
+        assert_eq!(device2.regions().len(), 7);
+        assert!(!device2.get_region_caps(1).is_empty());
+        assert_eq!(
+            device2.get_region_flags(1),
+            VFIO_REGION_INFO_FLAG_CAPS | mmap_flags
+        );
+
+        // Refreshing a region honors the current limit.
+        container.set_region_caps_max_size(87);
+        device2.refresh_region(2).unwrap();
+        assert!(matches!(
+            device2.refresh_region(1),
+            Err(VfioError::RegionCapsTooLarge {
+                index: 1,
+                argsz: 88
+            })
+        ));
+    }
+
+    #[test]
+    fn test_vfio_region_caps_query_failure() {
+        // The capabilities of region 1 are rejected, the region is kept without them.
+        vfio_syscall::inject_region_cap_query_failure(true);
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        vfio_syscall::inject_region_cap_query_failure(false);
+
+        assert_eq!(device.regions().len(), 7);
+        let region = device.region(1).unwrap();
+        assert_eq!((region.size, region.offset), (0x2000, 0x20000));
+        assert!(region.caps.is_empty());
+        assert!(device.get_region_caps(1).is_empty());
+    }
+
     #[test]
     fn test_vfio_device_info() {
         let tmp_file = TempFile::new().unwrap();
         let device = File::open(tmp_file.as_path()).unwrap();
         let dev_info = vfio_syscall::create_dev_info_for_test();
-        let device_info = VfioDeviceInfo::new(device, &dev_info);
+        let device_info = VfioDeviceInfo::new(device.into(), &dev_info);
 
         let irqs = device_info.get_irqs().unwrap();
         assert_eq!(irqs.len(), 3);
@@ -1228,7 +6168,24 @@ mod tests {
         assert_eq!(irq.count, 2048);
         assert_eq!(irq.index, 2);
 
-        let regions = device_info.get_regions().unwrap();
+        // Enumeration follows the reported count, including the ERR/REQ
+        // indexes and anything beyond them.
+        let mut dev_info = vfio_syscall::create_dev_info_for_test();
+        dev_info.num_irqs = VFIO_PCI_NUM_IRQS + 1;
+        let tmp_file = TempFile::new().unwrap();
+        let device = File::open(tmp_file.as_path()).unwrap();
+        let extended_info = VfioDeviceInfo::new(device.into(), &dev_info);
+        let irqs = extended_info.get_irqs().unwrap();
+        assert_eq!(irqs.len(), 5);
+        assert!(!irqs.contains_key(&VFIO_PCI_ERR_IRQ_INDEX));
+        let irq = irqs.get(&VFIO_PCI_REQ_IRQ_INDEX).unwrap();
+        assert_eq!(irq.flags, VFIO_IRQ_INFO_EVENTFD);
+        assert_eq!(irq.count, 1);
+        let irq = irqs.get(&VFIO_PCI_NUM_IRQS).unwrap();
+        assert_eq!(irq.flags, VFIO_IRQ_INFO_EVENTFD);
+        assert_eq!(irq.count, 0);
+
+        let regions = device_info.get_regions(VFIO_REGION_INFO_MAX_SIZE).unwrap();
         assert_eq!(regions.len(), 2);
         assert_eq!(regions[0].flags, 0);
         assert_eq!(regions[0].offset, 0x10000);
@@ -1259,15 +6216,758 @@ mod tests {
         }
     }
 
-    fn create_vfio_container() -> VfioContainer {
+    #[test]
+    fn test_vfio_irqs_lookup() {
+        let irq = |index| VfioIrq {
+            flags: VFIO_IRQ_INFO_EVENTFD,
+            index,
+            count: index + 1,
+        };
+        let irqs = VfioIrqs::new(vec![irq(4), irq(0), irq(2)]);
+        let indexes: Vec<u32> = irqs.values().map(|irq| irq.index).collect();
+        assert_eq!(indexes, vec![0, 2, 4]);
+        assert_eq!(irqs.get(&2).unwrap().count, 3);
+        assert_eq!(irqs[&4].count, 5);
+        assert!(irqs.get(&1).is_none());
+        assert!(irqs.get(&5).is_none());
+        assert!(VfioIrqs::default().get(&0).is_none());
+    }
+
+    #[test]
+    fn test_vfio_region_info_cap_raw() {
+        let ty = VfioRegionInfoCapType {
+            type_: 0x8086_0000 | 1,
+            subtype: 3,
+        };
+        let raw = vfio_region_info_cap_type::from(ty);
+        assert_eq!(raw.header.id as u32, VFIO_REGION_INFO_CAP_TYPE);
+        assert_eq!(raw.header.version, 1);
+        assert_eq!(raw.header.next, 0);
+        assert_eq!(VfioRegionInfoCapType::from(raw), ty);
+
+        let ssatgt = VfioRegionInfoCapNvlink2Ssatgt { tgt: 0x2_0000_0000 };
+        let raw = vfio_region_info_cap_nvlink2_ssatgt::from(ssatgt);
+        assert_eq!(raw.header.id as u32, VFIO_REGION_INFO_CAP_NVLINK2_SSATGT);
+        assert_eq!(VfioRegionInfoCapNvlink2Ssatgt::from(raw), ssatgt);
+
+        let lnkspd = VfioRegionInfoCapNvlink2Lnkspd { link_speed: 9 };
+        let raw = vfio_region_info_cap_nvlink2_lnkspd::from(lnkspd);
+        assert_eq!(raw.header.id as u32, VFIO_REGION_INFO_CAP_NVLINK2_LNKSPD);
+        assert_eq!(VfioRegionInfoCapNvlink2Lnkspd::from(raw), lnkspd);
+
+        for count in [0u64, 1, 37].iter() {
+            let sparse = VfioRegionInfoCapSparseMmap {
+                areas: (0..*count)
+                    .map(|i| VfioRegionSparseMmapArea {
+                        offset: i * 0x2000,
+                        size: 0x1000 + i,
+                    })
+                    .collect(),
+            };
+            let raw = sparse.to_raw();
+            assert_eq!(raw[0].header.id as u32, VFIO_REGION_INFO_CAP_SPARSE_MMAP);
+            assert_eq!(raw[0].header.version, 1);
+            assert_eq!(raw[0].nr_areas as u64, *count);
+            assert!(
+                mem::size_of_val(raw.as_slice())
+                    >= mem::size_of::<vfio_region_info_cap_sparse_mmap>()
+                        + *count as usize * mem::size_of::<vfio_region_sparse_mmap_area>()
+            );
+            assert_eq!(VfioRegionInfoCapSparseMmap::from_raw(&raw).unwrap(), sparse);
+        }
+
+        // Buffers too small for the advertised areas, or holding another capability.
+        let mut raw = vec_with_array_field::<
+            vfio_region_info_cap_sparse_mmap,
+            vfio_region_sparse_mmap_area,
+        >(0);
+        raw[0].header.id = VFIO_REGION_INFO_CAP_SPARSE_MMAP as u16;
+        raw[0].nr_areas = 4;
+        assert!(VfioRegionInfoCapSparseMmap::from_raw(&raw).is_none());
+        raw[0].nr_areas = 0;
+        assert!(VfioRegionInfoCapSparseMmap::from_raw(&raw).is_some());
+        raw[0].header.id = VFIO_REGION_INFO_CAP_TYPE as u16;
+        assert!(VfioRegionInfoCapSparseMmap::from_raw(&raw).is_none());
+        assert!(VfioRegionInfoCapSparseMmap::from_raw(&[]).is_none());
+    }
+
+    #[test]
+    fn test_vfio_device_region_discovery() {
+        // Region 0 carries the capabilities and region 3 can't be queried.
+        vfio_syscall::inject_region_layout(0, 3);
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+
+        let indexes: Vec<u32> = device.regions().iter().map(|r| r.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2, 4, 5, 6, 7]);
+        assert_eq!(device.get_region_flags(0), VFIO_REGION_INFO_FLAG_CAPS);
+        assert_eq!(device.get_region_caps(0).len(), 3);
+        assert!(device.msix_table_mappable(0));
+        assert!(device.region_requires_sparse_mmap(0));
+        assert!(!device.region_requires_sparse_mmap(1));
+
+        // Lookups past the missing region still find the right one.
+        assert_eq!(device.region_fd_offset(3), None);
+        assert_eq!(device.region_fd_offset(4), Some(0x50000));
+        assert_eq!(device.get_region_size(3), 0);
+        assert_eq!(device.get_region_size(4), 0x5000);
+        assert_eq!(device.get_region_offset(7), 0x80000);
+        assert!(matches!(
+            device.region_mmap_offset(3),
+            Err(VfioError::InvalidRegionIndex(3))
+        ));
+
+        // Non PCI devices don't need the PCI regions and irqs.
+        vfio_syscall::inject_device_flags(VFIO_DEVICE_FLAGS_PLATFORM);
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(device.get_region_caps(0).len(), 3);
+        vfio_syscall::inject_device_flags(0);
+        assert!(matches!(
+            VfioDevice::new(tmp_file.as_path(), container),
+            Err(VfioError::VfioDeviceGetInfo)
+        ));
+
+        vfio_syscall::inject_device_flags(VFIO_DEVICE_FLAGS_PCI);
+        vfio_syscall::inject_region_layout(1, 7);
+    }
+
+    #[test]
+    fn test_vfio_device_flags_decoded() {
+        assert_eq!(VfioDeviceFlags::from(0), VfioDeviceFlags::default());
+        let flags = VfioDeviceFlags::from(u32::MAX);
+        assert!(flags.reset && flags.pci && flags.platform && flags.amba && flags.ccw);
+        assert!(flags.ap && flags.fsl_mc && flags.caps && flags.cdx);
+        assert_eq!(flags.unknown_bits, u32::MAX << 9);
+        assert_eq!(flags.unknown_bit_positions(), (9..32).collect::<Vec<u32>>());
+
+        // Unknown bits are reported along with the known ones.
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let raw = VFIO_DEVICE_FLAGS_PCI | VFIO_DEVICE_FLAGS_CAPS | 1 << 12 | 1 << 31;
+        vfio_syscall::inject_device_flags(raw);
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(device.flags(), raw);
+        assert_eq!(
+            device.flags_decoded(),
+            VfioDeviceFlags {
+                pci: true,
+                caps: true,
+                unknown_bits: 1 << 12 | 1 << 31,
+                ..Default::default()
+            }
+        );
+        assert_eq!(device.flags_decoded().unknown_bit_positions(), vec![12, 31]);
+
+        vfio_syscall::inject_device_flags(VFIO_DEVICE_FLAGS_PLATFORM | VFIO_DEVICE_FLAGS_RESET);
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let flags = device.flags_decoded();
+        assert!(flags.platform && flags.reset && !flags.pci && !flags.caps);
+        assert_eq!(flags.unknown_bits, 0);
+        assert!(flags.unknown_bit_positions().is_empty());
+
+        vfio_syscall::inject_device_flags(VFIO_DEVICE_FLAGS_PCI);
+    }
+
+    #[test]
+    fn test_vfio_device_is_dma_coherent() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        assert!(device.is_dma_coherent());
+
+        vfio_syscall::inject_dma_coherent(false);
+        assert!(!device.is_dma_coherent());
+        vfio_syscall::inject_dma_coherent(true);
+    }
+
+    pub(crate) fn create_vfio_container() -> VfioContainer {
         let tmp_file = TempFile::new().unwrap();
         let container = File::open(tmp_file.as_path()).unwrap();
 
         VfioContainer {
-            container,
+            container: container.into(),
             device_fd: None,
             groups: Mutex::new(HashMap::new()),
+            groups_owner: Mutex::new(None),
+            bound_groups: Mutex::new(HashSet::new()),
+            detached_groups: Mutex::new(HashMap::new()),
+            iova_base: AtomicU64::new(0),
+            paths: VfioPaths::default(),
+            mappings: Mutex::new(BTreeMap::new()),
+            mapping_tags: Mutex::new(HashMap::new()),
+            mapping_budget: Mutex::new(None),
+            region_caps_max_size: Mutex::new(VFIO_REGION_INFO_MAX_SIZE),
+            dma_avail_watermark: Mutex::new(None),
+            dma_masks: Mutex::new(HashMap::new()),
+            iommu_info: RwLock::new(OnceCell::new()),
+            group_viable_wait: Mutex::new(None),
+            group_transitions: Mutex::new(HashSet::new()),
+            group_transition_done: Condvar::new(),
+            multi_group_policy: Mutex::new(MultiGroupPolicy::default()),
+            group_containers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn test_vfio_group_wait_viable() {
+        let container = create_vfio_container();
+
+        // Not viable yet, without waiting.
+        vfio_syscall::inject_group_not_viable(1);
+        assert!(matches!(
+            container.get_group(9, None),
+            Err(VfioError::GroupViable { group_id: 9, .. })
+        ));
+
+        // Viable after a few polls.
+        container.set_group_viable_wait(Some((Duration::from_secs(5), Duration::from_millis(1))));
+        vfio_syscall::inject_group_not_viable(3);
+        container.get_group(9, None).unwrap();
+        assert_eq!(container.group_ids(), vec![9]);
+
+        // Still not viable on timeout, because of a sibling device.
+        let root = TempDir::new_with_prefix("/tmp/vfio_group").unwrap();
+        let paths = VfioPaths {
+            dev_vfio_root: root.as_path().join("dev/vfio"),
+            sysfs_root: root.as_path().join("sys"),
+        };
+        let devices = root.as_path().join("sys/kernel/iommu_groups/10/devices");
+        for (name, driver) in [("0000:02:00.0", "vfio-pci"), ("0000:02:00.1", "igb")].iter() {
+            let driver_dir = root.as_path().join("sys/bus/pci/drivers").join(driver);
+            std::fs::create_dir_all(&driver_dir).unwrap();
+            std::fs::create_dir_all(devices.join(name)).unwrap();
+            std::os::unix::fs::symlink(driver_dir, devices.join(name).join("driver")).unwrap();
+        }
+        vfio_syscall::inject_group_not_viable(1);
+        match VfioGroup::new(10, &paths) {
+            Err(e @ VfioError::GroupViable { .. }) => assert_eq!(
+                e.to_string(),
+                "vfio group 10 is not viable, devices bound to other drivers: [\"0000:02:00.1\"]"
+            ),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let group = VfioGroup::open(10, &paths).unwrap();
+        vfio_syscall::inject_group_not_viable(u32::MAX);
+        match group.wait_viable(Duration::from_millis(20), Duration::from_millis(5)) {
+            Err(VfioError::GroupViableTimeout {
+                group_id: 10,
+                status: 0,
+                devices,
+            }) => assert_eq!(devices, vec!["0000:02:00.1".to_string()]),
+            r => panic!("unexpected result {:?}", r.err()),
         }
+        vfio_syscall::inject_group_not_viable(0);
+        group
+            .wait_viable(Duration::from_millis(0), Duration::from_millis(5))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_vfio_group_wait_viable_unlocked() {
+        let container = Arc::new(create_vfio_container());
+        let waiting = container.clone();
+        let waiter = thread::spawn(move || {
+            vfio_syscall::inject_group_not_viable(u32::MAX);
+            waiting.get_group(
+                9,
+                Some((Duration::from_millis(500), Duration::from_millis(1))),
+            )
+        });
+
+        // Other groups are opened and listed while the group is waited for.
+        thread::sleep(Duration::from_millis(20));
+        container.get_group(4, None).unwrap();
+        assert_eq!(container.group_ids(), vec![4]);
+        assert!(!waiter.is_finished());
+        assert!(matches!(
+            waiter.join().unwrap(),
+            Err(VfioError::GroupViableTimeout { group_id: 9, .. })
+        ));
+        assert_eq!(container.group_ids(), vec![4]);
+        assert!(container.group_transitions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_container_release_group() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+
+        // The IOMMU backend is set once, for the first group, and its failure is reported.
+        vfio_syscall::inject_set_iommu_failure();
+        assert!(matches!(
+            container.get_group(3, None),
+            Err(VfioError::ContainerSetIOMMU)
+        ));
+        assert_eq!(vfio_syscall::take_unset_container_log(), vec![(3, 0)]);
+        assert!(container.group_ids().is_empty());
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        container.get_group(4, None).unwrap();
+        assert_eq!(vfio_syscall::take_set_iommu_calls(), 2);
+
+        // Releasing the group waits for another thread to be done opening or releasing it.
+        let transition = container.begin_group_transition(3);
+        let dropper = thread::spawn(move || {
+            drop(device);
+            vfio_syscall::take_unset_container_log()
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(container.group_ids(), vec![3, 4]);
+        drop(transition);
+        assert_eq!(dropper.join().unwrap(), vec![(3, 0)]);
+        assert_eq!(container.group_ids(), vec![4]);
+        assert_eq!(*container.bound_groups.lock().unwrap(), HashSet::from([4]));
+    }
+
+    #[test]
+    fn test_vfio_container_attach_group() {
+        let container = create_vfio_container();
+        let paths = VfioPaths::default();
+
+        // Fresh group, bound by the container itself, which then lists its devices with the
+        // paths of the container.
+        let other_paths = VfioPaths {
+            sysfs_root: PathBuf::from("/tmp/vfio_sysfs"),
+            ..VfioPaths::default()
+        };
+        let group = VfioGroup::from_fd_with_paths(
+            8,
+            VfioGroup::open_group_file(8, &paths).unwrap().into(),
+            &other_paths,
+        )
+        .unwrap();
+        assert_eq!(group.paths, other_paths);
+        container.attach_group(group).unwrap();
+        assert_eq!(container.groups.lock().unwrap()[&8].paths, container.paths);
+        let group =
+            VfioGroup::from_fd(8, VfioGroup::open_group_file(8, &paths).unwrap().into()).unwrap();
+        container.attach_group(group).unwrap();
+        assert_eq!(container.groups.lock().unwrap().len(), 1);
+
+        // Group not bound by anyone but still busy.
+        let group =
+            VfioGroup::from_fd(9, VfioGroup::open_group_file(9, &paths).unwrap().into()).unwrap();
+        vfio_syscall::inject_set_container_busy(1);
+        match container.attach_group(group) {
+            Err(VfioError::GroupBusy { group_id }) => assert_eq!(group_id, 9),
+            _ => panic!("expect VfioError::GroupBusy"),
+        }
+
+        // Group already bound by the process which opened it.
+        vfio_syscall::inject_group_container_set(true);
+        let group =
+            VfioGroup::from_fd(9, VfioGroup::open_group_file(9, &paths).unwrap().into()).unwrap();
+        vfio_syscall::inject_set_container_busy(1);
+        container.attach_group(group).unwrap();
+        vfio_syscall::inject_group_container_set(false);
+        assert_eq!(container.groups.lock().unwrap().len(), 2);
+
+        // The device takes its reference on the attached group, and releases it on drop. The
+        // group bound by its opener is left bound, unlike the one bound by the container.
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(container);
+        vfio_syscall::take_unset_container_log();
+        let device = VfioDevice::new_in_group(tmp_file.as_path(), 9, container.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&device.binding.group), 2);
+        drop(device);
+        assert!(!container.groups.lock().unwrap().contains_key(&9));
+        assert!(!container.bound_groups.lock().unwrap().contains(&9));
+        assert!(vfio_syscall::take_unset_container_log().is_empty());
+        let device = VfioDevice::new_in_group(tmp_file.as_path(), 8, container.clone()).unwrap();
+        drop(device);
+        assert_eq!(vfio_syscall::take_unset_container_log().len(), 1);
+
+        // Detaching unbinds a borrowed group, which is owned by the container once reattached.
+        vfio_syscall::inject_group_container_set(true);
+        let group =
+            VfioGroup::from_fd(9, VfioGroup::open_group_file(9, &paths).unwrap().into()).unwrap();
+        vfio_syscall::inject_set_container_busy(1);
+        container.attach_group(group).unwrap();
+        vfio_syscall::inject_group_container_set(false);
+        let device = VfioDevice::new_in_group(tmp_file.as_path(), 9, container.clone()).unwrap();
+        container.detach_group(9).unwrap();
+        assert_eq!(vfio_syscall::take_unset_container_log().len(), 1);
+        container.reattach_group(9).unwrap();
+        drop(device);
+        assert_eq!(vfio_syscall::take_unset_container_log().len(), 1);
+    }
+
+    #[test]
+    fn test_vfio_device_drop() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        let other = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        let fds: Vec<(RawFd, u64)> = [
+            device.as_raw_fd(),
+            other.as_raw_fd(),
+            device.binding.group.as_raw_fd(),
+            container.as_raw_fd(),
+        ]
+        .iter()
+        .map(|fd| (*fd, vfio_syscall::fd_inode(*fd).unwrap()))
+        .collect();
+        let closed = |(fd, ino): (RawFd, u64)| vfio_syscall::fd_inode(fd) != Some(ino);
+        vfio_syscall::take_unset_container_log();
+
+        // The group is kept for the remaining device.
+        drop(device);
+        assert!(closed(fds[0]));
+        assert!(vfio_syscall::take_unset_container_log().is_empty());
+        assert_eq!(container.group_count(), 1);
+
+        // The device fd is closed before the group is unbound from the container.
+        drop(other);
+        assert!(closed(fds[1]));
+        assert_eq!(vfio_syscall::take_unset_container_log(), vec![(3, 0)]);
+        assert_eq!(container.group_count(), 0);
+        assert!(closed(fds[2]));
+
+        drop(container);
+        assert!(closed(fds[3]));
+    }
+
+    #[test]
+    fn test_vfio_device_drop_reentrant() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        vfio_syscall::take_unset_container_log();
+
+        // A device dropped while the same thread holds the groups lock, as from a callback of a
+        // container operation, leaves its group bound instead of deadlocking.
+        let hash = container.lock_groups();
+        drop(device);
+        drop(hash);
+        assert!(vfio_syscall::take_unset_container_log().is_empty());
+        assert_eq!(container.group_ids(), vec![3]);
+
+        // A device dropped while another thread holds the lock waits for it.
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        let hash = container.lock_groups();
+        let dropper = thread::spawn(move || {
+            drop(device);
+            vfio_syscall::take_unset_container_log()
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert!(hash.contains_key(&3));
+        drop(hash);
+        assert_eq!(dropper.join().unwrap(), vec![(3, 0)]);
+        assert!(container.group_ids().is_empty());
+        assert!(container.bound_groups.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_container_poisoned_groups_lock() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        vfio_syscall::take_unset_container_log();
+
+        let poisoner = container.clone();
+        thread::spawn(move || {
+            let _hash = poisoner.lock_groups();
+            panic!("container operation panicked");
+        })
+        .join()
+        .unwrap_err();
+        assert!(container.groups.is_poisoned());
+        assert_eq!(*container.groups_owner.lock().unwrap(), None);
+
+        // The group is still released with its last device, and the container usable.
+        drop(device);
+        assert_eq!(vfio_syscall::take_unset_container_log(), vec![(3, 0)]);
+        assert!(!container.groups.is_poisoned());
+        assert!(container.group_ids().is_empty());
+        let _device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(container.group_ids(), vec![3]);
+    }
+
+    #[test]
+    fn test_vfio_poisoned_dma_and_irq_locks() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = Arc::new(VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap());
+
+        let poisoner = device.clone();
+        thread::spawn(move || {
+            let _mappings = poisoner.binding.container.mappings.lock().unwrap();
+            let _tags = poisoner.binding.container.mapping_tags.lock().unwrap();
+            let _budget = poisoner.binding.container.mapping_budget.lock().unwrap();
+            let _irq_fds = poisoner.irq_fds.lock().unwrap();
+            let _config = poisoner.pci_config.lock().unwrap();
+            panic!("device operation panicked");
+        })
+        .join()
+        .unwrap_err();
+        assert!(container.mappings.is_poisoned());
+        assert!(device.irq_fds.is_poisoned());
+
+        // DMA, interrupt and reset operations keep working.
+        container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
+        assert_eq!(container.mapped_bytes(), 0x1000);
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+        assert!(container.mappings().is_empty());
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        device.enable_msix(vec![&evt]).unwrap();
+        device.disable_msix().unwrap();
+        device.reset().unwrap();
+        assert!(!container.mappings.is_poisoned());
+        assert!(!device.irq_fds.is_poisoned());
+    }
+
+    #[test]
+    fn test_vfio_container_detach_group() {
+        let container = Arc::new(create_vfio_container());
+        let paths = VfioPaths::default();
+        let group =
+            VfioGroup::from_fd(8, VfioGroup::open_group_file(8, &paths).unwrap().into()).unwrap();
+        container.attach_group(group).unwrap();
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new_in_group(tmp_file.as_path(), 9, container.clone()).unwrap();
+        container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
+
+        assert!(matches!(
+            container.detach_group(7),
+            Err(VfioError::GroupNotAttached(7))
+        ));
+        assert!(matches!(
+            container.reattach_group(9),
+            Err(VfioError::GroupNotDetached(9))
+        ));
+
+        container.detach_group(9).unwrap();
+        assert_eq!(container.group_ids(), vec![8]);
+        assert_eq!(container.mapped_bytes(), 0x1000);
+        assert!(matches!(
+            container.detach_group(9),
+            Err(VfioError::GroupNotAttached(9))
+        ));
+        container.reattach_group(9).unwrap();
+        assert_eq!(container.group_ids(), vec![8, 9]);
+        assert!(Arc::ptr_eq(
+            &container.groups.lock().unwrap()[&9],
+            &device.binding.group
+        ));
+
+        // Detaching the last group drops the mappings along with the IOMMU context.
+        container.detach_group(8).unwrap();
+        container.detach_group(9).unwrap();
+        assert!(container.group_ids().is_empty());
+        assert_eq!(container.group_count(), 0);
+        assert!(container.mappings().is_empty());
+
+        // The last device of a detached group makes the container forget it.
+        drop(device);
+        assert!(matches!(
+            container.reattach_group(9),
+            Err(VfioError::GroupNotDetached(9))
+        ));
+        container.reattach_group(8).unwrap();
+        assert_eq!(container.group_ids(), vec![8]);
+    }
+
+    #[test]
+    fn test_vfio_container_read_only() {
+        let container = Arc::new(create_vfio_container());
+        let paths = VfioPaths::default();
+        for id in [9, 8] {
+            let group =
+                VfioGroup::from_fd(id, VfioGroup::open_group_file(id, &paths).unwrap().into())
+                    .unwrap();
+            container.attach_group(group).unwrap();
+        }
+        container
+            .dma_map_tagged(Iova(0x1000), 0x1000, HostVa(0x8000), "view")
+            .unwrap();
+
+        let view = container.read_only();
+        let clone = view.clone();
+        assert_eq!(Arc::strong_count(&container), 3);
+        assert_eq!(view.group_ids(), vec![8, 9]);
+        assert_eq!(view.group_count(), 2);
+        assert!(view.supports_extension(VFIO_TYPE1v2_IOMMU));
+        assert!(!view.supports_extension(VFIO_UPDATE_VADDR));
+        assert!(clone.supports_dma_flag(VFIO_DMA_MAP_FLAG_READ));
+        assert!(clone.supports_dma_unmap_flag(VFIO_DMA_UNMAP_FLAG_ALL));
+        assert_eq!(view.iommu_page_size().unwrap(), Some(0x1000));
+        assert_eq!(
+            view.valid_iova_ranges().unwrap(),
+            container.valid_iova_ranges().unwrap()
+        );
+        assert_eq!(view.iova_base(), 0);
+        assert_eq!(view.mapped_bytes(), 0x1000);
+        assert_eq!(view.mappings(), container.mappings());
+        assert_eq!(view.mappings_by_tag("view"), container.mappings());
+        assert_eq!(view.multi_group_policy(), container.multi_group_policy());
+        assert!(view.fallback_group_ids().is_empty());
+        assert_eq!(view.dma_avail().unwrap(), None);
+        let notify = EventFd::new(EFD_NONBLOCK).unwrap();
+        assert!(matches!(
+            view.set_dma_avail_watermark(1, notify),
+            Err(VfioError::DmaAvailUnsupported)
+        ));
+        view.clear_dma_avail_watermark();
+        assert_eq!(view.paths(), container.paths());
+        assert_eq!(
+            format!("{:?}", view),
+            "VfioContainerRef { groups: [8, 9], mapped_bytes: 4096 }"
+        );
+
+        // The view follows the changes made through the container.
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+        assert_eq!(clone.mapped_bytes(), 0);
+        drop(clone);
+        drop(view);
+        assert_eq!(Arc::strong_count(&container), 1);
+    }
+
+    #[test]
+    fn test_vfio_container_share() {
+        let container = Arc::new(create_vfio_container());
+        let shared = container.share();
+        assert!(Arc::ptr_eq(&container, &shared));
+        container.get_group(3, None).unwrap();
+
+        let threads: Vec<_> = vec![(container.clone(), 0x10_0000), (shared.clone(), 0x20_0000)]
+            .into_iter()
+            .map(|(handle, iova)| {
+                std::thread::spawn(move || {
+                    vfio_syscall::inject_dma_iovas(&[iova], &[iova]);
+                    handle.vfio_dma_map(iova, 0x1000, 0x8000)
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap().unwrap();
+        }
+
+        let iovas = |handle: &VfioContainer| {
+            handle
+                .mappings()
+                .iter()
+                .map(|m| m.iova.raw_value())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(iovas(&container), vec![0x10_0000, 0x20_0000]);
+        assert_eq!(iovas(&shared), iovas(&container));
+        assert_eq!(shared.group_ids(), vec![3]);
+
+        drop(shared);
+        assert_eq!(Arc::strong_count(&container), 1);
+    }
+
+    #[test]
+    fn test_vfio_container_mapping_tags() {
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(
+            Path::new("/sys/bus/pci/devices/0000:03:00.0"),
+            container.clone(),
+        )
+        .unwrap();
+        vfio_syscall::inject_dma_iovas(&[0x10000, 0x20000, 0x30000], &[0x10000, 0x30000, 0x32000]);
+
+        container
+            .dma_map(Iova(0x1000), 0x1000, HostVa(0x8000))
+            .unwrap();
+        device
+            .dma_map(Iova(0x10000), 0x1000, HostVa(0x8000))
+            .unwrap();
+        container
+            .dma_map_tagged(Iova(0x20000), 0x1000, HostVa(0x8000), "virtio")
+            .unwrap();
+        container
+            .dma_map_tagged(Iova(0x30000), 0x3000, HostVa(0x8000), "virtio")
+            .unwrap();
+        // A mapping which fails isn't tagged.
+        container
+            .dma_map_tagged(Iova(0x40000), 0x1000, HostVa(0x8000), "other")
+            .unwrap_err();
+
+        let iovas = |mappings: Vec<VfioDmaMapping>| {
+            mappings
+                .iter()
+                .map(|m| m.iova.raw_value())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(iovas(container.mappings_by_tag(&device.name())), [0x10000]);
+        assert_eq!(
+            iovas(container.mappings_by_tag("virtio")),
+            [0x20000, 0x30000]
+        );
+        assert!(container.mappings_by_tag("other").is_empty());
+
+        // The parts left by a hole keep the tag of the mapping.
+        vfio_syscall::inject_dma_iovas(&[0x30000, 0x32000], &[0x30000, 0x32000]);
+        container.vfio_dma_punch_hole(0x31000, 0x1000).unwrap();
+        assert_eq!(
+            iovas(container.mappings_by_tag("virtio")),
+            [0x20000, 0x30000, 0x32000]
+        );
+
+        // Every mapping is attempted, the ones which fail keep their tag.
+        let report = container.unmap_by_tag("virtio");
+        assert_eq!(report.mappings.len(), 3);
+        assert_eq!(report.failed(), 1);
+        assert!(matches!(
+            report.mappings[0].outcome,
+            UnmapOutcome::Failed(_)
+        ));
+        assert_eq!(report.mappings[1].outcome, UnmapOutcome::Unmapped);
+        assert_eq!(iovas(container.mappings_by_tag("virtio")), [0x20000]);
+        assert_eq!(container.mappings().len(), 3);
+
+        // Untagged unmaps drop the tag of the mapping.
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+        vfio_syscall::inject_dma_iovas(&[], &[0x10000]);
+        container.dma_unmap(Iova(0x10000), 0x1000).unwrap();
+        assert!(container.mappings_by_tag(&device.name()).is_empty());
+        assert_eq!(container.mapping_tags.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_vfio_container_multi_group_policy() {
+        let container = create_vfio_container();
+        let group3 = container.get_group(3, None).unwrap();
+        container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
+
+        // The kernel refuses groups once the IOMMU is set.
+        vfio_syscall::inject_set_container_invalid(&container);
+        assert!(matches!(
+            container.get_group(4, None),
+            Err(VfioError::GroupSetContainerInvalid { group_id: 4 })
+        ));
+        assert_eq!(container.group_ids(), vec![3]);
+
+        // The group gets a container of its own, with the existing mappings.
+        container.set_multi_group_policy(MultiGroupPolicy::SharedOrFallbackToPerGroup);
+        vfio_syscall::take_map_dma_log();
+        let group4 = container.get_group(4, None).unwrap();
+        assert_eq!(container.group_ids(), vec![3, 4]);
+        assert_eq!(container.fallback_group_ids(), vec![4]);
+        let own_fd = container.group_containers.lock().unwrap()[&4].as_raw_fd();
+        assert_eq!(vfio_syscall::take_map_dma_log(), vec![(own_fd, 0x1000)]);
+        assert!(container.export_state(&[]).is_err());
+
+        // Mappings are replicated to the container of the group.
+        vfio_syscall::inject_dma_iovas(&[0x4000], &[0x4000]);
+        container.vfio_dma_map(0x4000, 0x1000, 0x8000).unwrap();
+        assert_eq!(
+            vfio_syscall::take_map_dma_log(),
+            vec![(container.as_raw_fd(), 0x4000), (own_fd, 0x4000)]
+        );
+        vfio_syscall::take_unmap_dma_log();
+        container.vfio_dma_unmap(0x4000, 0x1000).unwrap();
+        assert_eq!(
+            vfio_syscall::take_unmap_dma_log(),
+            vec![(0x4000, 0x1000), (0x4000, 0x1000)]
+        );
+
+        // The container is released with the group.
+        container.put_group(group4.clone());
+        assert_eq!(container.group_ids(), vec![3]);
+        assert!(container.fallback_group_ids().is_empty());
+        drop((group3, group4));
     }
 
     #[test]
@@ -1278,35 +6978,160 @@ mod tests {
         container.check_api_version().unwrap();
         container.check_extension(VFIO_TYPE1v2_IOMMU).unwrap();
 
-        let group = VfioGroup::new(1).unwrap();
+        let group = VfioGroup::new(1, &VfioPaths::default()).unwrap();
         container.device_add_group(&group).unwrap();
         container.device_del_group(&group).unwrap();
 
-        let group = container.get_group(3).unwrap();
+        let group = container.get_group(3, None).unwrap();
         assert_eq!(Arc::strong_count(&group), 2);
         assert_eq!(container.groups.lock().unwrap().len(), 1);
-        let group2 = container.get_group(4).unwrap();
+        let group2 = container.get_group(4, None).unwrap();
         assert_eq!(Arc::strong_count(&group2), 2);
         assert_eq!(container.groups.lock().unwrap().len(), 2);
 
-        let group3 = container.get_group(3).unwrap();
+        let group3 = container.get_group(3, None).unwrap();
         assert_eq!(Arc::strong_count(&group), 3);
-        let group4 = container.get_group(3).unwrap();
+        let group4 = container.get_group(3, None).unwrap();
         assert_eq!(Arc::strong_count(&group), 4);
         container.put_group(group4);
         assert_eq!(Arc::strong_count(&group), 3);
         container.put_group(group3);
         assert_eq!(Arc::strong_count(&group), 1);
 
+        drop(group);
+        drop(group2);
+        container.groups.lock().unwrap().clear();
+
+        // A single EBUSY is recovered by rebinding the group.
+        vfio_syscall::inject_set_container_busy(1);
+        container.get_group(5, None).unwrap();
+        assert_eq!(container.groups.lock().unwrap().len(), 1);
+
+        // A group still busy after the retry is reported, and can be retried later.
+        vfio_syscall::inject_set_container_busy(2);
+        match container.get_group(6, None) {
+            Err(VfioError::GroupBusy { group_id }) => assert_eq!(group_id, 6),
+            _ => panic!("expect VfioError::GroupBusy"),
+        }
+        assert_eq!(container.groups.lock().unwrap().len(), 1);
+        container.get_group(6, None).unwrap();
+        assert_eq!(container.groups.lock().unwrap().len(), 2);
+
         container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
         container.vfio_dma_map(0x2000, 0x2000, 0x8000).unwrap_err();
+        assert_eq!(
+            container.mappings(),
+            vec![VfioDmaMapping {
+                iova: Iova(0x1000),
+                size: 0x1000,
+                user_addr: HostVa(0x8000),
+                flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+            }]
+        );
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+        assert!(container.mappings().is_empty());
+
+        container
+            .dma_map(Iova(0x1000), 0x1000, HostVa(0x8000))
+            .unwrap();
+        assert_eq!(container.mappings()[0].iova, Iova(0x1000));
+        assert_eq!(container.mappings()[0].user_addr, HostVa(0x8000));
+        container.dma_unmap(Iova(0x1000), 0x1000).unwrap();
+        assert!(container.mappings().is_empty());
+
+        assert_eq!(
+            container.valid_iova_ranges().unwrap(),
+            vec![
+                VfioIovaRange {
+                    start: 0,
+                    end: 0xfedf_ffff,
+                },
+                VfioIovaRange {
+                    start: 0xfef0_0000,
+                    end: 0xffff_ffff_ffff,
+                },
+            ]
+        );
+        assert!(matches!(
+            container.vfio_dma_map(0xfed0_0000, 0x20_0000, 0x8000),
+            Err(VfioError::IommuDmaMapCrossWindow {
+                iova: 0xfed0_0000,
+                size: 0x20_0000,
+            })
+        ));
+        assert!(matches!(
+            container.vfio_dma_map(0x1_0000_0000_0000, 0x1000, 0x8000),
+            Err(VfioError::IommuDmaMapCrossWindow { .. })
+        ));
+        assert_eq!(container.iommu_page_size().unwrap(), Some(0x1000));
+        assert!(container.supports_dma_flag(VFIO_DMA_MAP_FLAG_READ));
+        assert!(container.supports_dma_flag(VFIO_DMA_MAP_FLAG_WRITE));
+        assert!(!container.supports_dma_flag(VFIO_DMA_MAP_FLAG_VADDR));
+        assert!(!container.supports_dma_flag(1 << 31));
+        assert!(container.supports_dma_unmap_flag(VFIO_DMA_UNMAP_FLAG_ALL));
+        assert!(!container.supports_dma_unmap_flag(VFIO_DMA_UNMAP_FLAG_VADDR));
+        assert!(!container.supports_dma_unmap_flag(VFIO_DMA_UNMAP_FLAG_GET_DIRTY_BITMAP));
+        vfio_syscall::take_iommu_info_queries(&container);
+        container.refresh_iommu_info().unwrap();
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 2);
+        assert_eq!(container.iommu_page_size().unwrap(), Some(0x1000));
+        assert_eq!(container.valid_iova_ranges().unwrap().len(), 2);
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 0);
+        assert!(matches!(
+            container.vfio_dma_map(0x1000, 0x1000, 0x8010),
+            Err(VfioError::IommuDmaMapUnalignedVaddr {
+                vaddr: 0x8010,
+                page_size: 0x1000,
+            })
+        ));
+
+        #[repr(C, align(4096))]
+        struct Pages([u8; 0x2000]);
+        let pages = Box::new(Pages([0; 0x2000]));
+        let buf = &pages.0;
+        assert!(matches!(
+            container.vfio_dma_map_buffer(0x1000, &buf[8..0x1008]),
+            Err(VfioError::IommuDmaMapUnalignedVaddr { .. })
+        ));
+        assert!(matches!(
+            container.vfio_dma_map_buffer(0x1000, &buf[..0x800]),
+            Err(VfioError::IommuDmaMapUnalignedSize {
+                size: 0x800,
+                page_size: 0x1000,
+            })
+        ));
+        assert!(matches!(
+            container.vfio_dma_map_buffer(0x1000, &buf[..0]),
+            Err(VfioError::IommuDmaMapUnalignedSize { size: 0, .. })
+        ));
+        container
+            .vfio_dma_map_buffer(0x1000, &buf[0x1000..])
+            .unwrap();
+        assert_eq!(
+            container.mappings()[0].user_addr,
+            HostVa::from_ptr(buf[0x1000..].as_ptr())
+        );
+        assert_eq!(container.mappings()[0].size, 0x1000);
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+
+        container
+            .vfio_dma_map_raw(0x1000, 0x1000, 0x8000, 0, false)
+            .unwrap_err();
+        container
+            .vfio_dma_map_raw(0x1000, 0x1000, 0x8000, VFIO_DMA_MAP_FLAG_READ, false)
+            .unwrap();
+        assert_eq!(container.mappings()[0].flags, VFIO_DMA_MAP_FLAG_READ);
+        container
+            .vfio_dma_map_raw(0x1000, 0x1000, 0x8000, 0, true)
+            .unwrap();
+        assert_eq!(container.mappings()[0].flags, 0);
         container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
         container.vfio_dma_unmap(0x2000, 0x2000).unwrap_err();
     }
 
     #[test]
     fn test_vfio_group() {
-        let group = VfioGroup::new(1).unwrap();
+        let group = VfioGroup::new(1, &VfioPaths::default()).unwrap();
         let tmp_file = TempFile::new().unwrap();
 
         assert_eq!(group.id, 1);
@@ -1315,10 +7140,45 @@ mod tests {
         assert_eq!(device.num_irqs, 3);
         assert_eq!(device.num_regions, 8);
 
-        let regions = device.get_regions().unwrap();
+        let regions = device.get_regions(VFIO_REGION_INFO_MAX_SIZE).unwrap();
         assert_eq!(regions.len(), 7)
     }
 
+    #[test]
+    fn test_vfio_device_config() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        device.read_config(0, &mut [0u8; 2]).unwrap_err();
+        device.command_register().unwrap_err();
+
+        device.push_region(VfioRegion {
+            index: VFIO_PCI_CONFIG_REGION_INDEX,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: 0x100,
+            offset: 0x1000,
+            caps: RegionCaps::default(),
+        });
+        device.write_config(0, &[0u8; 0x100]).unwrap();
+        device.write_config(0xff, &[0u8; 2]).unwrap_err();
+        device.read_config(0x100, &mut [0u8; 1]).unwrap_err();
+
+        // Memory space enabled, reserved bit 11 set, capability list in the status.
+        device.write_config(0x4, &[0x02, 0x08, 0x10, 0x00]).unwrap();
+        let mut cmd = device.command_register().unwrap();
+        assert!(cmd.memory_space);
+        assert!(!cmd.bus_master);
+        assert!(device.status_register().unwrap().capabilities_list);
+
+        cmd.bus_master = true;
+        cmd.interrupt_disable = true;
+        device.set_command_register(cmd).unwrap();
+        let mut buf = [0u8; 2];
+        device.read_config(0x4, &mut buf).unwrap();
+        assert_eq!(buf, [0x06, 0x0c]);
+    }
+
     #[test]
     fn test_vfio_device() {
         let tmp_file = TempFile::new().unwrap();
@@ -1328,12 +7188,12 @@ mod tests {
         assert!(device.as_raw_fd() > 0);
         assert_eq!(device.max_interrupts(), 2048);
 
-        device.reset();
-        assert_eq!(device.regions.len(), 7);
-        assert_eq!(device.irqs.len(), 3);
+        device.reset().unwrap();
+        assert_eq!(device.regions().len(), 7);
+        assert_eq!(device.irqs().len(), 3);
 
-        assert!(device.get_irq_info(3).is_none());
-        let irq = device.get_irq_info(2).unwrap();
+        assert!(device.irq_info(3).is_none());
+        let irq = device.irq_info(2).unwrap();
         assert_eq!(irq.count, 2048);
 
         device.trigger_irq(3, 0).unwrap_err();
@@ -1341,17 +7201,85 @@ mod tests {
         device.trigger_irq(2, 2047).unwrap();
         device.trigger_irq(2, 0).unwrap();
 
+        vfio_syscall::take_set_irqs_log();
+        device.trigger_irq_range(2, 0, 2048).unwrap();
+        device.trigger_irq_range(2, 2040, 8).unwrap();
+        device.trigger_irq_range(2, 2040, 9).unwrap_err();
+        device.trigger_irq_range(2, 0, 0).unwrap_err();
+        device.trigger_irq_range(2, u32::MAX, 2).unwrap_err();
+        device.trigger_irq_range(3, 0, 1).unwrap_err();
+        let flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER;
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(flags, 2, 0, vec![]), (flags, 2, 2040, vec![])]
+        );
+
         device.enable_irq(3, Vec::new()).unwrap_err();
         device.enable_irq(0, Vec::new()).unwrap_err();
         device.enable_irq(1, Vec::new()).unwrap();
 
-        device.disable_irq(3).unwrap_err();
+        device.disable_irq(3).unwrap_err();
+        device.disable_irq(1).unwrap();
+
+        // Disabling is a no-op when nothing is enabled.
+        vfio_syscall::take_set_irqs_log();
+        device.disable_irq(0).unwrap();
+        device.disable_irq(1).unwrap();
+        device.disable_msi().unwrap();
+        device.disable_msix().unwrap();
+        assert!(vfio_syscall::take_set_irqs_log().is_empty());
+
+        // Failing to disable keeps the interrupts enabled.
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        device.enable_irq(0, vec![&evt]).unwrap();
         device.disable_irq(0).unwrap_err();
-        device.disable_irq(1).unwrap();
+        device.disable_irq(0).unwrap_err();
+        assert!(device.irq_fds.lock().unwrap().contains_key(&0));
+        device.irq_fds.lock().unwrap().remove(&0);
 
         device.unmask_irq(3).unwrap_err();
         device.unmask_irq(1).unwrap_err();
         device.unmask_irq(0).unwrap();
+        assert!(matches!(
+            device.mask_irq(1),
+            Err(VfioError::VfioDeviceMaskIrq)
+        ));
+        vfio_syscall::take_set_irqs_log();
+        device.mask_irq(0).unwrap();
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(
+                VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_MASK,
+                0,
+                0,
+                vec![]
+            )]
+        );
+
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        device.enable_irq(1, vec![&evt]).unwrap();
+        assert_eq!(device.drain_irq(1, 0).unwrap(), None);
+        evt.write(1).unwrap();
+        evt.write(2).unwrap();
+        assert_eq!(device.drain_irq(1, 0).unwrap(), Some(3));
+        assert_eq!(device.drain_irq(1, 0).unwrap(), None);
+        device.drain_irq(1, 1).unwrap_err();
+        device.drain_irq(2, 0).unwrap_err();
+        device.disable_irq(1).unwrap();
+        device.drain_irq(1, 0).unwrap_err();
+
+        let evts = [
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        ];
+        device.enable_msix(evts.iter().collect()).unwrap();
+        device.reset_preserving_irqs().unwrap();
+        evts[1].write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 1).unwrap(), Some(1));
+        vfio_syscall::take_set_irqs_log();
+        device.disable_msix().unwrap();
+        device.disable_msix().unwrap();
+        assert_eq!(vfio_syscall::take_set_irqs_log().len(), 1);
 
         device.enable_msi(Vec::new()).unwrap();
         device.disable_msi().unwrap();
@@ -1366,6 +7294,9 @@ mod tests {
         assert_eq!(device.get_region_size(7), 0);
         assert_eq!(device.get_region_caps(1).len(), 3);
         assert_eq!(device.get_region_caps(7).len(), 0);
+        assert!(device.msix_table_mappable(1));
+        assert!(!device.msix_table_mappable(0));
+        assert!(!device.msix_table_mappable(7));
 
         let mut buf = [0u8; 16];
         device.region_read(7, &mut buf, 0x30000);
@@ -1373,12 +7304,876 @@ mod tests {
         device.region_write(7, &buf, 0x30000);
         device.region_write(1, &buf, 0x30000);
 
-        device.reset();
+        device.reset().unwrap();
+
+        drop(device);
+        assert_eq!(container.groups.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_vfio_device_new_in_group() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new_in_group(tmp_file.as_path(), 5, container.clone()).unwrap();
+
+        assert_eq!(device.binding.group.id(), 5);
+        assert!(container.groups.lock().unwrap().contains_key(&5));
+        assert_eq!(device.regions().len(), 7);
 
         drop(device);
         assert_eq!(container.groups.lock().unwrap().len(), 0);
     }
 
+    #[test]
+    fn test_vfio_device_assign_vector() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let trigger = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
+        let evts: Vec<EventFd> = (0..5)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect();
+        let raw = |i: usize| evts[i].as_raw_fd();
+
+        device.enable_msix(vec![&evts[0], &evts[1]]).unwrap();
+        vfio_syscall::take_set_irqs_log();
+
+        // The vector is retargeted by a single call, without detaching the old EventFd first.
+        device.assign_vector(2, 1, &evts[2]).unwrap();
+        device.assign_vector(2, 2, &evts[3]).unwrap();
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(trigger, 2, 1, vec![raw(2)]), (trigger, 2, 2, vec![raw(3)])]
+        );
+        evts[2].write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 1).unwrap(), Some(1));
+
+        // Out of range, or leaving a hole in the enabled vectors.
+        device.assign_vector(2, 2048, &evts[4]).unwrap_err();
+        device.assign_vector(2, 4, &evts[4]).unwrap_err();
+        device.assign_vector(3, 0, &evts[4]).unwrap_err();
+        assert!(vfio_syscall::take_set_irqs_log().is_empty());
+
+        // The whole table goes along with the new EventFd when the irq can't be resized.
+        // The other vectors keep the duplicates retained by the device.
+        device.irqs_mut().get_mut(&2).unwrap().flags |= VFIO_IRQ_INFO_NORESIZE;
+        let retained = |device: &VfioDevice| -> Vec<RawFd> {
+            device.irq_fds.lock().unwrap()[&2]
+                .iter()
+                .map(|fd| fd.as_raw_fd())
+                .collect()
+        };
+        let mut table = retained(&device);
+        device.assign_vector(2, 0, &evts[4]).unwrap();
+        table[0] = raw(4);
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(trigger, 2, 0, table)]
+        );
+        evts[4].write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 0).unwrap(), Some(1));
+
+        // Its number of vectors can't change while enabled.
+        let table = retained(&device);
+        device.assign_vector(2, 3, &evts[0]).unwrap_err();
+        assert!(vfio_syscall::take_set_irqs_log().is_empty());
+        assert_eq!(retained(&device), table);
+
+        // Vectors of an irq which isn't enabled yet can be assigned one after the other.
+        device.assign_vector(1, 0, &evts[0]).unwrap();
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(trigger, 1, 0, vec![raw(0)])]
+        );
+    }
+
+    #[test]
+    fn test_vfio_device_enable_irq_owned() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let trigger = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
+        let new_evts = |n: usize| -> Vec<EventFd> {
+            (0..n)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect()
+        };
+
+        let evts = new_evts(2);
+        let raw: Vec<RawFd> = evts.iter().map(|fd| fd.as_raw_fd()).collect();
+        vfio_syscall::take_set_irqs_log();
+        device.enable_irq_owned(2, evts).unwrap();
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(trigger, 2, 0, raw.clone())]
+        );
+        let first = device.irq_eventfd(2, 1).unwrap().unwrap();
+        first.write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 1).unwrap(), Some(1));
+        assert!(device.irq_eventfd(2, 2).unwrap().is_none());
+        assert!(device.irq_eventfd(1, 0).unwrap().is_none());
+
+        // Enabling again replaces the owned EventFds, and a failure keeps them.
+        let evts = new_evts(3);
+        let raw: Vec<RawFd> = evts.iter().map(|fd| fd.as_raw_fd()).collect();
+        device.enable_irq_owned(2, evts).unwrap();
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(trigger, 2, 0, raw.clone())]
+        );
+        first.write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 1).unwrap(), None);
+        device.irq_eventfd(2, 2).unwrap().unwrap().write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 2).unwrap(), Some(1));
+        device.enable_irq_owned(2, new_evts(2049)).unwrap_err();
+        assert_eq!(device.irq_fds.lock().unwrap()[&2].len(), 3);
+        assert_eq!(device.irq_fds.lock().unwrap()[&2][0].as_raw_fd(), raw[0]);
+
+        // Borrowed EventFds replace owned ones too.
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        device.enable_msix(vec![&evt]).unwrap();
+        evt.write(1).unwrap();
+        assert_eq!(device.drain_irq(2, 0).unwrap(), Some(1));
+        assert!(device.irq_eventfd(2, 1).unwrap().is_none());
+
+        device.disable_msix().unwrap();
+        assert!(device.irq_eventfd(2, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_vfio_device_refresh_irq_info() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let evts: Vec<EventFd> = (0..8)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect();
+
+        // The device grew its MSI-X table after its vectors were queried.
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSIX_IRQ_INDEX, 4);
+        assert_eq!(
+            device
+                .refresh_irq_info(VFIO_PCI_MSIX_IRQ_INDEX)
+                .unwrap()
+                .count,
+            4
+        );
+        assert_eq!(device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX).unwrap().count, 4);
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSIX_IRQ_INDEX, 8);
+        device.enable_msix(evts.iter().collect()).unwrap();
+        assert_eq!(device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX).unwrap().count, 8);
+
+        // The device shrank its MSI-X table, enabling is refused after a single query.
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSIX_IRQ_INDEX, 2);
+        assert_eq!(
+            device
+                .refresh_irq_info(VFIO_PCI_MSIX_IRQ_INDEX)
+                .unwrap()
+                .count,
+            2
+        );
+        assert!(matches!(
+            device.enable_msix(evts.iter().take(4).collect()),
+            Err(VfioError::VfioDeviceEnableIrq)
+        ));
+        assert_eq!(device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX).unwrap().count, 2);
+        device.enable_msix(evts.iter().take(2).collect()).unwrap();
+
+        // Copies taken earlier are left as they were, indexes without interrupts are kept.
+        let msi = device.irq_info(VFIO_PCI_MSI_IRQ_INDEX).unwrap();
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSI_IRQ_INDEX, 0);
+        let irq = device.refresh_irq_info(VFIO_PCI_MSI_IRQ_INDEX).unwrap();
+        assert_eq!((irq.index, irq.count), (VFIO_PCI_MSI_IRQ_INDEX, 0));
+        assert_eq!(msi.count, 32);
+        assert_eq!(device.irq_info(VFIO_PCI_MSI_IRQ_INDEX).unwrap().count, 0);
+        assert_eq!(device.irqs().len(), 3);
+        #[allow(deprecated)]
+        let legacy = device.get_irq_info(VFIO_PCI_MSI_IRQ_INDEX);
+        assert_eq!(legacy, device.irq_info(VFIO_PCI_MSI_IRQ_INDEX));
+        assert!(matches!(
+            device.enable_msi(evts.iter().take(1).collect()),
+            Err(VfioError::VfioDeviceEnableIrq)
+        ));
+
+        // An index without interrupts is queried again once vectors are requested from it.
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSI_IRQ_INDEX, 1);
+        device.enable_msi(evts.iter().take(1).collect()).unwrap();
+        assert_eq!(device.irq_info(VFIO_PCI_MSI_IRQ_INDEX).unwrap().count, 1);
+        assert!(matches!(
+            device.refresh_irq_info(VFIO_PCI_NUM_IRQS + 4),
+            Err(VfioError::VfioDeviceGetIrqInfo)
+        ));
+    }
+
+    #[test]
+    fn test_vfio_device_enable_device_specific_irq() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let trigger = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
+        // A reset completion interrupt past the PCI indexes, as a platform device may report.
+        let reset_index = VFIO_PCI_NUM_IRQS + 2;
+        device.irqs_mut().insert(
+            reset_index,
+            VfioIrq {
+                flags: VFIO_IRQ_INFO_EVENTFD,
+                index: reset_index,
+                count: 1,
+            },
+        );
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        vfio_syscall::take_set_irqs_log();
+        device.enable_irq(reset_index, vec![&evt]).unwrap();
+        let log = vfio_syscall::take_set_irqs_log();
+        assert_eq!(log.len(), 1);
+        assert_eq!((log[0].0, log[0].1, log[0].2), (trigger, reset_index, 0));
+        // The device registers its own duplicate of the EventFd.
+        assert_eq!(log[0].3.len(), 1);
+        evt.write(1).unwrap();
+        assert_eq!(device.drain_irq(reset_index, 0).unwrap(), Some(1));
+        device.disable_irq(reset_index).unwrap();
+        assert!(device.irq_eventfd(reset_index, 0).unwrap().is_none());
+
+        // Indexes the device doesn't report are rejected without an ioctl.
+        assert!(matches!(
+            device.enable_irq(reset_index + 1, vec![&evt]),
+            Err(VfioError::VfioDeviceEnableIrq)
+        ));
+        assert!(matches!(
+            device.enable_irq(reset_index, vec![&evt, &evt]),
+            Err(VfioError::VfioDeviceEnableIrq)
+        ));
+        assert_eq!(vfio_syscall::take_set_irqs_log().len(), 1);
+    }
+
+    #[test]
+    fn test_vfio_device_reset_and_wait() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        device.write_all_at(&[0u8; 4], 0x10000).unwrap();
+
+        // The device gets ready after a few polls.
+        let polls = std::cell::Cell::new(0);
+        device
+            .reset_and_wait(
+                |device| {
+                    polls.set(polls.get() + 1);
+                    if polls.get() == 3 {
+                        device.write_all_at(&[0xcd, 0xab], 0x10000).unwrap();
+                    }
+                    let mut reg = [0u8; 2];
+                    device.read_exact_at(&mut reg, 0x10000).unwrap();
+                    u16::from_le_bytes(reg) == 0xabcd
+                },
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert_eq!(polls.get(), 3);
+
+        // Never ready.
+        polls.set(0);
+        match device.reset_and_wait(
+            |_| {
+                polls.set(polls.get() + 1);
+                false
+            },
+            Duration::from_millis(25),
+        ) {
+            Err(e @ VfioError::DeviceResetTimeout { .. }) => {
+                assert_eq!(e.kind(), crate::VfioErrorKind::Busy)
+            }
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        assert!(polls.get() >= 3);
+        device.reset_and_wait(|_| true, Duration::ZERO).unwrap();
+
+        device.read_only = true;
+        assert!(matches!(
+            device.reset_and_wait(|_| panic!("not reset"), Duration::ZERO),
+            Err(VfioError::DeviceReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_vfio_device_mmap_region() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        // The regions below have no type, hence are unknown.
+        device.set_expose_unknown_regions(true);
+        let rw = VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE;
+        for (index, flags) in [(9, VFIO_REGION_INFO_FLAG_READ), (10, rw)].iter() {
+            device.push_region(VfioRegion {
+                index: *index,
+                flags: flags | VFIO_REGION_INFO_FLAG_MMAP,
+                size: 0x1000,
+                offset: u64::from(*index) * 0x1000,
+                caps: RegionCaps::default(),
+            });
+        }
+        device.write_all_at(&[0xaa; 0x2000], 0x9000).unwrap();
+
+        // The protection follows the region flags.
+        let ro = device.mmap_region(9).unwrap();
+        assert_eq!((ro.len(), ro.prot()), (0x1000, libc::PROT_READ));
+        // SAFETY: the mapping is 0x1000 bytes long.
+        assert_eq!(unsafe { *ro.as_ptr().add(0x10) }, 0xaa);
+        let mapped = device.mmap_region(10).unwrap();
+        assert_eq!(mapped.prot(), libc::PROT_READ | libc::PROT_WRITE);
+        // SAFETY: the mapping is 0x1000 bytes long and writable.
+        unsafe { *mapped.as_ptr().add(0x20) = 0x55 };
+        drop(mapped);
+        let mut buf = [0u8; 1];
+        device.region_read(10, &mut buf, 0x20);
+        assert_eq!(buf, [0x55]);
+
+        // Explicit protections must be allowed by the region flags.
+        let wo = device.mmap_region_prot(10, libc::PROT_WRITE).unwrap();
+        assert_eq!(wo.prot(), libc::PROT_WRITE);
+        for prot in [libc::PROT_WRITE, libc::PROT_READ | libc::PROT_EXEC].iter() {
+            assert!(matches!(
+                device.mmap_region_prot(9, *prot),
+                Err(VfioError::RegionMmapProt { index: 9, prot: p }) if p == *prot
+            ));
+        }
+        device.regions_mut().last_mut().unwrap().flags =
+            VFIO_REGION_INFO_FLAG_WRITE | VFIO_REGION_INFO_FLAG_MMAP;
+        device.mmap_region_prot(10, libc::PROT_READ).unwrap_err();
+        assert_eq!(device.mmap_region(10).unwrap().prot(), libc::PROT_WRITE);
+
+        // Read-only devices don't map writable regions for writing.
+        device.regions_mut().last_mut().unwrap().flags |= VFIO_REGION_INFO_FLAG_READ;
+        device.read_only = true;
+        assert_eq!(device.mmap_region(10).unwrap().prot(), libc::PROT_READ);
+        assert!(matches!(
+            device.mmap_region_prot(10, libc::PROT_WRITE),
+            Err(VfioError::DeviceReadOnly)
+        ));
+
+        // Regions which don't support mmap.
+        assert!(matches!(
+            device.mmap_region(0),
+            Err(VfioError::RegionNotMappable(0))
+        ));
+        assert!(matches!(
+            device.mmap_region(100),
+            Err(VfioError::InvalidRegionIndex(100))
+        ));
+    }
+
+    #[test]
+    fn test_vfio_device_mmap_fastpath() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        // The regions below have no type, hence are unknown.
+        device.set_expose_unknown_regions(true);
+        let rw = VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE;
+        let sparse = VfioRegionInfoCap::SparseMmap(VfioRegionInfoCapSparseMmap {
+            areas: vec![
+                VfioRegionSparseMmapArea {
+                    offset: 0,
+                    size: 0x1000,
+                },
+                VfioRegionSparseMmapArea {
+                    offset: 0x2000,
+                    size: 0x2000,
+                },
+            ],
+        });
+        for (index, flags, caps) in [
+            (9, rw | VFIO_REGION_INFO_FLAG_MMAP, vec![]),
+            (10, rw | VFIO_REGION_INFO_FLAG_MMAP, vec![sparse]),
+            (11, rw, vec![]),
+        ] {
+            device.push_region(VfioRegion {
+                index,
+                flags,
+                size: 0x4000,
+                offset: u64::from(index) * 0x4000,
+                caps: caps.into(),
+            });
+        }
+        let pattern: Vec<u8> = (0..0xc000).map(|i| (i % 251) as u8).collect();
+        device.write_all_at(&pattern, 0x24000).unwrap();
+
+        // Disabled by default.
+        let mut buf = vec![0u8; 0x2000];
+        device.region_read(9, &mut buf, 0x1000);
+        assert_eq!(buf, pattern[0x1000..0x3000]);
+        assert_eq!(device.mmap_fastpath_parts(9), None);
+
+        device.set_mmap_fastpath(true);
+        let mut small = [0u8; 16];
+        device.region_read(9, &mut small, 0x10);
+        assert_eq!(small, pattern[0x10..0x20]);
+        assert_eq!(device.mmap_fastpath_parts(9), None);
+        device.region_read(9, &mut buf, 0x1000);
+        assert_eq!(buf, pattern[0x1000..0x3000]);
+        assert_eq!(device.mmap_fastpath_parts(9), Some(1));
+        device.region_write(9, &[0xbb; 0x2000], 0x2000);
+        let mut raw = vec![0u8; 0x2000];
+        device.read_exact_at(&mut raw, 0x26000).unwrap();
+        assert_eq!(raw, vec![0xbb; 0x2000]);
+
+        // Transfers crossing sparse areas, and trap-only regions, use the device fd.
+        let mut buf = vec![0u8; 0x1800];
+        device.region_read(10, &mut buf, 0x800);
+        assert_eq!(buf, pattern[0x4800..0x6000]);
+        assert_eq!(device.mmap_fastpath_parts(10), Some(2));
+        device.region_read(10, &mut buf, 0x2000);
+        assert_eq!(buf, pattern[0x6000..0x7800]);
+        device.region_read(11, &mut buf, 0);
+        assert_eq!(buf, pattern[0x8000..0x9800]);
+        assert_eq!(device.mmap_fastpath_parts(11), Some(0));
+
+        // Resets and refreshes drop the mappings.
+        device.reset().unwrap();
+        assert_eq!(device.mmap_fastpath_parts(9), None);
+        device.region_read(9, &mut buf, 0);
+        assert_eq!(device.mmap_fastpath_parts(9), Some(1));
+        device.refresh_region(0).unwrap();
+        assert_eq!(device.mmap_fastpath_parts(9), None);
+    }
+
+    #[test]
+    fn test_vfio_device_region_access_policy() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        // The regions below have no type, hence are unknown.
+        device.set_expose_unknown_regions(true);
+        device.push_region(VfioRegion {
+            index: 9,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: 0x100,
+            offset: 0x1000,
+            caps: RegionCaps::default(),
+        });
+        device
+            .write_all_at(&[1, 2, 3, 4, 5, 6, 7, 8], 0x1000)
+            .unwrap();
+
+        // Permissive by default.
+        assert_eq!(
+            device.region_access_policy(9),
+            VfioRegionAccessPolicy::Permissive
+        );
+        let mut buf = [0u8; 3];
+        device.region_read(9, &mut buf, 1);
+        assert_eq!(buf, [2, 3, 4]);
+
+        device
+            .set_region_access_policy(9, VfioRegionAccessPolicy::CONFIG_SPACE)
+            .unwrap();
+        assert!(matches!(
+            device.set_region_access_policy(100, VfioRegionAccessPolicy::CONFIG_SPACE),
+            Err(VfioError::InvalidRegionIndex(100))
+        ));
+
+        let mut buf = [0u8; 4];
+        device.region_read(9, &mut buf, 4);
+        assert_eq!(buf, [5, 6, 7, 8]);
+        // Too wide, unaligned and odd sized accesses are dropped.
+        let mut buf = [0u8; 8];
+        device.region_read(9, &mut buf, 0);
+        assert_eq!(buf, [0u8; 8]);
+        let mut buf = [0u8; 2];
+        device.region_read(9, &mut buf, 1);
+        assert_eq!(buf, [0u8; 2]);
+        let mut buf = [0u8; 3];
+        device.region_read(9, &mut buf, 0);
+        assert_eq!(buf, [0u8; 3]);
+
+        device.region_write(9, &[0xff; 8], 0);
+        device.region_write(9, &[0xff; 2], 3);
+        device.region_write(9, &[0xee; 2], 2);
+        let mut buf = [0u8; 4];
+        device.region_read(9, &mut buf, 0);
+        assert_eq!(buf, [1, 2, 0xee, 0xee]);
+
+        // Kept across refreshes of the region.
+        device
+            .set_region_access_policy(2, VfioRegionAccessPolicy::Aligned { max_width: 4 })
+            .unwrap();
+        device.refresh_region(2).unwrap();
+        assert_eq!(
+            device.region_access_policy(2),
+            VfioRegionAccessPolicy::Aligned { max_width: 4 }
+        );
+
+        // Widths which aren't powers of two are refused.
+        for max_width in [0, 3, 6] {
+            let policy = VfioRegionAccessPolicy::Aligned { max_width };
+            assert!(matches!(
+                device.set_region_access_policy(9, policy),
+                Err(VfioError::InvalidRegionAccessWidth { index: 9, max_width: w })
+                    if w == max_width
+            ));
+        }
+
+        // The config space of a PCI device is restricted unless told otherwise.
+        device.push_region(VfioRegion {
+            index: VFIO_PCI_CONFIG_REGION_INDEX,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: 0x100,
+            offset: 0x2000,
+            caps: RegionCaps::default(),
+        });
+        assert_eq!(
+            device.region_access_policy(VFIO_PCI_CONFIG_REGION_INDEX),
+            VfioRegionAccessPolicy::CONFIG_SPACE
+        );
+        device
+            .set_region_access_policy(
+                VFIO_PCI_CONFIG_REGION_INDEX,
+                VfioRegionAccessPolicy::Permissive,
+            )
+            .unwrap();
+        assert_eq!(
+            device.region_access_policy(VFIO_PCI_CONFIG_REGION_INDEX),
+            VfioRegionAccessPolicy::Permissive
+        );
+        device.flags = VFIO_DEVICE_FLAGS_PLATFORM;
+        assert_eq!(
+            device.region_access_policy(1),
+            VfioRegionAccessPolicy::Permissive
+        );
+    }
+
+    #[test]
+    fn test_vfio_device_region_vectored() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        // The regions below have no type, hence are unknown.
+        device.set_expose_unknown_regions(true);
+        device.push_region(VfioRegion {
+            index: 9,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: 0x100,
+            offset: 0x1000,
+            caps: RegionCaps::default(),
+        });
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        device.write_all_at(&data, 0x1000).unwrap();
+
+        // Contiguous and discontiguous ranges.
+        let (mut a, mut b, mut c) = ([0u8; 2], [0u8; 4], [0u8; 1]);
+        device
+            .region_readv(9, &mut [(0x10, &mut a), (0x12, &mut b), (0xff, &mut c)])
+            .unwrap();
+        assert_eq!((a, b, c), ([0x10, 0x11], [0x12, 0x13, 0x14, 0x15], [0xff]));
+
+        device
+            .region_writev(
+                9,
+                &[(0x20, &[0xaa; 2]), (0x22, &[0xbb]), (0x40, &[0xcc; 4])],
+            )
+            .unwrap();
+        let mut buf = [0u8; 4];
+        device.region_read(9, &mut buf, 0x20);
+        assert_eq!(buf, [0xaa, 0xaa, 0xbb, 0x23]);
+        device.region_read(9, &mut buf, 0x40);
+        assert_eq!(buf, [0xcc; 4]);
+
+        // Nothing is accessed if any range is invalid.
+        let mut a = [0u8; 2];
+        assert!(matches!(
+            device.region_readv(9, &mut [(0x10, &mut a), (0xff, &mut [0u8; 2])]),
+            Err(VfioError::InvalidRegionAccess {
+                index: 9,
+                addr: 0xff,
+                size: 2
+            })
+        ));
+        assert_eq!(a, [0u8; 2]);
+        assert!(matches!(
+            device.region_writev(9, &[(0x20, &[0u8; 2]), (u64::MAX, &[0u8])]),
+            Err(VfioError::InvalidRegionAccess { .. })
+        ));
+        device.region_read(9, &mut buf, 0x20);
+        assert_eq!(buf, [0xaa, 0xaa, 0xbb, 0x23]);
+        assert!(matches!(
+            device.region_readv(100, &mut []),
+            Err(VfioError::InvalidRegionIndex(100))
+        ));
+
+        // The access policy applies to each range.
+        device
+            .set_region_access_policy(9, VfioRegionAccessPolicy::CONFIG_SPACE)
+            .unwrap();
+        device
+            .region_readv(9, &mut [(0x10, &mut [0u8; 4]), (0x14, &mut [0u8; 2])])
+            .unwrap();
+        device.region_writev(9, &[(0x11, &[0u8; 2])]).unwrap_err();
+
+        // Many contiguous ranges are split in several calls.
+        let mut bufs = vec![[0u8; 1]; 0x100];
+        let mut iovecs: Vec<(u64, &mut [u8])> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| (i as u64, &mut buf[..]))
+            .collect();
+        device
+            .set_region_access_policy(9, VfioRegionAccessPolicy::Permissive)
+            .unwrap();
+        device.region_readv(9, &mut iovecs).unwrap();
+        assert_eq!((bufs[0x40], bufs[0x81]), ([0xcc], [0x81]));
+        let addrs: Vec<(u64, usize)> = (0..IOV_MAX as u64 + 2).map(|a| (a, 1)).collect();
+        assert_eq!(
+            VfioDevice::contiguous_runs(&addrs),
+            vec![(0, IOV_MAX), (IOV_MAX, IOV_MAX + 2)]
+        );
+
+        device.read_only = true;
+        assert!(matches!(
+            device.region_writev(9, &[(0, &[0u8])]),
+            Err(VfioError::DeviceReadOnly)
+        ));
+        device.read_only = false;
+        device.regions_mut().last_mut().unwrap().flags = VFIO_REGION_INFO_FLAG_READ;
+        device.region_writev(9, &[(0, &[0u8])]).unwrap_err();
+        device.regions_mut().last_mut().unwrap().flags = VFIO_REGION_INFO_FLAG_WRITE;
+        let mut a = [0u8; 2];
+        assert!(matches!(
+            device.region_readv(9, &mut [(0x10, &mut a)]),
+            Err(VfioError::InvalidRegionAccess { index: 9, .. })
+        ));
+        assert_eq!(a, [0u8; 2]);
+    }
+
+    #[test]
+    fn test_vfio_device_numa_node() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_numa").unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(root.as_path(), container.clone()).unwrap();
+        assert_eq!(device.numa_node(), None);
+
+        let numa_node = root.as_path().join("numa_node");
+        fs::write(&numa_node, "1\n").unwrap();
+        assert_eq!(device.numa_node(), Some(1));
+        fs::write(&numa_node, "-1\n").unwrap();
+        assert_eq!(device.numa_node(), None);
+
+        // Mediated devices are attached to the node of their parent.
+        let sysfspath = crate::vfio_paths::tests::create_mdev_fixture(root.as_path(), true);
+        fs::write(sysfspath.join("numa_node"), "0\n").unwrap();
+        fs::write(sysfspath.join("../numa_node"), "3\n").unwrap();
+        let device = VfioDevice::new_mdev(&sysfspath, container).unwrap();
+        assert_eq!(device.numa_node(), Some(3));
+    }
+
+    #[test]
+    fn test_vfio_device_host_irq_numbers() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_msi_irqs").unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(root.as_path(), container).unwrap();
+        assert!(device.host_irq_numbers().is_empty());
+
+        let msi_irqs = root.as_path().join("msi_irqs");
+        fs::create_dir(&msi_irqs).unwrap();
+        for name in ["131", "129", "130", "garbage"] {
+            fs::write(msi_irqs.join(name), "msix\n").unwrap();
+        }
+        assert_eq!(device.host_irq_numbers(), vec![129, 130, 131]);
+    }
+
+    #[test]
+    fn test_vfio_device_mdev_info() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_mdev").unwrap();
+        let sysfspath = crate::vfio_paths::tests::create_mdev_fixture(root.as_path(), true);
+        let container = Arc::new(create_vfio_container());
+
+        let device = VfioDevice::new_mdev(&sysfspath, container.clone()).unwrap();
+        let info = device.mdev_info().unwrap();
+        assert_eq!(info.mdev_type, "nvidia-63");
+        assert_eq!(info.parent.as_deref(), Some("0000:00:02.0"));
+        assert_eq!(info.available_instances, Some(7));
+
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert!(device.mdev_info().is_none());
+        VfioDevice::new_mdev(tmp_file.as_path(), container).unwrap_err();
+    }
+
+    #[test]
+    fn test_vfio_device_reopen() {
+        // All the regions succeed, the configuration space is region 7.
+        vfio_syscall::inject_region_layout(1, u32::MAX);
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let config_offset = device.region(VFIO_PCI_CONFIG_REGION_INDEX).unwrap().offset;
+        device.write_all_at(&[0u8; 0x100], config_offset).unwrap();
+        let mut cmd = device.command_register().unwrap();
+        cmd.bus_master = true;
+        device.set_command_register(cmd).unwrap();
+        let evts = [
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        ];
+        device
+            .enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, evts.iter().collect())
+            .unwrap();
+        vfio_syscall::take_set_irqs_log();
+
+        // The new device fd starts zeroed, the command register and interrupts are restored.
+        let stale_ino = vfio_syscall::fd_inode(device.as_raw_fd()).unwrap();
+        device.reopen().unwrap();
+        assert!(!device.is_gone());
+        assert_eq!(device.regions().len(), 8);
+        assert!(device.command_register().unwrap().bus_master);
+        let raw: Vec<RawFd> = device.irq_fds.lock().unwrap()[&VFIO_PCI_MSIX_IRQ_INDEX]
+            .iter()
+            .map(|fd| fd.as_raw_fd())
+            .collect();
+        assert_eq!(raw.len(), 2);
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                VFIO_PCI_MSIX_IRQ_INDEX,
+                0,
+                raw.clone()
+            )]
+        );
+        assert_ne!(vfio_syscall::fd_inode(device.as_raw_fd()), Some(stale_ino));
+
+        // A device which can't be opened again is gone, until reopened.
+        vfio_syscall::inject_group_device_fd_failure(1);
+        assert!(matches!(device.reopen(), Err(VfioError::GroupGetDeviceFD)));
+        assert!(device.is_gone());
+        assert!(device.regions().is_empty());
+        assert!(device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX).is_none());
+        assert_eq!(
+            device.irq_fds.lock().unwrap()[&VFIO_PCI_MSIX_IRQ_INDEX]
+                .iter()
+                .map(|fd| fd.as_raw_fd())
+                .collect::<Vec<_>>(),
+            raw
+        );
+        assert!(matches!(
+            device.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, evts.iter().collect()),
+            Err(VfioError::DeviceGone)
+        ));
+        assert!(matches!(
+            device.region_writev(0, &[(0, &[0u8; 4])]),
+            Err(VfioError::DeviceGone)
+        ));
+        assert!(matches!(device.reset(), Err(VfioError::DeviceGone)));
+        assert!(matches!(
+            device.reset_preserving_irqs(),
+            Err(VfioError::DeviceGone)
+        ));
+        assert!(device.command_register().is_err());
+
+        // The interrupts enabled before the device was gone are restored on retry.
+        device.set_dma_mask(Some(u32::MAX.into()));
+        vfio_syscall::take_set_irqs_log();
+        device.reopen().unwrap();
+        assert!(!device.is_gone());
+        assert_eq!(device.regions().len(), 8);
+        assert!(device.command_register().unwrap().bus_master);
+        assert_eq!(
+            vfio_syscall::take_set_irqs_log(),
+            vec![(
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                VFIO_PCI_MSIX_IRQ_INDEX,
+                0,
+                raw
+            )]
+        );
+        assert_eq!(device.dma_mask(), Some(u32::MAX.into()));
+    }
+
+    #[test]
+    fn test_vfio_device_read_only() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new_read_only(tmp_file.as_path(), container).unwrap();
+        assert!(device.is_read_only());
+        assert_eq!(device.regions().len(), 7);
+        assert_eq!(device.irqs().len(), 3);
+
+        device.push_region(VfioRegion {
+            index: VFIO_PCI_CONFIG_REGION_INDEX,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: 0x100,
+            offset: 0x1000,
+            caps: RegionCaps::default(),
+        });
+        // An Intel device with a MSI-X capability.
+        let mut config = [0u8; 0x100];
+        config[0x00..0x02].copy_from_slice(&[0x86, 0x80]);
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40] = 0x11;
+        device.write_all_at(&config, 0x1000).unwrap();
+        assert_eq!(device.config().read_u16(0).unwrap(), 0x8086);
+        assert!(matches!(
+            device.write_config(0, &[0u8; 2]),
+            Err(VfioError::DeviceReadOnly)
+        ));
+        device.region_write(VFIO_PCI_CONFIG_REGION_INDEX, &[0u8; 2], 0);
+        assert_eq!(device.config().read_u16(0).unwrap(), 0x8086);
+
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        assert!(matches!(
+            device.enable_irq(1, vec![&evt]),
+            Err(VfioError::DeviceReadOnly)
+        ));
+        // Nothing to disable.
+        device.disable_irq(1).unwrap();
+
+        // Every other entry point modifying the device is refused.
+        let blocked: [(&str, Result<()>); 16] = [
+            ("reset", device.reset()),
+            (
+                "enable_irq_owned",
+                device.enable_irq_owned(2, vec![evt.try_clone().unwrap()]),
+            ),
+            ("assign_vector", device.assign_vector(2, 0, &evt)),
+            ("trigger_irq", device.trigger_irq(2, 0)),
+            ("unmask_irq", device.unmask_irq(0)),
+            ("mask_irq", device.mask_irq(0)),
+            ("reset_preserving_irqs", device.reset_preserving_irqs()),
+            (
+                "reset_and_wait",
+                device.reset_and_wait(|_| true, Duration::from_millis(1)),
+            ),
+            ("function_reset", device.function_reset()),
+            ("pci_hot_reset", device.pci_hot_reset(&[])),
+            ("flr_reset", device.flr_reset()),
+            ("region_writev", device.region_writev(0, &[(0, &[0u8; 4])])),
+            (
+                "mmap_region_prot",
+                device
+                    .mmap_region_prot(0, libc::PROT_READ | libc::PROT_WRITE)
+                    .map(|_| ()),
+            ),
+            (
+                "set_command_register",
+                device.set_command_register(PciCommand::default()),
+            ),
+            ("msix_function_mask", device.msix_function_mask(true)),
+            (
+                "dma_map",
+                device.dma_map(Iova(0x1000), 0x1000, HostVa(0x8000)),
+            ),
+        ];
+        for (name, result) in blocked {
+            assert!(
+                matches!(result, Err(VfioError::DeviceReadOnly)),
+                "{} isn't refused",
+                name
+            );
+        }
+        assert!(device.binding.container.mappings().is_empty());
+    }
+
     #[test]
     #[allow(clippy::redundant_clone)]
     fn test_vfio_region_info_cap() {
@@ -1430,6 +8225,770 @@ mod tests {
         assert_ne!(v8, v6.clone());
     }
 
+    #[test]
+    fn test_vfio_fmt() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let name = tmp_file.as_path().file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(
+            format!("{}", device),
+            format!("{} (pci, 7 regions, irqs INTX:1 MSI:32 MSI-X:2048)", name)
+        );
+        assert_eq!(
+            format!("{:?}", device),
+            format!(
+                "VfioDevice {{ path: {:?}, type: pci, regions: 7, irqs: [INTX:1 MSI:32 MSI-X:2048] }}",
+                tmp_file.as_path()
+            )
+        );
+
+        assert_eq!(
+            format!("{}", device.regions()[1]),
+            "region 1: 0x2000 (8 KiB) - [msix-mappable, type(0x5:0x6), sparse-mmap(1)]"
+        );
+        assert_eq!(
+            format!("{:?}", device.regions()[2]),
+            "VfioRegion { index: 2, size: 0x3000 (12 KiB), offset: 0x30000, flags: -, caps: [] }"
+        );
+
+        let mut region = device.regions()[0].clone();
+        region.flags = VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_MMAP;
+        region.size = 0x1234;
+        assert_eq!(format!("{}", region), "region 0: 0x1234 (4660 B) R|M []");
+
+        let irq = device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX).unwrap();
+        assert_eq!(format!("{}", irq), "MSI-X: 2048 vector(s) EVENTFD");
+        assert_eq!(
+            format!("{:?}", irq),
+            "VfioIrq { index: MSI-X, count: 2048, flags: EVENTFD }"
+        );
+        let irq = VfioIrq {
+            flags: VFIO_IRQ_INFO_EVENTFD | VFIO_IRQ_INFO_MASKABLE | VFIO_IRQ_INFO_AUTOMASKED,
+            index: 9,
+            count: 1,
+        };
+        assert_eq!(
+            format!("{}", irq),
+            "#9: 1 vector(s) EVENTFD|MASKABLE|AUTOMASKED"
+        );
+    }
+
+    #[test]
+    fn test_vfio_device_describe() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        *device.regions.get_mut().unwrap() = device.regions()[..3].into();
+        device.regions_mut()[2].caps = vec![
+            VfioRegionInfoCap::Nvlink2Ssatgt(VfioRegionInfoCapNvlink2Ssatgt { tgt: 0x2000 }),
+            VfioRegionInfoCap::Nvlink2Lnkspd(VfioRegionInfoCapNvlink2Lnkspd { link_speed: 9 }),
+        ]
+        .into();
+        device.regions_mut().swap(0, 2);
+
+        let name = device.name();
+        assert_eq!(
+            device.describe(),
+            format!(
+                "{} (pci, 3 regions, irqs INTX:1 MSI:32 MSI-X:2048)
+  region 0: 0x1000 (4 KiB) - [] at 0x10000
+  region 1: 0x2000 (8 KiB) - [msix-mappable, type(0x5:0x6), sparse-mmap(1)] at 0x20000
+    msix-mappable
+    type 0x5 subtype 0x6
+    sparse-mmap area 0x4 (3 B)
+  region 2: 0x3000 (12 KiB) - [nvlink2-ssatgt, nvlink2-lnkspd] at 0x30000
+    nvlink2 ssatgt 0x2000
+    nvlink2 link speed 9
+  INTX: 1 vector(s) MASKABLE
+  MSI: 32 vector(s) EVENTFD
+  MSI-X: 2048 vector(s) EVENTFD
+",
+                name
+            )
+        );
+    }
+
+    #[test]
+    fn test_vfio_region_mmap_offset() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        assert!(matches!(
+            device.region_mmap_offset(0),
+            Err(VfioError::RegionNotMappable(0))
+        ));
+        assert!(matches!(
+            device.region_mmap_offset(100),
+            Err(VfioError::InvalidRegionIndex(100))
+        ));
+        assert!(!device.region_requires_sparse_mmap(100));
+
+        device.regions_mut()[0].flags |= VFIO_REGION_INFO_FLAG_MMAP;
+        assert_eq!(device.region_mmap_offset(0).unwrap(), 0x10000);
+        assert!(!device.region_requires_sparse_mmap(0));
+
+        device.regions_mut()[1].flags |= VFIO_REGION_INFO_FLAG_MMAP;
+        assert!(device.region_requires_sparse_mmap(1));
+        assert!(matches!(
+            device.region_mmap_offset(1),
+            Err(VfioError::RegionSparseMmapOnly(1))
+        ));
+    }
+
+    #[test]
+    fn test_vfio_region_guest_mappings() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        let gpa = GuestAddress(0xe000_0000);
+        assert!(matches!(
+            device.region_guest_mappings(0, gpa),
+            Err(VfioError::RegionNotMappable(0))
+        ));
+        assert!(matches!(
+            device.region_guest_mappings(100, gpa),
+            Err(VfioError::InvalidRegionIndex(100))
+        ));
+
+        device.regions_mut()[0].flags |= VFIO_REGION_INFO_FLAG_MMAP;
+        assert_eq!(
+            device.region_guest_mappings(0, gpa).unwrap(),
+            vec![VfioRegionGuestMapping {
+                gpa,
+                fd_offset: 0x10000,
+                size: 0x1000,
+            }]
+        );
+        for &gpa in &[0xe000_0800, u64::MAX - 0xfff] {
+            assert!(matches!(
+                device.region_guest_mappings(0, GuestAddress(gpa)),
+                Err(VfioError::InvalidRegionGuestAddress { index: 0, .. })
+            ));
+        }
+
+        // The MSI-X table page is left out of the sparse mmap areas.
+        device.regions_mut()[1].flags |= VFIO_REGION_INFO_FLAG_MMAP;
+        device.regions_mut()[1].caps =
+            vec![VfioRegionInfoCap::SparseMmap(VfioRegionInfoCapSparseMmap {
+                areas: vec![
+                    VfioRegionSparseMmapArea {
+                        offset: 0x1000,
+                        size: 0x1000,
+                    },
+                    VfioRegionSparseMmapArea { offset: 0, size: 0 },
+                    VfioRegionSparseMmapArea {
+                        offset: 0x1000,
+                        size: 0x2000,
+                    },
+                ],
+            })]
+            .into();
+        assert_eq!(
+            device.region_guest_mappings(1, gpa).unwrap(),
+            vec![VfioRegionGuestMapping {
+                gpa: GuestAddress(0xe000_1000),
+                fd_offset: 0x21000,
+                size: 0x1000,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_vfio_region_class() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        let typed_region = |index, type_, subtype| VfioRegion {
+            index,
+            flags: VFIO_REGION_INFO_FLAG_READ,
+            size: 0x1000,
+            offset: u64::from(index) << 40,
+            caps: vec![VfioRegionInfoCap::Type(VfioRegionInfoCapType {
+                type_,
+                subtype,
+            })]
+            .into(),
+        };
+        device.push_region(typed_region(
+            VFIO_PCI_NUM_REGIONS,
+            VFIO_REGION_TYPE_GFX,
+            VFIO_REGION_SUBTYPE_GFX_EDID,
+        ));
+        // Unknown typed region, e.g. a config space mirror quirk.
+        device.push_region(typed_region(VFIO_PCI_NUM_REGIONS + 1, 0x1234, 0x1));
+        device.push_region(VfioRegion {
+            caps: RegionCaps::default(),
+            ..typed_region(VFIO_PCI_NUM_REGIONS + 2, 0, 0)
+        });
+        device.push_region(typed_region(
+            VFIO_PCI_NUM_REGIONS + 3,
+            VFIO_REGION_TYPE_PCI_VENDOR_TYPE | 0x8086,
+            VFIO_REGION_SUBTYPE_INTEL_IGD_OPREGION,
+        ));
+        // NVLink2 and migration regions are known whatever the device vendor.
+        device.push_region(typed_region(
+            VFIO_PCI_NUM_REGIONS + 4,
+            VFIO_REGION_TYPE_PCI_VENDOR_TYPE | u32::from(PCI_VENDOR_ID_NVIDIA),
+            VFIO_REGION_SUBTYPE_NVIDIA_NVLINK2_RAM,
+        ));
+        device.push_region(typed_region(
+            VFIO_PCI_NUM_REGIONS + 5,
+            VFIO_REGION_TYPE_MIGRATION,
+            VFIO_REGION_SUBTYPE_MIGRATION,
+        ));
+        *device.vendor_regions.get_mut().unwrap() = vec![(
+            VFIO_PCI_NUM_REGIONS + 3,
+            KnownVendorRegion::IntelIgdOpRegion,
+        )];
+
+        assert_eq!(device.region_class(0), Some(VfioRegionClass::Standard));
+        assert_eq!(device.region_class(1), Some(VfioRegionClass::Standard));
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS),
+            Some(VfioRegionClass::KnownDeviceSpecific)
+        );
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 1),
+            Some(VfioRegionClass::UnknownDeviceSpecific)
+        );
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 2),
+            Some(VfioRegionClass::UnknownDeviceSpecific)
+        );
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 3),
+            Some(VfioRegionClass::KnownDeviceSpecific)
+        );
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 4),
+            Some(VfioRegionClass::KnownDeviceSpecific)
+        );
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 5),
+            Some(VfioRegionClass::KnownDeviceSpecific)
+        );
+        assert_eq!(device.region_class(100), None);
+
+        assert!(!device.region_exposable(VFIO_PCI_NUM_REGIONS + 1));
+        assert!(!device.region_exposable(100));
+        assert_eq!(
+            device.exposable_regions(),
+            vec![
+                0,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                VFIO_PCI_NUM_REGIONS,
+                VFIO_PCI_NUM_REGIONS + 3,
+                VFIO_PCI_NUM_REGIONS + 4,
+                VFIO_PCI_NUM_REGIONS + 5
+            ]
+        );
+
+        // Unknown regions can't be accessed nor mapped.
+        let unknown = VFIO_PCI_NUM_REGIONS + 1;
+        let mut buf = [0u8; 4];
+        assert!(matches!(
+            device.region_readv(unknown, &mut [(0, &mut buf[..])]),
+            Err(VfioError::RegionNotExposed(i)) if i == unknown
+        ));
+        assert!(matches!(
+            device.mmap_region(unknown),
+            Err(VfioError::RegionNotExposed(i)) if i == unknown
+        ));
+        assert!(matches!(
+            device.region_guest_mappings(unknown, GuestAddress(0)),
+            Err(VfioError::RegionNotExposed(i)) if i == unknown
+        ));
+
+        // The policy can be changed while the device is shared.
+        let device = Arc::new(device);
+        device.set_expose_unknown_regions(true);
+        assert!(device.region_exposable(unknown));
+        assert_eq!(device.exposable_regions().len(), 13);
+        assert!(matches!(
+            device.mmap_region(unknown),
+            Err(VfioError::RegionNotMappable(i)) if i == unknown
+        ));
+        let mut device = Arc::try_unwrap(device).ok().unwrap();
+
+        // All the regions of a non PCI device are standard.
+        device.flags = VFIO_DEVICE_FLAGS_PLATFORM;
+        assert_eq!(
+            device.region_class(VFIO_PCI_NUM_REGIONS + 1),
+            Some(VfioRegionClass::Standard)
+        );
+    }
+
+    #[test]
+    fn test_vfio_device_single_vector() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        device.enable_msi_single(&evt).unwrap();
+        device.disable_msi().unwrap();
+        device.enable_msix_single(&evt).unwrap();
+        evt.write(1).unwrap();
+        assert_eq!(
+            device.drain_irq(VFIO_PCI_MSIX_IRQ_INDEX, 0).unwrap(),
+            Some(1)
+        );
+        device.disable_msix().unwrap();
+
+        // The device doesn't report any vector when queried again either.
+        vfio_syscall::inject_irq_count(VFIO_PCI_MSIX_IRQ_INDEX, 0);
+        device
+            .irqs_mut()
+            .get_mut(&VFIO_PCI_MSIX_IRQ_INDEX)
+            .unwrap()
+            .count = 0;
+        device.enable_msix_single(&evt).unwrap_err();
+        device.irqs_mut().remove(&VFIO_PCI_MSI_IRQ_INDEX);
+        device.enable_msi_single(&evt).unwrap_err();
+    }
+
+    #[test]
+    fn test_vfio_refresh_region() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        device.regions_mut()[0].size = 0x8000;
+        device.regions_mut()[1].caps = RegionCaps::default();
+
+        // The device may be shared, regions read before the refresh are left as they were.
+        let device = Arc::new(device);
+        let stale = device.region(0).unwrap();
+        let refresher = device.clone();
+        thread::spawn(move || refresher.refresh_region(0).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(stale.size, 0x8000);
+        assert_eq!(device.get_region_size(0), 0x1000);
+        assert!(device.get_region_caps(1).is_empty());
+        device.refresh_region(1).unwrap();
+        assert_eq!(device.get_region_caps(1).len(), 3);
+        assert_eq!(device.regions()[1].size, 0x2000);
+
+        assert!(matches!(
+            device.refresh_region(VFIO_PCI_CONFIG_REGION_INDEX),
+            Err(VfioError::InvalidRegionIndex(_))
+        ));
+    }
+
+    #[test]
+    fn test_known_vendor_region() {
+        let igd = VfioRegionInfoCapType {
+            type_: VFIO_REGION_TYPE_PCI_VENDOR_TYPE | 0x8086,
+            subtype: VFIO_REGION_SUBTYPE_INTEL_IGD_OPREGION,
+        };
+        assert_eq!(
+            KnownVendorRegion::from_type(0x8086, &igd),
+            Some(KnownVendorRegion::IntelIgdOpRegion)
+        );
+        assert_eq!(KnownVendorRegion::from_type(0x10de, &igd), None);
+
+        let nvlink = VfioRegionInfoCapType {
+            type_: VFIO_REGION_TYPE_PCI_VENDOR_TYPE | 0x10de,
+            subtype: VFIO_REGION_SUBTYPE_NVIDIA_NVLINK2_RAM,
+        };
+        assert_eq!(
+            KnownVendorRegion::from_type(0x10de, &nvlink),
+            Some(KnownVendorRegion::NvidiaNvlink2Ram)
+        );
+
+        let unknown = VfioRegionInfoCapType {
+            type_: VFIO_REGION_TYPE_PCI_VENDOR_TYPE | 0x15b3,
+            subtype: 1,
+        };
+        assert_eq!(KnownVendorRegion::from_type(0x15b3, &unknown), None);
+
+        let not_vendor = VfioRegionInfoCapType {
+            type_: VFIO_REGION_TYPE_GFX,
+            subtype: VFIO_REGION_SUBTYPE_GFX_EDID,
+        };
+        assert_eq!(KnownVendorRegion::from_type(0, &not_vendor), None);
+
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        assert!(device.known_vendor_regions().is_empty());
+
+        // Vendor regions are reported with their VFIO index, even past a missing region.
+        let region = |index, caps: Vec<VfioRegionInfoCap>| VfioRegion {
+            index,
+            flags: VFIO_REGION_INFO_FLAG_READ,
+            size: 0x1000,
+            offset: u64::from(index) << 16,
+            caps: caps.into(),
+        };
+        *device.regions.get_mut().unwrap() = vec![
+            region(0, Vec::new()),
+            region(
+                VFIO_PCI_NUM_REGIONS + 1,
+                vec![VfioRegionInfoCap::Type(nvlink)],
+            ),
+        ]
+        .into();
+        assert_eq!(
+            device.get_vendor_regions(),
+            vec![(
+                VFIO_PCI_NUM_REGIONS + 1,
+                KnownVendorRegion::NvidiaNvlink2Ram
+            )]
+        );
+    }
+
+    #[test]
+    fn test_vfio_device_dma_mask() {
+        let high = 1u64 << 32;
+        let below = high - 0x1000;
+        vfio_syscall::inject_dma_iovas(&[high, below], &[high, below]);
+        let container = Arc::new(create_vfio_container());
+        container.vfio_dma_map(high, 0x1000, 0x8000).unwrap();
+
+        // The mapping already beyond the mask is kept.
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(device.dma_mask(), None);
+        device.set_dma_mask(Some(u32::MAX.into()));
+        assert_eq!(device.dma_mask(), Some(u32::MAX.into()));
+        assert_eq!(container.mappings().len(), 1);
+
+        // Mappings must end at the mask at most, whichever device or container creates them.
+        container.vfio_dma_map(below, 0x1000, 0x8000).unwrap();
+        container.vfio_dma_unmap(below, 0x1000).unwrap();
+        match container.vfio_dma_map(below, 0x2000, 0x8000) {
+            Err(VfioError::IommuDmaMapBeyondDmaMask {
+                iova,
+                size,
+                device: name,
+                mask,
+            }) => {
+                assert_eq!((iova, size, mask), (below, 0x2000, u32::MAX.into()));
+                assert_eq!(name, device.name());
+            }
+            _ => panic!("expect VfioError::IommuDmaMapBeyondDmaMask"),
+        }
+        assert!(matches!(
+            device.dma_map(Iova(high), 0x1000, HostVa(0x8000)),
+            Err(VfioError::IommuDmaMapBeyondDmaMask { .. })
+        ));
+
+        device.set_dma_mask(None);
+        assert_eq!(device.dma_mask(), None);
+        container.vfio_dma_map(below, 0x2000, 0x8000).unwrap();
+        container.vfio_dma_unmap(below, 0x2000).unwrap();
+
+        // The mask goes away with the device object, another one opened for the same device
+        // keeping its own.
+        let other = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        assert_eq!(other.name(), device.name());
+        other.set_dma_mask(Some(u64::from(u32::MAX) + 0x1000));
+        device.set_dma_mask(Some(u32::MAX.into()));
+        assert_eq!(other.dma_mask(), Some(u64::from(u32::MAX) + 0x1000));
+        drop(device);
+        assert_eq!(other.dma_mask(), Some(u64::from(u32::MAX) + 0x1000));
+        container.vfio_dma_map(below, 0x2000, 0x8000).unwrap();
+        container.vfio_dma_unmap(below, 0x2000).unwrap();
+        assert!(matches!(
+            container.vfio_dma_map(high, 0x2000, 0x8000),
+            Err(VfioError::IommuDmaMapBeyondDmaMask { .. })
+        ));
+
+        // A poisoned lock doesn't keep the device from being dropped.
+        let poisoner = container.clone();
+        thread::spawn(move || {
+            let _masks = poisoner.dma_masks.lock().unwrap();
+            panic!("dma mask update panicked");
+        })
+        .join()
+        .unwrap_err();
+        drop(other);
+        container.vfio_dma_map(high, 0x2000, 0x8000).unwrap();
+    }
+
+    #[test]
+    fn test_vfio_dma_avail_watermark() {
+        let container = create_vfio_container();
+        let notify = EventFd::new(EFD_NONBLOCK).unwrap();
+        assert_eq!(container.dma_avail().unwrap(), None);
+        assert!(matches!(
+            container.set_dma_avail_watermark(90, notify.try_clone().unwrap()),
+            Err(VfioError::DmaAvailUnsupported)
+        ));
+
+        vfio_syscall::inject_dma_limit(Some(100));
+        assert_eq!(container.dma_avail().unwrap(), Some(100));
+        let iovas: Vec<u64> = (1..=20).map(|i| i * 0x1000).collect();
+        vfio_syscall::inject_dma_iovas(&iovas, &iovas);
+        container
+            .set_dma_avail_watermark(90, notify.try_clone().unwrap())
+            .unwrap();
+        container.iommu_page_size().unwrap();
+        vfio_syscall::take_iommu_info_queries(&container);
+        let map = |i: usize| container.vfio_dma_map(iovas[i], 0x1000, 0x8000).unwrap();
+
+        // No query until the threshold may be reached, 10 mappings later.
+        for i in 0..10 {
+            map(i);
+        }
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 0);
+        assert!(notify.read().is_err());
+        map(10);
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 2);
+        assert_eq!(notify.read().unwrap(), 1);
+
+        // Signaled once per crossing, queried on every mapping while below the threshold.
+        map(11);
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 2);
+        assert!(notify.read().is_err());
+        // Back above the threshold, 91 available after the 13th mapping.
+        vfio_syscall::inject_dma_limit(Some(104));
+        map(12);
+        assert!(notify.read().is_err());
+        map(13);
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 2);
+        map(14);
+        assert_eq!(notify.read().unwrap(), 1);
+
+        // Already below the threshold when set.
+        container
+            .set_dma_avail_watermark(95, notify.try_clone().unwrap())
+            .unwrap();
+        assert_eq!(notify.read().unwrap(), 1);
+        container.clear_dma_avail_watermark();
+        vfio_syscall::take_iommu_info_queries(&container);
+        map(15);
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 0);
+        vfio_syscall::inject_dma_limit(None);
+    }
+
+    #[test]
+    fn test_vfio_mapping_budget() {
+        let container = Arc::new(create_vfio_container());
+        container.set_mapping_budget(Some(0x800));
+        match container.vfio_dma_map(0x1000, 0x1000, 0x8000) {
+            Err(VfioError::MappingBudgetExceeded {
+                requested,
+                used,
+                budget,
+            }) => assert_eq!((requested, used, budget), (0x1000, 0, 0x800)),
+            _ => panic!("expect VfioError::MappingBudgetExceeded"),
+        }
+        container.set_mapping_budget(None);
+        container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
+        assert_eq!(container.mapped_bytes(), 0x1000);
+        container.vfio_dma_unmap(0x1000, 0x1000).unwrap();
+
+        // Only 4 out of 64 concurrent mappings fit in the budget.
+        const THREADS: u64 = 8;
+        const MAPS: u64 = 8;
+        let iovas: Vec<u64> = (0..THREADS * MAPS)
+            .map(|i| 0x10_0000 + i * 0x1000)
+            .collect();
+        container.set_mapping_budget(Some(0x4000));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let container = container.clone();
+                let iovas = iovas.clone();
+                std::thread::spawn(move || {
+                    vfio_syscall::inject_dma_iovas(&iovas, &iovas);
+                    (0..MAPS)
+                        .filter(|i| {
+                            let iova = iovas[(t * MAPS + i) as usize];
+                            container.vfio_dma_map(iova, 0x1000, 0x8000).is_ok()
+                        })
+                        .count()
+                })
+            })
+            .collect();
+        let mapped: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(mapped, 4);
+        assert_eq!(container.mapped_bytes(), 0x4000);
+        assert_eq!(container.mappings().len(), 4);
+    }
+
+    #[test]
+    fn test_vfio_iommu_info_once() {
+        const THREADS: usize = 8;
+        let container = Arc::new(create_vfio_container());
+        vfio_syscall::take_iommu_info_queries(&container);
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let container = container.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    container.iommu_page_size().unwrap()
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), Some(0x1000));
+        }
+
+        // A single initialization probes the info size, then fetches the capability chain.
+        assert_eq!(vfio_syscall::take_iommu_info_queries(&container), 2);
+    }
+
+    #[test]
+    fn test_vfio_iommu_info_bad_chain() {
+        let info_size = mem::size_of::<vfio_iommu_type1_info_v2>() as u32;
+        let ranges_size = mem::size_of::<vfio_iommu_type1_info_cap_iova_range>() as u32
+            + 2 * mem::size_of::<vfio_iova_range>() as u32;
+        let argsz =
+            info_size + ranges_size + mem::size_of::<vfio_iommu_type1_info_dma_avail>() as u32;
+        vfio_syscall::inject_dma_limit(Some(16));
+        let container = create_vfio_container();
+        assert_eq!(container.dma_avail().unwrap(), Some(16));
+
+        // A chain looping back on itself, or ending past the buffer, stops the walk.
+        for next in [info_size, argsz - 2, u32::MAX] {
+            vfio_syscall::inject_iova_ranges_cap(Some(next), None);
+            assert_eq!(container.dma_avail().unwrap(), None);
+        }
+
+        // Ranges overflowing the capability are ignored.
+        vfio_syscall::inject_iova_ranges_cap(None, Some(u32::MAX));
+        let container = create_vfio_container();
+        assert!(container.valid_iova_ranges().unwrap().is_empty());
+        assert_eq!(container.dma_avail().unwrap(), Some(16));
+
+        vfio_syscall::inject_iova_ranges_cap(None, None);
+        vfio_syscall::inject_dma_limit(None);
+    }
+
+    #[test]
+    fn test_vfio_dma_punch_hole() {
+        let container = create_vfio_container();
+        let rw = VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE;
+        vfio_syscall::inject_dma_iovas(
+            &[0x10000, 0x14000, 0x18000, 0x30000, 0x32000, 0x40000],
+            &[0x10000, 0x30000, 0x32000, 0x40000],
+        );
+
+        container.vfio_dma_map(0x10000, 0x10000, 0x80000).unwrap();
+        assert!(matches!(
+            container.vfio_dma_punch_hole(0x8000, 0x1000),
+            Err(VfioError::IommuDmaNoMapping { .. })
+        ));
+        assert!(matches!(
+            container.vfio_dma_punch_hole(0x1c000, 0x8000),
+            Err(VfioError::IommuDmaNoMapping { .. })
+        ));
+        container.vfio_dma_punch_hole(0x10000, 0).unwrap_err();
+
+        container.vfio_dma_punch_hole(0x14000, 0x4000).unwrap();
+        assert_eq!(
+            container.mappings(),
+            vec![
+                VfioDmaMapping {
+                    iova: Iova(0x10000),
+                    size: 0x4000,
+                    user_addr: HostVa(0x80000),
+                    flags: rw,
+                },
+                VfioDmaMapping {
+                    iova: Iova(0x18000),
+                    size: 0x8000,
+                    user_addr: HostVa(0x88000),
+                    flags: rw,
+                },
+            ]
+        );
+
+        // The whole mapping is the hole.
+        container.vfio_dma_punch_hole(0x10000, 0x4000).unwrap();
+        assert_eq!(container.mappings().len(), 1);
+        assert_eq!(container.mappings()[0].iova, Iova(0x18000));
+
+        // Remapping the upper part fails, the original mapping is restored.
+        container.vfio_dma_map(0x30000, 0x8000, 0x90000).unwrap();
+        assert!(matches!(
+            container.vfio_dma_punch_hole(0x32000, 0x1000),
+            Err(VfioError::IommuDmaMap(_))
+        ));
+        assert_eq!(
+            container.mappings()[1],
+            VfioDmaMapping {
+                iova: Iova(0x30000),
+                size: 0x8000,
+                user_addr: HostVa(0x90000),
+                flags: rw,
+            }
+        );
+
+        // Restoring fails as well.
+        vfio_syscall::inject_dma_iovas(&[0x1000], &[0x30000]);
+        assert!(matches!(
+            container.vfio_dma_punch_hole(0x30000, 0x1000),
+            Err(VfioError::IommuDmaRestore { .. })
+        ));
+        assert_eq!(container.mappings().len(), 1);
+        vfio_syscall::inject_dma_iovas(&[], &[]);
+    }
+
+    #[test]
+    fn test_region_pgsize_report() {
+        const PGSIZES: u64 = 0x4020_1000;
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x4000_0000, 0x7f00_0000_0000, 1 << 30);
+        assert_eq!(report.pgsize, 1 << 30);
+        assert_eq!(report.limited_by, None);
+
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x20_0000, 0x7f00_0020_0000, 1 << 30);
+        assert_eq!(report.pgsize, 0x20_0000);
+        assert_eq!(report.limited_by, Some(PgsizeLimit::IovaMisaligned));
+
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x20_0000, 0x7f00_0000_1000, 0x40_0000);
+        assert_eq!(report.pgsize, 0x1000);
+        assert_eq!(report.limited_by, Some(PgsizeLimit::VaddrMisaligned));
+
+        // Too small for 1G pages.
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x20_0000, 0x7f00_0020_0000, 0x40_0000);
+        assert_eq!(report.pgsize, 0x20_0000);
+        assert_eq!(report.limited_by, None);
+
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x20_0000, 0x7f00_0020_0000, 0x20_1000);
+        assert_eq!(report.pgsize, 0x1000);
+        assert_eq!(report.limited_by, Some(PgsizeLimit::SizeRemainder));
+
+        let report = RegionPgsizeReport::new(PGSIZES, 0, 0x800, 0x7f00_0000_0000, 0x1000);
+        assert_eq!(report.pgsize, 0);
+        assert_eq!(report.limited_by, Some(PgsizeLimit::IovaMisaligned));
+
+        // The mock IOMMU only supports 4K pages.
+        let container = create_vfio_container();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0x1000), 0x40_0000)]).unwrap();
+        let reports = container.mapping_pgsize_report(&mem).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].guest_addr, 0x1000);
+        assert_eq!(reports[0].iova, 0x1000);
+        assert_eq!(reports[0].size, 0x40_0000);
+        assert_eq!(reports[0].pgsize, 0x1000);
+        assert_eq!(reports[0].limited_by, None);
+
+        vfio_syscall::inject_iommu_pgsizes(0);
+        let container = create_vfio_container();
+        assert!(matches!(
+            container.mapping_pgsize_report(&mem),
+            Err(VfioError::IommuPgsizesUnsupported)
+        ));
+        vfio_syscall::inject_iommu_pgsizes(0x1000);
+    }
+
     #[test]
     fn test_vfio_map_guest_memory() {
         let addr1 = GuestAddress(0x1000);
@@ -1450,4 +9009,127 @@ mod tests {
 
         container.vfio_unmap_guest_memory(&mem1).unwrap();
     }
+
+    #[test]
+    fn test_vfio_unmap_guest_memory_best_effort() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+            (GuestAddress(0x20_0000), 0x1000),
+        ])
+        .unwrap();
+        let container = create_vfio_container();
+
+        // Mapping stops at the last region, and the second one can't be unmapped.
+        vfio_syscall::inject_dma_iovas(&[0x10_0000], &[]);
+        container.vfio_map_guest_memory(&mem).unwrap_err();
+        let report = match container.vfio_unmap_guest_memory_best_effort(&mem) {
+            Err(VfioError::GuestMemoryUnmap(report)) => report,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(report.regions.len(), 3);
+        assert_eq!(report.regions[0].outcome, UnmapOutcome::Unmapped);
+        assert!(matches!(report.regions[1].outcome, UnmapOutcome::Failed(_)));
+        assert_eq!(
+            report.regions[2],
+            RegionUnmap {
+                guest_addr: 0x20_0000,
+                size: 0x1000,
+                outcome: UnmapOutcome::NotMapped,
+            }
+        );
+        assert_eq!(report.failed(), 1);
+        assert!(report
+            .to_string()
+            .starts_with("1 unmapped, 1 not mapped, 1 failed; 0x100000+0x2000: "));
+
+        vfio_syscall::inject_dma_iovas(&[], &[0x10_0000]);
+        let report = container.vfio_unmap_guest_memory_best_effort(&mem).unwrap();
+        assert_eq!(
+            report.regions.iter().map(|r| r.outcome).collect::<Vec<_>>(),
+            vec![
+                UnmapOutcome::NotMapped,
+                UnmapOutcome::Unmapped,
+                UnmapOutcome::NotMapped
+            ]
+        );
+        assert!(container.mappings().is_empty());
+        vfio_syscall::inject_dma_iovas(&[], &[]);
+    }
+
+    #[test]
+    fn test_vfio_map_guest_memory_with_progress() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+            (GuestAddress(0x20_0000), 0x1000),
+        ])
+        .unwrap();
+        let container = create_vfio_container();
+        vfio_syscall::inject_dma_iovas(&[0x10_0000, 0x20_0000], &[]);
+
+        let mut calls = Vec::new();
+        container
+            .vfio_map_guest_memory_with_progress(&mem, |mapped, total| calls.push((mapped, total)))
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![(0x1000, 0x4000), (0x3000, 0x4000), (0x4000, 0x4000)]
+        );
+        assert_eq!(container.mapped_bytes(), 0x4000);
+
+        // Progress stops at the failing region.
+        let container = create_vfio_container();
+        vfio_syscall::inject_dma_iovas(&[0x20_0000], &[]);
+        calls.clear();
+        container
+            .vfio_map_guest_memory_with_progress(&mem, |mapped, total| calls.push((mapped, total)))
+            .unwrap_err();
+        assert_eq!(calls, vec![(0x1000, 0x4000)]);
+        vfio_syscall::inject_dma_iovas(&[], &[]);
+    }
+
+    #[test]
+    fn test_vfio_unmap_guest_memory_chunks() {
+        const CHUNK: u64 = 0x20_0000;
+        let start = GuestAddress(0x20_0000);
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(start, 3 * CHUNK as usize)]).unwrap();
+        let container = create_vfio_container();
+        let iovas = [start.0, start.0 + CHUNK, start.0 + 2 * CHUNK];
+        vfio_syscall::inject_dma_iovas(&iovas, &iovas);
+
+        for iova in iovas.iter() {
+            let host_addr = mem.get_host_address(GuestAddress(*iova)).unwrap();
+            container
+                .vfio_dma_map(*iova, CHUNK, host_addr as u64)
+                .unwrap();
+        }
+        vfio_syscall::take_unmap_dma_log();
+        container.vfio_unmap_guest_memory(&mem).unwrap();
+
+        assert_eq!(
+            vfio_syscall::take_unmap_dma_log(),
+            iovas.iter().map(|iova| (*iova, CHUNK)).collect::<Vec<_>>()
+        );
+        assert!(container.mappings().is_empty());
+        vfio_syscall::inject_dma_iovas(&[], &[]);
+    }
+
+    #[test]
+    fn test_vfio_map_guest_memory_iova_base() {
+        let container = Arc::new(create_vfio_container());
+        assert_eq!(container.iova_base(), 0);
+        container.share().set_iova_base(0x800);
+        assert_eq!(container.iova_base(), 0x800);
+
+        let mem1 = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0x800), 0x1000)]).unwrap();
+        container.vfio_map_guest_memory(&mem1).unwrap();
+        container.vfio_unmap_guest_memory(&mem1).unwrap();
+
+        let mem2 = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+        container.vfio_map_guest_memory(&mem2).unwrap_err();
+
+        container.set_iova_base(u64::MAX);
+        container.vfio_map_guest_memory(&mem1).unwrap_err();
+    }
 }
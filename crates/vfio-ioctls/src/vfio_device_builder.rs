@@ -0,0 +1,129 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Result, VfioContainer, VfioDevice};
+
+/// Builder of a [`VfioDevice`] opened with non-default options, see [`VfioDevice::builder()`].
+///
+/// Building without setting any option is the same as [`VfioDevice::new()`].
+pub struct VfioDeviceBuilder<'a> {
+    sysfspath: &'a Path,
+    container: Arc<VfioContainer>,
+    group_id: Option<u32>,
+    viable_wait: Option<(Duration, Duration)>,
+    mmap_fastpath: bool,
+}
+
+impl VfioDevice {
+    /// Get a builder to open a vfio device with non-default options.
+    ///
+    /// # Parameters
+    /// * `sysfspath`: specify the vfio device path in sys file system.
+    /// * `container`: the new VFIO device object will bind to this container object.
+    pub fn builder(sysfspath: &Path, container: Arc<VfioContainer>) -> VfioDeviceBuilder<'_> {
+        VfioDeviceBuilder {
+            sysfspath,
+            container,
+            group_id: None,
+            viable_wait: None,
+            mmap_fastpath: false,
+        }
+    }
+}
+
+impl VfioDeviceBuilder<'_> {
+    /// Use a known VFIO group id instead of resolving the `iommu_group` symlink of the device,
+    /// see [`VfioDevice::new_in_group()`].
+    pub fn group_id(mut self, group_id: u32) -> Self {
+        self.group_id = Some(group_id);
+        self
+    }
+
+    /// Wait for the group of the device to become viable if it isn't opened yet, see
+    /// [`VfioGroup::wait_viable()`](crate::VfioGroup::wait_viable).
+    ///
+    /// This overrides the wait set for the container by
+    /// [`VfioContainer::set_group_viable_wait()`]. Other groups of the container can be opened
+    /// and released while waiting.
+    ///
+    /// # Parameters
+    /// * `timeout`: time after which `VfioError::GroupViable` is returned.
+    /// * `poll_interval`: interval between two checks of the group status.
+    pub fn wait_viable(mut self, timeout: Duration, poll_interval: Duration) -> Self {
+        self.viable_wait = Some((timeout, poll_interval));
+        self
+    }
+
+    /// Copy the large transfers of `region_read()` and `region_write()` through mappings of the
+    /// regions, see [`VfioDevice::set_mmap_fastpath()`].
+    pub fn enable_mmap_fastpath(mut self) -> Self {
+        self.mmap_fastpath = true;
+        self
+    }
+
+    /// Open the device.
+    pub fn build(self) -> Result<VfioDevice> {
+        let group_id = match self.group_id {
+            Some(group_id) => group_id,
+            None => VfioDevice::get_group_id_from_path(self.sysfspath)?,
+        };
+        let group = self.container.get_group(group_id, self.viable_wait)?;
+        let device_info = group.get_device(self.sysfspath)?;
+
+        let mut device =
+            VfioDevice::from_device_info(self.sysfspath, device_info, group, self.container)?;
+        device.set_mmap_fastpath(self.mmap_fastpath);
+
+        Ok(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_ioctls::vfio_syscall;
+    use crate::VfioError;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_vfio_device_builder() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+
+        vfio_syscall::inject_group_not_viable(1);
+        assert!(matches!(
+            VfioDevice::builder(tmp_file.as_path(), container.clone()).build(),
+            Err(VfioError::GroupViable { group_id: 3, .. })
+        ));
+        assert!(container.group_ids().is_empty());
+
+        // The wait of the builder applies to the group it opens.
+        vfio_syscall::inject_group_not_viable(3);
+        let device = VfioDevice::builder(tmp_file.as_path(), container.clone())
+            .wait_viable(Duration::from_secs(5), Duration::from_millis(1))
+            .build()
+            .unwrap();
+        assert_eq!(container.group_ids(), vec![3]);
+
+        let other = VfioDevice::builder(tmp_file.as_path(), container.clone())
+            .group_id(4)
+            .enable_mmap_fastpath()
+            .build()
+            .unwrap();
+        assert_eq!(container.group_ids(), vec![3, 4]);
+        assert_eq!(other.binding.group.id(), 4);
+        assert_eq!(device.mmap_fastpath_parts(0), None);
+        let mut buf = vec![0u8; 0x1000];
+        other.region_read(0, &mut buf, 0);
+        assert_eq!(other.mmap_fastpath_parts(0), Some(0));
+        drop(device);
+        drop(other);
+        assert!(container.group_ids().is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::iter::FusedIterator;
+use std::mem;
+
+use crate::vfio_device::lock_or_recover;
+use crate::vfio_ioctls::*;
+use crate::{Iova, Result, VfioContainer, VfioError};
+
+// Pages covered by a word of the bitmap, chunk boundaries are multiples of it.
+const PAGES_PER_WORD: u64 = 64;
+
+/// Dirty pages bitmap of a chunk of IO virtual addresses, yielded by
+/// [`VfioContainer::dirty_bitmap_iter()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirtyBitmapChunk {
+    /// Size of the chunk, in bytes.
+    pub size: u64,
+    /// Size of the pages of the bitmap.
+    pub page_size: u64,
+    /// One bit per page of the chunk, bit `n % 64` of word `n / 64` for page `n`.
+    pub bitmap: Vec<u64>,
+}
+
+impl DirtyBitmapChunk {
+    /// Get the number of pages of the chunk.
+    pub fn pages(&self) -> u64 {
+        self.size / self.page_size
+    }
+
+    /// Check whether a page of the chunk is dirty.
+    ///
+    /// # Arguments
+    /// * `page` - Index of the page in the chunk.
+    pub fn is_dirty(&self, page: u64) -> bool {
+        page < self.pages()
+            && self.bitmap[(page / PAGES_PER_WORD) as usize] & (1 << (page % 64)) != 0
+    }
+
+    /// Count the dirty pages of the chunk.
+    pub fn dirty_count(&self) -> u64 {
+        self.bitmap
+            .iter()
+            .map(|word| u64::from(word.count_ones()))
+            .sum()
+    }
+
+    /// Iterate over the indexes of the dirty pages of the chunk, in ascending order.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bitmap.iter().enumerate().flat_map(|(index, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = u64::from(word.trailing_zeros());
+                word &= word - 1;
+                Some(index as u64 * PAGES_PER_WORD + bit)
+            })
+        })
+    }
+}
+
+/// Iterator fetching the dirty pages bitmap of an IOVA range chunk by chunk, returned by
+/// [`VfioContainer::dirty_bitmap_iter()`].
+pub struct DirtyBitmapIter<'a> {
+    container: &'a VfioContainer,
+    // Start of the range, chunk boundaries are whole words from it.
+    start: u64,
+    next: u64,
+    end: u64,
+    page_size: u64,
+    chunk_size: u64,
+    failed: bool,
+}
+
+impl DirtyBitmapIter<'_> {
+    // End of the chunk starting at `self.next`. The kernel refuses ranges splitting a DMA
+    // mapping, so a chunk ending inside one is extended to the first word boundary past it.
+    fn chunk_end(&self) -> u64 {
+        let word_size = PAGES_PER_WORD * self.page_size;
+        let mappings = lock_or_recover(&self.container.mappings);
+        let mut end = self.next.saturating_add(self.chunk_size).min(self.end);
+        while end < self.end {
+            let mapping_end = match mappings.range(..end).next_back() {
+                Some((iova, m)) if iova + m.size > end => iova + m.size,
+                _ => break,
+            };
+            let words = (mapping_end - self.start).div_ceil(word_size);
+            end = self
+                .start
+                .saturating_add(words.saturating_mul(word_size))
+                .min(self.end);
+        }
+
+        end
+    }
+}
+
+impl Iterator for DirtyBitmapIter<'_> {
+    type Item = Result<(Iova, DirtyBitmapChunk)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.next >= self.end {
+            return None;
+        }
+
+        let start = self.next;
+        let size = self.chunk_end() - start;
+        let words = (size / self.page_size).div_ceil(PAGES_PER_WORD);
+        let mut bitmap = vec![0u64; words as usize];
+        if let Err(e) = self
+            .container
+            .get_dirty_bitmap(start, size, self.page_size, &mut bitmap)
+        {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        self.next = start + size;
+
+        let chunk = DirtyBitmapChunk {
+            size,
+            page_size: self.page_size,
+            bitmap,
+        };
+        Some(Ok((Iova(start), chunk)))
+    }
+}
+
+impl FusedIterator for DirtyBitmapIter<'_> {}
+
+impl VfioContainer {
+    fn dirty_pages(&self, dirty: &vfio_iommu_type1_dirty_bitmap_get) -> Result<()> {
+        vfio_syscall::dirty_pages(self, dirty)?;
+        // The containers of their own of the groups track the pages of their devices.
+        for container in lock_or_recover(&self.group_containers).values() {
+            vfio_syscall::dirty_pages(container, dirty)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_dirty_tracking(&self, flags: u32) -> Result<()> {
+        let dirty = vfio_iommu_type1_dirty_bitmap_get {
+            argsz: (mem::size_of::<u32>() * 2) as u32,
+            flags,
+            ..Default::default()
+        };
+
+        self.dirty_pages(&dirty)
+    }
+
+    /// Start tracking the pages dirtied by DMA in the mappings of the container.
+    ///
+    /// Needed before fetching dirty pages bitmaps with
+    /// [`dirty_bitmap_iter()`](Self::dirty_bitmap_iter).
+    pub fn start_dirty_tracking(&self) -> Result<()> {
+        self.set_dirty_tracking(VFIO_IOMMU_DIRTY_PAGES_FLAG_START)
+    }
+
+    /// Stop tracking the pages dirtied by DMA in the mappings of the container.
+    pub fn stop_dirty_tracking(&self) -> Result<()> {
+        self.set_dirty_tracking(VFIO_IOMMU_DIRTY_PAGES_FLAG_STOP)
+    }
+
+    // Fetch the dirty pages bitmap of a range, merging those of the group containers.
+    fn get_dirty_bitmap(
+        &self,
+        iova: u64,
+        size: u64,
+        page_size: u64,
+        bitmap: &mut [u64],
+    ) -> Result<()> {
+        let mut dirty = vfio_iommu_type1_dirty_bitmap_get {
+            argsz: mem::size_of::<vfio_iommu_type1_dirty_bitmap_get>() as u32,
+            flags: VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP,
+            iova,
+            size,
+            pgsize: page_size,
+            bitmap_size: mem::size_of_val(bitmap) as u64,
+            data: bitmap.as_mut_ptr() as u64,
+        };
+        vfio_syscall::dirty_pages(self, &dirty)?;
+
+        let group_containers = lock_or_recover(&self.group_containers);
+        if !group_containers.is_empty() {
+            let mut group_bitmap = vec![0u64; bitmap.len()];
+            dirty.data = group_bitmap.as_mut_ptr() as u64;
+            for container in group_containers.values() {
+                group_bitmap.iter_mut().for_each(|word| *word = 0);
+                vfio_syscall::dirty_pages(container, &dirty)?;
+                for (word, group_word) in bitmap.iter_mut().zip(group_bitmap.iter()) {
+                    *word |= group_word;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the dirty pages bitmap of an IOVA range in chunks.
+    ///
+    /// Each chunk is fetched by its own `VFIO_IOMMU_DIRTY_PAGES` call when the iterator
+    /// advances, so that a migration loop can send the dirty pages of a chunk before fetching
+    /// the next one, rather than walking the whole range at once with a bitmap buffer of one bit
+    /// per page. Chunks are yielded with their first IO virtual address.
+    ///
+    /// Chunks cover `chunk_bytes` rounded down to a multiple of 64 pages, and at least 64 pages,
+    /// so that the bitmap words of consecutive chunks don't overlap. The last chunk covers the
+    /// rest of the range. The kernel refuses to split a DMA mapping, so a chunk which would end
+    /// inside one is extended to the first multiple of 64 pages past its end. The tracking must
+    /// have been started with [`start_dirty_tracking()`](Self::start_dirty_tracking). The
+    /// iteration ends after yielding an error.
+    ///
+    /// # Arguments
+    /// * `iova` - Start of the range, aligned to `page_size`.
+    /// * `size` - Size of the range, a multiple of `page_size`.
+    /// * `page_size` - Size of the pages of the bitmap, the smallest IOMMU page size.
+    /// * `chunk_bytes` - Size of the IOVA space covered by each chunk.
+    pub fn dirty_bitmap_iter(
+        &self,
+        iova: Iova,
+        size: u64,
+        page_size: u64,
+        chunk_bytes: u64,
+    ) -> Result<DirtyBitmapIter<'_>> {
+        let iova = iova.raw_value();
+        let end = iova.checked_add(size);
+        if !page_size.is_power_of_two()
+            || iova & (page_size - 1) != 0
+            || size & (page_size - 1) != 0
+            || end.is_none()
+        {
+            return Err(VfioError::InvalidDirtyBitmapRange {
+                iova,
+                size,
+                page_size,
+            });
+        }
+
+        let word_size = PAGES_PER_WORD.saturating_mul(page_size);
+        Ok(DirtyBitmapIter {
+            container: self,
+            start: iova,
+            next: iova,
+            end: end.unwrap(),
+            page_size,
+            chunk_size: (chunk_bytes / word_size).max(1).saturating_mul(word_size),
+            failed: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+
+    const PAGE: u64 = 0x1000;
+    const WORD: u64 = PAGES_PER_WORD * PAGE;
+
+    fn chunk_ranges(iter: DirtyBitmapIter) -> Vec<(u64, u64)> {
+        iter.map(|chunk| {
+            let (iova, chunk) = chunk.unwrap();
+            (iova.raw_value(), chunk.size)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_dirty_bitmap_chunk() {
+        let chunk = DirtyBitmapChunk {
+            size: 80 * PAGE,
+            page_size: PAGE,
+            bitmap: vec![1 << 3 | 1 << 63, 1 << 4],
+        };
+        assert_eq!(chunk.pages(), 80);
+        assert!(chunk.is_dirty(3));
+        assert!(chunk.is_dirty(68));
+        assert!(!chunk.is_dirty(4));
+        assert!(!chunk.is_dirty(128));
+        assert_eq!(chunk.dirty_count(), 3);
+        assert_eq!(chunk.dirty_pages().collect::<Vec<u64>>(), vec![3, 63, 68]);
+    }
+
+    #[test]
+    fn test_dirty_bitmap_iter() {
+        let container = create_vfio_container();
+        container.start_dirty_tracking().unwrap();
+        container.stop_dirty_tracking().unwrap();
+        assert_eq!(
+            vfio_syscall::take_dirty_pages_log(),
+            vec![
+                (VFIO_IOMMU_DIRTY_PAGES_FLAG_START, 0, 0),
+                (VFIO_IOMMU_DIRTY_PAGES_FLAG_STOP, 0, 0)
+            ]
+        );
+
+        // Chunks are rounded down to whole words, with a trailing partial chunk.
+        vfio_syscall::inject_dirty_pages(&[0, WORD + PAGE, 7 * WORD + 32 * PAGE], None);
+        let size = 7 * WORD + 48 * PAGE;
+        let chunks: Vec<(Iova, DirtyBitmapChunk)> = container
+            .dirty_bitmap_iter(Iova(0), size, PAGE, WORD + PAGE)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 8);
+        for (i, (iova, chunk)) in chunks.iter().take(7).enumerate() {
+            assert_eq!(iova.raw_value(), i as u64 * WORD);
+            assert_eq!(chunk.size, WORD);
+            assert_eq!(chunk.bitmap.len(), 1);
+        }
+        assert_eq!(chunks[0].1.dirty_pages().collect::<Vec<u64>>(), vec![0]);
+        assert_eq!(chunks[1].1.dirty_pages().collect::<Vec<u64>>(), vec![1]);
+        assert_eq!(chunks[2].1.dirty_count(), 0);
+        let (iova, last) = &chunks[7];
+        assert_eq!((iova.raw_value(), last.size), (7 * WORD, 48 * PAGE));
+        assert_eq!(last.dirty_pages().collect::<Vec<u64>>(), vec![32]);
+        let log = vfio_syscall::take_dirty_pages_log();
+        assert_eq!(log.len(), 8);
+        assert_eq!(
+            log[7],
+            (VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP, 7 * WORD, 48 * PAGE)
+        );
+
+        // Chunks don't split DMA mappings.
+        vfio_syscall::inject_dma_iovas(&[WORD + 0x30000], &[]);
+        container
+            .vfio_dma_map(WORD + 0x30000, 0x20000, 0x10000)
+            .unwrap();
+        let iter = container
+            .dirty_bitmap_iter(Iova(0), 4 * WORD, PAGE, 2 * WORD)
+            .unwrap();
+        assert_eq!(chunk_ranges(iter), vec![(0, 3 * WORD), (3 * WORD, WORD)]);
+        let iter = container
+            .dirty_bitmap_iter(Iova(0), 4 * WORD, PAGE, WORD)
+            .unwrap();
+        assert_eq!(
+            chunk_ranges(iter),
+            vec![(0, WORD), (WORD, 2 * WORD), (3 * WORD, WORD)]
+        );
+    }
+
+    #[test]
+    fn test_dirty_bitmap_iter_errors() {
+        let container = create_vfio_container();
+        for &(iova, size, page_size) in &[
+            (PAGE / 2, WORD, PAGE),
+            (0, WORD + 1, PAGE),
+            (0, WORD, 0x3000),
+            (u64::MAX - PAGE + 1, 2 * PAGE, PAGE),
+        ] {
+            assert!(matches!(
+                container.dirty_bitmap_iter(Iova(iova), size, page_size, WORD),
+                Err(VfioError::InvalidDirtyBitmapRange { .. })
+            ));
+        }
+
+        // A failure ends the iteration.
+        vfio_syscall::inject_dirty_pages(&[], Some(WORD));
+        let mut iter = container
+            .dirty_bitmap_iter(Iova(0), 4 * WORD, PAGE, WORD)
+            .unwrap();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(
+            iter.next(),
+            Some(Err(VfioError::IommuDirtyPages(_)))
+        ));
+        assert!(iter.next().is_none());
+        assert_eq!(vfio_syscall::take_dirty_pages_log().len(), 2);
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// An IO virtual address, as seen by devices doing DMA through the IOMMU.
+///
+/// Guest physical addresses are `vm_memory::GuestAddress`, they are turned into IO virtual
+/// addresses by the IOVA base of the container when mapping guest memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Iova(pub u64);
+
+/// A host virtual address, in the address space of the process mapping memory for DMA.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct HostVa(pub u64);
+
+impl Iova {
+    /// Get the raw address.
+    pub fn raw_value(self) -> u64 {
+        self.0
+    }
+
+    /// Add an offset to the address, returning `None` on overflow.
+    pub fn checked_add(self, offset: u64) -> Option<Self> {
+        self.0.checked_add(offset).map(Iova)
+    }
+}
+
+impl HostVa {
+    /// Get the raw address.
+    pub fn raw_value(self) -> u64 {
+        self.0
+    }
+
+    /// Add an offset to the address, returning `None` on overflow.
+    pub fn checked_add(self, offset: u64) -> Option<Self> {
+        self.0.checked_add(offset).map(HostVa)
+    }
+
+    /// Get the address of a buffer.
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        HostVa(ptr as u64)
+    }
+}
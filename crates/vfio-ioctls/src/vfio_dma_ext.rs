@@ -0,0 +1,113 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use vm_memory::{GuestAddress, GuestUsize};
+use vmm_sys_util::errno::Error as SysError;
+
+use crate::{Result, VfioContainer, VfioError};
+
+/// DMA mapping of guest memory with the `vm_memory` address types.
+///
+/// This is a thin layer over [`VfioContainer::vfio_dma_map()`] and
+/// [`VfioContainer::vfio_dma_unmap()`], which stay the canonical API. Guest physical addresses
+/// are turned into IO virtual addresses with the IOVA base of the container, as done by
+/// `vfio_map_guest_memory()`, and ranges wrapping around the address space are refused with
+/// `EOVERFLOW` rather than truncated.
+pub trait VfioDmaExt {
+    /// Map `len` bytes of host memory at `host` for DMA at the guest physical address `addr`.
+    ///
+    /// # Parameters
+    /// * addr: guest physical address to map the memory at.
+    /// * len: size of the memory region.
+    /// * host: host virtual address of the memory region to map.
+    fn dma_map_region(&self, addr: GuestAddress, len: GuestUsize, host: *const u8) -> Result<()>;
+
+    /// Unmap `len` bytes of guest memory mapped at the guest physical address `addr`.
+    ///
+    /// # Parameters
+    /// * addr: guest physical address of the mapped memory.
+    /// * len: size of the memory region.
+    fn dma_unmap_region(&self, addr: GuestAddress, len: GuestUsize) -> Result<()>;
+}
+
+// Get the IO virtual address of [addr, addr + len), which must not wrap around.
+fn region_iova(container: &VfioContainer, addr: GuestAddress, len: GuestUsize) -> Option<u64> {
+    let iova = container.guest_iova(addr)?;
+    iova.checked_add(len.checked_sub(1)?)?;
+    Some(iova)
+}
+
+impl VfioDmaExt for VfioContainer {
+    fn dma_map_region(&self, addr: GuestAddress, len: GuestUsize, host: *const u8) -> Result<()> {
+        let iova = region_iova(self, addr, len)
+            .ok_or_else(|| VfioError::IommuDmaMap(SysError::new(libc::EOVERFLOW)))?;
+        self.vfio_dma_map(iova, len, host as u64)
+    }
+
+    fn dma_unmap_region(&self, addr: GuestAddress, len: GuestUsize) -> Result<()> {
+        let iova = region_iova(self, addr, len)
+            .ok_or_else(|| VfioError::IommuDmaUnmap(SysError::new(libc::EOVERFLOW)))?;
+        self.vfio_dma_unmap(iova, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_ioctls::vfio_syscall;
+
+    #[test]
+    fn test_vfio_dma_ext() {
+        let container = create_vfio_container();
+        let host = 0x8000 as *const u8;
+        container
+            .dma_map_region(GuestAddress(0x1000), 0x1000, host)
+            .unwrap();
+        assert_eq!(container.mapped_bytes(), 0x1000);
+        container
+            .dma_unmap_region(GuestAddress(0x1000), 0x1000)
+            .unwrap();
+        assert_eq!(container.mapped_bytes(), 0);
+
+        // A region may end at the very last address, it is then up to the IOMMU to refuse it.
+        let last = u64::MAX - 0xfff;
+        vfio_syscall::inject_dma_iovas(&[], &[last]);
+        assert!(matches!(
+            container.dma_map_region(GuestAddress(last), 0x1000, host),
+            Err(VfioError::IommuDmaMapCrossWindow { iova, size: 0x1000 }) if iova == last
+        ));
+        container
+            .dma_unmap_region(GuestAddress(last), 0x1000)
+            .unwrap();
+
+        // One byte further, or the IOVA base pushing it further, it wraps around.
+        for (addr, len) in [(last + 1, 0x1000), (last, 0x1001), (u64::MAX, 2)] {
+            assert!(matches!(
+                container.dma_map_region(GuestAddress(addr), len, host),
+                Err(VfioError::IommuDmaMap(e)) if e.errno() == libc::EOVERFLOW
+            ));
+            assert!(matches!(
+                container.dma_unmap_region(GuestAddress(addr), len),
+                Err(VfioError::IommuDmaUnmap(e)) if e.errno() == libc::EOVERFLOW
+            ));
+        }
+        container.set_iova_base(0x1000);
+        assert!(matches!(
+            container.dma_map_region(GuestAddress(last), 0x1000, host),
+            Err(VfioError::IommuDmaMap(e)) if e.errno() == libc::EOVERFLOW
+        ));
+
+        // Guest addresses are offset by the IOVA base.
+        container.set_iova_base(0x10_0000);
+        vfio_syscall::inject_dma_iovas(&[0x10_1000], &[0x10_1000]);
+        container
+            .dma_map_region(GuestAddress(0x1000), 0x1000, host)
+            .unwrap();
+        assert_eq!(container.mappings()[0].iova.raw_value(), 0x10_1000);
+        container
+            .dma_unmap_region(GuestAddress(0x1000), 0x1000)
+            .unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::io::BufRead;
+use std::time::Duration;
+
+use crate::{Result, VfioDevice, VfioError};
+
+/// IOMMU reporting a DMA fault.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmaFaultIommu {
+    /// Intel VT-d, reporting faults as `DMAR:` kernel messages.
+    IntelVtd,
+    /// AMD-Vi, reporting faults as `AMD-Vi: Event logged` kernel messages.
+    AmdVi,
+}
+
+/// A DMA fault reported by the IOMMU in the kernel log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmaFaultRecord {
+    /// IOMMU which reported the fault.
+    pub iommu: DmaFaultIommu,
+    /// PCI address of the faulting device, `0000:03:00.0` for instance.
+    pub device: String,
+    /// IO virtual address the device accessed.
+    pub iova: u64,
+    /// Fault reason, `PTE Read access is not set` or `IO_PAGE_FAULT flags=0x0020` for instance.
+    pub reason: String,
+    /// Time since boot of the kernel message, if it has a timestamp.
+    pub timestamp: Option<Duration>,
+}
+
+// Parse a hexadecimal number with or without the 0x prefix.
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.trim();
+    u64::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+// Prefix a PCI address without domain with the default one.
+fn full_pci_address(address: &str) -> String {
+    if address.matches(':').count() == 1 {
+        format!("0000:{}", address)
+    } else {
+        address.to_string()
+    }
+}
+
+// Split the `[  123.456789] ` timestamp of a kernel message.
+fn split_timestamp(line: &str) -> (Option<Duration>, &str) {
+    let timestamp = line.trim_start().strip_prefix('[').and_then(|rest| {
+        let (timestamp, rest) = rest.split_once(']')?;
+        let (secs, fraction) = timestamp.trim().split_once('.')?;
+        if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // The kernel prints microseconds, but the fraction is scaled by its actual number of
+        // digits, keeping up to nanoseconds.
+        let digits = &fraction[..fraction.len().min(9)];
+        let nanos = digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32);
+        let secs = Duration::from_secs(secs.parse().ok()?);
+        Some((secs + Duration::from_nanos(u64::from(nanos)), rest))
+    });
+    match timestamp {
+        Some((timestamp, rest)) => (Some(timestamp), rest),
+        None => (None, line),
+    }
+}
+
+// DMAR: [DMA Read NO_PASID] Request device [03:00.0] fault addr 0xfffe0000 [fault reason 0x06]
+// PTE Read access is not set
+fn parse_intel(msg: &str) -> Option<(String, u64, String)> {
+    let (_, rest) = msg.split_once("DMAR: [")?;
+    let (_, rest) = rest.split_once("Request device [")?;
+    let (device, rest) = rest.split_once(']')?;
+    let (_, rest) = rest.split_once("fault addr ")?;
+    let addr = rest.split_whitespace().next()?;
+    let iova = parse_hex(addr)?;
+    let (_, rest) = rest.split_once("[fault reason ")?;
+    let (code, description) = rest.split_once(']')?;
+    let reason = match description.trim() {
+        "" => format!("fault reason {}", code.trim()),
+        description => description.to_string(),
+    };
+
+    Some((full_pci_address(device.trim()), iova, reason))
+}
+
+// AMD-Vi: Event logged [IO_PAGE_FAULT device=03:00.0 domain=0x0000 address=0xfffe0000 flags=0x0020]
+// Recent kernels print the device as the message prefix instead:
+// vfio-pci 0000:03:00.0: AMD-Vi: Event logged [IO_PAGE_FAULT domain=0x000e address=0xfd3a8000 flags=0x0000]
+fn parse_amd(msg: &str) -> Option<(String, u64, String)> {
+    let (prefix, rest) = msg.split_once("AMD-Vi: Event logged [")?;
+    let (event, _) = rest.split_once(']')?;
+    let mut fields = event.split_whitespace();
+    let mut reason = fields.next()?.to_string();
+    let mut device = prefix
+        .trim_end()
+        .strip_suffix(':')
+        .and_then(|prefix| prefix.split_whitespace().last())
+        .map(str::to_string);
+    let mut iova = None;
+    for field in fields {
+        match field.split_once('=') {
+            Some(("device", value)) => device = Some(value.to_string()),
+            Some(("address", value)) => iova = parse_hex(value),
+            Some(("flags", _)) => {
+                reason.push(' ');
+                reason.push_str(field);
+            }
+            _ => {}
+        }
+    }
+
+    Some((full_pci_address(&device?), iova?, reason))
+}
+
+/// Parse a DMA fault from a kernel log line, as printed by `dmesg` or read from `/dev/kmsg`.
+///
+/// Intel VT-d `DMAR:` faults and AMD-Vi `Event logged` events carrying an address are
+/// recognized, `None` is returned for any other line.
+///
+/// # Arguments
+/// * `line` - A kernel log line.
+pub fn parse_dma_fault(line: &str) -> Option<DmaFaultRecord> {
+    let (timestamp, msg) = split_timestamp(line);
+    let (iommu, (device, iova, reason)) = if let Some(fault) = parse_intel(msg) {
+        (DmaFaultIommu::IntelVtd, fault)
+    } else {
+        (DmaFaultIommu::AmdVi, parse_amd(msg)?)
+    };
+
+    Some(DmaFaultRecord {
+        iommu,
+        device,
+        iova,
+        reason,
+        timestamp,
+    })
+}
+
+/// Parse the DMA faults of a kernel log, in log order.
+///
+/// # Arguments
+/// * `log` - Kernel log, as printed by `dmesg` for instance.
+pub fn parse_dma_faults<R: BufRead>(log: R) -> Result<Vec<DmaFaultRecord>> {
+    let mut faults = Vec::new();
+    for line in log.lines() {
+        let line = line.map_err(VfioError::ReadDmaFaults)?;
+        if let Some(fault) = parse_dma_fault(&line) {
+            faults.push(fault);
+        }
+    }
+
+    Ok(faults)
+}
+
+impl VfioDevice {
+    /// Get the DMA faults of the device reported in a kernel log.
+    ///
+    /// The container interface has no fault reporting, so the faults are correlated from the
+    /// IOMMU driver messages, see [`parse_dma_faults()`]. The caller provides the log, from
+    /// `dmesg` or an ftrace buffer for instance, and decides how far back it goes. Faults are
+    /// matched with the PCI address of the device, so none are found for mediated devices.
+    ///
+    /// # Arguments
+    /// * `log` - Kernel log to look for faults in.
+    pub fn recent_dma_faults<R: BufRead>(&self, log: R) -> Result<Vec<DmaFaultRecord>> {
+        let name = full_pci_address(&self.name());
+        let mut faults = parse_dma_faults(log)?;
+        faults.retain(|fault| fault.device == name);
+
+        Ok(faults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::io::{self, BufReader, Read};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const KERNEL_LOG: &str = "\
+[    0.000000] Linux version 6.1.0
+[  120.000100] DMAR: DRHD: handling fault status reg 2
+[  120.000200] DMAR: [DMA Read NO_PASID] Request device [03:00.0] fault addr 0xfffe0000 [fault reason 0x06] PTE Read access is not set
+[  121.500000] DMAR: [DMA Write] Request device [04:00.0] PASID ffffffff fault addr fffd0000 [fault reason 05]
+[  122.000000] AMD-Vi: Event logged [IO_PAGE_FAULT device=03:00.0 domain=0x0000 address=0x00000000fffc0000 flags=0x0020]
+[  123.250000] vfio-pci 0000:03:00.0: AMD-Vi: Event logged [IO_PAGE_FAULT domain=0x000e address=0xfd3a8000 flags=0x0000]
+[  124.000000] AMD-Vi: Event logged [ILLEGAL_DEV_TABLE_ENTRY device=05:00.0 pasid=0x00000]
+vfio-pci 0001:03:00.0: AMD-Vi: Event logged [IO_PAGE_FAULT domain=0x000e address=0x1000 flags=0x0010]
+";
+
+    #[test]
+    fn test_parse_dma_faults() {
+        let faults = parse_dma_faults(KERNEL_LOG.as_bytes()).unwrap();
+        assert_eq!(
+            faults,
+            vec![
+                DmaFaultRecord {
+                    iommu: DmaFaultIommu::IntelVtd,
+                    device: "0000:03:00.0".to_string(),
+                    iova: 0xfffe_0000,
+                    reason: "PTE Read access is not set".to_string(),
+                    timestamp: Some(Duration::from_micros(120_000_200)),
+                },
+                DmaFaultRecord {
+                    iommu: DmaFaultIommu::IntelVtd,
+                    device: "0000:04:00.0".to_string(),
+                    iova: 0xfffd_0000,
+                    reason: "fault reason 05".to_string(),
+                    timestamp: Some(Duration::from_millis(121_500)),
+                },
+                DmaFaultRecord {
+                    iommu: DmaFaultIommu::AmdVi,
+                    device: "0000:03:00.0".to_string(),
+                    iova: 0xfffc_0000,
+                    reason: "IO_PAGE_FAULT flags=0x0020".to_string(),
+                    timestamp: Some(Duration::from_secs(122)),
+                },
+                DmaFaultRecord {
+                    iommu: DmaFaultIommu::AmdVi,
+                    device: "0000:03:00.0".to_string(),
+                    iova: 0xfd3a_8000,
+                    reason: "IO_PAGE_FAULT flags=0x0000".to_string(),
+                    timestamp: Some(Duration::from_millis(123_250)),
+                },
+                DmaFaultRecord {
+                    iommu: DmaFaultIommu::AmdVi,
+                    device: "0001:03:00.0".to_string(),
+                    iova: 0x1000,
+                    reason: "IO_PAGE_FAULT flags=0x0010".to_string(),
+                    timestamp: None,
+                },
+            ]
+        );
+
+        assert_eq!(parse_dma_fault(""), None);
+        assert_eq!(
+            parse_dma_fault("[  1.0] DMAR: [DMA Read] Request device ["),
+            None
+        );
+        assert_eq!(
+            parse_dma_fault("AMD-Vi: Event logged [IO_PAGE_FAULT address=0x1000]"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_split_timestamp() {
+        for (line, timestamp) in [
+            ("[  120.000200] DMAR", Duration::from_micros(120_000_200)),
+            ("[1.5] DMAR", Duration::from_millis(1_500)),
+            ("[2.25] DMAR", Duration::from_millis(2_250)),
+            ("[3.000000004] DMAR", Duration::from_nanos(3_000_000_004)),
+            ("[4.1234567891] DMAR", Duration::from_nanos(4_123_456_789)),
+        ] {
+            assert_eq!(split_timestamp(line), (Some(timestamp), " DMAR"));
+        }
+        for line in ["[5.] DMAR", "[6.-5] DMAR", "[x.5] DMAR", "[7] DMAR", "DMAR"] {
+            assert_eq!(split_timestamp(line), (None, line));
+        }
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }
+    }
+
+    #[test]
+    fn test_vfio_device_recent_dma_faults() {
+        let container = Arc::new(create_vfio_container());
+        let device =
+            VfioDevice::new(Path::new("/sys/bus/pci/devices/0000:03:00.0"), container).unwrap();
+
+        let faults = device.recent_dma_faults(KERNEL_LOG.as_bytes()).unwrap();
+        assert_eq!(
+            faults.iter().map(|f| f.iova).collect::<Vec<u64>>(),
+            vec![0xfffe_0000, 0xfffc_0000, 0xfd3a_8000]
+        );
+
+        assert!(matches!(
+            device.recent_dma_faults(BufReader::new(FailingReader)),
+            Err(VfioError::ReadDmaFaults(_))
+        ));
+    }
+}
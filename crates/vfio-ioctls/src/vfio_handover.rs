@@ -0,0 +1,508 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use once_cell::sync::OnceCell;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use vfio_bindings::bindings::vfio::VFIO_GROUP_FLAGS_CONTAINER_SET;
+use vmm_sys_util::eventfd::EventFd;
+
+#[cfg(feature = "serde")]
+use crate::vfio_device::VFIO_REGION_INFO_MAX_SIZE;
+use crate::vfio_device::{lock_or_recover, VfioContainerDeviceHandle, VfioDeviceInfo};
+use crate::{
+    MultiGroupPolicy, Result, VfioContainer, VfioDevice, VfioDmaMapping, VfioError, VfioGroup,
+    VfioPaths,
+};
+
+/// Version of the [`ContainerStateSnapshot`] layout, bumped on incompatible changes.
+pub const VFIO_CONTAINER_STATE_VERSION: u32 = 1;
+
+/// State of a group in a [`ContainerStateSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GroupStateSnapshot {
+    /// Group ID.
+    pub id: u32,
+    /// Index of the group fd in the fd array.
+    pub fd: usize,
+}
+
+/// Tag of a DMA mapping in a [`ContainerStateSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MappingTagSnapshot {
+    /// IOVA of the mapping.
+    pub iova: u64,
+    /// Component which created the mapping.
+    pub tag: String,
+}
+
+/// State of an irq index in a [`DeviceStateSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IrqStateSnapshot {
+    /// Irq index.
+    pub index: u32,
+    /// Indexes of the EventFds of each vector in the fd array.
+    pub fds: Vec<usize>,
+}
+
+/// State of a device in a [`ContainerStateSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceStateSnapshot {
+    /// Path of the device in sys file system.
+    pub sysfspath: PathBuf,
+    /// ID of the group of the device.
+    pub group_id: u32,
+    /// Index of the device fd in the fd array.
+    pub fd: usize,
+    /// The device was opened for read-only inspection.
+    pub read_only: bool,
+    /// DMA mask set with `VfioDevice::set_dma_mask()`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dma_mask: Option<u64>,
+    /// Irq indexes enabled through `VfioDevice::enable_irq()`, sorted by index.
+    pub irqs: Vec<IrqStateSnapshot>,
+}
+
+/// State of a container and of its devices, handed over to another process.
+///
+/// File descriptors aren't part of the snapshot, which references them by index into the fd
+/// array returned along with it by [`VfioContainer::export_state()`]. That array is meant to be
+/// transferred with `SCM_RIGHTS`, or kept open across `exec()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ContainerStateSnapshot {
+    /// Layout version, [`VFIO_CONTAINER_STATE_VERSION`] for snapshots built by this crate.
+    pub version: u32,
+    /// Index of the container fd in the fd array.
+    pub fd: usize,
+    /// Directory holding the VFIO container and group character devices.
+    pub dev_vfio_root: PathBuf,
+    /// Mount point of the sysfs file system.
+    pub sysfs_root: PathBuf,
+    /// Base of the guest IOVAs.
+    pub iova_base: u64,
+    /// Maximum number of bytes mapped through the container.
+    pub mapping_budget: Option<u64>,
+    /// Maximum size of the region info fetched for the devices, capabilities included.
+    #[cfg_attr(feature = "serde", serde(default = "default_region_caps_max_size"))]
+    pub region_caps_max_size: u32,
+    /// How groups refused by the kernel are bound.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub multi_group_policy: MultiGroupPolicy,
+    /// DMA mappings sorted by IOVA.
+    pub mappings: Vec<VfioDmaMapping>,
+    /// Tags of the DMA mappings which have one, sorted by IOVA.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mapping_tags: Vec<MappingTagSnapshot>,
+    /// Groups attached to the container, sorted by ID.
+    pub groups: Vec<GroupStateSnapshot>,
+    /// Devices of the container.
+    pub devices: Vec<DeviceStateSnapshot>,
+}
+
+// Snapshots exported before the limit was part of them used the default one.
+#[cfg(feature = "serde")]
+fn default_region_caps_max_size() -> u32 {
+    VFIO_REGION_INFO_MAX_SIZE
+}
+
+fn invalid_state(msg: String) -> VfioError {
+    VfioError::InvalidContainerState(msg)
+}
+
+impl VfioContainer {
+    /// Export the state of the container and of `devices` for a live update of the VMM.
+    ///
+    /// Returns the snapshot along with the fds it references, which remain owned by the
+    /// container and the devices. Only attached groups are exported, groups detached with
+    /// `detach_group()` are left out.
+    ///
+    /// The DMA mapping watermark, if any, isn't exported and must be armed again after the
+    /// import.
+    ///
+    /// # Parameters
+    /// * `devices`: the devices of the container to hand over.
+    pub fn export_state(
+        &self,
+        devices: &[&VfioDevice],
+    ) -> Result<(ContainerStateSnapshot, Vec<RawFd>)> {
+        if !self.fallback_group_ids().is_empty() {
+            return Err(invalid_state(
+                "groups bound to a container of their own can't be exported".to_string(),
+            ));
+        }
+        let mut fds = vec![self.container.as_raw_fd()];
+        let mut push = |fd: RawFd| {
+            fds.push(fd);
+            fds.len() - 1
+        };
+
+        let hash = lock_or_recover(&self.groups);
+        let mut group_ids: Vec<u32> = hash.keys().copied().collect();
+        group_ids.sort_unstable();
+        let groups = group_ids
+            .iter()
+            .map(|id| GroupStateSnapshot {
+                id: *id,
+                fd: push(hash[id].as_raw_fd()),
+            })
+            .collect();
+
+        let mut device_states = Vec::with_capacity(devices.len());
+        for device in devices {
+            let group_id = device.binding.group.id();
+            if !std::ptr::eq(&*device.binding.container, self) || !hash.contains_key(&group_id) {
+                return Err(invalid_state(format!(
+                    "device {} doesn't belong to the container",
+                    device.sysfspath.display()
+                )));
+            }
+
+            let fd = push(device.as_raw_fd());
+            let irq_fds = lock_or_recover(&device.irq_fds);
+            let mut irqs: Vec<IrqStateSnapshot> = irq_fds
+                .iter()
+                .map(|(index, event_fds)| IrqStateSnapshot {
+                    index: *index,
+                    fds: event_fds.iter().map(|e| push(e.as_raw_fd())).collect(),
+                })
+                .collect();
+            irqs.sort_by_key(|irq| irq.index);
+
+            device_states.push(DeviceStateSnapshot {
+                sysfspath: device.sysfspath.clone(),
+                group_id,
+                fd,
+                read_only: device.read_only,
+                dma_mask: lock_or_recover(&self.dma_masks)
+                    .get(&device.id)
+                    .map(|(_, mask)| *mask),
+                irqs,
+            });
+        }
+        drop(hash);
+
+        let mapped = lock_or_recover(&self.mappings);
+        let tags = lock_or_recover(&self.mapping_tags);
+        let mut mapping_tags: Vec<MappingTagSnapshot> = tags
+            .iter()
+            .map(|(iova, tag)| MappingTagSnapshot {
+                iova: *iova,
+                tag: tag.to_string(),
+            })
+            .collect();
+        mapping_tags.sort_unstable_by_key(|t| t.iova);
+        let mappings: Vec<VfioDmaMapping> = mapped.values().copied().collect();
+        drop(tags);
+        drop(mapped);
+
+        let snapshot = ContainerStateSnapshot {
+            version: VFIO_CONTAINER_STATE_VERSION,
+            fd: 0,
+            dev_vfio_root: self.paths.dev_vfio_root.clone(),
+            sysfs_root: self.paths.sysfs_root.clone(),
+            iova_base: self.iova_base(),
+            mapping_budget: *lock_or_recover(&self.mapping_budget),
+            region_caps_max_size: self.region_caps_max_size(),
+            multi_group_policy: self.multi_group_policy(),
+            mappings,
+            mapping_tags,
+            groups,
+            devices: device_states,
+        };
+
+        Ok((snapshot, fds))
+    }
+
+    /// Rebuild a container and its devices from a snapshot exported by another process.
+    ///
+    /// No ioctl modifying the state of the container, groups or devices is issued: groups
+    /// aren't bound again, the IOMMU type isn't set, mappings and interrupts are only recorded.
+    /// Each fd is checked to be what the snapshot claims with a cheap query instead:
+    /// `VFIO_GET_API_VERSION` for the container, `VFIO_GROUP_GET_STATUS` for the groups, which
+    /// must be viable and bound to a container, and `VFIO_DEVICE_GET_INFO` for the devices,
+    /// whose regions and irqs are queried again.
+    ///
+    /// The host addresses of the mappings are the ones of the exporting process, they must be
+    /// updated with `VFIO_DMA_MAP_FLAG_VADDR` once the memory is mapped in the new process.
+    ///
+    /// The DMA mapping watermark isn't part of the snapshot, as its `EventFd` is polled by the
+    /// exporting process: callers relying on it must arm it again on the imported container
+    /// with [`set_dma_avail_watermark()`](Self::set_dma_avail_watermark).
+    ///
+    /// # Parameters
+    /// * `snapshot`: the state exported by [`export_state()`](Self::export_state).
+    /// * `fds`: the fds referenced by the snapshot, in the same order.
+    /// * `device_fd`: An optional file handle of the hypervisor VFIO device, already holding
+    ///   the groups.
+    pub fn import_state(
+        snapshot: &ContainerStateSnapshot,
+        fds: Vec<File>,
+        device_fd: Option<VfioContainerDeviceHandle>,
+    ) -> Result<(Arc<VfioContainer>, Vec<VfioDevice>)> {
+        if snapshot.version != VFIO_CONTAINER_STATE_VERSION {
+            return Err(invalid_state(format!(
+                "unsupported version {}",
+                snapshot.version
+            )));
+        }
+        let mut fds: Vec<Option<File>> = fds.into_iter().map(Some).collect();
+        let mut take = |index: usize| {
+            fds.get_mut(index)
+                .and_then(Option::take)
+                .ok_or_else(|| invalid_state(format!("missing fd {}", index)))
+        };
+
+        let mappings: BTreeMap<u64, VfioDmaMapping> = snapshot
+            .mappings
+            .iter()
+            .map(|m| (m.iova.raw_value(), *m))
+            .collect();
+        let mut mapping_tags = HashMap::with_capacity(snapshot.mapping_tags.len());
+        for t in snapshot.mapping_tags.iter() {
+            if !mappings.contains_key(&t.iova) {
+                return Err(invalid_state(format!(
+                    "tag {} of an unknown mapping at {:#x}",
+                    t.tag, t.iova
+                )));
+            }
+            mapping_tags.insert(t.iova, Cow::Owned(t.tag.clone()));
+        }
+        let container = VfioContainer {
+            container: take(snapshot.fd)?.into(),
+            device_fd,
+            groups: Mutex::new(HashMap::new()),
+            groups_owner: Mutex::new(None),
+            bound_groups: Mutex::new(snapshot.groups.iter().map(|g| g.id).collect()),
+            detached_groups: Mutex::new(HashMap::new()),
+            iova_base: AtomicU64::new(snapshot.iova_base),
+            paths: VfioPaths {
+                dev_vfio_root: snapshot.dev_vfio_root.clone(),
+                sysfs_root: snapshot.sysfs_root.clone(),
+            },
+            mappings: Mutex::new(mappings),
+            mapping_tags: Mutex::new(mapping_tags),
+            mapping_budget: Mutex::new(snapshot.mapping_budget),
+            region_caps_max_size: Mutex::new(snapshot.region_caps_max_size),
+            dma_avail_watermark: Mutex::new(None),
+            dma_masks: Mutex::new(HashMap::new()),
+            iommu_info: RwLock::new(OnceCell::new()),
+            group_viable_wait: Mutex::new(None),
+            group_transitions: Mutex::new(HashSet::new()),
+            group_transition_done: Condvar::new(),
+            multi_group_policy: Mutex::new(snapshot.multi_group_policy),
+            group_containers: Mutex::new(HashMap::new()),
+        };
+        container.check_api_version()?;
+
+        let mut groups = HashMap::new();
+        for state in snapshot.groups.iter() {
+            let group = VfioGroup::from_fd_with_paths(state.id, take(state.fd)?, &container.paths)?;
+            if group.status()? & VFIO_GROUP_FLAGS_CONTAINER_SET == 0 {
+                return Err(invalid_state(format!(
+                    "group {} isn't bound to a container",
+                    state.id
+                )));
+            }
+            groups.insert(state.id, Arc::new(group));
+        }
+
+        let mut probed = Vec::with_capacity(snapshot.devices.len());
+        for state in snapshot.devices.iter() {
+            let group = groups.get(&state.group_id).cloned().ok_or_else(|| {
+                invalid_state(format!(
+                    "group {} of device {} isn't in the snapshot",
+                    state.group_id,
+                    state.sysfspath.display()
+                ))
+            })?;
+            let device_info = VfioDeviceInfo::from_device(take(state.fd)?.into())?;
+            let mut irq_fds = HashMap::new();
+            for irq in state.irqs.iter() {
+                let mut event_fds = Vec::with_capacity(irq.fds.len());
+                for fd in irq.fds.iter() {
+                    // SAFETY: the fd was just taken from the array we own.
+                    event_fds.push(unsafe { EventFd::from_raw_fd(take(*fd)?.into_raw_fd()) });
+                }
+                irq_fds.insert(irq.index, event_fds);
+            }
+            probed.push((state, device_info, group, irq_fds));
+        }
+
+        // Keep a reference on each group until all the devices are built, so that dropping
+        // the devices on failure doesn't unbind the groups from the container.
+        let pinned: Vec<Arc<VfioGroup>> = groups.values().cloned().collect();
+        *lock_or_recover(&container.groups) = groups;
+        let container = Arc::new(container);
+
+        let mut devices = Vec::with_capacity(probed.len());
+        for (state, device_info, group, irq_fds) in probed {
+            let mut device = VfioDevice::from_device_info(
+                &state.sysfspath,
+                device_info,
+                group,
+                container.clone(),
+            )?;
+            device.read_only = state.read_only;
+            if state.dma_mask.is_some() {
+                device.set_dma_mask(state.dma_mask);
+            }
+            *device.irq_fds.get_mut().unwrap() = irq_fds;
+            devices.push(device);
+        }
+        drop(pinned);
+
+        Ok((container, devices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_ioctls::vfio_syscall;
+    use crate::{HostVa, Iova};
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Duplicate the exported fds, as received by the new process.
+    fn dup_fds(fds: &[RawFd]) -> Vec<File> {
+        fds.iter()
+            // SAFETY: the fds are valid and the duplicates are owned by the returned files.
+            .map(|fd| unsafe { File::from_raw_fd(libc::dup(*fd)) })
+            .collect()
+    }
+
+    #[test]
+    fn test_vfio_container_handover() {
+        let container = Arc::new(create_vfio_container());
+        let tmp_file = TempFile::new().unwrap();
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+        let evts = [
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        ];
+        device.enable_msix(evts.iter().collect()).unwrap();
+        container.vfio_dma_map(0x1000, 0x1000, 0x8000).unwrap();
+        vfio_syscall::inject_dma_iovas(&[0x4000], &[]);
+        container
+            .dma_map_tagged(Iova(0x4000), 0x1000, HostVa(0x8000), "virtio")
+            .unwrap();
+        container.set_mapping_budget(Some(0x10000));
+        container.set_region_caps_max_size(0x1000);
+        container.set_multi_group_policy(MultiGroupPolicy::SharedOrFallbackToPerGroup);
+        device.set_dma_mask(Some(0xffff_ffff));
+
+        let (snapshot, fds) = container.export_state(&[&device]).unwrap();
+        assert_eq!(snapshot.version, VFIO_CONTAINER_STATE_VERSION);
+        assert_eq!(snapshot.groups, vec![GroupStateSnapshot { id: 3, fd: 1 }]);
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.devices[0].fd, 2);
+        assert_eq!(snapshot.devices[0].dma_mask, Some(0xffff_ffff));
+        assert_eq!(
+            snapshot.devices[0].irqs,
+            vec![IrqStateSnapshot {
+                index: 2,
+                fds: vec![3, 4]
+            }]
+        );
+        assert_eq!(
+            snapshot.mapping_tags,
+            vec![MappingTagSnapshot {
+                iova: 0x4000,
+                tag: "virtio".to_string()
+            }]
+        );
+        assert_eq!(fds.len(), 5);
+        assert_eq!(fds[0], container.as_raw_fd());
+
+        // Groups must still be bound to the container.
+        assert!(matches!(
+            VfioContainer::import_state(&snapshot, dup_fds(&fds), None),
+            Err(VfioError::InvalidContainerState(_))
+        ));
+
+        // Nothing is set up again.
+        vfio_syscall::inject_group_container_set(true);
+        vfio_syscall::take_set_irqs_log();
+        let (imported, devices) =
+            VfioContainer::import_state(&snapshot, dup_fds(&fds), None).unwrap();
+        assert!(vfio_syscall::take_set_irqs_log().is_empty());
+        assert_eq!(imported.group_ids(), vec![3]);
+        assert_eq!(imported.groups.lock().unwrap()[&3].paths, *imported.paths());
+        assert_eq!(imported.mappings(), container.mappings());
+        assert_eq!(
+            imported.mappings_by_tag("virtio"),
+            container.mappings_by_tag("virtio")
+        );
+        assert_eq!(imported.mappings_by_tag("virtio").len(), 1);
+        assert_eq!(*imported.mapping_budget.lock().unwrap(), Some(0x10000));
+        assert_eq!(imported.region_caps_max_size(), 0x1000);
+        assert_eq!(
+            imported.multi_group_policy(),
+            MultiGroupPolicy::SharedOrFallbackToPerGroup
+        );
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].topology(), device.topology());
+        assert_eq!(devices[0].dma_mask(), Some(0xffff_ffff));
+        // Mappings beyond the DMA mask are still refused.
+        assert!(matches!(
+            imported.dma_map(Iova(0x1_0000_0000), 0x1000, HostVa(0x8000)),
+            Err(VfioError::IommuDmaMapBeyondDmaMask { .. })
+        ));
+        evts[1].write(1).unwrap();
+        assert_eq!(devices[0].drain_irq(2, 1).unwrap(), Some(1));
+
+        // Incomplete fd array, and unknown layout.
+        assert!(matches!(
+            VfioContainer::import_state(&snapshot, dup_fds(&fds[..4]), None),
+            Err(VfioError::InvalidContainerState(_))
+        ));
+        let mut future = snapshot.clone();
+        future.version += 1;
+        assert!(matches!(
+            VfioContainer::import_state(&future, dup_fds(&fds), None),
+            Err(VfioError::InvalidContainerState(_))
+        ));
+        // Tags must belong to a mapping.
+        let mut stray = snapshot.clone();
+        stray.mapping_tags[0].iova = 0x5000;
+        assert!(matches!(
+            VfioContainer::import_state(&stray, dup_fds(&fds), None),
+            Err(VfioError::InvalidContainerState(_))
+        ));
+        vfio_syscall::inject_group_container_set(false);
+
+        // Read-only devices stay read-only.
+        let read_only = VfioDevice::new_read_only(tmp_file.as_path(), container.clone()).unwrap();
+        let (snapshot, fds) = container.export_state(&[&read_only]).unwrap();
+        assert!(snapshot.devices[0].read_only);
+        vfio_syscall::inject_group_container_set(true);
+        let (_, imported) = VfioContainer::import_state(&snapshot, dup_fds(&fds), None).unwrap();
+        vfio_syscall::inject_group_container_set(false);
+        assert!(imported[0].is_read_only());
+        assert!(matches!(
+            imported[0].enable_msix(evts.iter().collect()),
+            Err(VfioError::DeviceReadOnly)
+        ));
+
+        // Devices of another container can't be exported.
+        assert!(matches!(
+            container.export_state(&[&devices[0]]),
+            Err(VfioError::InvalidContainerState(_))
+        ));
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use vfio_bindings::bindings::vfio::*;
+
+use crate::vfio_ioctls::*;
+use crate::{VfioContainer, VfioDevice, VfioError, VfioPaths};
+
+/// VFIO features of the host, as probed by [`probe_host_capabilities()`].
+///
+/// Each feature is `Some(true)` if supported, `Some(false)` if not, and `None` if it couldn't
+/// be determined, because of restricted permissions for instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HostVfioCapabilities {
+    /// Release of the running kernel, `6.1.0-18-amd64` for instance.
+    pub kernel_release: Option<String>,
+    /// The VFIO container device can be opened, with the API version of this crate.
+    pub container: Option<bool>,
+    /// The type1v2 IOMMU backend, required by `VfioContainer`.
+    pub type1v2_iommu: Option<bool>,
+    /// Unmapping all the mappings at once (`VFIO_UNMAP_ALL` extension).
+    pub unmap_all: Option<bool>,
+    /// Updating the host virtual address of mappings (`VFIO_UPDATE_VADDR` extension).
+    pub update_vaddr: Option<bool>,
+    /// Dirty pages tracking of the type1 IOMMU, only probed from the container of a device.
+    pub dirty_tracking: Option<bool>,
+    /// VFIO device character devices, in `/dev/vfio/devices`.
+    pub device_cdev: Option<bool>,
+    /// The IOMMUFD interface, in `/dev/iommu`.
+    pub iommufd: Option<bool>,
+    /// The migration device feature, only probed on a device.
+    pub device_migration: Option<bool>,
+    /// The DMA logging device feature, for device dirty tracking, only probed on a device.
+    pub device_dma_logging: Option<bool>,
+    /// The low power entry device feature, only probed on a device.
+    pub device_low_power: Option<bool>,
+}
+
+/// Likely cause of a failure to open the VFIO container device, carried by
+/// [`VfioError::OpenContainer`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpenContainerHint {
+    /// The container device doesn't exist, the `vfio` module isn't loaded.
+    ModuleNotLoaded,
+    /// The container device can't be opened by the user, who may lack a group membership or a
+    /// udev rule granting access to it.
+    PermissionDenied,
+}
+
+impl OpenContainerHint {
+    /// Get the likely cause of an error opening the container device, if a common one.
+    pub fn from_io_error(e: &io::Error) -> Option<Self> {
+        match e.kind() {
+            io::ErrorKind::NotFound => Some(OpenContainerHint::ModuleNotLoaded),
+            io::ErrorKind::PermissionDenied => Some(OpenContainerHint::PermissionDenied),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OpenContainerHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenContainerHint::ModuleNotLoaded => {
+                write!(
+                    f,
+                    "the vfio module may not be loaded, try `modprobe vfio-pci`"
+                )
+            }
+            OpenContainerHint::PermissionDenied => write!(
+                f,
+                "the user may need to be in the group owning the VFIO devices, or a udev rule \
+                 granting access to them"
+            ),
+        }
+    }
+}
+
+/// Best-effort diagnosis of a missing IOMMU, carried by [`VfioError::IommuNotEnabled`].
+///
+/// Each finding is `None` if it couldn't be gathered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IommuDiagnosis {
+    /// Number of IOMMUs registered in `/sys/class/iommu`.
+    pub iommus: Option<usize>,
+    /// IOMMU options of the kernel command line, `intel_iommu=on` for instance.
+    pub cmdline_options: Option<Vec<String>>,
+}
+
+impl fmt::Display for IommuDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.iommus {
+            Some(0) => write!(
+                f,
+                "no IOMMU is registered, it may be disabled in the firmware (VT-d or AMD-Vi)"
+            )?,
+            Some(iommus) => write!(
+                f,
+                "{} IOMMUs are registered, the vfio_iommu_type1 module may not be loaded",
+                iommus
+            )?,
+            None => write!(f, "the registered IOMMUs couldn't be listed")?,
+        }
+        match &self.cmdline_options {
+            Some(options) if options.is_empty() => write!(
+                f,
+                "; the kernel command line has no intel_iommu= or amd_iommu= option, \
+                 intel_iommu=on may be needed"
+            ),
+            Some(options) => write!(
+                f,
+                "; IOMMU options of the kernel command line: {}",
+                options.join(" ")
+            ),
+            None => write!(f, "; the kernel command line couldn't be read"),
+        }
+    }
+}
+
+/// Diagnose why the type1 IOMMU backend isn't available, see
+/// [`diagnose_iommu_with_paths()`].
+pub fn diagnose_iommu() -> IommuDiagnosis {
+    diagnose_iommu_with_paths(&VfioPaths::default())
+}
+
+/// Diagnose why the type1 IOMMU backend isn't available using a non-standard sysfs location.
+///
+/// The IOMMUs registered in `class/iommu` of sysfs are counted, and the IOMMU options are
+/// looked up in the kernel command line, read from `proc/cmdline` next to the sysfs mount point.
+/// Nothing fails: findings which can't be gathered are left unknown.
+///
+/// # Arguments
+/// * `paths` - Locations of the sysfs tree.
+pub fn diagnose_iommu_with_paths(paths: &VfioPaths) -> IommuDiagnosis {
+    let iommus = fs::read_dir(paths.sysfs_root.join("class/iommu"))
+        .ok()
+        .map(|entries| entries.filter(|entry| entry.is_ok()).count());
+    let cmdline_options = paths
+        .sysfs_root
+        .parent()
+        .and_then(|root| fs::read_to_string(root.join("proc/cmdline")).ok())
+        .map(|cmdline| {
+            cmdline
+                .split_whitespace()
+                .filter(|option| {
+                    ["intel_iommu=", "amd_iommu=", "iommu="]
+                        .iter()
+                        .any(|prefix| option.starts_with(prefix))
+                })
+                .map(String::from)
+                .collect()
+        });
+
+    IommuDiagnosis {
+        iommus,
+        cmdline_options,
+    }
+}
+
+// Read the kernel release with uname(), which doesn't depend on /proc being mounted.
+fn kernel_release() -> Option<String> {
+    // SAFETY: utsname is a plain C structure of arrays, for which all zeros is a valid value.
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    // SAFETY: uts is a valid utsname structure, which uname() fills.
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    // SAFETY: uname() nul-terminates the fields it fills.
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+
+    Some(release.to_string_lossy().into_owned())
+}
+
+// Check whether a path exists, unknown when it can't be looked up.
+fn path_exists(path: &Path) -> Option<bool> {
+    match fs::metadata(path) {
+        Ok(_) => Some(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Some(false),
+        Err(_) => None,
+    }
+}
+
+// Probe a device feature with the given GET or SET operation.
+fn probe_device_feature(device: &VfioDevice, feature: u32, op: u32) -> Option<bool> {
+    let probe = vfio_device_feature {
+        argsz: mem::size_of::<vfio_device_feature>() as u32,
+        flags: VFIO_DEVICE_FEATURE_PROBE | op | feature,
+    };
+    match vfio_syscall::probe_device_feature(device, &probe) {
+        Ok(()) => Some(true),
+        // Kernels without the feature interface, or without this feature, fail with ENOTTY.
+        Err(VfioError::VfioDeviceFeature(e))
+            if matches!(e.errno(), libc::ENOTTY | libc::EINVAL | libc::EOPNOTSUPP) =>
+        {
+            Some(false)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Probe the VFIO features of the host, see [`probe_host_capabilities_with_paths()`].
+///
+/// # Arguments
+/// * `device` - An optional device to probe the device features on.
+pub fn probe_host_capabilities(device: Option<&VfioDevice>) -> HostVfioCapabilities {
+    probe_host_capabilities_with_paths(&VfioPaths::default(), device)
+}
+
+/// Probe the VFIO features of the host using non-standard VFIO device locations.
+///
+/// A throwaway container is opened to check the extensions, and closed before returning. The
+/// IOMMUFD device is looked up next to the VFIO directory, as `/dev/iommu` for `/dev/vfio`.
+/// Device features and dirty pages tracking need an open device, and are left unknown without
+/// one. Nothing fails: features which can't be probed are reported as unknown.
+///
+/// # Arguments
+/// * `paths` - Locations of the VFIO character devices.
+/// * `device` - An optional device to probe the device features on.
+pub fn probe_host_capabilities_with_paths(
+    paths: &VfioPaths,
+    device: Option<&VfioDevice>,
+) -> HostVfioCapabilities {
+    let mut caps = HostVfioCapabilities {
+        kernel_release: kernel_release(),
+        device_cdev: path_exists(&paths.dev_vfio_root.join("devices")),
+        iommufd: paths.iommufd_path().and_then(|path| path_exists(&path)),
+        ..Default::default()
+    };
+
+    match VfioContainer::open_unchecked(None, paths.clone()) {
+        Ok(container) => {
+            caps.container = Some(container.check_api_version().is_ok());
+            if caps.container == Some(true) {
+                caps.type1v2_iommu = Some(container.supports_extension(VFIO_TYPE1v2_IOMMU));
+                caps.unmap_all = Some(container.supports_extension(VFIO_UNMAP_ALL));
+                caps.update_vaddr = Some(container.supports_extension(VFIO_UPDATE_VADDR));
+            }
+        }
+        Err(VfioError::OpenContainer(e, _)) if e.kind() == io::ErrorKind::NotFound => {
+            caps.container = Some(false);
+        }
+        Err(_) => {}
+    }
+
+    if let Some(device) = device {
+        caps.dirty_tracking = Some(
+            device
+                .binding
+                .container
+                .supports_dma_unmap_flag(VFIO_DMA_UNMAP_FLAG_GET_DIRTY_BITMAP),
+        );
+        caps.device_migration = probe_device_feature(
+            device,
+            VFIO_DEVICE_FEATURE_MIGRATION,
+            VFIO_DEVICE_FEATURE_GET,
+        );
+        caps.device_dma_logging = probe_device_feature(
+            device,
+            VFIO_DEVICE_FEATURE_DMA_LOGGING_START,
+            VFIO_DEVICE_FEATURE_SET,
+        );
+        caps.device_low_power = probe_device_feature(
+            device,
+            VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY,
+            VFIO_DEVICE_FEATURE_SET,
+        );
+    }
+
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::sync::Arc;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_probe_host_capabilities() {
+        let dev = TempDir::new().unwrap();
+        let paths = VfioPaths {
+            dev_vfio_root: dev.as_path().join("vfio"),
+            ..Default::default()
+        };
+        fs::create_dir_all(paths.dev_vfio_root.join("devices")).unwrap();
+
+        let caps = probe_host_capabilities_with_paths(&paths, None);
+        assert!(caps.kernel_release.is_some_and(|r| !r.is_empty()));
+        assert_eq!(caps.container, Some(true));
+        assert_eq!(caps.type1v2_iommu, Some(true));
+        assert_eq!(caps.unmap_all, Some(true));
+        assert_eq!(caps.update_vaddr, Some(false));
+        assert_eq!(caps.device_cdev, Some(true));
+        assert_eq!(caps.iommufd, Some(false));
+        assert_eq!(caps.dirty_tracking, None);
+        assert_eq!(caps.device_migration, None);
+
+        fs::write(dev.as_path().join("iommu"), b"").unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device =
+            VfioDevice::new(Path::new("/sys/bus/pci/devices/0000:03:00.0"), container).unwrap();
+        let caps = probe_host_capabilities_with_paths(&paths, Some(&device));
+        assert_eq!(caps.iommufd, Some(true));
+        assert_eq!(caps.dirty_tracking, Some(false));
+        assert_eq!(caps.device_migration, Some(true));
+        assert_eq!(caps.device_dma_logging, Some(false));
+        // Denied probes are unknown rather than unsupported.
+        assert_eq!(caps.device_low_power, None);
+    }
+
+    #[test]
+    fn test_open_container_hint() {
+        for &(kind, hint) in &[
+            (
+                io::ErrorKind::NotFound,
+                Some(OpenContainerHint::ModuleNotLoaded),
+            ),
+            (
+                io::ErrorKind::PermissionDenied,
+                Some(OpenContainerHint::PermissionDenied),
+            ),
+            (io::ErrorKind::Other, None),
+        ] {
+            assert_eq!(OpenContainerHint::from_io_error(&kind.into()), hint);
+        }
+    }
+
+    #[test]
+    fn test_diagnose_iommu() {
+        let root = TempDir::new().unwrap();
+        let paths = VfioPaths {
+            sysfs_root: root.as_path().join("sys"),
+            ..Default::default()
+        };
+        // Nothing can be gathered without sysfs and procfs.
+        assert_eq!(diagnose_iommu_with_paths(&paths), IommuDiagnosis::default());
+
+        let class_iommu = paths.sysfs_root.join("class/iommu");
+        fs::create_dir_all(&class_iommu).unwrap();
+        fs::create_dir_all(root.as_path().join("proc")).unwrap();
+        let cmdline = root.as_path().join("proc/cmdline");
+        fs::write(
+            &cmdline,
+            "BOOT_IMAGE=/vmlinuz root=/dev/sda1 intel_iommu=off quiet\n",
+        )
+        .unwrap();
+        let diagnosis = diagnose_iommu_with_paths(&paths);
+        assert_eq!(diagnosis.iommus, Some(0));
+        assert_eq!(
+            diagnosis.cmdline_options,
+            Some(vec!["intel_iommu=off".to_string()])
+        );
+        assert_eq!(
+            diagnosis.to_string(),
+            "no IOMMU is registered, it may be disabled in the firmware (VT-d or AMD-Vi); IOMMU \
+             options of the kernel command line: intel_iommu=off"
+        );
+
+        fs::create_dir(class_iommu.join("dmar0")).unwrap();
+        fs::write(&cmdline, "root=/dev/sda1 iommu=pt amd_iommu=on\n").unwrap();
+        let diagnosis = diagnose_iommu_with_paths(&paths);
+        assert_eq!(diagnosis.iommus, Some(1));
+        assert_eq!(
+            diagnosis.cmdline_options,
+            Some(vec!["iommu=pt".to_string(), "amd_iommu=on".to_string()])
+        );
+
+        fs::write(&cmdline, "root=/dev/sda1\n").unwrap();
+        assert!(diagnose_iommu_with_paths(&paths)
+            .to_string()
+            .contains("no intel_iommu= or amd_iommu= option"));
+
+        // The container reports a missing type1 backend with the diagnosis.
+        vfio_syscall::inject_type1v2_iommu(false);
+        let result = VfioContainer::new_with_paths(None, paths.clone());
+        vfio_syscall::inject_type1v2_iommu(true);
+        match result {
+            Err(VfioError::IommuNotEnabled { diagnosis }) => {
+                assert_eq!(diagnosis.iommus, Some(1))
+            }
+            _ => panic!("the missing type1 backend wasn't diagnosed"),
+        }
+        VfioContainer::new_with_paths(None, paths).unwrap();
+    }
+}
@@ -7,14 +7,14 @@
 #![allow(non_snake_case)]
 
 use std::ffi::CStr;
-use std::fs::File;
 use std::mem::size_of;
+use std::os::fd::OwnedFd;
 use std::os::unix::io::AsRawFd;
 
 use vfio_bindings::bindings::vfio::*;
 use vmm_sys_util::errno::Error as SysError;
 
-use crate::vfio_device::{vfio_region_info_with_cap, VfioDeviceInfo};
+use crate::vfio_device::{vfio_iommu_info_with_cap, vfio_region_info_with_cap};
 use crate::{Result, VfioContainer, VfioDevice, VfioError, VfioGroup};
 
 ioctl_io_nr!(VFIO_GET_API_VERSION, VFIO_TYPE, VFIO_BASE);
@@ -43,6 +43,193 @@ ioctl_io_nr!(VFIO_IOMMU_MAP_DMA, VFIO_TYPE, VFIO_BASE + 13);
 ioctl_io_nr!(VFIO_IOMMU_UNMAP_DMA, VFIO_TYPE, VFIO_BASE + 14);
 ioctl_io_nr!(VFIO_IOMMU_ENABLE, VFIO_TYPE, VFIO_BASE + 15);
 ioctl_io_nr!(VFIO_IOMMU_DISABLE, VFIO_TYPE, VFIO_BASE + 16);
+ioctl_io_nr!(VFIO_IOMMU_DIRTY_PAGES, VFIO_TYPE, VFIO_BASE + 17);
+ioctl_io_nr!(VFIO_DEVICE_FEATURE, VFIO_TYPE, VFIO_BASE + 17);
+ioctl_io_nr!(VFIO_DEVICE_BIND_IOMMUFD, VFIO_TYPE, VFIO_BASE + 18);
+ioctl_io_nr!(VFIO_DEVICE_ATTACH_IOMMUFD_PT, VFIO_TYPE, VFIO_BASE + 19);
+ioctl_io_nr!(VFIO_DEVICE_DETACH_IOMMUFD_PT, VFIO_TYPE, VFIO_BASE + 20);
+
+// The IOMMUFD interface, in /dev/iommu, shares the VFIO ioctl type and isn't part of the
+// vfio-bindings v5.0.0 definitions.
+const IOMMUFD_CMD_BASE: u32 = 0x80;
+ioctl_io_nr!(IOMMU_DESTROY, VFIO_TYPE, IOMMUFD_CMD_BASE);
+ioctl_io_nr!(IOMMU_IOAS_ALLOC, VFIO_TYPE, IOMMUFD_CMD_BASE + 1);
+ioctl_io_nr!(IOMMU_IOAS_MAP, VFIO_TYPE, IOMMUFD_CMD_BASE + 5);
+ioctl_io_nr!(IOMMU_IOAS_UNMAP, VFIO_TYPE, IOMMUFD_CMD_BASE + 6);
+
+pub(crate) const IOMMU_IOAS_MAP_FIXED_IOVA: u32 = 1 << 0;
+pub(crate) const IOMMU_IOAS_MAP_WRITEABLE: u32 = 1 << 1;
+pub(crate) const IOMMU_IOAS_MAP_READABLE: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct iommu_destroy {
+    pub size: u32,
+    pub id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct iommu_ioas_alloc {
+    pub size: u32,
+    pub flags: u32,
+    pub out_ioas_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct iommu_ioas_map {
+    pub size: u32,
+    pub flags: u32,
+    pub ioas_id: u32,
+    pub __reserved: u32,
+    pub user_va: u64,
+    pub length: u64,
+    pub iova: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct iommu_ioas_unmap {
+    pub size: u32,
+    pub ioas_id: u32,
+    pub iova: u64,
+    pub length: u64,
+}
+
+// Binding a device opened through its character device, in /dev/vfio/devices, to an IOMMUFD.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_device_bind_iommufd {
+    pub argsz: u32,
+    pub flags: u32,
+    pub iommufd: i32,
+    pub out_devid: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_device_attach_iommufd_pt {
+    pub argsz: u32,
+    pub flags: u32,
+    pub pt_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_device_detach_iommufd_pt {
+    pub argsz: u32,
+    pub flags: u32,
+}
+
+// The device feature interface is more recent than the vfio-bindings v5.0.0 definitions.
+pub(crate) const VFIO_DEVICE_FEATURE_GET: u32 = 1 << 16;
+pub(crate) const VFIO_DEVICE_FEATURE_SET: u32 = 1 << 17;
+pub(crate) const VFIO_DEVICE_FEATURE_PROBE: u32 = 1 << 18;
+pub(crate) const VFIO_DEVICE_FEATURE_MIGRATION: u32 = 1;
+pub(crate) const VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY: u32 = 3;
+pub(crate) const VFIO_DEVICE_FEATURE_DMA_LOGGING_START: u32 = 6;
+pub(crate) const VFIO_DEVICE_FEATURE_MIG_DATA_SIZE: u32 = 9;
+
+// struct vfio_device_feature without payload, as used to probe a feature.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_device_feature {
+    pub argsz: u32,
+    pub flags: u32,
+}
+
+// struct vfio_device_feature followed by struct vfio_device_feature_mig_data_size.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_device_feature_mig_data_size {
+    pub argsz: u32,
+    pub flags: u32,
+    pub stop_copy_length: u64,
+}
+
+// The IOMMU info capability chain is more recent than the vfio-bindings v5.0.0 definitions.
+pub(crate) const VFIO_IOMMU_INFO_CAPS: u32 = 1 << 1;
+pub(crate) const VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE: u32 = 1;
+pub(crate) const VFIO_IOMMU_TYPE1_INFO_CAP_MIGRATION: u32 = 2;
+
+// The v1 migration region type, more recent than the vfio-bindings v5.0.0 definitions.
+pub(crate) const VFIO_REGION_TYPE_MIGRATION: u32 = 3;
+pub(crate) const VFIO_REGION_SUBTYPE_MIGRATION: u32 = 1;
+
+// Device flags more recent than the vfio-bindings v5.0.0 definitions.
+pub(crate) const VFIO_DEVICE_FLAGS_FSL_MC: u32 = 1 << 6;
+pub(crate) const VFIO_DEVICE_FLAGS_CAPS: u32 = 1 << 7;
+pub(crate) const VFIO_DEVICE_FLAGS_CDX: u32 = 1 << 8;
+
+// DMA map and unmap flags, and the extensions advertising them, newer than vfio-bindings v5.0.0.
+pub(crate) const VFIO_UNMAP_ALL: u32 = 9;
+pub(crate) const VFIO_UPDATE_VADDR: u32 = 10;
+/// Update the host virtual address of an existing mapping (`VFIO_UPDATE_VADDR` extension).
+pub const VFIO_DMA_MAP_FLAG_VADDR: u32 = 1 << 2;
+/// Return the dirty pages bitmap of the unmapped range.
+pub const VFIO_DMA_UNMAP_FLAG_GET_DIRTY_BITMAP: u32 = 1 << 0;
+/// Unmap all the mappings of the container (`VFIO_UNMAP_ALL` extension).
+pub const VFIO_DMA_UNMAP_FLAG_ALL: u32 = 1 << 1;
+/// Invalidate the host virtual address of mappings (`VFIO_UPDATE_VADDR` extension).
+pub const VFIO_DMA_UNMAP_FLAG_VADDR: u32 = 1 << 2;
+
+// Dirty pages tracking of the type1 IOMMU, newer than vfio-bindings v5.0.0.
+pub(crate) const VFIO_IOMMU_DIRTY_PAGES_FLAG_START: u32 = 1 << 0;
+pub(crate) const VFIO_IOMMU_DIRTY_PAGES_FLAG_STOP: u32 = 1 << 1;
+pub(crate) const VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP: u32 = 1 << 2;
+
+// struct vfio_iommu_type1_dirty_bitmap followed by struct vfio_iommu_type1_dirty_bitmap_get, with
+// its struct vfio_bitmap inlined. Starting and stopping the tracking only use argsz and flags.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_iommu_type1_dirty_bitmap_get {
+    pub argsz: u32,
+    pub flags: u32,
+    pub iova: u64,
+    pub size: u64,
+    pub pgsize: u64,
+    pub bitmap_size: u64,
+    pub data: u64,
+}
+
+// struct vfio_iommu_type1_info with the cap_offset field added by Linux v5.4.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_iommu_type1_info_v2 {
+    pub argsz: u32,
+    pub flags: u32,
+    pub iova_pgsizes: u64,
+    pub cap_offset: u32,
+    pub pad: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_iova_range {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub(crate) struct vfio_iommu_type1_info_cap_iova_range {
+    pub header: vfio_info_cap_header,
+    pub nr_iovas: u32,
+    pub reserved: u32,
+    pub iova_ranges: __IncompleteArrayField<vfio_iova_range>,
+}
+
+// Capability of the IOMMU info reporting the number of DMA mappings still available, added by
+// Linux v5.10.
+pub(crate) const VFIO_IOMMU_TYPE1_INFO_DMA_AVAIL: u32 = 3;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct vfio_iommu_type1_info_dma_avail {
+    pub header: vfio_info_cap_header,
+    pub avail: u32,
+}
 
 #[cfg(not(test))]
 // Safety:
@@ -109,8 +296,42 @@ pub(crate) mod vfio_syscall {
         }
     }
 
+    pub(crate) fn dirty_pages(
+        container: &VfioContainer,
+        dirty: &vfio_iommu_type1_dirty_bitmap_get,
+    ) -> Result<()> {
+        // SAFETY: file is vfio container, dirty is constructed by us with a bitmap buffer of
+        // the size it advertises, and we check the return value.
+        let ret = unsafe { ioctl_with_ref(container, VFIO_IOMMU_DIRTY_PAGES(), dirty) };
+        if ret != 0 {
+            Err(VfioError::IommuDirtyPages(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_iommu_info(
+        container: &VfioContainer,
+        infos: &mut [vfio_iommu_info_with_cap],
+    ) -> Result<()> {
+        if infos.is_empty()
+            || infos[0].info.argsz as usize > infos.len() * size_of::<vfio_iommu_type1_info_v2>()
+        {
+            return Err(VfioError::IommuGetInfo(SysError::new(libc::EINVAL)));
+        }
+
+        // SAFETY: we are the owner of container and infos which are valid value,
+        // and we verify the return value.
+        let ret = unsafe { ioctl_with_mut_ref(container, VFIO_IOMMU_GET_INFO(), &mut infos[0]) };
+        if ret != 0 {
+            Err(VfioError::IommuGetInfo(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
     pub(crate) fn get_group_status(
-        file: &File,
+        file: &OwnedFd,
         group_status: &mut vfio_group_status,
     ) -> Result<()> {
         // SAFETY: we are the owner of group and group_status which are valid value.
@@ -122,14 +343,14 @@ pub(crate) mod vfio_syscall {
         }
     }
 
-    pub(crate) fn get_group_device_fd(group: &VfioGroup, path: &CStr) -> Result<File> {
+    pub(crate) fn get_group_device_fd(group: &VfioGroup, path: &CStr) -> Result<OwnedFd> {
         // SAFETY: we are the owner of self and path_ptr which are valid value.
         let fd = unsafe { ioctl_with_ptr(group, VFIO_GROUP_GET_DEVICE_FD(), path.as_ptr()) };
         if fd < 0 {
             Err(VfioError::GroupGetDeviceFD)
         } else {
             // SAFETY: fd is valid FD
-            Ok(unsafe { File::from_raw_fd(fd) })
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
         }
     }
 
@@ -139,7 +360,14 @@ pub(crate) mod vfio_syscall {
         // and we verify the ret value
         let ret = unsafe { ioctl_with_ref(group, VFIO_GROUP_SET_CONTAINER(), &container_raw_fd) };
         if ret < 0 {
-            Err(VfioError::GroupSetContainer)
+            let errno = SysError::last().errno();
+            if errno == libc::EBUSY {
+                Err(VfioError::GroupBusy { group_id: group.id })
+            } else if errno == libc::EINVAL {
+                Err(VfioError::GroupSetContainerInvalid { group_id: group.id })
+            } else {
+                Err(VfioError::GroupSetContainer)
+            }
         } else {
             Ok(())
         }
@@ -159,7 +387,7 @@ pub(crate) mod vfio_syscall {
         }
     }
 
-    pub(crate) fn get_device_info(file: &File, dev_info: &mut vfio_device_info) -> Result<()> {
+    pub(crate) fn get_device_info(file: &OwnedFd, dev_info: &mut vfio_device_info) -> Result<()> {
         // SAFETY: we are the owner of dev and dev_info which are valid value,
         // and we verify the return value.
         let ret = unsafe { ioctl_with_mut_ref(file, VFIO_DEVICE_GET_INFO(), dev_info) };
@@ -171,9 +399,7 @@ pub(crate) mod vfio_syscall {
     }
 
     pub(crate) fn set_device_irqs(device: &VfioDevice, irq_set: &[vfio_irq_set]) -> Result<()> {
-        if irq_set.is_empty()
-            || irq_set[0].argsz as usize > irq_set.len() * size_of::<vfio_irq_set>()
-        {
+        if irq_set.is_empty() || irq_set[0].argsz as usize > std::mem::size_of_val(irq_set) {
             Err(VfioError::VfioDeviceSetIrq)
         } else {
             // SAFETY: we are the owner of self and irq_set which are valid value
@@ -191,26 +417,170 @@ pub(crate) mod vfio_syscall {
         unsafe { ioctl(device, VFIO_DEVICE_RESET()) }
     }
 
-    pub(crate) fn get_device_irq_info(
-        dev_info: &VfioDeviceInfo,
+    pub(crate) fn get_pci_hot_reset_info(
+        device: &VfioDevice,
+        infos: &mut [vfio_pci_hot_reset_info],
+    ) -> Result<()> {
+        if infos.is_empty() || infos[0].argsz as usize > std::mem::size_of_val(infos) {
+            return Err(VfioError::VfioDeviceHotResetInfo(SysError::new(
+                libc::EINVAL,
+            )));
+        }
+
+        // SAFETY: we are the owner of device and infos which are valid value,
+        // and we verify the return value.
+        let ret = unsafe {
+            ioctl_with_mut_ref(device, VFIO_DEVICE_GET_PCI_HOT_RESET_INFO(), &mut infos[0])
+        };
+        if ret < 0 {
+            Err(VfioError::VfioDeviceHotResetInfo(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn pci_hot_reset(device: &VfioDevice, resets: &[vfio_pci_hot_reset]) -> Result<()> {
+        if resets.is_empty() || resets[0].argsz as usize > std::mem::size_of_val(resets) {
+            return Err(VfioError::VfioDevicePciHotReset(SysError::new(
+                libc::EINVAL,
+            )));
+        }
+
+        // SAFETY: we are the owner of device and resets which are valid value,
+        // and we verify the return value.
+        let ret = unsafe { ioctl_with_ref(device, VFIO_DEVICE_PCI_HOT_RESET(), &resets[0]) };
+        if ret < 0 {
+            Err(VfioError::VfioDevicePciHotReset(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_device_feature_mig_data_size(
+        device: &VfioDevice,
+        feature: &mut vfio_device_feature_mig_data_size,
+    ) -> Result<()> {
+        // SAFETY: we are the owner of device and feature which are valid value,
+        // and we verify the return value.
+        let ret = unsafe { ioctl_with_mut_ref(device, VFIO_DEVICE_FEATURE(), feature) };
+        if ret < 0 {
+            Err(VfioError::VfioDeviceFeature(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn probe_device_feature(
+        device: &VfioDevice,
+        feature: &vfio_device_feature,
+    ) -> Result<()> {
+        // SAFETY: we are the owner of device and feature which are valid value,
+        // and we verify the return value.
+        let ret = unsafe { ioctl_with_ref(device, VFIO_DEVICE_FEATURE(), feature) };
+        if ret < 0 {
+            Err(VfioError::VfioDeviceFeature(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn ioas_alloc<F: AsRawFd>(iommufd: &F, alloc: &mut iommu_ioas_alloc) -> Result<()> {
+        // SAFETY: file is iommufd, alloc is constructed by us, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(iommufd, IOMMU_IOAS_ALLOC(), alloc) };
+        if ret < 0 {
+            Err(VfioError::IommufdIoasAlloc(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn ioas_map<F: AsRawFd>(iommufd: &F, map: &mut iommu_ioas_map) -> Result<()> {
+        // SAFETY: file is iommufd, map is constructed by us, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(iommufd, IOMMU_IOAS_MAP(), map) };
+        if ret < 0 {
+            Err(VfioError::IommufdIoasMap(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn ioas_unmap<F: AsRawFd>(iommufd: &F, unmap: &mut iommu_ioas_unmap) -> Result<()> {
+        // SAFETY: file is iommufd, unmap is constructed by us, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(iommufd, IOMMU_IOAS_UNMAP(), unmap) };
+        if ret < 0 {
+            Err(VfioError::IommufdIoasUnmap(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn iommufd_destroy<F: AsRawFd>(iommufd: &F, destroy: &iommu_destroy) -> i32 {
+        // SAFETY: file is iommufd, destroy is constructed by us.
+        unsafe { ioctl_with_ref(iommufd, IOMMU_DESTROY(), destroy) }
+    }
+
+    pub(crate) fn bind_iommufd<F: AsRawFd>(
+        device: &F,
+        bind: &mut vfio_device_bind_iommufd,
+    ) -> Result<()> {
+        // SAFETY: file is a VFIO device, bind is constructed by us, and we check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(device, VFIO_DEVICE_BIND_IOMMUFD(), bind) };
+        if ret < 0 {
+            Err(VfioError::IommufdBindDevice(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn attach_iommufd_pt<F: AsRawFd>(
+        device: &F,
+        attach: &mut vfio_device_attach_iommufd_pt,
+    ) -> Result<()> {
+        // SAFETY: file is a VFIO device, attach is constructed by us, and we check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(device, VFIO_DEVICE_ATTACH_IOMMUFD_PT(), attach) };
+        if ret < 0 {
+            Err(VfioError::IommufdAttachDevice(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn detach_iommufd_pt<F: AsRawFd>(
+        device: &F,
+        detach: &vfio_device_detach_iommufd_pt,
+    ) -> Result<()> {
+        // SAFETY: file is a VFIO device, detach is constructed by us, and we check the return
+        // value.
+        let ret = unsafe { ioctl_with_ref(device, VFIO_DEVICE_DETACH_IOMMUFD_PT(), detach) };
+        if ret < 0 {
+            Err(VfioError::IommufdDetachDevice(SysError::last()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_device_irq_info<F: AsRawFd>(
+        device: &F,
         irq_info: &mut vfio_irq_info,
     ) -> Result<()> {
         // SAFETY: we are the owner of dev and irq_info which are valid value
-        let ret = unsafe { ioctl_with_mut_ref(dev_info, VFIO_DEVICE_GET_IRQ_INFO(), irq_info) };
+        let ret = unsafe { ioctl_with_mut_ref(device, VFIO_DEVICE_GET_IRQ_INFO(), irq_info) };
         if ret < 0 {
-            Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(-ret)))
+            Err(VfioError::VfioDeviceGetIrqInfo)
         } else {
             Ok(())
         }
     }
 
-    pub(crate) fn get_device_region_info(
-        dev_info: &VfioDeviceInfo,
+    pub(crate) fn get_device_region_info<F: AsRawFd>(
+        device: &F,
         reg_info: &mut vfio_region_info,
     ) -> Result<()> {
         // SAFETY: we are the owner of dev and region_info which are valid value
         // and we verify the return value.
-        let ret = unsafe { ioctl_with_mut_ref(dev_info, VFIO_DEVICE_GET_REGION_INFO(), reg_info) };
+        let ret = unsafe { ioctl_with_mut_ref(device, VFIO_DEVICE_GET_REGION_INFO(), reg_info) };
         if ret < 0 {
             Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(-ret)))
         } else {
@@ -218,8 +588,8 @@ pub(crate) mod vfio_syscall {
         }
     }
 
-    pub(crate) fn get_device_region_info_cap(
-        dev_info: &VfioDeviceInfo,
+    pub(crate) fn get_device_region_info_cap<F: AsRawFd>(
+        device: &F,
         reg_infos: &mut [vfio_region_info_with_cap],
     ) -> Result<()> {
         if reg_infos.is_empty()
@@ -233,7 +603,7 @@ pub(crate) mod vfio_syscall {
             // SAFETY: we are the owner of dev and region_info which are valid value,
             // and we verify the return value.
             let ret = unsafe {
-                ioctl_with_mut_ref(dev_info, VFIO_DEVICE_GET_REGION_INFO(), &mut reg_infos[0])
+                ioctl_with_mut_ref(device, VFIO_DEVICE_GET_REGION_INFO(), &mut reg_infos[0])
             };
             if ret < 0 {
                 Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(-ret)))
@@ -247,6 +617,8 @@ pub(crate) mod vfio_syscall {
 #[cfg(test)]
 pub(crate) mod vfio_syscall {
     use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::os::unix::io::RawFd;
     use vfio_bindings::bindings::vfio::{vfio_device_info, VFIO_IRQ_INFO_EVENTFD};
     use vmm_sys_util::tempfile::TempFile;
 
@@ -254,23 +626,92 @@ pub(crate) mod vfio_syscall {
         VFIO_API_VERSION as i32
     }
 
+    thread_local! {
+        // The IOMMU enforces DMA cache coherency.
+        static DMA_COHERENT: Cell<bool> = const { Cell::new(true) };
+        // The type1v2 IOMMU backend is available.
+        static TYPE1V2_IOMMU: Cell<bool> = const { Cell::new(true) };
+    }
+
+    pub(crate) fn inject_dma_coherent(coherent: bool) {
+        DMA_COHERENT.with(|c| c.set(coherent));
+    }
+
+    pub(crate) fn inject_type1v2_iommu(supported: bool) {
+        TYPE1V2_IOMMU.with(|c| c.set(supported));
+    }
+
     pub(crate) fn check_extension(_container: &VfioContainer, val: u32) -> Result<u32> {
-        if val == VFIO_TYPE1v2_IOMMU {
-            Ok(1)
-        } else {
-            Err(VfioError::VfioExtension)
+        match val {
+            VFIO_TYPE1v2_IOMMU => Ok(TYPE1V2_IOMMU.with(|c| c.get()) as u32),
+            VFIO_UNMAP_ALL => Ok(1),
+            VFIO_UPDATE_VADDR => Ok(0),
+            VFIO_DMA_CC_IOMMU => Ok(DMA_COHERENT.with(|c| c.get()) as u32),
+            _ => Err(VfioError::VfioExtension),
         }
     }
 
+    thread_local! {
+        // Number of set_iommu() calls, and whether the next one fails.
+        static SET_IOMMU: Cell<(u32, bool)> = const { Cell::new((0, false)) };
+    }
+
+    pub(crate) fn inject_set_iommu_failure() {
+        SET_IOMMU.with(|c| c.set((c.get().0, true)));
+    }
+
+    pub(crate) fn take_set_iommu_calls() -> u32 {
+        SET_IOMMU.with(|c| {
+            let (calls, fail) = c.get();
+            c.set((0, fail));
+            calls
+        })
+    }
+
     pub(crate) fn set_iommu(_container: &VfioContainer, _val: u32) -> Result<()> {
-        Ok(())
+        let (calls, fail) = SET_IOMMU.with(|c| c.get());
+        SET_IOMMU.with(|c| c.set((calls + 1, false)));
+        if fail {
+            Err(VfioError::ContainerSetIOMMU)
+        } else {
+            Ok(())
+        }
+    }
+
+    thread_local! {
+        // IOVAs accepted by map_dma() and unmap_dma() respectively, in addition to 0x1000.
+        static DMA_IOVAS: RefCell<(Vec<u64>, Vec<u64>)> =
+            const { RefCell::new((Vec::new(), Vec::new())) };
+    }
+
+    pub(crate) fn inject_dma_iovas(map: &[u64], unmap: &[u64]) {
+        DMA_IOVAS.with(|v| *v.borrow_mut() = (map.to_vec(), unmap.to_vec()));
+    }
+
+    thread_local! {
+        // (iova, size) of the unmap_dma() calls.
+        static UNMAP_DMA_LOG: RefCell<Vec<(u64, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn take_unmap_dma_log() -> Vec<(u64, u64)> {
+        UNMAP_DMA_LOG.with(|v| v.take())
+    }
+
+    thread_local! {
+        // (container fd, iova) of the map_dma() calls.
+        static MAP_DMA_LOG: RefCell<Vec<(RawFd, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn take_map_dma_log() -> Vec<(RawFd, u64)> {
+        MAP_DMA_LOG.with(|v| v.take())
     }
 
     pub(crate) fn map_dma(
-        _container: &VfioContainer,
+        container: &VfioContainer,
         dma_map: &vfio_iommu_type1_dma_map,
     ) -> Result<()> {
-        if dma_map.iova == 0x1000 {
+        MAP_DMA_LOG.with(|v| v.borrow_mut().push((container.as_raw_fd(), dma_map.iova)));
+        if dma_map.iova == 0x1000 || DMA_IOVAS.with(|v| v.borrow().0.contains(&dma_map.iova)) {
             Ok(())
         } else {
             Err(VfioError::IommuDmaMap(SysError::last()))
@@ -281,8 +722,9 @@ pub(crate) mod vfio_syscall {
         _container: &VfioContainer,
         dma_map: &mut vfio_iommu_type1_dma_unmap,
     ) -> Result<()> {
-        if dma_map.iova == 0x1000 {
-            if dma_map.size == 0x2000 {
+        UNMAP_DMA_LOG.with(|v| v.borrow_mut().push((dma_map.iova, dma_map.size)));
+        if dma_map.iova == 0x1000 || DMA_IOVAS.with(|v| v.borrow().1.contains(&dma_map.iova)) {
+            if dma_map.iova == 0x1000 && dma_map.size == 0x2000 {
                 dma_map.size = 0x1000;
             }
             Ok(())
@@ -291,23 +733,302 @@ pub(crate) mod vfio_syscall {
         }
     }
 
+    thread_local! {
+        // IOVAs of the dirty pages, and IOVA at which fetching the bitmap fails.
+        static DIRTY_PAGES: RefCell<(Vec<u64>, Option<u64>)> =
+            const { RefCell::new((Vec::new(), None)) };
+        // (flags, iova, size) of the dirty_pages() calls.
+        static DIRTY_PAGES_LOG: RefCell<Vec<(u32, u64, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn inject_dirty_pages(pages: &[u64], failing_iova: Option<u64>) {
+        DIRTY_PAGES.with(|v| *v.borrow_mut() = (pages.to_vec(), failing_iova));
+    }
+
+    pub(crate) fn take_dirty_pages_log() -> Vec<(u32, u64, u64)> {
+        DIRTY_PAGES_LOG.with(|v| v.take())
+    }
+
+    pub(crate) fn dirty_pages(
+        _container: &VfioContainer,
+        dirty: &vfio_iommu_type1_dirty_bitmap_get,
+    ) -> Result<()> {
+        DIRTY_PAGES_LOG.with(|v| v.borrow_mut().push((dirty.flags, dirty.iova, dirty.size)));
+        if dirty.flags != VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP {
+            return Ok(());
+        }
+
+        let (pages, failing_iova) = DIRTY_PAGES.with(|v| v.borrow().clone());
+        let words = (dirty.size / dirty.pgsize).div_ceil(64);
+        if failing_iova == Some(dirty.iova) || dirty.bitmap_size < words * 8 {
+            return Err(VfioError::IommuDirtyPages(SysError::new(libc::EINVAL)));
+        }
+        // SAFETY: the caller passes a bitmap buffer of bitmap_size bytes.
+        let bitmap =
+            unsafe { std::slice::from_raw_parts_mut(dirty.data as *mut u64, words as usize) };
+        for page in pages {
+            if page >= dirty.iova && page < dirty.iova + dirty.size {
+                let n = (page - dirty.iova) / dirty.pgsize;
+                bitmap[(n / 64) as usize] |= 1 << (n % 64);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Number of get_iommu_info() calls, indexed by container fd.
+    static IOMMU_INFO_QUERIES: std::sync::Mutex<Vec<(i32, usize)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    pub(crate) fn take_iommu_info_queries(container: &VfioContainer) -> usize {
+        let fd = container.as_raw_fd();
+        let mut queries = IOMMU_INFO_QUERIES.lock().unwrap();
+        let count = queries
+            .iter()
+            .filter(|(f, _)| *f == fd)
+            .map(|(_, c)| *c)
+            .sum();
+        queries.retain(|(f, _)| *f != fd);
+        count
+    }
+
+    thread_local! {
+        // Number of DMA mappings a container may create, None if DMA_AVAIL isn't reported.
+        static DMA_LIMIT: Cell<Option<u32>> = const { Cell::new(None) };
+        // Corrupted `next` and `nr_iovas` fields of the IOVA ranges capability.
+        static IOVA_RANGES_CAP: Cell<(Option<u32>, Option<u32>)> =
+            const { Cell::new((None, None)) };
+        // Page sizes supported by the IOMMU, 0 if they aren't reported.
+        static IOMMU_PGSIZES: Cell<u64> = const { Cell::new(0x1000) };
+    }
+
+    pub(crate) fn inject_iommu_pgsizes(pgsizes: u64) {
+        IOMMU_PGSIZES.with(|c| c.set(pgsizes));
+    }
+
+    pub(crate) fn inject_iova_ranges_cap(next: Option<u32>, nr_iovas: Option<u32>) {
+        IOVA_RANGES_CAP.with(|c| c.set((next, nr_iovas)));
+    }
+
+    // Report DMA_AVAIL as `limit` minus the map_dma() calls made on the container.
+    pub(crate) fn inject_dma_limit(limit: Option<u32>) {
+        DMA_LIMIT.with(|c| c.set(limit));
+    }
+
+    pub(crate) fn get_iommu_info(
+        container: &VfioContainer,
+        infos: &mut [vfio_iommu_info_with_cap],
+    ) -> Result<()> {
+        IOMMU_INFO_QUERIES
+            .lock()
+            .unwrap()
+            .push((container.as_raw_fd(), 1));
+        let dma_limit = DMA_LIMIT.with(|c| c.get());
+        let info_size = size_of::<vfio_iommu_type1_info_v2>();
+        let ranges_size =
+            size_of::<vfio_iommu_type1_info_cap_iova_range>() + 2 * size_of::<vfio_iova_range>();
+        let cap_size = match dma_limit {
+            Some(_) => ranges_size + size_of::<vfio_iommu_type1_info_dma_avail>(),
+            None => ranges_size,
+        };
+        if infos.is_empty() || infos[0].info.argsz as usize > infos.len() * info_size {
+            return Err(VfioError::IommuGetInfo(SysError::new(libc::EINVAL)));
+        }
+
+        let info = &mut infos[0].info;
+        info.flags = VFIO_IOMMU_INFO_CAPS;
+        info.iova_pgsizes = IOMMU_PGSIZES.with(|c| c.get());
+        if info.iova_pgsizes != 0 {
+            info.flags |= VFIO_IOMMU_INFO_PGSIZES;
+        }
+        if (info.argsz as usize) < info_size + cap_size {
+            info.argsz = (info_size + cap_size) as u32;
+            info.cap_offset = 0;
+            return Ok(());
+        }
+        info.cap_offset = info_size as u32;
+
+        // Two IOVA windows around the x86 MSI doorbell range.
+        let (next, nr_iovas) = IOVA_RANGES_CAP.with(|c| c.get());
+        let cap = vfio_iommu_type1_info_cap_iova_range {
+            header: vfio_info_cap_header {
+                id: VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE as u16,
+                version: 1,
+                next: next.unwrap_or(if dma_limit.is_some() {
+                    (info_size + ranges_size) as u32
+                } else {
+                    0
+                }),
+            },
+            nr_iovas: nr_iovas.unwrap_or(2),
+            ..Default::default()
+        };
+        let ranges = [
+            vfio_iova_range {
+                start: 0,
+                end: 0xfedf_ffff,
+            },
+            vfio_iova_range {
+                start: 0xfef0_0000,
+                end: 0xffff_ffff_ffff,
+            },
+        ];
+        // SAFETY: infos is large enough to hold the capability and its ranges, as checked above.
+        unsafe {
+            let cap_ptr = (infos.as_mut_ptr() as *mut u8).add(info_size);
+            std::ptr::write_unaligned(cap_ptr as *mut vfio_iommu_type1_info_cap_iova_range, cap);
+            std::ptr::write_unaligned(
+                cap_ptr.add(size_of::<vfio_iommu_type1_info_cap_iova_range>())
+                    as *mut [vfio_iova_range; 2],
+                ranges,
+            );
+        }
+
+        if let Some(limit) = dma_limit {
+            let fd = container.as_raw_fd();
+            let maps = MAP_DMA_LOG.with(|v| v.borrow().iter().filter(|(f, _)| *f == fd).count());
+            let cap = vfio_iommu_type1_info_dma_avail {
+                header: vfio_info_cap_header {
+                    id: VFIO_IOMMU_TYPE1_INFO_DMA_AVAIL as u16,
+                    version: 1,
+                    next: 0,
+                },
+                avail: limit.saturating_sub(maps as u32),
+            };
+            // SAFETY: infos is large enough to hold the capability, as checked above.
+            unsafe {
+                let cap_ptr = (infos.as_mut_ptr() as *mut u8).add(info_size + ranges_size);
+                std::ptr::write_unaligned(cap_ptr as *mut vfio_iommu_type1_info_dma_avail, cap);
+            }
+        }
+
+        Ok(())
+    }
+
+    thread_local! {
+        // Whether get_group_status() reports the group as bound to a container.
+        static GROUP_CONTAINER_SET: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub(crate) fn inject_group_container_set(set: bool) {
+        GROUP_CONTAINER_SET.with(|c| c.set(set));
+    }
+
+    thread_local! {
+        // Number of get_group_status() calls reporting the group as not viable.
+        static GROUP_NOT_VIABLE_POLLS: Cell<u32> = const { Cell::new(0) };
+    }
+
+    pub(crate) fn inject_group_not_viable(polls: u32) {
+        GROUP_NOT_VIABLE_POLLS.with(|c| c.set(polls));
+    }
+
     pub(crate) fn get_group_status(
-        _file: &File,
+        _file: &OwnedFd,
         group_status: &mut vfio_group_status,
     ) -> Result<()> {
-        group_status.flags = VFIO_GROUP_FLAGS_VIABLE;
+        let not_viable = GROUP_NOT_VIABLE_POLLS.with(|c| {
+            let polls = c.get();
+            c.set(polls.saturating_sub(1));
+            polls > 0
+        });
+        group_status.flags = if not_viable {
+            0
+        } else {
+            VFIO_GROUP_FLAGS_VIABLE
+        };
+        if GROUP_CONTAINER_SET.with(|c| c.get()) {
+            group_status.flags |= VFIO_GROUP_FLAGS_CONTAINER_SET;
+        }
         Ok(())
     }
 
-    pub(crate) fn get_group_device_fd(_group: &VfioGroup, _path: &CStr) -> Result<File> {
+    // Inode of the file `fd` refers to, if it is open.
+    pub(crate) fn fd_inode(fd: RawFd) -> Option<u64> {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: stat is valid for writes and only read if fstat() succeeded.
+        unsafe {
+            if libc::fstat(fd, stat.as_mut_ptr()) < 0 {
+                return None;
+            }
+            Some(stat.assume_init().st_ino)
+        }
+    }
+
+    thread_local! {
+        // (group fd, device fd, device inode) of the fds returned by get_group_device_fd().
+        static DEVICE_FDS: RefCell<Vec<(RawFd, RawFd, u64)>> = const { RefCell::new(Vec::new()) };
+        // (group id, number of open device fds of the group) of the unset_group_container() calls.
+        static UNSET_CONTAINER_LOG: RefCell<Vec<(u32, usize)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn take_unset_container_log() -> Vec<(u32, usize)> {
+        UNSET_CONTAINER_LOG.with(|v| v.take())
+    }
+
+    thread_local! {
+        // Number of upcoming get_group_device_fd() calls failing as for an unbound device.
+        static GROUP_DEVICE_FD_FAILS: Cell<u32> = const { Cell::new(0) };
+    }
+
+    pub(crate) fn inject_group_device_fd_failure(count: u32) {
+        GROUP_DEVICE_FD_FAILS.with(|c| c.set(count));
+    }
+
+    pub(crate) fn get_group_device_fd(group: &VfioGroup, _path: &CStr) -> Result<OwnedFd> {
+        let fails = GROUP_DEVICE_FD_FAILS.with(|c| {
+            let count = c.get();
+            c.set(count.saturating_sub(1));
+            count > 0
+        });
+        if fails {
+            return Err(VfioError::GroupGetDeviceFD);
+        }
         let tmp_file = TempFile::new().unwrap();
-        let device = File::open(tmp_file.as_path()).unwrap();
+        let device = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp_file.as_path())
+            .unwrap();
+        let ino = fd_inode(device.as_raw_fd()).unwrap();
+        DEVICE_FDS.with(|v| {
+            v.borrow_mut()
+                .push((group.as_raw_fd(), device.as_raw_fd(), ino))
+        });
+
+        Ok(device.into())
+    }
+
+    thread_local! {
+        // Number of upcoming set_group_container() calls failing with EBUSY.
+        static SET_CONTAINER_BUSY: Cell<u32> = const { Cell::new(0) };
+    }
 
-        Ok(device)
+    pub(crate) fn inject_set_container_busy(count: u32) {
+        SET_CONTAINER_BUSY.with(|c| c.set(count));
+    }
+
+    thread_local! {
+        // Container fd of the set_group_container() calls failing with EINVAL.
+        static SET_CONTAINER_INVALID: Cell<RawFd> = const { Cell::new(-1) };
+    }
+
+    pub(crate) fn inject_set_container_invalid(container: &VfioContainer) {
+        SET_CONTAINER_INVALID.with(|c| c.set(container.as_raw_fd()));
     }
 
     pub(crate) fn set_group_container(group: &VfioGroup, container: &VfioContainer) -> Result<()> {
-        if group.as_raw_fd() >= 0 && container.as_raw_fd() >= 0 {
+        let busy = SET_CONTAINER_BUSY.with(|c| {
+            let count = c.get();
+            c.set(count.saturating_sub(1));
+            count > 0
+        });
+        if busy {
+            Err(VfioError::GroupBusy { group_id: group.id })
+        } else if SET_CONTAINER_INVALID.with(|c| c.get()) == container.as_raw_fd() {
+            Err(VfioError::GroupSetContainerInvalid { group_id: group.id })
+        } else if group.as_raw_fd() >= 0 && container.as_raw_fd() >= 0 {
             Ok(())
         } else {
             Err(VfioError::GroupSetContainer)
@@ -318,6 +1039,14 @@ pub(crate) mod vfio_syscall {
         group: &VfioGroup,
         container: &VfioContainer,
     ) -> Result<()> {
+        let open = DEVICE_FDS.with(|v| {
+            let mut fds = v.borrow_mut();
+            fds.retain(|(_, fd, ino)| fd_inode(*fd) == Some(*ino));
+            fds.iter()
+                .filter(|(fd, _, _)| *fd == group.as_raw_fd())
+                .count()
+        });
+        UNSET_CONTAINER_LOG.with(|v| v.borrow_mut().push((group.id(), open)));
         if group.as_raw_fd() >= 0 && container.as_raw_fd() >= 0 {
             Ok(())
         } else {
@@ -325,21 +1054,77 @@ pub(crate) mod vfio_syscall {
         }
     }
 
-    pub(crate) fn get_device_info(_file: &File, dev_info: &mut vfio_device_info) -> Result<()> {
-        dev_info.flags = VFIO_DEVICE_FLAGS_PCI;
+    thread_local! {
+        // Flags reported by get_device_info().
+        static DEVICE_FLAGS: Cell<u32> = const { Cell::new(VFIO_DEVICE_FLAGS_PCI) };
+        // Indexes of the region reporting capabilities and of the region failing to be queried.
+        static REGION_LAYOUT: Cell<(u32, u32)> = const { Cell::new((1, 7)) };
+        // Flags reported along with VFIO_REGION_INFO_FLAG_CAPS by the region with capabilities.
+        static REGION_CAPS_FLAGS: Cell<u32> = const { Cell::new(0) };
+        // Number of regions reporting the same capabilities, from the one of REGION_LAYOUT.
+        static REGION_CAPS_COUNT: Cell<u32> = const { Cell::new(1) };
+    }
+
+    pub(crate) fn inject_device_flags(flags: u32) {
+        DEVICE_FLAGS.with(|c| c.set(flags));
+    }
+
+    pub(crate) fn inject_region_layout(caps: u32, failing: u32) {
+        REGION_LAYOUT.with(|c| c.set((caps, failing)));
+    }
+
+    pub(crate) fn inject_region_caps_flags(flags: u32) {
+        REGION_CAPS_FLAGS.with(|c| c.set(flags));
+    }
+
+    pub(crate) fn inject_region_caps_count(count: u32) {
+        REGION_CAPS_COUNT.with(|c| c.set(count));
+    }
+
+    // Check whether region `index` reports capabilities.
+    fn region_has_caps(index: u32) -> bool {
+        let (caps, _) = REGION_LAYOUT.with(|c| c.get());
+        index >= caps && index - caps < REGION_CAPS_COUNT.with(|c| c.get())
+    }
+
+    pub(crate) fn get_device_info(_file: &OwnedFd, dev_info: &mut vfio_device_info) -> Result<()> {
+        dev_info.flags = DEVICE_FLAGS.with(|c| c.get());
         dev_info.num_regions = VFIO_PCI_CONFIG_REGION_INDEX + 1;
         dev_info.num_irqs = VFIO_PCI_MSIX_IRQ_INDEX + 1;
         Ok(())
     }
 
+    // A set_device_irqs() call: flags, index, start and the eventfds for DATA_EVENTFD.
+    pub(crate) type SetIrqsCall = (u32, u32, u32, Vec<i32>);
+
+    thread_local! {
+        // set_device_irqs() calls, in order.
+        static SET_IRQS_LOG: RefCell<Vec<SetIrqsCall>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn take_set_irqs_log() -> Vec<SetIrqsCall> {
+        SET_IRQS_LOG.with(|v| v.take())
+    }
+
     #[allow(clippy::if_same_then_else)]
     pub(crate) fn set_device_irqs(_device: &VfioDevice, irq_sets: &[vfio_irq_set]) -> Result<()> {
-        if irq_sets.is_empty()
-            || irq_sets[0].argsz as usize > irq_sets.len() * size_of::<vfio_irq_set>()
-        {
+        if irq_sets.is_empty() || irq_sets[0].argsz as usize > std::mem::size_of_val(irq_sets) {
             Err(VfioError::VfioDeviceSetIrq)
         } else {
             let irq_set = &irq_sets[0];
+            let fds = if irq_set.flags & VFIO_IRQ_SET_DATA_EVENTFD != 0 {
+                // SAFETY: callers size irq_sets to hold count eventfds.
+                let data = unsafe { irq_set.data.as_slice(irq_set.count as usize * 4) };
+                data.chunks(4)
+                    .map(|fd| i32::from_le_bytes([fd[0], fd[1], fd[2], fd[3]]))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            SET_IRQS_LOG.with(|v| {
+                v.borrow_mut()
+                    .push((irq_set.flags, irq_set.index, irq_set.start, fds))
+            });
             if irq_set.flags == VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER
                 && irq_set.index == 0
                 && irq_set.count == 0
@@ -361,47 +1146,157 @@ pub(crate) mod vfio_syscall {
         }
     }
 
+    thread_local! {
+        // reset() fails, as for a device which doesn't come back from the reset.
+        static RESET_FAILS: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub(crate) fn inject_reset_failure(fails: bool) {
+        RESET_FAILS.with(|c| c.set(fails));
+    }
+
     pub(crate) fn reset(_device: &VfioDevice) -> i32 {
-        0
+        if RESET_FAILS.with(|c| c.get()) {
+            -1
+        } else {
+            0
+        }
+    }
+
+    thread_local! {
+        // Devices reported by get_pci_hot_reset_info(), which fails with ENODEV when empty.
+        static HOT_RESET_INFO: RefCell<Vec<vfio_pci_dependent_device>> =
+            const { RefCell::new(Vec::new()) };
+        // Number of groups passed to each pci_hot_reset() call.
+        static HOT_RESET_LOG: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn inject_hot_reset_info(devices: &[vfio_pci_dependent_device]) {
+        HOT_RESET_INFO.with(|v| *v.borrow_mut() = devices.to_vec());
+    }
+
+    pub(crate) fn take_hot_reset_log() -> Vec<u32> {
+        HOT_RESET_LOG.with(|v| v.take())
+    }
+
+    pub(crate) fn get_pci_hot_reset_info(
+        _device: &VfioDevice,
+        infos: &mut [vfio_pci_hot_reset_info],
+    ) -> Result<()> {
+        let devices = HOT_RESET_INFO.with(|v| v.borrow().clone());
+        if devices.is_empty() {
+            return Err(VfioError::VfioDeviceHotResetInfo(SysError::new(
+                libc::ENODEV,
+            )));
+        }
+
+        let needed = size_of::<vfio_pci_hot_reset_info>()
+            + devices.len() * size_of::<vfio_pci_dependent_device>();
+        let info = &mut infos[0];
+        info.count = devices.len() as u32;
+        if (info.argsz as usize) < needed {
+            return Err(VfioError::VfioDeviceHotResetInfo(SysError::new(
+                libc::ENOSPC,
+            )));
+        }
+        // SAFETY: the caller reserved space for argsz bytes.
+        unsafe { info.devices.as_mut_slice(devices.len()) }.copy_from_slice(&devices);
+
+        Ok(())
+    }
+
+    pub(crate) fn pci_hot_reset(_device: &VfioDevice, resets: &[vfio_pci_hot_reset]) -> Result<()> {
+        HOT_RESET_LOG.with(|v| v.borrow_mut().push(resets[0].count));
+        Ok(())
+    }
+
+    pub(crate) fn get_device_feature_mig_data_size(
+        _device: &VfioDevice,
+        feature: &mut vfio_device_feature_mig_data_size,
+    ) -> Result<()> {
+        if feature.flags == VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIG_DATA_SIZE {
+            feature.stop_copy_length = 0x2800;
+            Ok(())
+        } else {
+            Err(VfioError::VfioDeviceFeature(SysError::new(libc::EINVAL)))
+        }
+    }
+
+    // Migration is supported, DMA logging isn't, and low power entry is denied.
+    pub(crate) fn probe_device_feature(
+        _device: &VfioDevice,
+        feature: &vfio_device_feature,
+    ) -> Result<()> {
+        let errno = match feature.flags & !(VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_SET) {
+            f if f == VFIO_DEVICE_FEATURE_PROBE | VFIO_DEVICE_FEATURE_MIGRATION => return Ok(()),
+            f if f == VFIO_DEVICE_FEATURE_PROBE | VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY => {
+                libc::EPERM
+            }
+            _ => libc::ENOTTY,
+        };
+
+        Err(VfioError::VfioDeviceFeature(SysError::new(errno)))
     }
 
-    pub(crate) fn get_device_region_info(
-        _dev_info: &VfioDeviceInfo,
+    pub(crate) fn get_device_region_info<F: AsRawFd>(
+        _device: &F,
         reg_info: &mut vfio_region_info,
     ) -> Result<()> {
+        let (_, failing) = REGION_LAYOUT.with(|c| c.get());
         match reg_info.index {
-            0 => {
-                reg_info.flags = 0;
-                reg_info.size = 0x1000;
-                reg_info.offset = 0x10000;
+            idx if idx == failing => {
+                return Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(
+                    libc::EINVAL,
+                )))
             }
-            1 => {
+            idx if region_has_caps(idx) => {
                 reg_info.argsz = 88;
-                reg_info.flags = VFIO_REGION_INFO_FLAG_CAPS;
+                reg_info.flags = VFIO_REGION_INFO_FLAG_CAPS | REGION_CAPS_FLAGS.with(|c| c.get());
                 reg_info.size = 0x2000;
                 reg_info.offset = 0x20000;
             }
-            idx if (2..7).contains(&idx) => {
+            idx if idx < 8 => {
                 reg_info.flags = 0;
                 reg_info.size = (idx as u64 + 1) * 0x1000;
                 reg_info.offset = (idx as u64 + 1) * 0x10000;
             }
-            7 => {
-                return Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(
-                    libc::EINVAL,
-                )))
-            }
             _ => panic!("invalid device region index"),
         }
 
         Ok(())
     }
 
-    pub(crate) fn get_device_region_info_cap(
-        _dev_info: &VfioDeviceInfo,
+    thread_local! {
+        // Number of get_device_region_info_cap() calls.
+        static REGION_CAP_QUERIES: Cell<u32> = const { Cell::new(0) };
+        // get_device_region_info_cap() rejects the query, as some kernels do.
+        static REGION_CAP_QUERY_FAILS: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub(crate) fn take_region_cap_queries() -> u32 {
+        REGION_CAP_QUERIES.with(|c| c.replace(0))
+    }
+
+    pub(crate) fn inject_region_cap_query_failure(fails: bool) {
+        REGION_CAP_QUERY_FAILS.with(|c| c.set(fails));
+    }
+
+    thread_local! {
+        // Raw capability chain returned at offset 32 instead of the default one.
+        static REGION_CAP_CHAIN: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn inject_region_cap_chain(chain: Vec<u8>) {
+        REGION_CAP_CHAIN.with(|v| *v.borrow_mut() = chain);
+    }
+
+    pub(crate) fn get_device_region_info_cap<F: AsRawFd>(
+        _device: &F,
         reg_infos: &mut [vfio_region_info_with_cap],
     ) -> Result<()> {
-        if reg_infos.is_empty()
+        REGION_CAP_QUERIES.with(|c| c.set(c.get() + 1));
+        if REGION_CAP_QUERY_FAILS.with(|c| c.get())
+            || reg_infos.is_empty()
             || reg_infos[0].region_info.argsz as usize
                 > reg_infos.len() * size_of::<vfio_region_info>()
         {
@@ -410,9 +1305,24 @@ pub(crate) mod vfio_syscall {
             )));
         }
 
+        let chain = REGION_CAP_CHAIN.with(|v| v.take());
+        if !chain.is_empty() {
+            reg_infos[0].region_info.cap_offset = 32;
+            // SAFETY: the slice covers the whole buffer, made of plain data.
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(
+                    reg_infos.as_mut_ptr() as *mut u8,
+                    std::mem::size_of_val(reg_infos),
+                )
+            };
+            let len = chain.len().min(bytes.len() - 32);
+            bytes[32..32 + len].copy_from_slice(&chain[..len]);
+            return Ok(());
+        }
+
         let reg_info = &mut reg_infos[0];
         match reg_info.region_info.index {
-            1 => {
+            idx if region_has_caps(idx) => {
                 reg_info.region_info.cap_offset = 32;
                 // SAFETY: data structure returned by kernel is trusted.
                 let header = unsafe {
@@ -449,14 +1359,97 @@ pub(crate) mod vfio_syscall {
                 mmap.size = 0x3;
                 mmap.offset = 0x4;
             }
-            _ => panic!("invalid device region index"),
+            // Any other region reports the CAPS flag with an empty chain.
+            _ => reg_info.region_info.cap_offset = 0,
+        }
+
+        Ok(())
+    }
+
+    thread_local! {
+        // Next IO virtual address allocated by ioas_map(), and the unmapped ranges.
+        static IOAS_NEXT_IOVA: Cell<u64> = const { Cell::new(0x10_0000) };
+        static IOAS_UNMAP_LOG: RefCell<Vec<(u64, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn ioas_alloc<F: AsRawFd>(_iommufd: &F, alloc: &mut iommu_ioas_alloc) -> Result<()> {
+        alloc.out_ioas_id = 2;
+        Ok(())
+    }
+
+    pub(crate) fn ioas_map<F: AsRawFd>(_iommufd: &F, map: &mut iommu_ioas_map) -> Result<()> {
+        if map.length == 0 {
+            return Err(VfioError::IommufdIoasMap(SysError::new(libc::EINVAL)));
+        }
+        if map.flags & IOMMU_IOAS_MAP_FIXED_IOVA == 0 {
+            map.iova = IOAS_NEXT_IOVA.with(|next| next.replace(next.get() + map.length));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn ioas_unmap<F: AsRawFd>(_iommufd: &F, unmap: &mut iommu_ioas_unmap) -> Result<()> {
+        IOAS_UNMAP_LOG.with(|v| v.borrow_mut().push((unmap.iova, unmap.length)));
+        Ok(())
+    }
+
+    pub(crate) fn take_ioas_unmap_log() -> Vec<(u64, u64)> {
+        IOAS_UNMAP_LOG.with(|v| v.take())
+    }
+
+    pub(crate) fn iommufd_destroy<F: AsRawFd>(_iommufd: &F, _destroy: &iommu_destroy) -> i32 {
+        0
+    }
+
+    thread_local! {
+        // Next device id handed out by bind_iommufd(), and the IO address space attached to.
+        static IOMMUFD_NEXT_DEVID: Cell<u32> = const { Cell::new(1) };
+        static IOMMUFD_ATTACHED_PT: Cell<Option<u32>> = const { Cell::new(None) };
+    }
+
+    pub(crate) fn bind_iommufd<F: AsRawFd>(
+        _device: &F,
+        bind: &mut vfio_device_bind_iommufd,
+    ) -> Result<()> {
+        if bind.iommufd < 0 {
+            return Err(VfioError::IommufdBindDevice(SysError::new(libc::EBADF)));
         }
+        bind.out_devid = IOMMUFD_NEXT_DEVID.with(|next| next.replace(next.get() + 1));
+        Ok(())
+    }
 
+    pub(crate) fn attach_iommufd_pt<F: AsRawFd>(
+        _device: &F,
+        attach: &mut vfio_device_attach_iommufd_pt,
+    ) -> Result<()> {
+        IOMMUFD_ATTACHED_PT.with(|pt| pt.set(Some(attach.pt_id)));
         Ok(())
     }
 
-    pub(crate) fn get_device_irq_info(
-        _dev_info: &VfioDeviceInfo,
+    pub(crate) fn detach_iommufd_pt<F: AsRawFd>(
+        _device: &F,
+        _detach: &vfio_device_detach_iommufd_pt,
+    ) -> Result<()> {
+        match IOMMUFD_ATTACHED_PT.with(|pt| pt.take()) {
+            Some(_) => Ok(()),
+            None => Err(VfioError::IommufdDetachDevice(SysError::new(libc::EINVAL))),
+        }
+    }
+
+    pub(crate) fn iommufd_attached_pt() -> Option<u32> {
+        IOMMUFD_ATTACHED_PT.with(|pt| pt.get())
+    }
+
+    thread_local! {
+        // Number of interrupts reported for an index, overriding the default ones.
+        static IRQ_COUNTS: RefCell<Vec<(u32, u32)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) fn inject_irq_count(index: u32, count: u32) {
+        IRQ_COUNTS.with(|v| v.borrow_mut().push((index, count)));
+    }
+
+    pub(crate) fn get_device_irq_info<F: AsRawFd>(
+        _device: &F,
         irq_info: &mut vfio_irq_info,
     ) -> Result<()> {
         match irq_info.index {
@@ -472,12 +1465,26 @@ pub(crate) mod vfio_syscall {
                 irq_info.flags = VFIO_IRQ_INFO_EVENTFD;
                 irq_info.count = 2048;
             }
-            3 => {
-                return Err(VfioError::VfioDeviceGetRegionInfo(SysError::new(
-                    libc::EINVAL,
-                )))
+            3 => return Err(VfioError::VfioDeviceGetIrqInfo),
+            4 => {
+                irq_info.flags = VFIO_IRQ_INFO_EVENTFD;
+                irq_info.count = 1;
             }
-            _ => panic!("invalid device irq index"),
+            5 => {
+                irq_info.flags = VFIO_IRQ_INFO_EVENTFD;
+                irq_info.count = 0;
+            }
+            _ => return Err(VfioError::VfioDeviceGetIrqInfo),
+        }
+        let injected = IRQ_COUNTS.with(|v| {
+            v.borrow()
+                .iter()
+                .rev()
+                .find(|(index, _)| *index == irq_info.index)
+                .copied()
+        });
+        if let Some((_, count)) = injected {
+            irq_info.count = count;
         }
 
         Ok(())
@@ -497,6 +1504,14 @@ pub(crate) mod vfio_syscall {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_iommufd_ioctl_code() {
+        assert_eq!(IOMMU_DESTROY(), 15232);
+        assert_eq!(IOMMU_IOAS_ALLOC(), 15233);
+        assert_eq!(IOMMU_IOAS_MAP(), 15237);
+        assert_eq!(IOMMU_IOAS_UNMAP(), 15238);
+    }
+
     #[test]
     fn test_vfio_ioctl_code() {
         assert_eq!(VFIO_GET_API_VERSION(), 15204);
@@ -513,5 +1528,9 @@ mod tests {
         assert_eq!(VFIO_DEVICE_RESET(), 15215);
         assert_eq!(VFIO_DEVICE_IOEVENTFD(), 15220);
         assert_eq!(VFIO_IOMMU_DISABLE(), 15220);
+        assert_eq!(VFIO_DEVICE_FEATURE(), 15221);
+        assert_eq!(VFIO_DEVICE_BIND_IOMMUFD(), 15222);
+        assert_eq!(VFIO_DEVICE_ATTACH_IOMMUFD_PT(), 15223);
+        assert_eq!(VFIO_DEVICE_DETACH_IOMMUFD_PT(), 15224);
     }
 }
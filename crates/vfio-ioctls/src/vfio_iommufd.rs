@@ -0,0 +1,273 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use log::warn;
+
+use crate::vfio_ioctls::*;
+use crate::{HostVa, Iova, Result, VfioError, VfioPaths};
+
+/// An IO address space of the IOMMUFD interface, in `/dev/iommu`.
+///
+/// Unlike the type1 IOMMU of a [`VfioContainer`](crate::VfioContainer), which maps memory at
+/// the IO virtual addresses picked by the caller, IOMMUFD can allocate them itself. Devices,
+/// opened through their character device in `/dev/vfio/devices`, perform DMA within the IO
+/// address space once attached to it with [`attach_device()`](Self::attach_device). The IO
+/// address space is released when the object is dropped.
+pub struct IommufdContainer {
+    iommufd: File,
+    ioas_id: u32,
+}
+
+impl IommufdContainer {
+    /// Open `/dev/iommu` and allocate an IO address space.
+    pub fn new() -> Result<Self> {
+        Self::new_with_paths(&VfioPaths::default())
+    }
+
+    /// Open the IOMMUFD device found next to a non-standard VFIO directory, and allocate an IO
+    /// address space.
+    ///
+    /// # Arguments
+    /// * `paths`: Locations of the VFIO character devices, see [`VfioPaths::iommufd_path()`].
+    pub fn new_with_paths(paths: &VfioPaths) -> Result<Self> {
+        let path = paths
+            .iommufd_path()
+            .ok_or_else(|| VfioError::OpenIommufd(io::ErrorKind::NotFound.into()))?;
+        let iommufd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(VfioError::OpenIommufd)?;
+        let mut alloc = iommu_ioas_alloc {
+            size: mem::size_of::<iommu_ioas_alloc>() as u32,
+            ..Default::default()
+        };
+        vfio_syscall::ioas_alloc(&iommufd, &mut alloc)?;
+
+        Ok(IommufdContainer {
+            iommufd,
+            ioas_id: alloc.out_ioas_id,
+        })
+    }
+
+    /// Get the id of the IO address space, to attach devices to it.
+    pub fn ioas_id(&self) -> u32 {
+        self.ioas_id
+    }
+
+    /// Bind a VFIO device to this IOMMUFD and attach it to the IO address space.
+    ///
+    /// The device id allocated by the kernel is returned. The device stays bound until its
+    /// file is closed: VFIO has no way to undo the bind, so if attaching the device fails
+    /// after it was bound, the caller has to close the device file before binding it again.
+    ///
+    /// # Parameters
+    /// * device: VFIO device, opened through its character device in `/dev/vfio/devices`.
+    pub fn attach_device<F: AsRawFd>(&self, device: &F) -> Result<u32> {
+        let mut bind = vfio_device_bind_iommufd {
+            argsz: mem::size_of::<vfio_device_bind_iommufd>() as u32,
+            iommufd: self.iommufd.as_raw_fd(),
+            ..Default::default()
+        };
+        vfio_syscall::bind_iommufd(device, &mut bind)?;
+
+        let mut attach = vfio_device_attach_iommufd_pt {
+            argsz: mem::size_of::<vfio_device_attach_iommufd_pt>() as u32,
+            pt_id: self.ioas_id,
+            ..Default::default()
+        };
+        if let Err(e) = vfio_syscall::attach_iommufd_pt(device, &mut attach) {
+            warn!(
+                "Device {} stays bound to the IOMMUFD until closed: {}",
+                bind.out_devid, e
+            );
+            return Err(e);
+        }
+
+        Ok(bind.out_devid)
+    }
+
+    /// Detach a VFIO device from the IO address space.
+    ///
+    /// # Parameters
+    /// * device: VFIO device attached with [`attach_device()`](Self::attach_device).
+    pub fn detach_device<F: AsRawFd>(&self, device: &F) -> Result<()> {
+        let detach = vfio_device_detach_iommufd_pt {
+            argsz: mem::size_of::<vfio_device_detach_iommufd_pt>() as u32,
+            flags: 0,
+        };
+        vfio_syscall::detach_iommufd_pt(device, &detach)
+    }
+
+    /// Map memory for DMA at a given IO virtual address.
+    ///
+    /// The memory is mapped readable and writable by the device.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address to map the memory at.
+    /// * size: size of the memory to map.
+    /// * user_addr: host virtual address of the memory.
+    pub fn dma_map(&self, iova: Iova, size: usize, user_addr: HostVa) -> Result<()> {
+        self.ioas_map(Some(iova), size, user_addr).map(|_| ())
+    }
+
+    /// Map memory for DMA at an IO virtual address allocated by the kernel, and return it.
+    ///
+    /// The memory is mapped readable and writable by the device. The kernel picks the IO
+    /// virtual address among the ranges usable by the devices attached to the address space,
+    /// so devices should be attached with [`attach_device()`](Self::attach_device) first.
+    ///
+    /// # Parameters
+    /// * size: size of the memory to map.
+    /// * user_addr: host virtual address of the memory.
+    pub fn dma_map_auto(&self, size: usize, user_addr: HostVa) -> Result<Iova> {
+        self.ioas_map(None, size, user_addr)
+    }
+
+    // Map memory at `iova`, or at an address allocated by the kernel, which is returned.
+    fn ioas_map(&self, iova: Option<Iova>, size: usize, user_addr: HostVa) -> Result<Iova> {
+        let mut flags = IOMMU_IOAS_MAP_READABLE | IOMMU_IOAS_MAP_WRITEABLE;
+        if iova.is_some() {
+            flags |= IOMMU_IOAS_MAP_FIXED_IOVA;
+        }
+        let mut map = iommu_ioas_map {
+            size: mem::size_of::<iommu_ioas_map>() as u32,
+            flags,
+            ioas_id: self.ioas_id,
+            user_va: user_addr.raw_value(),
+            length: size as u64,
+            iova: iova.map_or(0, |iova| iova.raw_value()),
+            ..Default::default()
+        };
+        vfio_syscall::ioas_map(self, &mut map)?;
+
+        Ok(Iova(map.iova))
+    }
+
+    /// Unmap the DMA mappings within a range of IO virtual addresses.
+    ///
+    /// The size the kernel actually unmapped is returned.
+    ///
+    /// # Parameters
+    /// * iova: IO virtual address of the range.
+    /// * size: size of the range.
+    pub fn dma_unmap(&self, iova: Iova, size: u64) -> Result<u64> {
+        let mut unmap = iommu_ioas_unmap {
+            size: mem::size_of::<iommu_ioas_unmap>() as u32,
+            ioas_id: self.ioas_id,
+            iova: iova.raw_value(),
+            length: size,
+        };
+        vfio_syscall::ioas_unmap(self, &mut unmap)?;
+
+        Ok(unmap.length)
+    }
+}
+
+impl AsRawFd for IommufdContainer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iommufd.as_raw_fd()
+    }
+}
+
+impl Drop for IommufdContainer {
+    fn drop(&mut self) {
+        let destroy = iommu_destroy {
+            size: mem::size_of::<iommu_destroy>() as u32,
+            id: self.ioas_id,
+        };
+        if vfio_syscall::iommufd_destroy(self, &destroy) < 0 {
+            warn!(
+                "Failed to destroy iommufd IO address space {}: {}",
+                self.ioas_id,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_iommufd_dma_map_auto() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_iommufd").unwrap();
+        let paths = VfioPaths {
+            dev_vfio_root: root.as_path().join("vfio"),
+            ..VfioPaths::default()
+        };
+        assert!(matches!(
+            IommufdContainer::new_with_paths(&paths),
+            Err(VfioError::OpenIommufd(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            IommufdContainer::new_with_paths(&VfioPaths {
+                dev_vfio_root: PathBuf::from("/"),
+                ..VfioPaths::default()
+            }),
+            Err(VfioError::OpenIommufd(_))
+        ));
+
+        File::create(root.as_path().join("iommu")).unwrap();
+        let container = IommufdContainer::new_with_paths(&paths).unwrap();
+        assert_eq!(container.ioas_id(), 2);
+
+        // The kernel allocates the addresses, one after the other here.
+        let first = container.dma_map_auto(0x2000, HostVa(0x8000)).unwrap();
+        let second = container.dma_map_auto(0x1000, HostVa(0x20000)).unwrap();
+        assert_eq!(second.raw_value(), first.raw_value() + 0x2000);
+        assert!(matches!(
+            container.dma_map_auto(0, HostVa(0x8000)),
+            Err(VfioError::IommufdIoasMap(_))
+        ));
+        // Fixed addresses don't move the allocation.
+        container
+            .dma_map(Iova(0x1000), 0x1000, HostVa(0x8000))
+            .unwrap();
+        let third = container.dma_map_auto(0x1000, HostVa(0x8000)).unwrap();
+        assert_eq!(third.raw_value(), second.raw_value() + 0x1000);
+
+        assert_eq!(container.dma_unmap(first, 0x2000).unwrap(), 0x2000);
+        assert_eq!(
+            vfio_syscall::take_ioas_unmap_log(),
+            vec![(first.raw_value(), 0x2000)]
+        );
+    }
+
+    #[test]
+    fn test_iommufd_attach_device() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_iommufd").unwrap();
+        let paths = VfioPaths {
+            dev_vfio_root: root.as_path().join("vfio"),
+            ..VfioPaths::default()
+        };
+        File::create(root.as_path().join("iommu")).unwrap();
+        let container = IommufdContainer::new_with_paths(&paths).unwrap();
+        let device = File::create(root.as_path().join("vfio0")).unwrap();
+
+        assert!(matches!(
+            container.detach_device(&device),
+            Err(VfioError::IommufdDetachDevice(_))
+        ));
+        let first = container.attach_device(&device).unwrap();
+        assert_eq!(
+            vfio_syscall::iommufd_attached_pt(),
+            Some(container.ioas_id())
+        );
+        container.detach_device(&device).unwrap();
+        assert_eq!(vfio_syscall::iommufd_attached_pt(), None);
+
+        // Every binding gets a device id of its own.
+        let second = container.attach_device(&device).unwrap();
+        assert_ne!(first, second);
+    }
+}
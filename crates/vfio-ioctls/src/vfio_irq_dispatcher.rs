@@ -0,0 +1,396 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{error, warn};
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::vfio_device::lock_or_recover;
+use crate::{Result, VfioDevice, VfioError};
+
+// Token used to wake up the dispatcher thread when the dispatcher is dropped.
+const EXIT_TOKEN: u64 = u64::MAX;
+// Maximum number of events handled per epoll_wait() call.
+const EPOLL_EVENTS_LEN: usize = 64;
+
+/// Callback invoked with the device, the irq index and the vector of a triggered interrupt.
+pub type VfioIrqHandler = dyn Fn(&VfioDevice, u32, u32) + Send + Sync;
+
+fn irq_token(irq_index: u32, vector: u32) -> u64 {
+    (u64::from(irq_index) << 32) | u64::from(vector)
+}
+
+struct DispatcherInner {
+    device: Arc<VfioDevice>,
+    epoll: Epoll,
+    handler: Box<VfioIrqHandler>,
+    // EventFds registered with the device, indexed by irq index.
+    irqs: Mutex<HashMap<u32, Vec<EventFd>>>,
+    exit_evt: EventFd,
+}
+
+impl DispatcherInner {
+    fn dispatch(&self, timeout: i32) -> Result<usize> {
+        let mut events = vec![EpollEvent::default(); EPOLL_EVENTS_LEN];
+        let num_events = match self.epoll.wait(timeout, &mut events[..]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(0),
+            Err(e) => return Err(VfioError::IrqDispatcher(e)),
+        };
+
+        let mut triggered = Vec::with_capacity(num_events);
+        {
+            let irqs = lock_or_recover(&self.irqs);
+            for event in events.iter().take(num_events) {
+                let token = event.data();
+                if token == EXIT_TOKEN {
+                    continue;
+                }
+                let irq_index = (token >> 32) as u32;
+                let vector = token as u32;
+                // The vector may have been disabled after epoll_wait() returned.
+                if let Some(evt) = irqs
+                    .get(&irq_index)
+                    .and_then(|fds| fds.get(vector as usize))
+                {
+                    if let Err(e) = evt.read() {
+                        if e.kind() != io::ErrorKind::WouldBlock {
+                            warn!("Failed to read irq eventfd {}/{}: {}", irq_index, vector, e);
+                        }
+                        continue;
+                    }
+                    triggered.push((irq_index, vector));
+                }
+            }
+        }
+
+        for (irq_index, vector) in triggered.iter() {
+            (self.handler)(&self.device, *irq_index, *vector);
+        }
+
+        Ok(triggered.len())
+    }
+
+    fn deregister(&self, irq_index: u32, fds: &[EventFd]) {
+        for (vector, evt) in fds.iter().enumerate() {
+            self.deregister_fd(irq_token(irq_index, vector as u32), evt);
+        }
+    }
+
+    fn deregister_fd(&self, token: u64, evt: &EventFd) {
+        if let Err(e) = self.epoll.ctl(
+            ControlOperation::Delete,
+            evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, token),
+        ) {
+            warn!(
+                "Failed to deregister irq {}/{}: {}",
+                token >> 32,
+                token as u32,
+                e
+            );
+        }
+    }
+}
+
+/// Epoll based dispatcher for VFIO device interrupts.
+///
+/// The dispatcher creates and owns the EventFds passed to the kernel for each enabled interrupt
+/// index, and registers them into an internal epoll instance. Interrupts are reported through
+/// a user supplied callback, either by calling [`dispatch()`](Self::dispatch) when the epoll fd
+/// returned by `as_raw_fd()` is readable from an outer event loop, or from a dedicated thread
+/// started with [`spawn()`](Self::spawn).
+///
+/// All enabled interrupts are disabled, and the EventFds closed, when the dispatcher is dropped.
+pub struct VfioIrqDispatcher {
+    inner: Arc<DispatcherInner>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VfioIrqDispatcher {
+    /// Create a new interrupt dispatcher for a VFIO device.
+    ///
+    /// # Arguments
+    /// * `device` - The VFIO device whose interrupts are dispatched.
+    /// * `handler` - Callback invoked with the irq index and vector of each triggered interrupt.
+    pub fn new<F>(device: Arc<VfioDevice>, handler: F) -> Result<Self>
+    where
+        F: Fn(&VfioDevice, u32, u32) + Send + Sync + 'static,
+    {
+        let epoll = Epoll::new().map_err(VfioError::IrqDispatcher)?;
+        let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(VfioError::IrqDispatcher)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                exit_evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, EXIT_TOKEN),
+            )
+            .map_err(VfioError::IrqDispatcher)?;
+
+        Ok(VfioIrqDispatcher {
+            inner: Arc::new(DispatcherInner {
+                device,
+                epoll,
+                handler: Box::new(handler),
+                irqs: Mutex::new(HashMap::new()),
+                exit_evt,
+            }),
+            thread: None,
+        })
+    }
+
+    /// Enable `count` interrupts of the `irq_index` type and dispatch them to the callback.
+    ///
+    /// Interrupts previously enabled for `irq_index` through the dispatcher are replaced.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to enable.
+    /// * `count` - Number of vectors to enable, starting from vector 0.
+    pub fn enable_irq(&self, irq_index: u32, count: u32) -> Result<()> {
+        let mut fds = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            fds.push(EventFd::new(EFD_NONBLOCK).map_err(VfioError::IrqDispatcher)?);
+        }
+
+        let mut irqs = lock_or_recover(&self.inner.irqs);
+        self.inner
+            .device
+            .enable_irq(irq_index, fds.iter().collect())?;
+        if let Some(old_fds) = irqs.remove(&irq_index) {
+            self.inner.deregister(irq_index, &old_fds);
+        }
+
+        for (vector, evt) in fds.iter().enumerate() {
+            if let Err(e) = self.inner.epoll.ctl(
+                ControlOperation::Add,
+                evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, irq_token(irq_index, vector as u32)),
+            ) {
+                self.inner.deregister(irq_index, &fds[..vector]);
+                let _ = self.inner.device.disable_irq(irq_index);
+                return Err(VfioError::IrqDispatcher(e));
+            }
+        }
+        irqs.insert(irq_index, fds);
+
+        Ok(())
+    }
+
+    /// Bind a new EventFd to a single vector and dispatch it to the callback.
+    ///
+    /// The vector must be enabled through the dispatcher, or right after the last vector enabled
+    /// through it, as for [`VfioDevice::assign_vector()`]. An interrupt pending on the replaced
+    /// EventFd is carried over to the new one.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to update.
+    /// * `vector` - The sub-index into the interrupt group of `irq_index`.
+    pub fn assign_vector(&self, irq_index: u32, vector: u32) -> Result<()> {
+        let evt = EventFd::new(EFD_NONBLOCK).map_err(VfioError::IrqDispatcher)?;
+
+        let mut irqs = lock_or_recover(&self.inner.irqs);
+        if irqs.get(&irq_index).map_or(0, |fds| fds.len()) < vector as usize {
+            return Err(VfioError::VfioDeviceEnableIrq);
+        }
+
+        let token = irq_token(irq_index, vector);
+        self.inner
+            .epoll
+            .ctl(
+                ControlOperation::Add,
+                evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, token),
+            )
+            .map_err(VfioError::IrqDispatcher)?;
+        if let Err(e) = self.inner.device.assign_vector(irq_index, vector, &evt) {
+            self.inner.deregister_fd(token, &evt);
+            return Err(e);
+        }
+
+        let fds = irqs.entry(irq_index).or_default();
+        if let Some(old_evt) = fds.get_mut(vector as usize) {
+            self.inner.deregister_fd(token, old_evt);
+            if old_evt.read().is_ok() {
+                if let Err(e) = evt.write(1) {
+                    warn!("Failed to forward irq {}/{}: {}", irq_index, vector, e);
+                }
+            }
+            *old_evt = evt;
+        } else {
+            fds.push(evt);
+        }
+
+        Ok(())
+    }
+
+    /// Disable the interrupts of the `irq_index` type and close the associated EventFds.
+    ///
+    /// # Arguments
+    /// * `irq_index` - The type (INTX, MSI or MSI-X) of interrupts to disable.
+    pub fn disable_irq(&self, irq_index: u32) -> Result<()> {
+        let mut irqs = lock_or_recover(&self.inner.irqs);
+        self.inner.device.disable_irq(irq_index)?;
+        if let Some(fds) = irqs.remove(&irq_index) {
+            self.inner.deregister(irq_index, &fds);
+        }
+
+        Ok(())
+    }
+
+    /// Wait for interrupts and invoke the callback for each of them.
+    ///
+    /// Return the number of interrupts dispatched.
+    ///
+    /// # Arguments
+    /// * `timeout` - Timeout in milliseconds as for `epoll_wait()`, -1 waits forever.
+    pub fn dispatch(&self, timeout: i32) -> Result<usize> {
+        self.inner.dispatch(timeout)
+    }
+
+    /// Dispatch interrupts from a dedicated thread until the dispatcher is dropped.
+    pub fn spawn(&mut self) -> Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+
+        let inner = self.inner.clone();
+        let thread = thread::Builder::new()
+            .name("vfio_irq".to_string())
+            .spawn(move || loop {
+                if let Err(e) = inner.dispatch(-1) {
+                    error!("Failed to dispatch VFIO interrupts: {}", e);
+                    break;
+                }
+                if inner.exit_evt.read().is_ok() {
+                    break;
+                }
+            })
+            .map_err(VfioError::IrqDispatcher)?;
+        self.thread = Some(thread);
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for VfioIrqDispatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.epoll.as_raw_fd()
+    }
+}
+
+impl Drop for VfioIrqDispatcher {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(e) = self.inner.exit_evt.write(1) {
+                error!("Failed to stop VFIO irq dispatcher thread: {}", e);
+            } else if thread.join().is_err() {
+                error!("VFIO irq dispatcher thread panicked");
+            }
+        }
+
+        let mut irqs = lock_or_recover(&self.inner.irqs);
+        for (irq_index, fds) in irqs.drain() {
+            if let Err(e) = self.inner.device.disable_irq(irq_index) {
+                warn!("Failed to disable irq index {}: {}", irq_index, e);
+            }
+            self.inner.deregister(irq_index, &fds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_ioctls::vfio_syscall;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn create_vfio_device() -> Arc<VfioDevice> {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        Arc::new(VfioDevice::new(tmp_file.as_path(), container).unwrap())
+    }
+
+    #[test]
+    fn test_vfio_irq_dispatcher() {
+        let device = create_vfio_device();
+        let last = Arc::new(AtomicU64::new(0));
+        let last2 = last.clone();
+        let dispatcher = VfioIrqDispatcher::new(device, move |_, index, vector| {
+            last2.store(irq_token(index, vector), Ordering::SeqCst);
+        })
+        .unwrap();
+
+        assert!(dispatcher.as_raw_fd() >= 0);
+        dispatcher.enable_irq(3, 1).unwrap_err();
+        dispatcher.enable_irq(2, 4).unwrap();
+        assert_eq!(dispatcher.dispatch(0).unwrap(), 0);
+
+        dispatcher.inner.irqs.lock().unwrap()[&2][3]
+            .write(1)
+            .unwrap();
+        assert_eq!(dispatcher.dispatch(0).unwrap(), 1);
+        assert_eq!(last.load(Ordering::SeqCst), irq_token(2, 3));
+
+        // A pending interrupt follows the vector to its new EventFd.
+        let old_fd = dispatcher.inner.irqs.lock().unwrap()[&2][1]
+            .try_clone()
+            .unwrap();
+        old_fd.write(1).unwrap();
+        dispatcher.assign_vector(2, 1).unwrap();
+        old_fd.write(1).unwrap();
+        assert_eq!(dispatcher.dispatch(0).unwrap(), 1);
+        assert_eq!(last.load(Ordering::SeqCst), irq_token(2, 1));
+        assert_eq!(dispatcher.dispatch(0).unwrap(), 0);
+        // Vectors can only be appended after the last one.
+        dispatcher.assign_vector(2, 5).unwrap_err();
+        dispatcher.assign_vector(2, 4).unwrap();
+        assert_eq!(dispatcher.inner.irqs.lock().unwrap()[&2].len(), 5);
+
+        dispatcher.disable_irq(2).unwrap();
+        assert!(dispatcher.inner.irqs.lock().unwrap().is_empty());
+        dispatcher.disable_irq(2).unwrap();
+
+        // Interrupts already disabled on the device aren't disabled again on drop.
+        dispatcher.enable_irq(1, 2).unwrap();
+        dispatcher.inner.device.disable_msi().unwrap();
+        vfio_syscall::take_set_irqs_log();
+        drop(dispatcher);
+        assert!(vfio_syscall::take_set_irqs_log().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_irq_dispatcher_spawn() {
+        let device = create_vfio_device();
+        let count = Arc::new(AtomicU64::new(0));
+        let count2 = count.clone();
+        let mut dispatcher = VfioIrqDispatcher::new(device, move |_, _, _| {
+            count2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        dispatcher.enable_irq(1, 2).unwrap();
+        dispatcher.spawn().unwrap();
+        dispatcher.inner.irqs.lock().unwrap()[&1][1]
+            .write(1)
+            .unwrap();
+        for _ in 0..100 {
+            if count.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        drop(dispatcher);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use byteorder::{ByteOrder, LittleEndian};
+use vfio_bindings::bindings::vfio::*;
+
+use crate::fam::vec_with_array_field;
+
+// Action of a VFIO_DEVICE_SET_IRQS call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IrqSetAction {
+    Trigger,
+    Mask,
+    Unmask,
+}
+
+// Data of a VFIO_DEVICE_SET_IRQS call, one entry per vector of the range for Bool and Eventfd.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IrqSetData<'a> {
+    None,
+    // Not used by the crate yet, selects the vectors of the range the action applies to.
+    #[allow(dead_code)]
+    Bool(&'a [bool]),
+    Eventfd(&'a [RawFd]),
+}
+
+// Builder of the variable sized vfio_irq_set argument of VFIO_DEVICE_SET_IRQS. The default
+// targets no vector of index 0, with ACTION_TRIGGER and DATA_NONE.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct VfioIrqSet<'a> {
+    index: u32,
+    start: u32,
+    count: u32,
+    action: IrqSetAction,
+    data: IrqSetData<'a>,
+}
+
+impl<'a> VfioIrqSet<'a> {
+    pub(crate) fn new() -> Self {
+        VfioIrqSet {
+            index: 0,
+            start: 0,
+            count: 0,
+            action: IrqSetAction::Trigger,
+            data: IrqSetData::None,
+        }
+    }
+
+    pub(crate) fn index(mut self, index: u32) -> Self {
+        self.index = index;
+        self
+    }
+
+    // Target `count` vectors from `start`. A zero count with DATA_NONE and ACTION_TRIGGER
+    // disables the whole index.
+    pub(crate) fn range(mut self, start: u32, count: u32) -> Self {
+        self.start = start;
+        self.count = count;
+        self
+    }
+
+    pub(crate) fn action(mut self, action: IrqSetAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub(crate) fn data(mut self, data: IrqSetData<'a>) -> Self {
+        self.data = data;
+        self
+    }
+
+    // Build the argument, sized and packed for the data. The data must hold one entry per
+    // vector of the range.
+    pub(crate) fn build(&self) -> Vec<vfio_irq_set> {
+        let (data_flag, data_len) = match self.data {
+            IrqSetData::None => (VFIO_IRQ_SET_DATA_NONE, 0),
+            IrqSetData::Bool(values) => (VFIO_IRQ_SET_DATA_BOOL, mem::size_of_val(values)),
+            IrqSetData::Eventfd(fds) => (VFIO_IRQ_SET_DATA_EVENTFD, mem::size_of_val(fds)),
+        };
+        debug_assert!(match self.data {
+            IrqSetData::None => true,
+            IrqSetData::Bool(values) => values.len() == self.count as usize,
+            IrqSetData::Eventfd(fds) => fds.len() == self.count as usize,
+        });
+        let action_flag = match self.action {
+            IrqSetAction::Trigger => VFIO_IRQ_SET_ACTION_TRIGGER,
+            IrqSetAction::Mask => VFIO_IRQ_SET_ACTION_MASK,
+            IrqSetAction::Unmask => VFIO_IRQ_SET_ACTION_UNMASK,
+        };
+
+        let mut irq_set = vec_with_array_field::<vfio_irq_set, u8>(data_len);
+        irq_set[0].argsz = (mem::size_of::<vfio_irq_set>() + data_len) as u32;
+        irq_set[0].flags = data_flag | action_flag;
+        irq_set[0].index = self.index;
+        irq_set[0].start = self.start;
+        irq_set[0].count = self.count;
+
+        // SAFETY: vec_with_array_field() reserved data_len bytes after the header.
+        let data = unsafe { irq_set[0].data.as_mut_slice(data_len) };
+        match self.data {
+            IrqSetData::None => {}
+            IrqSetData::Bool(values) => {
+                for (byte, value) in data.iter_mut().zip(values) {
+                    *byte = u8::from(*value);
+                }
+            }
+            IrqSetData::Eventfd(fds) => {
+                for (fd, raw_fd) in data.chunks_exact_mut(mem::size_of::<RawFd>()).zip(fds) {
+                    LittleEndian::write_i32(fd, *raw_fd);
+                }
+            }
+        }
+
+        irq_set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Get the header fields and the data bytes of a built argument.
+    fn parts(irq_set: &[vfio_irq_set]) -> ((u32, u32, u32, u32, u32), Vec<u8>) {
+        let header = &irq_set[0];
+        let data_len = header.argsz as usize - mem::size_of::<vfio_irq_set>();
+        assert!(header.argsz as usize <= mem::size_of_val(irq_set));
+        // SAFETY: the argument was built with room for argsz bytes.
+        let data = unsafe { header.data.as_slice(data_len) }.to_vec();
+        (
+            (
+                header.argsz,
+                header.flags,
+                header.index,
+                header.start,
+                header.count,
+            ),
+            data,
+        )
+    }
+
+    #[test]
+    fn test_vfio_irq_set_builder() {
+        let size = mem::size_of::<vfio_irq_set>() as u32;
+
+        // Disable a whole index.
+        let irq_set = VfioIrqSet::new().index(2).build();
+        let flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER;
+        assert_eq!(parts(&irq_set), ((size, flags, 2, 0, 0), vec![]));
+
+        let irq_set = VfioIrqSet::new()
+            .range(0, 1)
+            .action(IrqSetAction::Unmask)
+            .build();
+        let flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_UNMASK;
+        assert_eq!(parts(&irq_set), ((size, flags, 0, 0, 1), vec![]));
+
+        // Trigger a range of vectors, DATA_NONE carries no payload whatever the count.
+        let irq_set = VfioIrqSet::new().index(2).range(8, 16).build();
+        let flags = VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER;
+        assert_eq!(parts(&irq_set), ((size, flags, 2, 8, 16), vec![]));
+        assert_eq!(irq_set.len(), 1);
+
+        let irq_set = VfioIrqSet::new()
+            .index(1)
+            .range(3, 3)
+            .action(IrqSetAction::Mask)
+            .data(IrqSetData::Bool(&[true, false, true]))
+            .build();
+        let flags = VFIO_IRQ_SET_DATA_BOOL | VFIO_IRQ_SET_ACTION_MASK;
+        assert_eq!(parts(&irq_set), ((size + 3, flags, 1, 3, 3), vec![1, 0, 1]));
+
+        let irq_set = VfioIrqSet::new()
+            .index(2)
+            .range(4, 2)
+            .data(IrqSetData::Eventfd(&[7, -1]))
+            .build();
+        let flags = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
+        assert_eq!(
+            parts(&irq_set),
+            (
+                (size + 8, flags, 2, 4, 2),
+                vec![7, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]
+            )
+        );
+    }
+}
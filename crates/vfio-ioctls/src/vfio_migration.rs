@@ -0,0 +1,149 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+
+use crate::vfio_ioctls::*;
+use crate::{Result, VfioDevice, VfioError};
+
+/// Iterator over the device state read from a VFIO migration data fd in bounded chunks.
+///
+/// Each chunk is at most `max_chunk` bytes, and no larger than the remaining amount of data
+/// estimated from `VFIO_DEVICE_FEATURE_MIG_DATA_SIZE`. As the reported size is only an estimate,
+/// reading goes on with `max_chunk` sized buffers until the data fd reports end of file.
+#[derive(Debug)]
+pub struct VfioMigrationChunks {
+    data_fd: File,
+    max_chunk: usize,
+    remaining: u64,
+    done: bool,
+}
+
+impl Iterator for VfioMigrationChunks {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut len = self.max_chunk;
+        if self.remaining > 0 && self.remaining < len as u64 {
+            len = self.remaining as usize;
+        }
+
+        let mut buf = vec![0u8; len];
+        let mut read = 0;
+        while read < len {
+            match self.data_fd.read(&mut buf[read..]) {
+                Ok(0) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(VfioError::ReadMigrationData(e)));
+                }
+            }
+        }
+
+        self.remaining = self.remaining.saturating_sub(read as u64);
+        if read == 0 {
+            return None;
+        }
+        buf.truncate(read);
+
+        Some(Ok(buf))
+    }
+}
+
+impl VfioDevice {
+    /// Get the estimated amount of data the device produces in the STOP_COPY migration state.
+    pub fn migration_data_size(&self) -> Result<u64> {
+        let mut feature = vfio_device_feature_mig_data_size {
+            argsz: mem::size_of::<vfio_device_feature_mig_data_size>() as u32,
+            flags: VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIG_DATA_SIZE,
+            stop_copy_length: 0,
+        };
+        vfio_syscall::get_device_feature_mig_data_size(self, &mut feature)?;
+
+        Ok(feature.stop_copy_length)
+    }
+
+    /// Read the device state from a migration data fd in bounded chunks.
+    ///
+    /// This lets the caller pipeline the device state transfer instead of buffering the whole
+    /// state in memory.
+    ///
+    /// # Arguments
+    /// * `data_fd` - Migration data fd returned by the kernel when entering a saving state.
+    /// * `max_chunk` - Maximum size in bytes of each chunk, must not be 0.
+    pub fn save_state_staged(
+        &self,
+        data_fd: File,
+        max_chunk: usize,
+    ) -> Result<VfioMigrationChunks> {
+        if max_chunk == 0 {
+            return Err(VfioError::ReadMigrationData(io::Error::from(
+                io::ErrorKind::InvalidInput,
+            )));
+        }
+
+        Ok(VfioMigrationChunks {
+            data_fd,
+            max_chunk,
+            remaining: self.migration_data_size()?,
+            done: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::io::Write;
+    use std::sync::Arc;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn create_data_fd(len: usize) -> File {
+        let tmp_file = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        tmp_file.as_file().write_all(&data).unwrap();
+        File::open(tmp_file.as_path()).unwrap()
+    }
+
+    #[test]
+    fn test_save_state_staged() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        assert_eq!(device.migration_data_size().unwrap(), 0x2800);
+        device
+            .save_state_staged(create_data_fd(0x10), 0)
+            .unwrap_err();
+
+        let chunks: Vec<Vec<u8>> = device
+            .save_state_staged(create_data_fd(0x3000), 0x1000)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![0x1000, 0x1000, 0x800, 0x800]);
+        assert_eq!(chunks[1][0], 0);
+        assert_eq!(chunks[3][0x7ff], 0xff);
+
+        let sizes: Vec<usize> = device
+            .save_state_staged(create_data_fd(0x2000), 0x10000)
+            .unwrap()
+            .map(|c| c.unwrap().len())
+            .collect();
+        assert_eq!(sizes, vec![0x2000]);
+    }
+}
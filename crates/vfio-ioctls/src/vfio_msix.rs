@@ -0,0 +1,331 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use crate::{MsixInfo, Result, VfioDevice, VfioError};
+
+// The MSI-X table is made of dword registers.
+const DWORD: u64 = 4;
+const QWORD: u64 = 8;
+
+/// Handling of the MSI-X table accesses which aren't aligned dword or qword accesses.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MsixTableAccessPolicy {
+    /// Fail them with `VfioError::InvalidRegionAccess`, without accessing the device.
+    #[default]
+    Reject,
+    /// Carry them out as a sequence of aligned dword accesses, a partially written dword being
+    /// read first and merged with the written bytes.
+    Split,
+}
+
+/// Width- and alignment-checked access to the MSI-X vector table of a device.
+///
+/// The PCIe specification requires the table to be accessed with aligned dword or qword
+/// operations, and some devices misbehave on anything else, so accesses relayed from a guest
+/// are checked and handled according to a `MsixTableAccessPolicy`. Offsets are relative to
+/// the start of the table.
+pub struct MsixTableAccessor<'a> {
+    device: &'a VfioDevice,
+    info: MsixInfo,
+    policy: MsixTableAccessPolicy,
+}
+
+impl<'a> MsixTableAccessor<'a> {
+    /// Create an accessor for the MSI-X table of a device, located with
+    /// `VfioDevice::msix_info()`.
+    ///
+    /// Returns `None` if the device doesn't have a MSI-X capability.
+    ///
+    /// # Arguments
+    /// * `device` - The device owning the table.
+    /// * `policy` - Handling of non-conforming accesses.
+    pub fn new(device: &'a VfioDevice, policy: MsixTableAccessPolicy) -> Result<Option<Self>> {
+        let info = match device.msix_info()? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        if info.table_offset + info.table_size() > device.get_region_size(info.table_bir) {
+            return Err(VfioError::InvalidRegionAccess {
+                index: info.table_bir,
+                addr: info.table_offset,
+                size: info.table_size(),
+            });
+        }
+
+        Ok(Some(MsixTableAccessor {
+            device,
+            info,
+            policy,
+        }))
+    }
+
+    /// The decoded MSI-X capability the table was located with.
+    pub fn info(&self) -> &MsixInfo {
+        &self.info
+    }
+
+    /// The handling of non-conforming accesses.
+    pub fn policy(&self) -> MsixTableAccessPolicy {
+        self.policy
+    }
+
+    /// Check whether an access of `size` bytes at `offset` is an aligned dword or qword access.
+    pub fn is_conforming(offset: u64, size: u64) -> bool {
+        (size == DWORD || size == QWORD) && offset & (size - 1) == 0
+    }
+
+    // Check an access against the table bounds and the policy, returning whether it has to be
+    // split in dword accesses.
+    fn check_access(&self, offset: u64, size: u64) -> Result<bool> {
+        let error = VfioError::InvalidRegionAccess {
+            index: self.info.table_bir,
+            addr: self.info.table_offset.saturating_add(offset),
+            size,
+        };
+        match offset.checked_add(size) {
+            Some(end) if end <= self.info.table_size() => {}
+            _ => return Err(error),
+        }
+        if Self::is_conforming(offset, size) {
+            return Ok(false);
+        }
+        match self.policy {
+            MsixTableAccessPolicy::Reject => Err(error),
+            MsixTableAccessPolicy::Split => Ok(true),
+        }
+    }
+
+    fn read_raw(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let base = self.device.get_region_offset(self.info.table_bir) + self.info.table_offset;
+        self.device
+            .read_exact_at(data, base + offset)
+            .map_err(VfioError::VfioDeviceRegionAccess)
+    }
+
+    fn write_raw(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let base = self.device.get_region_offset(self.info.table_bir) + self.info.table_offset;
+        self.device
+            .write_all_at(data, base + offset)
+            .map_err(VfioError::VfioDeviceRegionAccess)
+    }
+
+    // Iterate over the dwords covering `size` bytes at `offset`, as (dword offset, first byte
+    // in the dword, first byte in the access, length) tuples.
+    fn dwords(offset: u64, size: u64) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+        let end = offset + size;
+        let first = offset & !(DWORD - 1);
+        (first..end).step_by(DWORD as usize).map(move |dword| {
+            let start = dword.max(offset);
+            let len = (dword + DWORD).min(end) - start;
+            (
+                dword,
+                (start - dword) as usize,
+                (start - offset) as usize,
+                len as usize,
+            )
+        })
+    }
+
+    /// Read from the table.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in the table.
+    /// * `data` - Buffer to read to.
+    pub fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        if !self.check_access(offset, data.len() as u64)? {
+            return self.read_raw(offset, data);
+        }
+
+        for (dword, skip, pos, len) in Self::dwords(offset, data.len() as u64) {
+            let mut buf = [0u8; DWORD as usize];
+            self.read_raw(dword, &mut buf)?;
+            data[pos..pos + len].copy_from_slice(&buf[skip..skip + len]);
+        }
+        Ok(())
+    }
+
+    /// Write to the table.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in the table.
+    /// * `data` - Data to write.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.device.check_writable()?;
+        if !self.check_access(offset, data.len() as u64)? {
+            return self.write_raw(offset, data);
+        }
+
+        for (dword, skip, pos, len) in Self::dwords(offset, data.len() as u64) {
+            let mut buf = [0u8; DWORD as usize];
+            if len < buf.len() {
+                self.read_raw(dword, &mut buf)?;
+            }
+            buf[skip..skip + len].copy_from_slice(&data[pos..pos + len]);
+            self.write_raw(dword, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_pci::tests::create_vfio_device;
+
+    const TABLE_OFFSET: u64 = 0x100;
+
+    // A device with a 4 vectors MSI-X table at offset 0x100 of BAR 0.
+    fn create_msix_device(table: u32) -> VfioDevice {
+        let mut config = vec![0u8; 0x100];
+        config[0x06] = 0x10;
+        config[0x34] = 0x50;
+        config[0x50..0x54].copy_from_slice(&[0x11, 0x00, 0x03, 0x80]);
+        config[0x54..0x58].copy_from_slice(&table.to_le_bytes());
+        config[0x58..0x5c].copy_from_slice(&[0x00, 0x08, 0x00, 0x00]);
+
+        let device = create_vfio_device(&config);
+        device
+            .write_all_at(&[0u8; 0x40], device.get_region_offset(0) + TABLE_OFFSET)
+            .unwrap();
+
+        device
+    }
+
+    fn table(device: &VfioDevice) -> Vec<u8> {
+        let mut table = vec![0u8; 0x40];
+        device
+            .read_exact_at(&mut table, device.get_region_offset(0) + TABLE_OFFSET)
+            .unwrap();
+        table
+    }
+
+    #[test]
+    fn test_msix_info() {
+        let device = create_msix_device(TABLE_OFFSET as u32);
+        assert_eq!(
+            device.msix_info().unwrap(),
+            Some(MsixInfo {
+                offset: 0x50,
+                msg_ctl: 0x8003,
+                enabled: true,
+                function_mask: false,
+                vectors: 4,
+                table_bir: 0,
+                table_offset: TABLE_OFFSET,
+                pba_bir: 0,
+                pba_offset: 0x800,
+            })
+        );
+        let info = device.msix_info().unwrap().unwrap();
+        assert_eq!((info.table_size(), info.pba_size()), (0x40, 8));
+    }
+
+    #[test]
+    fn test_msix_table_accessor() {
+        let device = create_msix_device(TABLE_OFFSET as u32);
+        let accessor = MsixTableAccessor::new(&device, MsixTableAccessPolicy::Reject)
+            .unwrap()
+            .unwrap();
+        assert_eq!(accessor.info().vectors, 4);
+
+        // Aligned dword and qword accesses.
+        accessor.write(0x00, &[0x00, 0x00, 0xe0, 0xfe]).unwrap();
+        accessor
+            .write(0x08, &[0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0])
+            .unwrap();
+        let mut buf = [0u8; 8];
+        accessor.read(0x00, &mut buf).unwrap();
+        assert_eq!(buf, [0x00, 0x00, 0xe0, 0xfe, 0, 0, 0, 0]);
+        let mut dword = [0u8; 4];
+        accessor.read(0x08, &mut dword).unwrap();
+        assert_eq!(u32::from_le_bytes(dword), 0x1122_3344);
+
+        // A 2 bytes write straddling the message data and the mask bit of vector 0.
+        assert!(matches!(
+            accessor.write(0x0b, &[0xaa, 0x01]),
+            Err(VfioError::InvalidRegionAccess {
+                index: 0,
+                addr: 0x10b,
+                size: 2
+            })
+        ));
+        // A 8 bytes access crossing from vector 0 to vector 1.
+        assert!(matches!(
+            accessor.write(0x0c, &[0x01, 0, 0, 0, 0x00, 0x10, 0xe0, 0xfe]),
+            Err(VfioError::InvalidRegionAccess { size: 8, .. })
+        ));
+        accessor.read(0x0c, &mut buf).unwrap_err();
+        accessor.read(0x02, &mut dword).unwrap_err();
+        accessor.read(0x00, &mut []).unwrap_err();
+        assert_eq!(
+            &table(&device)[0x08..0x14],
+            &[0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // Same accesses, split in dword accesses.
+        let accessor = MsixTableAccessor::new(&device, MsixTableAccessPolicy::Split)
+            .unwrap()
+            .unwrap();
+        accessor.write(0x0b, &[0xaa, 0x01]).unwrap();
+        assert_eq!(
+            &table(&device)[0x08..0x10],
+            &[0x44, 0x33, 0x22, 0xaa, 0x01, 0, 0, 0]
+        );
+        let mut word = [0u8; 2];
+        accessor.read(0x0b, &mut word).unwrap();
+        assert_eq!(word, [0xaa, 0x01]);
+
+        accessor
+            .write(0x0c, &[0x00, 0, 0, 0, 0x00, 0x10, 0xe0, 0xfe])
+            .unwrap();
+        assert_eq!(
+            &table(&device)[0x0c..0x14],
+            &[0x00, 0, 0, 0, 0x00, 0x10, 0xe0, 0xfe]
+        );
+        accessor.read(0x0e, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0x00, 0x10, 0xe0, 0xfe, 0, 0]);
+
+        accessor.write(0x3f, &[0xff]).unwrap();
+        assert_eq!(table(&device)[0x3c..0x40], [0, 0, 0, 0xff]);
+
+        // Out of the table.
+        accessor.write(0x40, &[0u8; 4]).unwrap_err();
+        accessor.read(0x3e, &mut dword).unwrap_err();
+        accessor.read(u64::MAX, &mut dword).unwrap_err();
+
+        let mut device = device;
+        device.read_only = true;
+        let accessor = MsixTableAccessor::new(&device, MsixTableAccessPolicy::Split)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            accessor.write(0x00, &[0u8; 4]),
+            Err(VfioError::DeviceReadOnly)
+        ));
+        accessor.read(0x00, &mut dword).unwrap();
+    }
+
+    #[test]
+    fn test_msix_table_accessor_location() {
+        // Table past the end of BAR 0.
+        let device = create_msix_device(0xff0);
+        assert!(matches!(
+            MsixTableAccessor::new(&device, MsixTableAccessPolicy::Reject),
+            Err(VfioError::InvalidRegionAccess {
+                index: 0,
+                addr: 0xff0,
+                size: 0x40
+            })
+        ));
+
+        // No MSI-X capability.
+        let device = create_msix_device(0);
+        device.config().write_u8(0x34, 0).unwrap();
+        assert!(
+            MsixTableAccessor::new(&device, MsixTableAccessPolicy::Reject)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use vfio_bindings::bindings::vfio::VFIO_GROUP_FLAGS_VIABLE;
+
+use crate::vfio_device::lock_or_recover;
+use crate::{Result, VfioContainer, VfioDevice, VfioGroup};
+
+/// A reason preventing a device from being passed through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PassthroughBlocker {
+    /// The device has no IOMMU group.
+    NoIommuGroup,
+    /// The device isn't bound to a VFIO driver.
+    NotVfioDevice {
+        /// Driver the device is bound to, if any.
+        driver: Option<String>,
+    },
+    /// The group character device can't be opened, such as when used by another process.
+    GroupUnavailable(String),
+    /// The group isn't viable.
+    GroupNotViable {
+        /// Flags reported by `VFIO_GROUP_GET_STATUS`.
+        status: u32,
+    },
+    /// A device of the same group is bound to a driver other than VFIO.
+    SiblingNotBound {
+        /// Name of the sibling device.
+        device: String,
+        /// Driver the sibling device is bound to.
+        driver: String,
+    },
+    /// The container doesn't support a required extension.
+    MissingExtension(u32),
+}
+
+impl fmt::Display for PassthroughBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassthroughBlocker::NoIommuGroup => write!(f, "device has no iommu group"),
+            PassthroughBlocker::NotVfioDevice {
+                driver: Some(driver),
+            } => {
+                write!(f, "device is bound to {} instead of a vfio driver", driver)
+            }
+            PassthroughBlocker::NotVfioDevice { driver: None } => {
+                write!(f, "device isn't bound to a vfio driver")
+            }
+            PassthroughBlocker::GroupUnavailable(e) => write!(f, "group unavailable: {}", e),
+            PassthroughBlocker::GroupNotViable { status } => {
+                write!(f, "group isn't viable (status {:#x})", status)
+            }
+            PassthroughBlocker::SiblingNotBound { device, driver } => {
+                write!(f, "group device {} is bound to {}", device, driver)
+            }
+            PassthroughBlocker::MissingExtension(extension) => {
+                write!(f, "container doesn't support extension {}", extension)
+            }
+        }
+    }
+}
+
+/// Result of [`validate_device_passthrough()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassthroughReport {
+    /// IOMMU group of the device, if found.
+    pub group_id: Option<u32>,
+    /// Reasons preventing the device from being passed through, empty if none was found.
+    pub blockers: Vec<PassthroughBlocker>,
+}
+
+impl PassthroughReport {
+    /// Check whether the device can be passed through.
+    pub fn is_ok(&self) -> bool {
+        self.blockers.is_empty()
+    }
+}
+
+// Name of the driver the device at `sysfspath` is bound to.
+fn device_driver(sysfspath: &Path) -> Option<String> {
+    let driver = fs::read_link(sysfspath.join("driver")).ok()?;
+    Some(driver.file_name()?.to_str()?.to_string())
+}
+
+/// Check whether a device can be passed through, without binding anything.
+///
+/// The device must be bound to a VFIO driver and its group must be viable, with all its
+/// devices bound to VFIO drivers or unbound, and the container must support all the
+/// `required_extensions`. Every blocker found is reported rather than stopping at the first one,
+/// errors are only returned when the checks themselves fail.
+///
+/// The group is opened to query its status, unless the container already holds it.
+///
+/// # Parameters
+/// * `sysfspath`: specify the vfio device path in sys file system.
+/// * `container`: the container the device would be bound to.
+/// * `required_extensions`: `VFIO_CHECK_EXTENSION` arguments such as `VFIO_TYPE1v2_IOMMU`.
+pub fn validate_device_passthrough(
+    sysfspath: &Path,
+    container: &VfioContainer,
+    required_extensions: &[u32],
+) -> Result<PassthroughReport> {
+    let mut blockers = Vec::new();
+
+    match device_driver(sysfspath) {
+        Some(driver) if driver.contains("vfio") => {}
+        driver => blockers.push(PassthroughBlocker::NotVfioDevice { driver }),
+    }
+
+    let group_id = VfioDevice::get_group_id_from_path(sysfspath).ok();
+    match group_id {
+        Some(group_id) => {
+            let attached = lock_or_recover(&container.groups).get(&group_id).cloned();
+            let status = match attached {
+                Some(group) => Some(group.status()?),
+                None => match VfioGroup::open(group_id, container.paths()) {
+                    Ok(group) => Some(group.status()?),
+                    Err(e) => {
+                        blockers.push(PassthroughBlocker::GroupUnavailable(e.to_string()));
+                        None
+                    }
+                },
+            };
+            if let Some(status) = status {
+                if status & VFIO_GROUP_FLAGS_VIABLE == 0 {
+                    blockers.push(PassthroughBlocker::GroupNotViable { status });
+                }
+            }
+
+            let name = sysfspath.file_name().and_then(|name| name.to_str());
+            for device in container.paths().iommu_group_devices(group_id)? {
+                if device.blocks_viability() && Some(device.name.as_str()) != name {
+                    blockers.push(PassthroughBlocker::SiblingNotBound {
+                        device: device.name,
+                        driver: device.driver.unwrap_or_default(),
+                    });
+                }
+            }
+        }
+        None => blockers.push(PassthroughBlocker::NoIommuGroup),
+    }
+
+    for extension in required_extensions {
+        if !container.supports_extension(*extension) {
+            blockers.push(PassthroughBlocker::MissingExtension(*extension));
+        }
+    }
+
+    Ok(PassthroughReport { group_id, blockers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_ioctls::{vfio_syscall, VFIO_UPDATE_VADDR};
+    use crate::VfioPaths;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use vfio_bindings::bindings::vfio::VFIO_TYPE1v2_IOMMU;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Bind `device` of the sysfs tree at `root` to `driver`, or unbind it.
+    fn bind(root: &Path, device: &Path, driver: Option<&str>) {
+        let link = device.join("driver");
+        let _ = fs::remove_file(&link);
+        if let Some(driver) = driver {
+            let driver_dir = root.join("sys/bus/pci/drivers").join(driver);
+            fs::create_dir_all(&driver_dir).unwrap();
+            symlink(driver_dir, link).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_device_passthrough() {
+        let root = TempDir::new_with_prefix("/tmp/vfio_passthrough").unwrap();
+        let root = root.as_path();
+        let devices = root.join("sys/kernel/iommu_groups/3/devices");
+        let device: PathBuf = devices.join("0000:01:00.0");
+        let sibling: PathBuf = devices.join("0000:01:00.1");
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(&sibling).unwrap();
+        bind(root, &device, Some("vfio-pci"));
+        bind(root, &sibling, Some("e1000e"));
+
+        let mut container = create_vfio_container();
+        container.paths = VfioPaths {
+            dev_vfio_root: root.join("dev/vfio"),
+            sysfs_root: root.join("sys"),
+        };
+
+        let report = validate_device_passthrough(&device, &container, &[]).unwrap();
+        assert_eq!(report.group_id, Some(3));
+        assert_eq!(
+            report.blockers,
+            vec![PassthroughBlocker::SiblingNotBound {
+                device: "0000:01:00.1".to_string(),
+                driver: "e1000e".to_string(),
+            }]
+        );
+        assert!(!report.is_ok());
+
+        // Every blocker is reported, the device itself isn't reported as a sibling.
+        bind(root, &device, Some("igb"));
+        bind(root, &sibling, None);
+        vfio_syscall::inject_group_not_viable(1);
+        let report = validate_device_passthrough(
+            &device,
+            &container,
+            &[VFIO_TYPE1v2_IOMMU, VFIO_UPDATE_VADDR],
+        )
+        .unwrap();
+        assert_eq!(
+            report.blockers,
+            vec![
+                PassthroughBlocker::NotVfioDevice {
+                    driver: Some("igb".to_string())
+                },
+                PassthroughBlocker::GroupNotViable { status: 0 },
+                PassthroughBlocker::MissingExtension(VFIO_UPDATE_VADDR),
+            ]
+        );
+        assert_eq!(
+            report.blockers[0].to_string(),
+            "device is bound to igb instead of a vfio driver"
+        );
+
+        bind(root, &device, Some("vfio-pci"));
+        let report =
+            validate_device_passthrough(&device, &container, &[VFIO_TYPE1v2_IOMMU]).unwrap();
+        assert!(report.is_ok());
+
+        // The group devices can't be listed.
+        fs::remove_dir_all(&devices).unwrap();
+        validate_device_passthrough(&device, &container, &[]).unwrap_err();
+    }
+}
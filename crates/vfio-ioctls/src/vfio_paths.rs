@@ -0,0 +1,387 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Result, VfioError};
+
+/// Locations of the VFIO character devices and of the sysfs tree.
+///
+/// Inside containers `/dev/vfio` and `/sys` may be bind-mounted at non-standard locations, in
+/// which case a `VfioPaths` pointing to them can be passed to
+/// [`VfioContainer::new_with_paths()`](struct.VfioContainer.html#method.new_with_paths).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfioPaths {
+    /// Directory holding the VFIO container and group character devices.
+    pub dev_vfio_root: PathBuf,
+    /// Mount point of the sysfs file system.
+    pub sysfs_root: PathBuf,
+}
+
+impl Default for VfioPaths {
+    fn default() -> Self {
+        VfioPaths {
+            dev_vfio_root: PathBuf::from("/dev/vfio"),
+            sysfs_root: PathBuf::from("/sys"),
+        }
+    }
+}
+
+/// An IOVA range reserved by the host for an IOMMU group, such as the MSI doorbell window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfioReservedRegion {
+    /// First IO virtual address of the range.
+    pub start: u64,
+    /// Last IO virtual address of the range, inclusive.
+    pub end: u64,
+    /// Type of the reserved range, as reported by the kernel ("msi", "direct", ...).
+    pub type_: String,
+}
+
+/// A device of an IOMMU group, as listed in sysfs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfioGroupDevice {
+    /// Name of the device, such as its PCI address.
+    pub name: String,
+    /// Name of the driver the device is bound to, if any.
+    pub driver: Option<String>,
+}
+
+impl VfioGroupDevice {
+    /// Check whether the device prevents its group from being viable.
+    ///
+    /// A group is viable once every device is either unbound, bound to a VFIO driver or to
+    /// `pci-stub`.
+    pub fn blocks_viability(&self) -> bool {
+        match &self.driver {
+            Some(driver) => !driver.starts_with("vfio") && driver != "pci-stub",
+            None => false,
+        }
+    }
+}
+
+/// Mediated device properties, read from sysfs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MdevInfo {
+    /// Type the mediated device was created from, such as `nvidia-63`.
+    pub mdev_type: String,
+    /// Name of the parent physical device, such as its PCI address.
+    pub parent: Option<String>,
+    /// Number of devices of the same type which can still be created on the parent.
+    pub available_instances: Option<u32>,
+}
+
+impl MdevInfo {
+    /// Read the properties of the mediated device at `sysfspath`.
+    ///
+    /// `mdev_type` is a symlink to the type directory of the parent device on most kernels, but
+    /// may also be found as a directory, in which case its `name` attribute is used as the type.
+    ///
+    /// # Parameters
+    /// * `sysfspath`: the mdev device path in sys file system.
+    pub fn from_sysfs(sysfspath: &Path) -> Result<Self> {
+        let type_path = sysfspath.join("mdev_type");
+        let read_error = |e| VfioError::ReadSysfs(e, type_path.to_string_lossy().into_owned());
+
+        let mdev_type = match fs::read_link(&type_path) {
+            Ok(target) => target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.to_string()),
+            Err(_) => {
+                let name = fs::read_to_string(type_path.join("name")).map_err(read_error)?;
+                Some(name.trim().to_string())
+            }
+        }
+        .filter(|name| !name.is_empty())
+        .ok_or(VfioError::InvalidPath)?;
+
+        let parent = fs::canonicalize(sysfspath)
+            .ok()
+            .and_then(|path| Some(path.parent()?.file_name()?.to_str()?.to_string()));
+        let available_instances = fs::read_to_string(type_path.join("available_instances"))
+            .ok()
+            .and_then(|count| count.trim().parse().ok());
+
+        Ok(MdevInfo {
+            mdev_type,
+            parent,
+            available_instances,
+        })
+    }
+}
+
+impl VfioPaths {
+    /// Path of the VFIO container character device.
+    pub fn container_path(&self) -> PathBuf {
+        self.dev_vfio_root.join("vfio")
+    }
+
+    /// Path of the IOMMUFD character device, next to the VFIO directory: `/dev/iommu` for
+    /// `/dev/vfio`.
+    pub fn iommufd_path(&self) -> Option<PathBuf> {
+        self.dev_vfio_root.parent().map(|dev| dev.join("iommu"))
+    }
+
+    /// Path of the character device of the VFIO group `id`.
+    pub fn group_path(&self, id: u32) -> PathBuf {
+        self.dev_vfio_root.join(id.to_string())
+    }
+
+    fn iommu_group_dir(&self, id: u32) -> PathBuf {
+        self.sysfs_root
+            .join("kernel/iommu_groups")
+            .join(id.to_string())
+    }
+
+    /// List the IDs of the groups exposed by VFIO, in ascending order.
+    pub fn list_vfio_groups(&self) -> Result<Vec<u32>> {
+        let entries = fs::read_dir(&self.dev_vfio_root).map_err(|e| {
+            VfioError::ReadSysfs(e, self.dev_vfio_root.to_string_lossy().into_owned())
+        })?;
+
+        let mut groups: Vec<u32> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+            .collect();
+        groups.sort_unstable();
+
+        Ok(groups)
+    }
+
+    /// List the devices of the IOMMU group `id`, sorted by name.
+    ///
+    /// # Parameters
+    /// * `id`: ID of the IOMMU group.
+    pub fn iommu_group_devices(&self, id: u32) -> Result<Vec<VfioGroupDevice>> {
+        let path = self.iommu_group_dir(id).join("devices");
+        let entries = fs::read_dir(&path)
+            .map_err(|e| VfioError::ReadSysfs(e, path.to_string_lossy().into_owned()))?;
+
+        let mut devices: Vec<VfioGroupDevice> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                let driver = fs::read_link(entry.path().join("driver"))
+                    .ok()
+                    .and_then(|driver| Some(driver.file_name()?.to_str()?.to_string()));
+                Some(VfioGroupDevice { name, driver })
+            })
+            .collect();
+        devices.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(devices)
+    }
+
+    /// Get the IOVA ranges reserved by the host for the IOMMU group `id`.
+    ///
+    /// # Parameters
+    /// * `id`: ID of the IOMMU group.
+    pub fn iommu_group_reserved_regions(&self, id: u32) -> Result<Vec<VfioReservedRegion>> {
+        let path = self.iommu_group_dir(id).join("reserved_regions");
+        let content = fs::read_to_string(&path)
+            .map_err(|e| VfioError::ReadSysfs(e, path.to_string_lossy().into_owned()))?;
+
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(parse_reserved_region)
+            .collect()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+fn parse_reserved_region(line: &str) -> Result<VfioReservedRegion> {
+    let mut fields = line.split_whitespace();
+    let invalid = || VfioError::InvalidReservedRegion(line.to_string());
+
+    let start = fields.next().and_then(parse_hex).ok_or_else(invalid)?;
+    let end = fields.next().and_then(parse_hex).ok_or_else(invalid)?;
+    let type_ = fields.next().ok_or_else(invalid)?.to_string();
+    if end < start {
+        return Err(invalid());
+    }
+
+    Ok(VfioReservedRegion { start, end, type_ })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn create_fixture() -> (TempDir, VfioPaths) {
+        let root = TempDir::new_with_prefix("/tmp/vfio_paths").unwrap();
+        let paths = VfioPaths {
+            dev_vfio_root: root.as_path().join("dev/vfio"),
+            sysfs_root: root.as_path().join("sys"),
+        };
+
+        fs::create_dir_all(&paths.dev_vfio_root).unwrap();
+        for name in ["vfio", "12", "7", "devices"].iter() {
+            fs::write(paths.dev_vfio_root.join(name), "").unwrap();
+        }
+
+        let group_dir = paths.iommu_group_dir(12);
+        fs::create_dir_all(&group_dir).unwrap();
+        fs::write(
+            group_dir.join("reserved_regions"),
+            "0x00000000fee00000 0x00000000feefffff msi\n0x0000000000000000 0x0000000000000fff direct\n",
+        )
+        .unwrap();
+        let group_dir = paths.iommu_group_dir(7);
+        fs::create_dir_all(&group_dir).unwrap();
+        fs::write(group_dir.join("reserved_regions"), "0xfee00000 msi\n").unwrap();
+
+        let drivers = root.as_path().join("sys/bus/pci/drivers");
+        for driver in ["vfio-pci", "e1000e"].iter() {
+            fs::create_dir_all(drivers.join(driver)).unwrap();
+        }
+        let devices = paths.iommu_group_dir(12).join("devices");
+        for (name, driver) in [
+            ("0000:01:00.1", Some("e1000e")),
+            ("0000:01:00.0", Some("vfio-pci")),
+            ("0000:01:00.2", None),
+        ]
+        .iter()
+        {
+            let device = devices.join(name);
+            fs::create_dir_all(&device).unwrap();
+            if let Some(driver) = driver {
+                symlink(drivers.join(driver), device.join("driver")).unwrap();
+            }
+        }
+
+        (root, paths)
+    }
+
+    #[test]
+    fn test_vfio_paths_default() {
+        let paths = VfioPaths::default();
+
+        assert_eq!(paths.container_path(), Path::new("/dev/vfio/vfio"));
+        assert_eq!(paths.group_path(3), Path::new("/dev/vfio/3"));
+        assert_eq!(paths.iommufd_path().unwrap(), Path::new("/dev/iommu"));
+        assert_eq!(paths.sysfs_root, Path::new("/sys"));
+    }
+
+    #[test]
+    fn test_list_vfio_groups() {
+        let (_root, paths) = create_fixture();
+        assert_eq!(paths.list_vfio_groups().unwrap(), vec![7, 12]);
+
+        let paths = VfioPaths {
+            dev_vfio_root: PathBuf::from("/nonexistent/dev/vfio"),
+            ..VfioPaths::default()
+        };
+        paths.list_vfio_groups().unwrap_err();
+    }
+
+    #[test]
+    fn test_iommu_group_reserved_regions() {
+        let (_root, paths) = create_fixture();
+
+        let regions = paths.iommu_group_reserved_regions(12).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(
+            regions[0],
+            VfioReservedRegion {
+                start: 0xfee0_0000,
+                end: 0xfeef_ffff,
+                type_: "msi".to_string(),
+            }
+        );
+        assert_eq!(regions[1].end, 0xfff);
+        assert_eq!(regions[1].type_, "direct");
+
+        paths.iommu_group_reserved_regions(7).unwrap_err();
+        paths.iommu_group_reserved_regions(8).unwrap_err();
+    }
+
+    #[test]
+    fn test_iommu_group_devices() {
+        let (_root, paths) = create_fixture();
+
+        let devices = paths.iommu_group_devices(12).unwrap();
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["0000:01:00.0", "0000:01:00.1", "0000:01:00.2"]);
+        assert_eq!(devices[0].driver.as_deref(), Some("vfio-pci"));
+        assert_eq!(devices[1].driver.as_deref(), Some("e1000e"));
+        assert_eq!(devices[2].driver, None);
+
+        let blockers: Vec<&str> = devices
+            .iter()
+            .filter(|d| d.blocks_viability())
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(blockers, ["0000:01:00.1"]);
+        assert!(!VfioGroupDevice {
+            name: String::new(),
+            driver: Some("pci-stub".to_string()),
+        }
+        .blocks_viability());
+
+        paths.iommu_group_devices(7).unwrap_err();
+    }
+
+    // Lay out a mediated device the way sysfs does, with mdev_type as a symlink to the type
+    // directory of the parent or as a plain directory.
+    pub(crate) fn create_mdev_fixture(root: &Path, type_symlink: bool) -> PathBuf {
+        let parent = root.join("sys/devices/pci0000:00/0000:00:02.0");
+        let type_dir = parent.join("mdev_supported_types/nvidia-63");
+        let device = parent.join("83b8f4f2-509f-382f-3c1e-e6bfe0fa1001");
+        fs::create_dir_all(&device).unwrap();
+        if type_symlink {
+            fs::create_dir_all(&type_dir).unwrap();
+            symlink(
+                "../mdev_supported_types/nvidia-63",
+                device.join("mdev_type"),
+            )
+            .unwrap();
+        } else {
+            let type_dir = device.join("mdev_type");
+            fs::create_dir_all(&type_dir).unwrap();
+            fs::write(type_dir.join("name"), "nvidia-63\n").unwrap();
+        }
+        fs::write(device.join("mdev_type/available_instances"), "7\n").unwrap();
+
+        let bus = root.join("sys/bus/mdev/devices");
+        fs::create_dir_all(&bus).unwrap();
+        let link = bus.join("83b8f4f2-509f-382f-3c1e-e6bfe0fa1001");
+        symlink(&device, &link).unwrap();
+        link
+    }
+
+    #[test]
+    fn test_mdev_info() {
+        let expected = MdevInfo {
+            mdev_type: "nvidia-63".to_string(),
+            parent: Some("0000:00:02.0".to_string()),
+            available_instances: Some(7),
+        };
+        for type_symlink in [true, false].iter() {
+            let root = TempDir::new_with_prefix("/tmp/vfio_mdev").unwrap();
+            let device = create_mdev_fixture(root.as_path(), *type_symlink);
+            assert_eq!(MdevInfo::from_sysfs(&device).unwrap(), expected);
+        }
+
+        // Missing optional attributes.
+        let root = TempDir::new_with_prefix("/tmp/vfio_mdev").unwrap();
+        let device = create_mdev_fixture(root.as_path(), true);
+        fs::remove_file(device.join("mdev_type/available_instances")).unwrap();
+        assert_eq!(
+            MdevInfo::from_sysfs(&device).unwrap().available_instances,
+            None
+        );
+
+        // Not a mediated device.
+        MdevInfo::from_sysfs(&root.as_path().join("sys/devices")).unwrap_err();
+    }
+}
@@ -0,0 +1,1762 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+use once_cell::sync::OnceCell;
+use vfio_bindings::bindings::vfio::{VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_MSIX_IRQ_INDEX};
+use vmm_sys_util::errno::Error as SysError;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::vfio_device::{lock_or_recover, read_or_recover, write_or_recover};
+use crate::{QuirkRemediation, Result, VfioDevice, VfioError};
+
+// Offsets of registers in the PCI configuration space.
+const PCI_VENDOR_ID: u64 = 0x00;
+const PCI_DEVICE_ID: u64 = 0x02;
+const PCI_COMMAND: u64 = 0x04;
+const PCI_STATUS: u64 = 0x06;
+const PCI_CAPABILITY_LIST: u64 = 0x34;
+
+// Standard capabilities live after the 64 bytes header, and take at least 4 bytes each.
+const PCI_STD_HEADER_SIZEOF: u8 = 0x40;
+const PCI_CAP_MAX: usize = (256 - PCI_STD_HEADER_SIZEOF as usize) / 4;
+
+const PCI_COMMAND_IO: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_SPECIAL: u16 = 1 << 3;
+const PCI_COMMAND_INVALIDATE: u16 = 1 << 4;
+const PCI_COMMAND_VGA_PALETTE: u16 = 1 << 5;
+const PCI_COMMAND_PARITY: u16 = 1 << 6;
+const PCI_COMMAND_SERR: u16 = 1 << 8;
+const PCI_COMMAND_FAST_BACK: u16 = 1 << 9;
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const PCI_COMMAND_MASK: u16 = PCI_COMMAND_IO
+    | PCI_COMMAND_MEMORY
+    | PCI_COMMAND_MASTER
+    | PCI_COMMAND_SPECIAL
+    | PCI_COMMAND_INVALIDATE
+    | PCI_COMMAND_VGA_PALETTE
+    | PCI_COMMAND_PARITY
+    | PCI_COMMAND_SERR
+    | PCI_COMMAND_FAST_BACK
+    | PCI_COMMAND_INTX_DISABLE;
+
+const PCI_STATUS_INTERRUPT: u16 = 1 << 3;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_STATUS_66MHZ: u16 = 1 << 5;
+const PCI_STATUS_FAST_BACK: u16 = 1 << 7;
+const PCI_STATUS_PARITY: u16 = 1 << 8;
+const PCI_STATUS_DEVSEL_SHIFT: u16 = 9;
+const PCI_STATUS_DEVSEL_MASK: u16 = 0x3 << PCI_STATUS_DEVSEL_SHIFT;
+const PCI_STATUS_SIG_TARGET_ABORT: u16 = 1 << 11;
+const PCI_STATUS_REC_TARGET_ABORT: u16 = 1 << 12;
+const PCI_STATUS_REC_MASTER_ABORT: u16 = 1 << 13;
+const PCI_STATUS_SIG_SYSTEM_ERROR: u16 = 1 << 14;
+const PCI_STATUS_DETECTED_PARITY: u16 = 1 << 15;
+
+// MSI capability registers, the data and mask offsets depend on the address size.
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+const PCI_MSI_FLAGS: u64 = 0x02;
+const PCI_MSI_FLAGS_ENABLE: u16 = 1 << 0;
+const PCI_MSI_FLAGS_QMASK_SHIFT: u16 = 1;
+const PCI_MSI_FLAGS_QSIZE_SHIFT: u16 = 4;
+const PCI_MSI_FLAGS_Q_MASK: u16 = 0x7;
+const PCI_MSI_FLAGS_64BIT: u16 = 1 << 7;
+const PCI_MSI_FLAGS_MASKBIT: u16 = 1 << 8;
+const PCI_MSI_ADDRESS_LO: u64 = 0x04;
+const PCI_MSI_ADDRESS_HI: u64 = 0x08;
+const PCI_MSI_DATA_32: u64 = 0x08;
+const PCI_MSI_DATA_64: u64 = 0x0c;
+const PCI_MSI_MASK_32: u64 = 0x0c;
+const PCI_MSI_MASK_64: u64 = 0x10;
+const PCI_MSI_PENDING_32: u64 = 0x10;
+const PCI_MSI_PENDING_64: u64 = 0x14;
+const PCI_MSIX_FLAGS: u64 = 0x02;
+const PCI_MSIX_FLAGS_QSIZE: u16 = 0x7ff;
+const PCI_MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+const PCI_MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+const PCI_MSIX_TABLE: u64 = 0x04;
+const PCI_MSIX_TABLE_BIR: u32 = 0x7;
+const PCI_MSIX_TABLE_OFFSET: u32 = !PCI_MSIX_TABLE_BIR;
+const PCI_MSIX_PBA: u64 = 0x08;
+const PCI_MSIX_PBA_BIR: u32 = 0x7;
+const PCI_MSIX_PBA_OFFSET: u32 = !PCI_MSIX_PBA_BIR;
+
+// PCI Express capability and the registers used for Function Level Reset.
+const PCI_CAP_ID_EXP: u8 = 0x10;
+const PCI_EXP_DEVCAP: u64 = 0x04;
+const PCI_EXP_DEVCAP_FLR: u32 = 1 << 28;
+const PCI_EXP_DEVCTL: u64 = 0x08;
+const PCI_EXP_DEVCTL_BCR_FLR: u16 = 1 << 15;
+
+// Extended capabilities live after the standard configuration space, and take at least 8 bytes
+// each.
+const PCI_CFG_SPACE_SIZE: u16 = 0x100;
+const PCI_CFG_SPACE_EXP_SIZE: u64 = 0x1000;
+const PCI_EXT_CAP_MAX: usize = (PCI_CFG_SPACE_EXP_SIZE as usize - PCI_CFG_SPACE_SIZE as usize) / 8;
+
+// Device Serial Number extended capability, holding the 64 bits serial after its header.
+const PCI_EXT_CAP_ID_DSN: u16 = 0x03;
+const PCI_DSN_LOWER: u64 = 0x04;
+const PCI_DSN_UPPER: u64 = 0x08;
+
+// Single Root I/O Virtualization extended capability.
+const PCI_EXT_CAP_ID_SRIOV: u16 = 0x10;
+const PCI_SRIOV_CTRL: u64 = 0x08;
+const PCI_SRIOV_CTRL_VFE: u16 = 1 << 0;
+const PCI_SRIOV_INITIAL_VF: u64 = 0x0c;
+const PCI_SRIOV_TOTAL_VF: u64 = 0x0e;
+const PCI_SRIOV_NUM_VF: u64 = 0x10;
+const PCI_SRIOV_VF_OFFSET: u64 = 0x14;
+const PCI_SRIOV_VF_STRIDE: u64 = 0x16;
+const PCI_SRIOV_VF_DID: u64 = 0x1a;
+
+// Time a function is given to complete a FLR, as mandated by the PCIe specification.
+const PCI_FLR_WAIT: Duration = Duration::from_millis(100);
+
+/// Decoded PCI command register.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PciCommand {
+    /// Respond to I/O space accesses.
+    pub io_space: bool,
+    /// Respond to memory space accesses.
+    pub memory_space: bool,
+    /// Allow the device to issue DMA requests.
+    pub bus_master: bool,
+    /// Monitor special cycle operations.
+    pub special_cycles: bool,
+    /// Allow the memory write and invalidate command.
+    pub memory_write_invalidate: bool,
+    /// Snoop VGA palette writes.
+    pub vga_palette_snoop: bool,
+    /// Report parity errors.
+    pub parity_error_response: bool,
+    /// Enable the SERR# driver.
+    pub serr: bool,
+    /// Allow fast back-to-back transactions to different devices.
+    pub fast_back_to_back: bool,
+    /// Prevent the device from asserting INTx.
+    pub interrupt_disable: bool,
+}
+
+impl From<u16> for PciCommand {
+    fn from(reg: u16) -> Self {
+        PciCommand {
+            io_space: reg & PCI_COMMAND_IO != 0,
+            memory_space: reg & PCI_COMMAND_MEMORY != 0,
+            bus_master: reg & PCI_COMMAND_MASTER != 0,
+            special_cycles: reg & PCI_COMMAND_SPECIAL != 0,
+            memory_write_invalidate: reg & PCI_COMMAND_INVALIDATE != 0,
+            vga_palette_snoop: reg & PCI_COMMAND_VGA_PALETTE != 0,
+            parity_error_response: reg & PCI_COMMAND_PARITY != 0,
+            serr: reg & PCI_COMMAND_SERR != 0,
+            fast_back_to_back: reg & PCI_COMMAND_FAST_BACK != 0,
+            interrupt_disable: reg & PCI_COMMAND_INTX_DISABLE != 0,
+        }
+    }
+}
+
+impl From<PciCommand> for u16 {
+    fn from(cmd: PciCommand) -> Self {
+        let bits = [
+            (cmd.io_space, PCI_COMMAND_IO),
+            (cmd.memory_space, PCI_COMMAND_MEMORY),
+            (cmd.bus_master, PCI_COMMAND_MASTER),
+            (cmd.special_cycles, PCI_COMMAND_SPECIAL),
+            (cmd.memory_write_invalidate, PCI_COMMAND_INVALIDATE),
+            (cmd.vga_palette_snoop, PCI_COMMAND_VGA_PALETTE),
+            (cmd.parity_error_response, PCI_COMMAND_PARITY),
+            (cmd.serr, PCI_COMMAND_SERR),
+            (cmd.fast_back_to_back, PCI_COMMAND_FAST_BACK),
+            (cmd.interrupt_disable, PCI_COMMAND_INTX_DISABLE),
+        ];
+
+        bits.iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |reg, (_, bit)| reg | bit)
+    }
+}
+
+/// Decoded PCI status register.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PciStatus {
+    /// An INTx interrupt is pending.
+    pub interrupt_status: bool,
+    /// The device implements a capability list.
+    pub capabilities_list: bool,
+    /// The device is 66 MHz capable.
+    pub capable_66mhz: bool,
+    /// The device accepts fast back-to-back transactions.
+    pub fast_back_to_back: bool,
+    /// A data parity error was detected while the device was bus master.
+    pub master_data_parity_error: bool,
+    /// DEVSEL# timing: 0 is fast, 1 is medium and 2 is slow.
+    pub devsel_timing: u8,
+    /// The device terminated a transaction with a target abort.
+    pub signaled_target_abort: bool,
+    /// A transaction of the device was terminated with a target abort.
+    pub received_target_abort: bool,
+    /// A transaction of the device was terminated with a master abort.
+    pub received_master_abort: bool,
+    /// The device asserted SERR#.
+    pub signaled_system_error: bool,
+    /// The device detected a parity error.
+    pub detected_parity_error: bool,
+}
+
+impl From<u16> for PciStatus {
+    fn from(reg: u16) -> Self {
+        PciStatus {
+            interrupt_status: reg & PCI_STATUS_INTERRUPT != 0,
+            capabilities_list: reg & PCI_STATUS_CAP_LIST != 0,
+            capable_66mhz: reg & PCI_STATUS_66MHZ != 0,
+            fast_back_to_back: reg & PCI_STATUS_FAST_BACK != 0,
+            master_data_parity_error: reg & PCI_STATUS_PARITY != 0,
+            devsel_timing: ((reg & PCI_STATUS_DEVSEL_MASK) >> PCI_STATUS_DEVSEL_SHIFT) as u8,
+            signaled_target_abort: reg & PCI_STATUS_SIG_TARGET_ABORT != 0,
+            received_target_abort: reg & PCI_STATUS_REC_TARGET_ABORT != 0,
+            received_master_abort: reg & PCI_STATUS_REC_MASTER_ABORT != 0,
+            signaled_system_error: reg & PCI_STATUS_SIG_SYSTEM_ERROR != 0,
+            detected_parity_error: reg & PCI_STATUS_DETECTED_PARITY != 0,
+        }
+    }
+}
+
+/// Decoded MSI capability, with the configuration space offsets of its registers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MsiCapInfo {
+    /// Offset of the capability.
+    pub offset: u8,
+    /// Raw message control register.
+    pub msg_ctl: u16,
+    /// MSI is enabled.
+    pub enabled: bool,
+    /// Number of vectors the device can request (Multiple Message Capable).
+    pub max_vectors: u32,
+    /// Number of vectors enabled by the driver (Multiple Message Enable).
+    pub enabled_vectors: u32,
+    /// The message address is 64 bits wide.
+    pub addr_64bit: bool,
+    /// Vectors can be masked individually.
+    pub per_vector_mask: bool,
+    /// Offset of the message control register.
+    pub msg_ctl_offset: u64,
+    /// Offset of the low 32 bits of the message address.
+    pub addr_lo_offset: u64,
+    /// Offset of the high 32 bits of the message address, for 64 bits addresses.
+    pub addr_hi_offset: Option<u64>,
+    /// Offset of the 16 bits message data.
+    pub data_offset: u64,
+    /// Offset of the mask bits, with per vector masking.
+    pub mask_offset: Option<u64>,
+    /// Offset of the pending bits, with per vector masking.
+    pub pending_offset: Option<u64>,
+}
+
+impl MsiCapInfo {
+    fn new(offset: u8, msg_ctl: u16) -> Self {
+        let addr_64bit = msg_ctl & PCI_MSI_FLAGS_64BIT != 0;
+        let per_vector_mask = msg_ctl & PCI_MSI_FLAGS_MASKBIT != 0;
+        let (data, mask, pending) = if addr_64bit {
+            (PCI_MSI_DATA_64, PCI_MSI_MASK_64, PCI_MSI_PENDING_64)
+        } else {
+            (PCI_MSI_DATA_32, PCI_MSI_MASK_32, PCI_MSI_PENDING_32)
+        };
+        // Encodings past 32 vectors are reserved.
+        let vectors = |shift: u16| 1u32 << ((msg_ctl >> shift) & PCI_MSI_FLAGS_Q_MASK).min(5);
+        // Registers of a malformed capability may lie past the configuration space.
+        let reg = |reg: u64| u64::from(offset) + reg;
+
+        MsiCapInfo {
+            offset,
+            msg_ctl,
+            enabled: msg_ctl & PCI_MSI_FLAGS_ENABLE != 0,
+            max_vectors: vectors(PCI_MSI_FLAGS_QMASK_SHIFT),
+            enabled_vectors: vectors(PCI_MSI_FLAGS_QSIZE_SHIFT),
+            addr_64bit,
+            per_vector_mask,
+            msg_ctl_offset: reg(PCI_MSI_FLAGS),
+            addr_lo_offset: reg(PCI_MSI_ADDRESS_LO),
+            addr_hi_offset: if addr_64bit {
+                Some(reg(PCI_MSI_ADDRESS_HI))
+            } else {
+                None
+            },
+            data_offset: reg(data),
+            mask_offset: if per_vector_mask {
+                Some(reg(mask))
+            } else {
+                None
+            },
+            pending_offset: if per_vector_mask {
+                Some(reg(pending))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Decoded MSI-X capability, with the location of the vector table and Pending Bit Array.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MsixInfo {
+    /// Offset of the capability.
+    pub offset: u8,
+    /// Raw message control register.
+    pub msg_ctl: u16,
+    /// MSI-X is enabled.
+    pub enabled: bool,
+    /// All the vectors are masked (Function Mask).
+    pub function_mask: bool,
+    /// Number of vectors of the table.
+    pub vectors: u32,
+    /// BAR holding the vector table.
+    pub table_bir: u32,
+    /// Offset of the vector table in its BAR.
+    pub table_offset: u64,
+    /// BAR holding the Pending Bit Array.
+    pub pba_bir: u32,
+    /// Offset of the Pending Bit Array in its BAR.
+    pub pba_offset: u64,
+}
+
+impl MsixInfo {
+    /// Size of a vector table entry, in bytes.
+    pub const ENTRY_SIZE: u64 = 16;
+
+    /// Size of the vector table, in bytes.
+    pub fn table_size(&self) -> u64 {
+        u64::from(self.vectors) * Self::ENTRY_SIZE
+    }
+
+    /// Size of the Pending Bit Array, made of 64 bits entries, in bytes.
+    pub fn pba_size(&self) -> u64 {
+        u64::from(self.vectors).div_ceil(64) * 8
+    }
+}
+
+/// Decoded SR-IOV capability of a physical function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SriovCapInfo {
+    /// Offset of the capability.
+    pub offset: u16,
+    /// The virtual functions are enabled (VF Enable).
+    pub enabled: bool,
+    /// Number of virtual functions initially associated with the physical function.
+    pub initial_vfs: u16,
+    /// Maximum number of virtual functions the physical function can spawn.
+    pub total_vfs: u16,
+    /// Number of virtual functions currently set up.
+    pub num_vfs: u16,
+    /// Routing ID offset of the first virtual function from the physical function.
+    pub vf_offset: u16,
+    /// Routing ID distance between two consecutive virtual functions.
+    pub vf_stride: u16,
+    /// PCI device ID of the virtual functions.
+    pub vf_device_id: u16,
+}
+
+impl SriovCapInfo {
+    /// Get the routing ID (bus, device and function numbers) of a virtual function.
+    ///
+    /// The offset and stride depend on the number of virtual functions, so the layout is the
+    /// one for the current `num_vfs`. Returns `None` for a virtual function past `total_vfs`,
+    /// or whose routing ID is past the last bus.
+    ///
+    /// # Arguments
+    /// * `pf_rid`: routing ID of the physical function.
+    /// * `vf`: index of the virtual function, from 0.
+    pub fn vf_routing_id(&self, pf_rid: u16, vf: u16) -> Option<u16> {
+        if vf >= self.total_vfs {
+            return None;
+        }
+
+        pf_rid
+            .checked_add(self.vf_offset)?
+            .checked_add(vf.checked_mul(self.vf_stride)?)
+    }
+}
+
+/// Interrupt delivery statistics of a MSI-X vector.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MsixVectorStat {
+    /// The pending bit of the vector is set in the PBA.
+    pub pending: bool,
+    /// Number of interrupts signaled on the EventFd of the vector and not consumed yet.
+    pub count: u64,
+}
+
+// Get the counter of an EventFd without resetting it, as reading it would.
+fn peek_eventfd(event_fd: &EventFd) -> u64 {
+    let fd = event_fd.as_raw_fd();
+    let count = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))
+        .ok()
+        .and_then(|fdinfo| {
+            let count = fdinfo
+                .lines()
+                .find_map(|line| line.strip_prefix("eventfd-count:"))?;
+            u64::from_str_radix(count.trim(), 16).ok()
+        });
+    if let Some(count) = count {
+        return count;
+    }
+
+    // Without procfs, only tell whether the counter is set.
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfd is valid for the duration of the call, which doesn't wait.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    u64::from(ret > 0 && pollfd.revents & libc::POLLIN != 0)
+}
+
+/// Hook called with the offset and data of each write to the configuration space.
+///
+/// The data may be modified in place, and the write is dropped when `false` is returned.
+pub type VfioPciConfigWriteHook = dyn Fn(u64, &mut [u8]) -> bool + Send + Sync;
+
+/// Hook called with the offset and data of each read from the configuration space.
+///
+/// The data may be modified in place before being returned to the caller.
+pub type VfioPciConfigReadHook = dyn Fn(u64, &mut [u8]) + Send + Sync;
+
+/// A standard PCI capability found in the configuration space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VfioPciCapability {
+    /// Capability ID (`PCI_CAP_ID_*`).
+    pub id: u8,
+    /// Offset of the capability in the configuration space.
+    pub offset: u8,
+}
+
+/// A PCI Express extended capability found in the configuration space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VfioPciExtCapability {
+    /// Extended capability ID (`PCI_EXT_CAP_ID_*`).
+    pub id: u16,
+    /// Capability version.
+    pub version: u8,
+    /// Offset of the capability in the configuration space.
+    pub offset: u16,
+}
+
+impl VfioPciExtCapability {
+    /// Get the name of the capability, `None` for IDs unknown to this crate.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self.id {
+            0x01 => "Advanced Error Reporting",
+            0x02 | 0x09 => "Virtual Channel",
+            0x03 => "Device Serial Number",
+            0x04 => "Power Budgeting",
+            0x0b => "Vendor-Specific",
+            0x0d => "Access Control Services",
+            0x0e => "Alternative Routing-ID Interpretation",
+            0x0f => "Address Translation Services",
+            0x10 => "Single Root I/O Virtualization",
+            0x13 => "Page Request Interface",
+            0x15 => "Resizable BAR",
+            0x17 => "TLP Processing Hints",
+            0x18 => "Latency Tolerance Reporting",
+            0x19 => "Secondary PCI Express",
+            0x1b => "Process Address Space ID",
+            0x1d => "Downstream Port Containment",
+            0x1e => "L1 PM Substates",
+            0x1f => "Precision Time Measurement",
+            0x23 => "Designated Vendor-Specific",
+            0x25 => "Data Link Feature",
+            0x26 => "Physical Layer 16.0 GT/s",
+            _ => return None,
+        };
+
+        Some(name)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct PciConfigState {
+    caps: Option<Vec<VfioPciCapability>>,
+    // Command register as last written through the accessor, restored by VfioDevice::reopen().
+    command: Option<u16>,
+    pre_write: Option<Arc<VfioPciConfigWriteHook>>,
+    post_read: Option<Arc<VfioPciConfigReadHook>>,
+}
+
+/// Accessor for the PCI configuration space of a VFIO device.
+///
+/// Reads and writes go through the optional interception hooks, while the raw
+/// `VfioDevice::region_read()` and `VfioDevice::region_write()` on `VFIO_PCI_CONFIG_REGION_INDEX`
+/// bypass them. The capability list is walked once and cached until the device is reset.
+pub struct VfioPciConfig<'a> {
+    device: &'a VfioDevice,
+}
+
+impl<'a> VfioPciConfig<'a> {
+    fn region_offset(&self, offset: u64, len: usize) -> Result<u64> {
+        let region = self
+            .device
+            .region(VFIO_PCI_CONFIG_REGION_INDEX)
+            .ok_or_else(|| {
+                VfioError::VfioDeviceConfigAccess(io::Error::from(io::ErrorKind::NotFound))
+            })?;
+
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= region.size => Ok(region.offset + offset),
+            _ => Err(VfioError::VfioDeviceConfigAccess(io::Error::from(
+                io::ErrorKind::InvalidInput,
+            ))),
+        }
+    }
+
+    fn read_raw(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let offset = self.region_offset(offset, buf.len())?;
+        self.device
+            .read_exact_at(buf, offset)
+            .map_err(VfioError::VfioDeviceConfigAccess)
+    }
+
+    /// Read from the configuration space.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data destination and buf length is read size
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_raw(offset, buf)?;
+
+        let hook = lock_or_recover(&self.device.pci_config).post_read.clone();
+        if let Some(hook) = hook {
+            hook(offset, buf);
+        }
+
+        Ok(())
+    }
+
+    /// Write to the configuration space.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data source and buf length is write size
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.device.check_writable()?;
+        let file_offset = self.region_offset(offset, buf.len())?;
+
+        let hook = lock_or_recover(&self.device.pci_config).pre_write.clone();
+        let mut data = buf.to_vec();
+        if let Some(hook) = hook {
+            if !hook(offset, &mut data) {
+                return Ok(());
+            }
+        }
+
+        self.device
+            .write_all_at(&data, file_offset)
+            .map_err(VfioError::VfioDeviceConfigAccess)?;
+        self.track_command(offset, &data);
+
+        Ok(())
+    }
+
+    // Update the copy of the command register with the bytes of a write overlapping it.
+    fn track_command(&self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        if end <= PCI_COMMAND || offset >= PCI_COMMAND + 2 {
+            return;
+        }
+        let mut state = lock_or_recover(&self.device.pci_config);
+        let mut reg = state.command.unwrap_or(0).to_le_bytes();
+        for (i, byte) in reg.iter_mut().enumerate() {
+            let pos = PCI_COMMAND + i as u64;
+            if offset <= pos && pos < end {
+                *byte = data[(pos - offset) as usize];
+            }
+        }
+        state.command = Some(u16::from_le_bytes(reg));
+    }
+
+    /// Read a byte from the configuration space.
+    pub fn read_u8(&self, offset: u64) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read(offset, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Read a little endian word from the configuration space.
+    pub fn read_u16(&self, offset: u64) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read(offset, &mut buf)?;
+        Ok(LittleEndian::read_u16(&buf))
+    }
+
+    /// Read a little endian double word from the configuration space.
+    pub fn read_u32(&self, offset: u64) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read(offset, &mut buf)?;
+        Ok(LittleEndian::read_u32(&buf))
+    }
+
+    /// Write a byte to the configuration space.
+    pub fn write_u8(&self, offset: u64, val: u8) -> Result<()> {
+        self.write(offset, &[val])
+    }
+
+    /// Write a little endian word to the configuration space.
+    pub fn write_u16(&self, offset: u64, val: u16) -> Result<()> {
+        let mut buf = [0u8; 2];
+        LittleEndian::write_u16(&mut buf, val);
+        self.write(offset, &buf)
+    }
+
+    /// Write a little endian double word to the configuration space.
+    pub fn write_u32(&self, offset: u64, val: u32) -> Result<()> {
+        let mut buf = [0u8; 4];
+        LittleEndian::write_u32(&mut buf, val);
+        self.write(offset, &buf)
+    }
+
+    /// Get the standard capabilities of the device, in list order.
+    ///
+    /// The list is read without going through the interception hooks, and cached until the
+    /// device is reset or `invalidate_capabilities()` is called.
+    pub fn capabilities(&self) -> Result<Vec<VfioPciCapability>> {
+        if let Some(caps) = lock_or_recover(&self.device.pci_config).caps.as_ref() {
+            return Ok(caps.clone());
+        }
+
+        let caps = self.walk_capabilities()?;
+        lock_or_recover(&self.device.pci_config).caps = Some(caps.clone());
+
+        Ok(caps)
+    }
+
+    fn walk_capabilities(&self) -> Result<Vec<VfioPciCapability>> {
+        let mut caps = Vec::new();
+        let mut buf = [0u8; 2];
+        self.read_raw(PCI_STATUS, &mut buf)?;
+        if LittleEndian::read_u16(&buf) & PCI_STATUS_CAP_LIST == 0 {
+            return Ok(caps);
+        }
+
+        self.read_raw(PCI_CAPABILITY_LIST, &mut buf[..1])?;
+        let mut next = buf[0] & !0x3;
+        // Bound the walk in case the list loops.
+        while next >= PCI_STD_HEADER_SIZEOF && caps.len() < PCI_CAP_MAX {
+            self.read_raw(u64::from(next), &mut buf)?;
+            caps.push(VfioPciCapability {
+                id: buf[0],
+                offset: next,
+            });
+            next = buf[1] & !0x3;
+        }
+
+        Ok(caps)
+    }
+
+    /// Find the offset of the first standard capability with the given ID.
+    ///
+    /// # Arguments
+    /// * `id`: capability ID (`PCI_CAP_ID_*`)
+    pub fn find_capability(&self, id: u8) -> Result<Option<u8>> {
+        Ok(self
+            .capabilities()?
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| c.offset))
+    }
+
+    /// Get the PCI Express extended capabilities of the device, in list order.
+    ///
+    /// The list is read without going through the interception hooks. It is empty if the
+    /// configuration space region doesn't extend past the first 256 bytes, as for conventional
+    /// PCI devices.
+    pub fn extended_capabilities(&self) -> Result<Vec<VfioPciExtCapability>> {
+        let mut caps = Vec::new();
+        let size = self
+            .device
+            .get_region_size(VFIO_PCI_CONFIG_REGION_INDEX)
+            .min(PCI_CFG_SPACE_EXP_SIZE);
+        let mut next = PCI_CFG_SPACE_SIZE;
+        let mut buf = [0u8; 4];
+        // Bound the walk in case the list loops.
+        while u64::from(next) + 4 <= size && caps.len() < PCI_EXT_CAP_MAX {
+            self.read_raw(u64::from(next), &mut buf)?;
+            let header = LittleEndian::read_u32(&buf);
+            // An empty list has a null header, and absent devices read as all ones.
+            if header == 0 || header == u32::MAX {
+                break;
+            }
+            caps.push(VfioPciExtCapability {
+                id: header as u16,
+                version: (header >> 16) as u8 & 0xf,
+                offset: next,
+            });
+            next = (header >> 20) as u16 & !0x3;
+            if next < PCI_CFG_SPACE_SIZE {
+                break;
+            }
+        }
+
+        Ok(caps)
+    }
+
+    /// Find the offset of the first extended capability with the given ID.
+    ///
+    /// # Arguments
+    /// * `id`: extended capability ID (`PCI_EXT_CAP_ID_*`)
+    pub fn find_ext_capability(&self, id: u16) -> Result<Option<u16>> {
+        Ok(self
+            .extended_capabilities()?
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| c.offset))
+    }
+
+    /// Find and decode the MSI capability.
+    ///
+    /// Returns `None` if the device doesn't have one.
+    pub fn msi_cap_info(&self) -> Result<Option<MsiCapInfo>> {
+        match self.find_capability(PCI_CAP_ID_MSI)? {
+            Some(offset) => {
+                let msg_ctl = self.read_u16(u64::from(offset) + PCI_MSI_FLAGS)?;
+                Ok(Some(MsiCapInfo::new(offset, msg_ctl)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Find and decode the SR-IOV extended capability.
+    ///
+    /// Returns `None` if the device doesn't have one.
+    pub fn sriov_cap_info(&self) -> Result<Option<SriovCapInfo>> {
+        let offset = match self.find_ext_capability(PCI_EXT_CAP_ID_SRIOV)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let reg = |reg: u64| self.read_u16(u64::from(offset) + reg);
+
+        Ok(Some(SriovCapInfo {
+            offset,
+            enabled: reg(PCI_SRIOV_CTRL)? & PCI_SRIOV_CTRL_VFE != 0,
+            initial_vfs: reg(PCI_SRIOV_INITIAL_VF)?,
+            total_vfs: reg(PCI_SRIOV_TOTAL_VF)?,
+            num_vfs: reg(PCI_SRIOV_NUM_VF)?,
+            vf_offset: reg(PCI_SRIOV_VF_OFFSET)?,
+            vf_stride: reg(PCI_SRIOV_VF_STRIDE)?,
+            vf_device_id: reg(PCI_SRIOV_VF_DID)?,
+        }))
+    }
+
+    /// Find and decode the MSI-X capability.
+    ///
+    /// Returns `None` if the device doesn't have one.
+    pub fn msix_info(&self) -> Result<Option<MsixInfo>> {
+        let offset = match self.find_capability(PCI_CAP_ID_MSIX)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let msg_ctl = self.read_u16(u64::from(offset) + PCI_MSIX_FLAGS)?;
+        let table = self.read_u32(u64::from(offset) + PCI_MSIX_TABLE)?;
+        let pba = self.read_u32(u64::from(offset) + PCI_MSIX_PBA)?;
+
+        Ok(Some(MsixInfo {
+            offset,
+            msg_ctl,
+            enabled: msg_ctl & PCI_MSIX_FLAGS_ENABLE != 0,
+            function_mask: msg_ctl & PCI_MSIX_FLAGS_MASKALL != 0,
+            vectors: u32::from(msg_ctl & PCI_MSIX_FLAGS_QSIZE) + 1,
+            table_bir: table & PCI_MSIX_TABLE_BIR,
+            table_offset: u64::from(table & PCI_MSIX_TABLE_OFFSET),
+            pba_bir: pba & PCI_MSIX_PBA_BIR,
+            pba_offset: u64::from(pba & PCI_MSIX_PBA_OFFSET),
+        }))
+    }
+
+    /// Get a writer coalescing adjacent writes to reduce the number of syscalls.
+    ///
+    /// See [`BufferedConfigWriter`] for when buffered writes reach the device.
+    pub fn buffered_writer(&self) -> BufferedConfigWriter<'a> {
+        BufferedConfigWriter::new(self.device)
+    }
+
+    /// Drop the cached capability list, so that it is walked again on next use.
+    pub fn invalidate_capabilities(&self) {
+        lock_or_recover(&self.device.pci_config).caps = None;
+    }
+
+    /// Set the hook intercepting writes, replacing the previous one.
+    pub fn set_pre_write_hook<F>(&self, hook: F)
+    where
+        F: Fn(u64, &mut [u8]) -> bool + Send + Sync + 'static,
+    {
+        lock_or_recover(&self.device.pci_config).pre_write = Some(Arc::new(hook));
+    }
+
+    /// Set the hook intercepting reads, replacing the previous one.
+    pub fn set_post_read_hook<F>(&self, hook: F)
+    where
+        F: Fn(u64, &mut [u8]) + Send + Sync + 'static,
+    {
+        lock_or_recover(&self.device.pci_config).post_read = Some(Arc::new(hook));
+    }
+
+    /// Remove both interception hooks.
+    pub fn clear_hooks(&self) {
+        let mut state = lock_or_recover(&self.device.pci_config);
+        state.pre_write = None;
+        state.post_read = None;
+    }
+
+    /// Read and decode the PCI command register.
+    pub fn command(&self) -> Result<PciCommand> {
+        self.read_u16(PCI_COMMAND).map(PciCommand::from)
+    }
+
+    /// Encode and write the PCI command register, preserving its reserved bits.
+    ///
+    /// # Arguments
+    /// * `cmd` - The new value of the command register.
+    pub fn set_command(&self, cmd: PciCommand) -> Result<()> {
+        let reg = self.read_u16(PCI_COMMAND)?;
+        self.write_u16(PCI_COMMAND, (reg & !PCI_COMMAND_MASK) | u16::from(cmd))
+    }
+
+    /// Read and decode the PCI status register.
+    pub fn status(&self) -> Result<PciStatus> {
+        self.read_u16(PCI_STATUS).map(PciStatus::from)
+    }
+}
+
+// Coalesced writes never span more than one configuration cycle.
+const CONFIG_WRITE_WINDOW: u64 = 4;
+
+/// Writer coalescing adjacent configuration space writes of the same width.
+///
+/// Writes following each other byte after byte, or word after word, within a double word are
+/// merged and reach the device as a single write. Buffered data is written on `read()`, on a
+/// write of a different width or to a non adjacent offset, on `flush()` and when the writer is
+/// dropped. Errors of buffered writes are reported by the call doing the flush, except on drop
+/// where they are only logged. Interception hooks see the merged writes.
+///
+/// Writes touching a must-flush offset are never buffered. The set initially holds the command
+/// register and the message control registers of the MSI and MSI-X capabilities.
+pub struct BufferedConfigWriter<'a> {
+    device: &'a VfioDevice,
+    // Offset, data and width of the individual writes of the buffered write.
+    pending: Option<(u64, Vec<u8>, usize)>,
+    must_flush: BTreeSet<u64>,
+}
+
+impl<'a> BufferedConfigWriter<'a> {
+    fn new(device: &'a VfioDevice) -> Self {
+        let mut writer = BufferedConfigWriter {
+            device,
+            pending: None,
+            must_flush: BTreeSet::new(),
+        };
+        writer.add_must_flush(PCI_COMMAND, 2);
+        let config = device.config();
+        for id in [PCI_CAP_ID_MSI, PCI_CAP_ID_MSIX].iter() {
+            if let Ok(Some(cap)) = config.find_capability(*id) {
+                writer.add_must_flush(u64::from(cap) + PCI_MSI_FLAGS, 2);
+            }
+        }
+
+        writer
+    }
+
+    /// Make writes to `[offset, offset + len)` go straight to the device.
+    pub fn add_must_flush(&mut self, offset: u64, len: u64) {
+        self.must_flush.extend(offset..offset.saturating_add(len));
+    }
+
+    /// Allow writes to `[offset, offset + len)` to be buffered.
+    pub fn remove_must_flush(&mut self, offset: u64, len: u64) {
+        for off in offset..offset.saturating_add(len) {
+            self.must_flush.remove(&off);
+        }
+    }
+
+    /// Write to the configuration space, possibly buffering the data.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data source and buf length is write size
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let end = offset.saturating_add(buf.len() as u64);
+        if buf.is_empty() || self.must_flush.range(offset..end).next().is_some() {
+            self.flush()?;
+            return self.device.config().write(offset, buf);
+        }
+
+        // Report invalid writes right away rather than on flush.
+        self.device.check_writable()?;
+        self.device.config().region_offset(offset, buf.len())?;
+
+        if let Some((start, data, width)) = self.pending.as_mut() {
+            if *width == buf.len()
+                && *start + data.len() as u64 == offset
+                && (end - 1) / CONFIG_WRITE_WINDOW == *start / CONFIG_WRITE_WINDOW
+            {
+                data.extend_from_slice(buf);
+                return Ok(());
+            }
+        }
+
+        self.flush()?;
+        self.pending = Some((offset, buf.to_vec(), buf.len()));
+
+        Ok(())
+    }
+
+    /// Write the buffered data, then read from the configuration space.
+    ///
+    /// # Arguments
+    /// * `offset`: offset in the configuration space
+    /// * `buf`: data destination and buf length is read size
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.flush()?;
+        self.device.config().read(offset, buf)
+    }
+
+    /// Write the buffered data to the device.
+    pub fn flush(&mut self) -> Result<()> {
+        match self.pending.take() {
+            Some((offset, data, _)) => self.device.config().write(offset, &data),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BufferedConfigWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush buffered config write: {}", e);
+        }
+    }
+}
+
+impl VfioDevice {
+    /// Get an accessor for the PCI configuration space of the device.
+    pub fn config(&self) -> VfioPciConfig<'_> {
+        VfioPciConfig { device: self }
+    }
+
+    // Get the command register as last written through config(), if it was.
+    pub(crate) fn written_command(&self) -> Option<u16> {
+        lock_or_recover(&self.pci_config).command
+    }
+
+    // Write the command register again, after the device was opened again.
+    pub(crate) fn restore_command(&self, command: u16) -> Result<()> {
+        self.config().write_u16(PCI_COMMAND, command)
+    }
+
+    /// Read and decode the PCI command register of the device.
+    pub fn command_register(&self) -> Result<PciCommand> {
+        self.config().command()
+    }
+
+    /// Encode and write the PCI command register of the device.
+    ///
+    /// Reserved bits of the register are preserved.
+    ///
+    /// # Arguments
+    /// * `cmd` - The new value of the command register.
+    pub fn set_command_register(&self, cmd: PciCommand) -> Result<()> {
+        self.config().set_command(cmd)
+    }
+
+    /// Read and decode the PCI status register of the device.
+    pub fn status_register(&self) -> Result<PciStatus> {
+        self.config().status()
+    }
+
+    /// Find and decode the MSI capability of the device.
+    ///
+    /// Returns `None` if the device doesn't have one.
+    pub fn msi_cap_info(&self) -> Result<Option<MsiCapInfo>> {
+        self.config().msi_cap_info()
+    }
+
+    /// Walk the PCI Express extended capability list of the device, starting at offset 0x100.
+    ///
+    /// The capabilities (AER, DSN, ARI, SR-IOV...) are returned in list order with their ID,
+    /// version and offset, see [`VfioPciConfig::extended_capabilities()`]. The list is empty
+    /// when the configuration space region of the device stops at 256 bytes.
+    pub fn walk_pcie_extended_capabilities(&self) -> Result<Vec<VfioPciExtCapability>> {
+        self.config().extended_capabilities()
+    }
+
+    /// Find and decode the MSI-X capability of the device.
+    ///
+    /// Returns `None` if the device doesn't have one.
+    pub fn msix_info(&self) -> Result<Option<MsixInfo>> {
+        self.config().msix_info()
+    }
+
+    /// Set or clear the Function Mask bit of the MSI-X capability of the device.
+    ///
+    /// While the bit is set, all the vectors are masked regardless of their own mask bit,
+    /// which is faster than masking them one by one when reconfiguring the table. The other
+    /// bits of the message control register are preserved.
+    ///
+    /// # Arguments
+    /// * `masked` - Whether all the vectors are masked.
+    pub fn msix_function_mask(&self, masked: bool) -> Result<()> {
+        let offset = self.msix_flags_offset()?;
+        let config = self.config();
+        let msg_ctl = config.read_u16(offset)?;
+        let msg_ctl = if masked {
+            msg_ctl | PCI_MSIX_FLAGS_MASKALL
+        } else {
+            msg_ctl & !PCI_MSIX_FLAGS_MASKALL
+        };
+        config.write_u16(offset, msg_ctl)
+    }
+
+    /// Check whether the Function Mask bit of the MSI-X capability of the device is set.
+    pub fn msix_is_function_masked(&self) -> Result<bool> {
+        let offset = self.msix_flags_offset()?;
+        Ok(self.config().read_u16(offset)? & PCI_MSIX_FLAGS_MASKALL != 0)
+    }
+
+    // Get the offset of the MSI-X message control register.
+    fn msix_flags_offset(&self) -> Result<u64> {
+        match self.config().find_capability(PCI_CAP_ID_MSIX)? {
+            Some(offset) => Ok(u64::from(offset) + PCI_MSIX_FLAGS),
+            None => Err(VfioError::NoMsixCapability),
+        }
+    }
+
+    /// Get the pending state and interrupt count of each MSI-X vector of the device.
+    ///
+    /// The pending bits are read from the Pending Bit Array in the BAR advertised by the MSI-X
+    /// capability, and the counts are the ones of the EventFds registered through
+    /// `enable_irq()`, which are left untouched for the VMM to consume the interrupts: they are
+    /// read from `/proc/self/fdinfo`, or only tell whether interrupts are waiting if it can't be
+    /// read. Vectors without an EventFd report a zero count.
+    ///
+    /// Returns an empty list if the device doesn't have a MSI-X capability.
+    pub fn msix_vector_stats(&self) -> Result<Vec<MsixVectorStat>> {
+        let info = match self.msix_info()? {
+            Some(info) => info,
+            None => return Ok(Vec::new()),
+        };
+        let vectors = info.vectors as usize;
+
+        let mut bits = vec![0u8; info.pba_size() as usize];
+        if info.pba_offset + info.pba_size() > self.get_region_size(info.pba_bir) {
+            return Err(VfioError::ReadMsixPba(io::Error::from(
+                io::ErrorKind::InvalidInput,
+            )));
+        }
+        self.read_exact_at(
+            &mut bits,
+            self.get_region_offset(info.pba_bir) + info.pba_offset,
+        )
+        .map_err(VfioError::ReadMsixPba)?;
+
+        let irq_fds = lock_or_recover(&self.irq_fds);
+        Ok((0..vectors)
+            .map(|vector| {
+                let count = irq_fds
+                    .get(&VFIO_PCI_MSIX_IRQ_INDEX)
+                    .and_then(|fds| fds.get(vector))
+                    .map_or(0, peek_eventfd);
+                MsixVectorStat {
+                    pending: bits[vector / 8] & (1 << (vector % 8)) != 0,
+                    count,
+                }
+            })
+            .collect())
+    }
+
+    /// Get the PCI vendor and device IDs of the device.
+    ///
+    /// The IDs are read from the configuration space on the first successful call, which
+    /// may block on concurrent callers doing the same, and are cached until the device is
+    /// reset, by `reset()`, `function_reset()`, `flr_reset()` or a hot reset, or reopened.
+    pub fn pci_ids(&self) -> Result<(u16, u16)> {
+        read_or_recover(&self.pci_ids)
+            .get_or_try_init(|| {
+                let config = self.config();
+                Ok((
+                    config.read_u16(PCI_VENDOR_ID)?,
+                    config.read_u16(PCI_DEVICE_ID)?,
+                ))
+            })
+            .copied()
+    }
+
+    /// Read the PCI vendor and device IDs again, dropping the cached ones.
+    ///
+    /// Resets already drop them, as they may reload the device firmware and change its
+    /// identity. This is needed when the identity changes otherwise, such as after a firmware
+    /// update through a vendor region.
+    pub fn refresh_pci_ids(&self) -> Result<(u16, u16)> {
+        self.invalidate_pci_ids();
+        self.pci_ids()
+    }
+
+    // Drop the cached IDs, to be read again on next use.
+    pub(crate) fn invalidate_pci_ids(&self) {
+        *write_or_recover(&self.pci_ids) = OnceCell::new();
+    }
+
+    /// Find and decode the SR-IOV extended capability of the device.
+    ///
+    /// This tells how many virtual functions a physical function can spawn, and their routing
+    /// IDs with [`SriovCapInfo::vf_routing_id()`]. Returns `None` if the device doesn't have
+    /// the capability, such as virtual functions and devices without SR-IOV support.
+    pub fn sriov_cap_info(&self) -> Result<Option<SriovCapInfo>> {
+        self.config().sriov_cap_info()
+    }
+
+    /// Read the serial number of the Device Serial Number extended capability.
+    ///
+    /// Unlike the PCI address, the serial identifies the device across host reboots. Returns
+    /// `None` if the device doesn't have the capability.
+    pub fn device_serial_number(&self) -> Result<Option<u64>> {
+        let config = self.config();
+        match config.find_ext_capability(PCI_EXT_CAP_ID_DSN)? {
+            Some(cap) => {
+                let lower = config.read_u32(u64::from(cap) + PCI_DSN_LOWER)?;
+                let upper = config.read_u32(u64::from(cap) + PCI_DSN_UPPER)?;
+                Ok(Some(u64::from(upper) << 32 | u64::from(lower)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Get the offset of the PCI Express capability if the device advertises FLR support, and
+    // isn't known for a broken FLR.
+    pub(crate) fn flr_capability(&self) -> Result<Option<u8>> {
+        if self.has_quirk(QuirkRemediation::AvoidFunctionReset) {
+            return Ok(None);
+        }
+        let config = self.config();
+        match config.find_capability(PCI_CAP_ID_EXP)? {
+            Some(cap)
+                if config.read_u32(u64::from(cap) + PCI_EXP_DEVCAP)? & PCI_EXP_DEVCAP_FLR != 0 =>
+            {
+                Ok(Some(cap))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Reset the device with a Function Level Reset initiated from its configuration space.
+    ///
+    /// This works for devices supporting FLR through their PCI Express capability, even when
+    /// the kernel doesn't offer `VFIO_DEVICE_RESET` for them. The call blocks for the 100ms
+    /// the device is given to complete the reset. Devices known for a broken FLR, see
+    /// [`detect_known_quirks()`](crate::detect_known_quirks), are refused.
+    ///
+    /// The cached PCI capabilities, IDs and region mappings of the device are invalidated.
+    pub fn flr_reset(&self) -> Result<()> {
+        self.check_writable()?;
+        let cap = self
+            .flr_capability()?
+            .ok_or(VfioError::VfioDeviceReset(SysError::new(libc::ENOTTY)))?;
+
+        let config = self.config();
+        let devctl = u64::from(cap) + PCI_EXP_DEVCTL;
+        let val = config.read_u16(devctl)?;
+        config.write_u16(devctl, val | PCI_EXP_DEVCTL_BCR_FLR)?;
+        thread::sleep(PCI_FLR_WAIT);
+        config.invalidate_capabilities();
+        self.invalidate_pci_ids();
+        self.invalidate_mmap_fastpath();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use crate::vfio_device::RegionCaps;
+    use crate::VfioRegion;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use vfio_bindings::bindings::vfio::{
+        VFIO_REGION_INFO_FLAG_MMAP, VFIO_REGION_INFO_FLAG_READ, VFIO_REGION_INFO_FLAG_WRITE,
+    };
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use vmm_sys_util::tempfile::TempFile;
+
+    pub(crate) const CONFIG_OFFSET: u64 = 0x7000;
+
+    // Config space of an Intel device with MSI, MSI-X and PCI Express capabilities.
+    fn create_config_fixture() -> Vec<u8> {
+        let mut config = vec![0u8; 0x100];
+        config[0x00..0x04].copy_from_slice(&[0x86, 0x80, 0x3c, 0x15]);
+        config[0x04..0x08].copy_from_slice(&[0x06, 0x04, 0x10, 0x00]);
+        config[0x34] = 0x40;
+        config[0x40..0x42].copy_from_slice(&[0x05, 0x50]);
+        config[0x50..0x52].copy_from_slice(&[0x11, 0x72]);
+        config[0x70..0x72].copy_from_slice(&[0x10, 0x00]);
+        config
+    }
+
+    // A device with the given configuration space.
+    pub(crate) fn create_vfio_device(config: &[u8]) -> VfioDevice {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let mut device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+
+        device.push_region(VfioRegion {
+            index: VFIO_PCI_CONFIG_REGION_INDEX,
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: config.len() as u64,
+            offset: CONFIG_OFFSET,
+            caps: RegionCaps::default(),
+        });
+        device.write_all_at(config, CONFIG_OFFSET).unwrap();
+
+        device
+    }
+
+    #[test]
+    fn test_vfio_pci_config_accessors() {
+        let device = create_vfio_device(&create_config_fixture());
+        let config = device.config();
+
+        assert_eq!(config.read_u16(0).unwrap(), 0x8086);
+        assert_eq!(config.read_u32(0).unwrap(), 0x153c_8086);
+        assert_eq!(config.read_u8(0x34).unwrap(), 0x40);
+        config.read_u32(0xfe).unwrap_err();
+        config.write_u8(0x100, 0).unwrap_err();
+
+        config.write_u32(0x10, 0xfebf_0000).unwrap();
+        assert_eq!(config.read_u32(0x10).unwrap(), 0xfebf_0000);
+        config.write_u16(0x3c, 0x010b).unwrap();
+        assert_eq!(config.read_u8(0x3d).unwrap(), 0x01);
+        let mut raw = [0u8; 2];
+        device.region_read(VFIO_PCI_CONFIG_REGION_INDEX, &mut raw, 0x3c);
+        assert_eq!(raw, [0x0b, 0x01]);
+
+        assert!(config.command().unwrap().bus_master);
+        assert!(config.status().unwrap().capabilities_list);
+    }
+
+    #[test]
+    fn test_vfio_pci_ids() {
+        const THREADS: usize = 8;
+        let device = create_vfio_device(&create_config_fixture());
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reads2 = reads.clone();
+        device.config().set_post_read_hook(move |offset, _| {
+            if offset == PCI_VENDOR_ID {
+                reads2.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let device = Arc::new(device);
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let device = device.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    device.pci_ids().unwrap()
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), (0x8086, 0x153c));
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // The cached IDs are only updated on refresh, which works on a shared device, and reset.
+        device.config().write_u16(PCI_DEVICE_ID, 0x1533).unwrap();
+        assert_eq!(device.pci_ids().unwrap(), (0x8086, 0x153c));
+        assert_eq!(device.refresh_pci_ids().unwrap(), (0x8086, 0x1533));
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        device.config().write_u16(PCI_DEVICE_ID, 0x153c).unwrap();
+        device.reset().unwrap();
+        assert_eq!(device.pci_ids().unwrap(), (0x8086, 0x153c));
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_vfio_pci_config_capabilities() {
+        let device = create_vfio_device(&create_config_fixture());
+        let config = device.config();
+
+        assert_eq!(
+            config.capabilities().unwrap(),
+            vec![
+                VfioPciCapability {
+                    id: 0x05,
+                    offset: 0x40
+                },
+                VfioPciCapability {
+                    id: 0x11,
+                    offset: 0x50
+                },
+                VfioPciCapability {
+                    id: 0x10,
+                    offset: 0x70
+                },
+            ]
+        );
+        assert_eq!(config.find_capability(0x11).unwrap(), Some(0x50));
+        assert_eq!(config.find_capability(0x09).unwrap(), None);
+
+        // The list is cached until the device is reset.
+        config.write_u8(0x34, 0).unwrap();
+        assert_eq!(config.capabilities().unwrap().len(), 3);
+        device.reset().unwrap();
+        assert!(device.config().capabilities().unwrap().is_empty());
+
+        // A looping list is bounded.
+        let mut looping = create_config_fixture();
+        looping[0x71] = 0x40;
+        let device = create_vfio_device(&looping);
+        assert_eq!(device.config().capabilities().unwrap().len(), PCI_CAP_MAX);
+
+        // No capability list.
+        let mut no_caps = create_config_fixture();
+        no_caps[0x06] = 0;
+        let device = create_vfio_device(&no_caps);
+        assert!(device.config().capabilities().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_pci_config_hooks() {
+        let device = create_vfio_device(&create_config_fixture());
+        let config = device.config();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reads2 = reads.clone();
+
+        // Hide the MSI-X capability and forbid disabling bus mastering.
+        config.set_post_read_hook(move |offset, data| {
+            reads2.fetch_add(1, Ordering::SeqCst);
+            if offset == 0x51 {
+                data[0] = 0x70;
+            }
+        });
+        config.set_pre_write_hook(|offset, data| {
+            if offset == PCI_COMMAND {
+                data[0] |= PCI_COMMAND_MASTER as u8;
+            }
+            offset != 0x10
+        });
+
+        assert_eq!(config.read_u8(0x51).unwrap(), 0x70);
+        assert_eq!(device.config().read_u8(0x41).unwrap(), 0x50);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        // The capability cache ignores the hooks.
+        assert_eq!(config.capabilities().unwrap().len(), 3);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+
+        device.set_command_register(PciCommand::default()).unwrap();
+        assert!(device.command_register().unwrap().bus_master);
+        config.write_u32(0x10, 0xffff_ffff).unwrap();
+        assert_eq!(config.read_u32(0x10).unwrap(), 0);
+
+        config.clear_hooks();
+        config.write_u32(0x10, 0xffff_ffff).unwrap();
+        assert_eq!(config.read_u32(0x10).unwrap(), 0xffff_ffff);
+        assert_eq!(config.read_u8(0x51).unwrap(), 0x72);
+    }
+
+    #[test]
+    fn test_pci_command() {
+        let cmd = PciCommand::from(0x0506);
+        assert_eq!(
+            cmd,
+            PciCommand {
+                memory_space: true,
+                bus_master: true,
+                serr: true,
+                interrupt_disable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(u16::from(cmd), 0x0506);
+        assert_eq!(u16::from(PciCommand::from(0xffff)), PCI_COMMAND_MASK);
+    }
+
+    #[test]
+    fn test_pci_status() {
+        let status = PciStatus::from(0x4210);
+        assert!(status.capabilities_list);
+        assert!(status.signaled_system_error);
+        assert_eq!(status.devsel_timing, 1);
+        assert!(!status.interrupt_status);
+        assert!(!status.detected_parity_error);
+    }
+
+    #[test]
+    fn test_vfio_pci_flr_reset() {
+        let mut config = create_config_fixture();
+        let device = create_vfio_device(&config);
+        assert_eq!(device.flr_capability().unwrap(), None);
+        assert!(matches!(
+            device.flr_reset(),
+            Err(VfioError::VfioDeviceReset(e)) if e.errno() == libc::ENOTTY
+        ));
+
+        // Advertise FLR in the PCI Express device capabilities.
+        config[0x77] = 0x10;
+        config[0x78..0x7a].copy_from_slice(&[0x10, 0x28]);
+        let device = create_vfio_device(&config);
+        assert_eq!(device.flr_capability().unwrap(), Some(0x70));
+        device.config().capabilities().unwrap();
+        device.pci_ids().unwrap();
+        device.flr_reset().unwrap();
+        assert_eq!(device.config().read_u16(0x78).unwrap(), 0xa810);
+        assert!(device.pci_config.lock().unwrap().caps.is_none());
+        assert!(device.pci_ids.read().unwrap().get().is_none());
+
+        // The fallback of reset() when VFIO_DEVICE_RESET isn't available.
+        device.config().write_u16(0x78, 0x2810).unwrap();
+        device.reset().unwrap();
+        assert_eq!(device.config().read_u16(0x78).unwrap(), 0xa810);
+
+        // An FLR which can't be initiated fails reset() too, here with the device control
+        // register beyond the configuration space.
+        let mut device = create_vfio_device(&config);
+        device
+            .regions_mut()
+            .iter_mut()
+            .find(|r| r.index == VFIO_PCI_CONFIG_REGION_INDEX)
+            .unwrap()
+            .size = 0x78;
+        assert_eq!(device.flr_capability().unwrap(), Some(0x70));
+        device.reset().unwrap_err();
+    }
+
+    #[test]
+    fn test_vfio_pci_extended_capabilities() {
+        // Conventional configuration space.
+        let device = create_vfio_device(&create_config_fixture());
+        assert!(device.config().extended_capabilities().unwrap().is_empty());
+        assert_eq!(device.device_serial_number().unwrap(), None);
+
+        // AER version 2 followed by DSN version 1.
+        let mut config = create_config_fixture();
+        config.resize(0x1000, 0);
+        config[0x100..0x104].copy_from_slice(&[0x01, 0x00, 0x02, 0x14]);
+        config[0x140..0x144].copy_from_slice(&[0x03, 0x00, 0x01, 0x00]);
+        config[0x144..0x14c].copy_from_slice(&[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        let device = create_vfio_device(&config);
+        assert_eq!(
+            device.config().extended_capabilities().unwrap(),
+            vec![
+                VfioPciExtCapability {
+                    id: 0x01,
+                    version: 2,
+                    offset: 0x100
+                },
+                VfioPciExtCapability {
+                    id: 0x03,
+                    version: 1,
+                    offset: 0x140
+                },
+            ]
+        );
+        assert_eq!(device.config().find_ext_capability(0x0b).unwrap(), None);
+        assert_eq!(
+            device.device_serial_number().unwrap(),
+            Some(0x0102_0304_0506_0708)
+        );
+
+        assert_eq!(
+            device
+                .walk_pcie_extended_capabilities()
+                .unwrap()
+                .iter()
+                .map(|c| (c.offset, c.name()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0x100, Some("Advanced Error Reporting")),
+                (0x140, Some("Device Serial Number")),
+            ]
+        );
+
+        // The whole extended configuration space is accessible.
+        device.config().write_u32(0xffc, 0xdead_beef).unwrap();
+        assert_eq!(device.config().read_u32(0xffc).unwrap(), 0xdead_beef);
+        device.config().read_u32(0xffe).unwrap_err();
+        device.config().write_u8(0x1000, 0).unwrap_err();
+
+        // A looping list is bounded.
+        config[0x142..0x144].copy_from_slice(&[0x01, 0x14]);
+        let device = create_vfio_device(&config);
+        assert_eq!(
+            device.config().extended_capabilities().unwrap().len(),
+            PCI_EXT_CAP_MAX
+        );
+
+        // No extended capability.
+        config[0x100..0x104].copy_from_slice(&[0xff; 4]);
+        let device = create_vfio_device(&config);
+        assert!(device.config().extended_capabilities().unwrap().is_empty());
+        assert_eq!(device.device_serial_number().unwrap(), None);
+    }
+
+    #[test]
+    fn test_vfio_pci_sriov_cap_info() {
+        let device = create_vfio_device(&create_config_fixture());
+        assert_eq!(device.sriov_cap_info().unwrap(), None);
+
+        // 64 VFs supported, 8 enabled, the first one 2 functions after the PF.
+        let mut config = create_config_fixture();
+        config.resize(0x1000, 0);
+        config[0x100..0x104].copy_from_slice(&[0x10, 0x00, 0x01, 0x00]);
+        config[0x108..0x10a].copy_from_slice(&[0x09, 0x00]);
+        config[0x10c..0x112].copy_from_slice(&[0x40, 0x00, 0x40, 0x00, 0x08, 0x00]);
+        config[0x114..0x118].copy_from_slice(&[0x02, 0x00, 0x01, 0x00]);
+        config[0x11a..0x11c].copy_from_slice(&[0x5c, 0x15]);
+        let device = create_vfio_device(&config);
+        let info = device.sriov_cap_info().unwrap().unwrap();
+        assert_eq!(
+            info,
+            SriovCapInfo {
+                offset: 0x100,
+                enabled: true,
+                initial_vfs: 64,
+                total_vfs: 64,
+                num_vfs: 8,
+                vf_offset: 2,
+                vf_stride: 1,
+                vf_device_id: 0x155c,
+            }
+        );
+
+        // The VFs of the PF at 03:00.0 start at 03:00.2.
+        assert_eq!(info.vf_routing_id(0x0300, 0), Some(0x0302));
+        assert_eq!(info.vf_routing_id(0x0300, 63), Some(0x0341));
+        assert_eq!(info.vf_routing_id(0x0300, 64), None);
+        assert_eq!(info.vf_routing_id(0xffc0, 63), None);
+    }
+
+    #[test]
+    fn test_vfio_pci_msi_cap_info() {
+        let mut config = create_config_fixture();
+        // 32 bits addresses, 4 vectors capable, 2 enabled.
+        config[0x42..0x44].copy_from_slice(&[0x15, 0x00]);
+        let device = create_vfio_device(&config);
+        assert_eq!(
+            device.msi_cap_info().unwrap(),
+            Some(MsiCapInfo {
+                offset: 0x40,
+                msg_ctl: 0x15,
+                enabled: true,
+                max_vectors: 4,
+                enabled_vectors: 2,
+                addr_64bit: false,
+                per_vector_mask: false,
+                msg_ctl_offset: 0x42,
+                addr_lo_offset: 0x44,
+                addr_hi_offset: None,
+                data_offset: 0x48,
+                mask_offset: None,
+                pending_offset: None,
+            })
+        );
+
+        // 64 bits addresses with per vector masking, single vector.
+        device.config().write_u16(0x42, 0x0180).unwrap();
+        let info = device.msi_cap_info().unwrap().unwrap();
+        assert!(!info.enabled);
+        assert_eq!(info.max_vectors, 1);
+        assert_eq!(info.enabled_vectors, 1);
+        assert_eq!(info.addr_hi_offset, Some(0x48));
+        assert_eq!(info.data_offset, 0x4c);
+        assert_eq!(info.mask_offset, Some(0x50));
+        assert_eq!(info.pending_offset, Some(0x54));
+
+        // Capability at the end of the configuration space, with registers past it.
+        let mut end = config.clone();
+        end[0x34] = 0xf4;
+        end[0xf4..0xf8].copy_from_slice(&[0x05, 0x00, 0x80, 0x01]);
+        let device = create_vfio_device(&end);
+        let info = device.msi_cap_info().unwrap().unwrap();
+        assert_eq!(info.offset, 0xf4);
+        assert_eq!(info.msg_ctl_offset, 0xf6);
+        assert_eq!(info.data_offset, 0x100);
+        assert_eq!(info.mask_offset, Some(0x104));
+        assert_eq!(info.pending_offset, Some(0x108));
+
+        // No MSI capability.
+        config[0x34] = 0x50;
+        let device = create_vfio_device(&config);
+        assert_eq!(device.msi_cap_info().unwrap(), None);
+    }
+
+    #[test]
+    fn test_vfio_pci_msix_vector_stats() {
+        let mut config = create_config_fixture();
+        // 4 vectors, PBA at offset 0x800 of BAR 0.
+        config[0x52..0x54].copy_from_slice(&[0x03, 0x00]);
+        config[0x58..0x5c].copy_from_slice(&[0x00, 0x08, 0x00, 0x00]);
+        let device = create_vfio_device(&config);
+        let bar0 = device.get_region_offset(0);
+        device
+            .write_all_at(&[0x05, 0, 0, 0, 0, 0, 0, 0], bar0 + 0x800)
+            .unwrap();
+
+        // A blocking EventFd which wasn't signaled doesn't block the check.
+        let evts = [
+            EventFd::new(0).unwrap(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        ];
+        device.enable_msix(evts.iter().collect()).unwrap();
+        evts[1].write(1).unwrap();
+        evts[1].write(1).unwrap();
+
+        let stat = |pending, count| MsixVectorStat { pending, count };
+        assert_eq!(
+            device.msix_vector_stats().unwrap(),
+            vec![stat(true, 0), stat(false, 2), stat(true, 0), stat(false, 0)]
+        );
+        // The interrupts are left for the EventFd reader.
+        assert_eq!(device.msix_vector_stats().unwrap()[1], stat(false, 2));
+        assert_eq!(evts[1].read().unwrap(), 2);
+        assert_eq!(device.msix_vector_stats().unwrap()[1], stat(false, 0));
+
+        // PBA out of the BAR.
+        device.config().write_u32(0x58, 0x1000).unwrap();
+        assert!(matches!(
+            device.msix_vector_stats(),
+            Err(VfioError::ReadMsixPba(_))
+        ));
+
+        // No MSI-X capability.
+        config[0x40..0x42].copy_from_slice(&[0x05, 0x70]);
+        let device = create_vfio_device(&config);
+        assert!(device.msix_vector_stats().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vfio_pci_msix_function_mask() {
+        let mut config = create_config_fixture();
+        // MSI-X enabled with 4 vectors.
+        config[0x52..0x54].copy_from_slice(&[0x03, 0x80]);
+        let device = create_vfio_device(&config);
+
+        assert!(!device.msix_is_function_masked().unwrap());
+        device.msix_function_mask(true).unwrap();
+        assert!(device.msix_is_function_masked().unwrap());
+        assert_eq!(device.config().read_u16(0x52).unwrap(), 0xc003);
+        let info = device.msix_info().unwrap().unwrap();
+        assert!(info.enabled && info.function_mask);
+        device.msix_function_mask(false).unwrap();
+        assert!(!device.msix_is_function_masked().unwrap());
+        assert_eq!(device.config().read_u16(0x52).unwrap(), 0x8003);
+
+        // No MSI-X capability.
+        config[0x40..0x42].copy_from_slice(&[0x05, 0x70]);
+        let device = create_vfio_device(&config);
+        assert!(matches!(
+            device.msix_function_mask(true),
+            Err(VfioError::NoMsixCapability)
+        ));
+        assert!(matches!(
+            device.msix_is_function_masked(),
+            Err(VfioError::NoMsixCapability)
+        ));
+
+        // Capability at the end of the configuration space, with its PBA register past it.
+        config[0x34] = 0xf8;
+        config[0xf8..0xfc].copy_from_slice(&[0x11, 0x00, 0x03, 0xc0]);
+        let device = create_vfio_device(&config);
+        assert!(device.msix_is_function_masked().unwrap());
+        assert!(device.msix_info().is_err());
+    }
+
+    #[test]
+    fn test_vfio_pci_mmap_fastpath_msix_bar() {
+        let mut device = create_vfio_device(&create_config_fixture());
+        let flags =
+            VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE | VFIO_REGION_INFO_FLAG_MMAP;
+        device.regions_mut()[0].flags = flags;
+        device.regions_mut()[2].flags = flags;
+        device.write_all_at(&[0x5a; 0x3000], 0x30000).unwrap();
+        device.set_mmap_fastpath(true);
+
+        // The MSI-X table is in BAR 0, which is never mapped.
+        let mut buf = vec![0u8; 0x1000];
+        device.region_read(0, &mut buf, 0);
+        assert_eq!(device.mmap_fastpath_parts(0), Some(0));
+        device.region_read(2, &mut buf, 0x1000);
+        assert_eq!(buf, vec![0x5a; 0x1000]);
+        assert_eq!(device.mmap_fastpath_parts(2), Some(1));
+    }
+
+    #[test]
+    fn test_vfio_pci_buffered_config_writer() {
+        let device = create_vfio_device(&create_config_fixture());
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let writes2 = writes.clone();
+        device.config().set_pre_write_hook(move |offset, data| {
+            writes2.lock().unwrap().push((offset, data.to_vec()));
+            true
+        });
+        let take = || std::mem::take(&mut *writes.lock().unwrap());
+
+        // Byte writes within a double word are merged, the next double word starts a new write.
+        let mut writer = device.config().buffered_writer();
+        for (i, b) in [0x10u8, 0x20, 0x30, 0x40].iter().enumerate() {
+            writer.write(0x10 + i as u64, &[*b]).unwrap();
+        }
+        assert!(take().is_empty());
+        writer.write(0x14, &[0x50]).unwrap();
+        assert_eq!(take(), vec![(0x10, vec![0x10, 0x20, 0x30, 0x40])]);
+        writer.write(0x18, &[0x01, 0x02]).unwrap();
+        assert_eq!(take(), vec![(0x14, vec![0x50])]);
+
+        // A read flushes, and so does a change of width.
+        let mut buf = [0u8; 2];
+        writer.read(0x18, &mut buf).unwrap();
+        assert_eq!(buf, [0x01, 0x02]);
+        assert_eq!(take(), vec![(0x18, vec![0x01, 0x02])]);
+        writer.write(0x20, &[0xaa]).unwrap();
+        writer.write(0x21, &[0xbb, 0xcc]).unwrap();
+        assert_eq!(take(), vec![(0x20, vec![0xaa])]);
+
+        // Command register and MSI/MSI-X message control writes aren't buffered.
+        writer.write(0x04, &[0x06]).unwrap();
+        assert_eq!(take(), vec![(0x21, vec![0xbb, 0xcc]), (0x04, vec![0x06])]);
+        writer.write(0x42, &[0x01, 0x00]).unwrap();
+        writer.write(0x52, &[0x00, 0x80]).unwrap();
+        assert_eq!(
+            take(),
+            vec![(0x42, vec![0x01, 0x00]), (0x52, vec![0x00, 0x80])]
+        );
+
+        writer.add_must_flush(0x3c, 1);
+        writer.write(0x3c, &[0x0b]).unwrap();
+        assert_eq!(take(), vec![(0x3c, vec![0x0b])]);
+        writer.remove_must_flush(0x04, 2);
+        writer.write(0x04, &[0x06]).unwrap();
+        assert!(take().is_empty());
+        writer.flush().unwrap();
+        assert_eq!(take(), vec![(0x04, vec![0x06])]);
+
+        // Out of range writes are refused immediately, pending data is flushed on drop.
+        writer.write(0x100, &[0]).unwrap_err();
+        writer.write(0x30, &[0x12]).unwrap();
+        drop(writer);
+        assert_eq!(take(), vec![(0x30, vec![0x12])]);
+        assert_eq!(device.config().read_u8(0x30).unwrap(), 0x12);
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{Result, VfioDevice, VfioError, VfioPaths};
+use QuirkRemediation::*;
+
+/// What to do for a device with a known quirk to work reliably with VFIO.
+///
+/// Power state issues aren't covered, as the power state of devices is left to vfio-pci:
+/// devices which don't come back from D3hot while idle need the `disable_idle_d3` parameter
+/// of vfio-pci, see [`VfioPciModuleParam::DisableIdleD3`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QuirkRemediation {
+    /// The device doesn't come back from a secondary bus reset, it must not be hot reset.
+    AvoidBusReset,
+    /// The device advertises FLR but doesn't support it, another reset method must be used.
+    AvoidFunctionReset,
+    /// The INTx disable bit of the device is broken, the `nointxmask` parameter of vfio-pci
+    /// must be set for INTx to work.
+    RequiresNoIntxMask,
+}
+
+impl QuirkRemediation {
+    /// Get the vfio-pci module parameter to set for the remediation, if it is one.
+    pub fn module_param(self) -> Option<VfioPciModuleParam> {
+        match self {
+            QuirkRemediation::RequiresNoIntxMask => Some(VfioPciModuleParam::NoIntxMask),
+            QuirkRemediation::AvoidBusReset | QuirkRemediation::AvoidFunctionReset => None,
+        }
+    }
+}
+
+/// A device with a documented issue, from the table checked by [`detect_known_quirks()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KnownQuirk {
+    /// PCI vendor ID of the device.
+    pub vendor: u16,
+    /// PCI device ID of the device.
+    pub device: u16,
+    /// How to work around the issue.
+    pub remediation: QuirkRemediation,
+    /// Description of the issue.
+    pub description: &'static str,
+}
+
+impl fmt::Display for KnownQuirk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x}: {} ({:?})",
+            self.vendor, self.device, self.description, self.remediation
+        )
+    }
+}
+
+const fn quirk(
+    vendor: u16,
+    device: u16,
+    remediation: QuirkRemediation,
+    description: &'static str,
+) -> KnownQuirk {
+    KnownQuirk {
+        vendor,
+        device,
+        remediation,
+        description,
+    }
+}
+
+// Devices with documented issues, sorted by vendor and device ID. A device may have several
+// entries, one per remediation. New entries should name the kernel quirk or the report
+// documenting the issue.
+#[rustfmt::skip]
+static KNOWN_QUIRKS: &[KnownQuirk] = &[
+    quirk(0x1022, 0x1487, AvoidFunctionReset, "Matisse HD audio FLR hangs the device (quirk_no_flr)"),
+    quirk(0x1022, 0x148c, AvoidFunctionReset, "Starship USB 3.0 controller FLR hangs the device (quirk_no_flr)"),
+    quirk(0x1022, 0x149c, AvoidFunctionReset, "Matisse USB 3.0 controller FLR hangs the device (quirk_no_flr)"),
+    quirk(0x1022, 0x7901, AvoidFunctionReset, "FCH SATA controller FLR hangs the device (quirk_no_flr)"),
+    quirk(0x10ec, 0x8169, RequiresNoIntxMask, "RTL8169 INTx disable bit is broken (quirk_broken_intx_masking)"),
+    quirk(0x1425, 0x0030, RequiresNoIntxMask, "Chelsio T4 INTx disable bit is broken (quirk_broken_intx_masking)"),
+    quirk(0x168c, 0x0030, AvoidBusReset, "AR93xx wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x168c, 0x0032, AvoidBusReset, "AR9485 wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x168c, 0x0033, AvoidBusReset, "AR958x wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x168c, 0x0034, AvoidBusReset, "AR9462 wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x168c, 0x003c, AvoidBusReset, "QCA988x wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x168c, 0x003e, AvoidBusReset, "QCA6174 wireless adapters don't return from bus reset (quirk_no_bus_reset)"),
+    quirk(0x1814, 0x0601, RequiresNoIntxMask, "Ralink RT2800 INTx disable bit is broken (quirk_broken_intx_masking)"),
+    quirk(0x8086, 0x1502, AvoidFunctionReset, "82579LM Ethernet FLR hangs the device (quirk_no_flr)"),
+    quirk(0x8086, 0x1503, AvoidFunctionReset, "82579V Ethernet FLR hangs the device (quirk_no_flr)"),
+];
+
+/// Get the known quirks of a PCI device from its vendor and device IDs.
+///
+/// # Arguments
+/// * `vendor` - PCI vendor ID of the device.
+/// * `device` - PCI device ID of the device.
+pub fn known_quirks(vendor: u16, device: u16) -> Vec<KnownQuirk> {
+    KNOWN_QUIRKS
+        .iter()
+        .filter(|quirk| quirk.vendor == vendor && quirk.device == device)
+        .copied()
+        .collect()
+}
+
+/// Look up the known quirks of a device in the built-in table of devices with documented
+/// issues.
+///
+/// The device is identified by the vendor and device IDs of its PCI configuration space.
+/// Devices which aren't PCI, or whose IDs can't be read, have no known quirks.
+pub fn detect_known_quirks(device: &VfioDevice) -> Vec<KnownQuirk> {
+    match device.pci_ids() {
+        Ok((vendor, device_id)) => known_quirks(vendor, device_id),
+        Err(_) => Vec::new(),
+    }
+}
+
+impl VfioDevice {
+    /// Check whether a known quirk of the device calls for the given remediation.
+    ///
+    /// # Arguments
+    /// * `remediation` - The remediation to look for.
+    pub fn has_quirk(&self, remediation: QuirkRemediation) -> bool {
+        detect_known_quirks(self)
+            .iter()
+            .any(|quirk| quirk.remediation == remediation)
+    }
+}
+
+/// A boolean parameter of the vfio-pci driver.
+///
+/// The parameters are global to all the devices bound to vfio-pci, they are exposed in sysfs
+/// by `vfio_pci_core` on recent kernels and by `vfio_pci` on older ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VfioPciModuleParam {
+    /// Don't put idle devices in D3hot, `disable_idle_d3`.
+    DisableIdleD3,
+    /// Don't rely on the INTx disable bit, masking INTx at the interrupt controller instead,
+    /// `nointxmask`.
+    NoIntxMask,
+    /// Don't claim the legacy VGA resources of VGA devices, `disable_vga`, read-only.
+    DisableVga,
+    /// Bind devices denied by default, `disable_denylist`, read-only.
+    DisableDenylist,
+    /// Allow enabling the virtual functions of devices, `enable_sriov`.
+    EnableSriov,
+}
+
+impl VfioPciModuleParam {
+    /// Get the name of the parameter in sysfs.
+    pub fn name(self) -> &'static str {
+        match self {
+            VfioPciModuleParam::DisableIdleD3 => "disable_idle_d3",
+            VfioPciModuleParam::NoIntxMask => "nointxmask",
+            VfioPciModuleParam::DisableVga => "disable_vga",
+            VfioPciModuleParam::DisableDenylist => "disable_denylist",
+            VfioPciModuleParam::EnableSriov => "enable_sriov",
+        }
+    }
+
+    // Find the sysfs file of the parameter, in vfio_pci_core or in vfio_pci.
+    fn path(self, paths: &VfioPaths) -> Option<PathBuf> {
+        ["vfio_pci_core", "vfio_pci"]
+            .iter()
+            .map(|module| {
+                paths
+                    .sysfs_root
+                    .join("module")
+                    .join(module)
+                    .join("parameters")
+                    .join(self.name())
+            })
+            .find(|path| path.exists())
+    }
+
+    /// Read the value of the parameter.
+    ///
+    /// Returns `None` if vfio-pci isn't loaded or the parameter can't be read.
+    ///
+    /// # Arguments
+    /// * `paths` - Locations of the sysfs tree.
+    pub fn get(self, paths: &VfioPaths) -> Option<bool> {
+        let value = fs::read_to_string(self.path(paths)?).ok()?;
+        match value.trim() {
+            "Y" | "1" => Some(true),
+            "N" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Set the value of the parameter.
+    ///
+    /// This needs root privileges, and affects all the devices bound to vfio-pci. Parameters
+    /// which can only be set when loading the module fail with a permission error.
+    ///
+    /// # Arguments
+    /// * `paths` - Locations of the sysfs tree.
+    /// * `value` - New value of the parameter.
+    pub fn set(self, paths: &VfioPaths, value: bool) -> Result<()> {
+        let path = self.path(paths).ok_or(VfioError::InvalidPath)?;
+        fs::write(&path, if value { "Y" } else { "N" })
+            .map_err(|e| VfioError::WriteSysfs(e, path.display().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_pci::tests::create_vfio_device;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn create_pci_device(vendor: u16, device_id: u16) -> VfioDevice {
+        let mut config = [0u8; 0x100];
+        config[0..2].copy_from_slice(&vendor.to_le_bytes());
+        config[2..4].copy_from_slice(&device_id.to_le_bytes());
+        create_vfio_device(&config)
+    }
+
+    #[test]
+    fn test_known_quirks_table() {
+        // Sorted, without duplicates, and described.
+        for pair in KNOWN_QUIRKS.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert!(
+                (a.vendor, a.device, a.remediation as u8)
+                    < (b.vendor, b.device, b.remediation as u8),
+                "{} isn't sorted before {}",
+                a,
+                b
+            );
+        }
+        for quirk in KNOWN_QUIRKS {
+            assert!(!quirk.description.is_empty());
+            assert!(known_quirks(quirk.vendor, quirk.device).contains(quirk));
+        }
+    }
+
+    #[test]
+    fn test_detect_known_quirks() {
+        for quirk in KNOWN_QUIRKS {
+            let device = create_pci_device(quirk.vendor, quirk.device);
+            assert!(detect_known_quirks(&device).contains(quirk));
+            assert!(device.has_quirk(quirk.remediation));
+        }
+
+        let device = create_pci_device(0x8086, 0x153c);
+        assert!(detect_known_quirks(&device).is_empty());
+        assert!(!device.has_quirk(AvoidBusReset));
+    }
+
+    #[test]
+    fn test_vfio_pci_module_params() {
+        let root = TempDir::new().unwrap();
+        let paths = VfioPaths {
+            sysfs_root: root.as_path().to_path_buf(),
+            ..Default::default()
+        };
+        let param = VfioPciModuleParam::DisableIdleD3;
+        assert_eq!(param.get(&paths), None);
+        assert!(matches!(
+            param.set(&paths, true),
+            Err(VfioError::InvalidPath)
+        ));
+
+        // Older kernels expose the parameters in vfio_pci.
+        let params = root.as_path().join("module/vfio_pci/parameters");
+        fs::create_dir_all(&params).unwrap();
+        fs::write(params.join("disable_idle_d3"), "N\n").unwrap();
+        fs::write(params.join("enable_sriov"), "garbage\n").unwrap();
+        assert_eq!(param.get(&paths), Some(false));
+        param.set(&paths, true).unwrap();
+        assert_eq!(param.get(&paths), Some(true));
+        assert_eq!(VfioPciModuleParam::EnableSriov.get(&paths), None);
+
+        let core_params = root.as_path().join("module/vfio_pci_core/parameters");
+        fs::create_dir_all(&core_params).unwrap();
+        fs::write(core_params.join("disable_idle_d3"), "N\n").unwrap();
+        assert_eq!(param.get(&paths), Some(false));
+
+        assert_eq!(
+            RequiresNoIntxMask.module_param(),
+            Some(VfioPciModuleParam::NoIntxMask)
+        );
+        assert_eq!(AvoidBusReset.module_param(), None);
+    }
+}
@@ -0,0 +1,607 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use log::{debug, warn};
+use vfio_bindings::bindings::vfio::*;
+use vmm_sys_util::errno::Error as SysError;
+
+use crate::fam::vec_with_array_field;
+use crate::vfio_ioctls::vfio_syscall;
+use crate::{QuirkRemediation, Result, VfioDevice, VfioError, VfioGroup};
+
+/// Address of a PCI function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VfioPciAddress {
+    /// PCI segment.
+    pub segment: u16,
+    /// Bus number.
+    pub bus: u8,
+    /// Device and function numbers, as `device << 3 | function`.
+    pub devfn: u8,
+}
+
+impl VfioPciAddress {
+    /// Parse a PCI address in the `ssss:bb:dd.f` format used by sysfs.
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut parts = name.split([':', '.']);
+        let segment = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let device = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let function = u8::from_str_radix(parts.next()?, 16).ok()?;
+        if parts.next().is_some() || device > 0x1f || function > 0x7 {
+            return None;
+        }
+
+        Some(VfioPciAddress {
+            segment,
+            bus,
+            devfn: device << 3 | function,
+        })
+    }
+
+    /// Function number within the PCI device.
+    pub fn function(&self) -> u8 {
+        self.devfn & 0x7
+    }
+}
+
+impl fmt::Display for VfioPciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment,
+            self.bus,
+            self.devfn >> 3,
+            self.function()
+        )
+    }
+}
+
+/// A device affected by a PCI hot reset, as reported by `VfioDevice::hot_reset_info()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VfioPciDependentDevice {
+    /// VFIO group of the device.
+    pub group_id: u32,
+    /// PCI address of the device.
+    pub address: VfioPciAddress,
+}
+
+impl VfioDevice {
+    /// Get the PCI address of the device, from the name of its sysfs path.
+    pub fn pci_address(&self) -> Option<VfioPciAddress> {
+        VfioPciAddress::parse(self.sysfspath.file_name()?.to_str()?)
+    }
+
+    /// Get the devices affected by a PCI hot reset of the device.
+    ///
+    /// The device itself is part of the list. `VfioError::VfioDeviceHotResetInfo` with ENODEV
+    /// is returned when the device doesn't support hot resets.
+    pub fn hot_reset_info(&self) -> Result<Vec<VfioPciDependentDevice>> {
+        let mut count = 0;
+        loop {
+            let mut info =
+                vec_with_array_field::<vfio_pci_hot_reset_info, vfio_pci_dependent_device>(count);
+            info[0].argsz = (mem::size_of::<vfio_pci_hot_reset_info>()
+                + count * mem::size_of::<vfio_pci_dependent_device>())
+                as u32;
+
+            match vfio_syscall::get_pci_hot_reset_info(self, &mut info) {
+                Ok(()) => {
+                    let count = (info[0].count as usize).min(count);
+                    // SAFETY: enough space is reserved for count entries through
+                    // vec_with_array_field().
+                    let devices = unsafe { info[0].devices.as_slice(count) };
+                    return Ok(devices
+                        .iter()
+                        .map(|d| VfioPciDependentDevice {
+                            group_id: d.group_id,
+                            address: VfioPciAddress {
+                                segment: d.segment,
+                                bus: d.bus,
+                                devfn: d.devfn,
+                            },
+                        })
+                        .collect());
+                }
+                // The kernel reports the number of devices when the buffer is too small.
+                Err(VfioError::VfioDeviceHotResetInfo(e))
+                    if e.errno() == libc::ENOSPC && info[0].count as usize > count =>
+                {
+                    count = info[0].count as usize;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reset the bus of the device, and all the devices reported by `hot_reset_info()`.
+    ///
+    /// The kernel refuses the reset unless `groups` contains the groups of all the affected
+    /// devices. The cached PCI capabilities, IDs and region mappings of the device are
+    /// invalidated.
+    ///
+    /// # Parameters
+    /// * `groups`: groups of the devices affected by the reset.
+    pub fn pci_hot_reset(&self, groups: &[&VfioGroup]) -> Result<()> {
+        self.check_writable()?;
+
+        let mut reset = vec_with_array_field::<vfio_pci_hot_reset, i32>(groups.len());
+        reset[0].argsz =
+            (mem::size_of::<vfio_pci_hot_reset>() + groups.len() * mem::size_of::<i32>()) as u32;
+        reset[0].count = groups.len() as u32;
+        {
+            // SAFETY: enough space is reserved for the group fds through
+            // vec_with_array_field().
+            let fds = unsafe { reset[0].group_fds.as_mut_slice(groups.len()) };
+            for (fd, group) in fds.iter_mut().zip(groups.iter()) {
+                *fd = group.as_raw_fd();
+            }
+        }
+
+        vfio_syscall::pci_hot_reset(self, &reset)?;
+        self.config().invalidate_capabilities();
+        self.invalidate_pci_ids();
+        self.invalidate_mmap_fastpath();
+
+        Ok(())
+    }
+
+    /// Reset the device with `VFIO_DEVICE_RESET`, failing unlike `reset()` if the device doesn't
+    /// support it.
+    ///
+    /// The cached PCI capabilities, IDs and region mappings of the device are invalidated.
+    pub fn function_reset(&self) -> Result<()> {
+        self.check_writable()?;
+        if self.flags & VFIO_DEVICE_FLAGS_RESET == 0 {
+            return Err(VfioError::VfioDeviceReset(SysError::new(libc::ENOTTY)));
+        }
+        if vfio_syscall::reset(self) < 0 {
+            return Err(VfioError::VfioDeviceReset(SysError::last()));
+        }
+        self.config().invalidate_capabilities();
+        self.invalidate_pci_ids();
+        self.invalidate_mmap_fastpath();
+
+        Ok(())
+    }
+}
+
+/// What is known about a device when planning the reset of several devices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfioResetTarget {
+    /// PCI address of the device, if known.
+    pub address: Option<VfioPciAddress>,
+    /// VFIO group of the device.
+    pub group_id: u32,
+    /// Whether the device supports `VFIO_DEVICE_RESET`.
+    pub function_reset: bool,
+    /// Devices affected by a hot reset of the device, `None` if hot resets are unsupported.
+    pub hot_reset_dependents: Option<Vec<VfioPciDependentDevice>>,
+}
+
+/// A step of a [`VfioResetPlan`]. Devices are designated by their index in the planned slice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VfioResetStep {
+    /// PCI hot reset issued through `device`, resetting all of `devices`.
+    HotReset {
+        /// Device the hot reset is issued through.
+        device: usize,
+        /// Planned devices reset by the hot reset.
+        devices: Vec<usize>,
+        /// Groups of all the devices affected by the hot reset.
+        groups: Vec<u32>,
+    },
+    /// `VFIO_DEVICE_RESET` of a single device.
+    FunctionReset {
+        /// Device to reset.
+        device: usize,
+    },
+}
+
+/// Ordered resets needed to reset a set of devices.
+///
+/// Devices sharing a hot reset domain are reset together with a single hot reset, which is
+/// only planned when the groups of all the affected devices belong to the planned devices.
+/// Other devices get a function reset, ordered so that function 0 of a multifunction device,
+/// usually the one controlling the others, is reset last.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VfioResetPlan {
+    /// Resets to issue, in order.
+    pub steps: Vec<VfioResetStep>,
+    /// Devices which can be reset neither by a function reset nor by an allowed hot reset.
+    pub unresettable: Vec<usize>,
+}
+
+impl VfioResetPlan {
+    /// Compute the resets needed to reset all of `targets`.
+    pub fn new(targets: &[VfioResetTarget]) -> Self {
+        let owned: BTreeSet<u32> = targets.iter().map(|t| t.group_id).collect();
+
+        // Hot resets allowed for each device, with the planned devices they cover.
+        let mut hot_resets = Vec::new();
+        for (index, target) in targets.iter().enumerate() {
+            let dependents = match (&target.address, &target.hot_reset_dependents) {
+                (Some(_), Some(dependents)) => dependents,
+                _ => continue,
+            };
+            let groups: BTreeSet<u32> = dependents.iter().map(|d| d.group_id).collect();
+            if !groups.is_subset(&owned) {
+                debug!("Hot reset through device #{} affects foreign groups", index);
+                continue;
+            }
+            let devices: Vec<usize> = targets
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| match t.address {
+                    Some(address) => dependents.iter().any(|d| d.address == address),
+                    None => false,
+                })
+                .map(|(i, _)| i)
+                .collect();
+            hot_resets.push((index, devices, groups.into_iter().collect::<Vec<u32>>()));
+        }
+        // Prefer the hot resets covering the most devices.
+        hot_resets.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+
+        let mut reset = vec![false; targets.len()];
+        let mut hot_reset_steps = Vec::new();
+        for (device, devices, groups) in hot_resets {
+            let pending: Vec<usize> = devices.iter().copied().filter(|&d| !reset[d]).collect();
+            // A function reset is less disruptive than a hot reset for a single device.
+            if pending.is_empty() || (pending.len() == 1 && targets[pending[0]].function_reset) {
+                continue;
+            }
+            for &d in devices.iter() {
+                reset[d] = true;
+            }
+            hot_reset_steps.push(VfioResetStep::HotReset {
+                device,
+                devices,
+                groups,
+            });
+        }
+
+        let mut function_resets: Vec<usize> = (0..targets.len())
+            .filter(|&i| !reset[i] && targets[i].function_reset)
+            .collect();
+        function_resets.sort_by_key(|&i| {
+            let address = targets[i].address;
+            let function0 = match address {
+                Some(address) => address.function() == 0,
+                None => false,
+            };
+            (function0, std::cmp::Reverse(address), i)
+        });
+        for &i in function_resets.iter() {
+            reset[i] = true;
+        }
+
+        let mut steps: Vec<VfioResetStep> = function_resets
+            .into_iter()
+            .map(|device| VfioResetStep::FunctionReset { device })
+            .collect();
+        steps.extend(hot_reset_steps);
+
+        VfioResetPlan {
+            steps,
+            unresettable: (0..targets.len()).filter(|&i| !reset[i]).collect(),
+        }
+    }
+}
+
+/// Reset several devices owned by a VM together, such as the functions of a multifunction
+/// adapter.
+pub struct VfioDeviceGroupReset<'a> {
+    devices: Vec<&'a VfioDevice>,
+    plan: VfioResetPlan,
+}
+
+impl<'a> VfioDeviceGroupReset<'a> {
+    /// Plan the reset of `devices`.
+    ///
+    /// Each device is queried for its hot reset domain, devices not supporting hot resets are
+    /// planned for a function reset only. No hot reset is planned which would reset a device
+    /// known not to return from a bus reset, see [`detect_known_quirks()`].
+    ///
+    /// [`detect_known_quirks()`]: crate::detect_known_quirks
+    pub fn new(devices: &[&'a VfioDevice]) -> Self {
+        let no_bus_reset: Vec<VfioPciAddress> = devices
+            .iter()
+            .filter(|device| device.has_quirk(QuirkRemediation::AvoidBusReset))
+            .filter_map(|device| device.pci_address())
+            .collect();
+        let targets: Vec<VfioResetTarget> = devices
+            .iter()
+            .map(|device| VfioResetTarget {
+                address: device.pci_address(),
+                group_id: device.binding.group.id(),
+                function_reset: device.flags & VFIO_DEVICE_FLAGS_RESET != 0,
+                hot_reset_dependents: match device.hot_reset_info() {
+                    Ok(dependents)
+                        if dependents.iter().any(|d| no_bus_reset.contains(&d.address)) =>
+                    {
+                        debug!(
+                            "No hot reset for device {}: quirky dependent",
+                            device.name()
+                        );
+                        None
+                    }
+                    Ok(dependents) => Some(dependents),
+                    Err(e) => {
+                        debug!("No hot reset for device {}: {}", device.name(), e);
+                        None
+                    }
+                },
+            })
+            .collect();
+
+        VfioDeviceGroupReset {
+            devices: devices.to_vec(),
+            plan: VfioResetPlan::new(&targets),
+        }
+    }
+
+    /// Get the planned resets.
+    pub fn plan(&self) -> &VfioResetPlan {
+        &self.plan
+    }
+
+    /// Issue the planned resets in order.
+    ///
+    /// A failing step doesn't prevent the following ones. The result of each step is returned.
+    pub fn execute(&self) -> Vec<(VfioResetStep, Result<()>)> {
+        for &i in self.plan.unresettable.iter() {
+            warn!("Device {} can't be reset", self.devices[i].name());
+        }
+
+        self.plan
+            .steps
+            .iter()
+            .map(|step| {
+                let result = match step {
+                    VfioResetStep::FunctionReset { device } => {
+                        self.devices[*device].function_reset()
+                    }
+                    VfioResetStep::HotReset {
+                        device,
+                        devices,
+                        groups,
+                    } => self.hot_reset(*device, devices, groups),
+                };
+                (step.clone(), result)
+            })
+            .collect()
+    }
+
+    fn hot_reset(&self, device: usize, devices: &[usize], group_ids: &[u32]) -> Result<()> {
+        let mut groups: Vec<&VfioGroup> = Vec::with_capacity(group_ids.len());
+        for &id in group_ids {
+            let group = self
+                .devices
+                .iter()
+                .map(|d| &*d.binding.group)
+                .find(|g| g.id() == id)
+                .ok_or(VfioError::HotResetGroupNotOwned(id))?;
+            groups.push(group);
+        }
+
+        self.devices[device].pci_hot_reset(&groups)?;
+        for &d in devices.iter().filter(|&&d| d != device) {
+            self.devices[d].config().invalidate_capabilities();
+            self.devices[d].invalidate_pci_ids();
+            self.devices[d].invalidate_mmap_fastpath();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn address(bus: u8, devfn: u8) -> VfioPciAddress {
+        VfioPciAddress {
+            segment: 0,
+            bus,
+            devfn,
+        }
+    }
+
+    fn dependent(group_id: u32, bus: u8, devfn: u8) -> VfioPciDependentDevice {
+        VfioPciDependentDevice {
+            group_id,
+            address: address(bus, devfn),
+        }
+    }
+
+    fn target(
+        group_id: u32,
+        bus: u8,
+        devfn: u8,
+        function_reset: bool,
+        dependents: Option<Vec<VfioPciDependentDevice>>,
+    ) -> VfioResetTarget {
+        VfioResetTarget {
+            address: Some(address(bus, devfn)),
+            group_id,
+            function_reset,
+            hot_reset_dependents: dependents,
+        }
+    }
+
+    #[test]
+    fn test_vfio_pci_address() {
+        let addr = VfioPciAddress::parse("0000:3b:1f.7").unwrap();
+        assert_eq!(addr, address(0x3b, 0xff));
+        assert_eq!(addr.function(), 7);
+        assert_eq!(format!("{}", addr), "0000:3b:1f.7");
+        assert!(VfioPciAddress::parse("0000:3b:20.0").is_none());
+        assert!(VfioPciAddress::parse("0000:3b:00.0.1").is_none());
+        assert!(VfioPciAddress::parse("vfio0").is_none());
+    }
+
+    #[test]
+    fn test_vfio_reset_plan() {
+        // Three functions of an adapter sharing a bus, and an unrelated device.
+        let bus1 = vec![dependent(1, 1, 0), dependent(1, 1, 1), dependent(2, 1, 2)];
+        let targets = vec![
+            target(1, 1, 0, true, Some(bus1.clone())),
+            target(1, 1, 1, true, Some(bus1.clone())),
+            target(2, 1, 2, false, Some(bus1)),
+            target(3, 2, 0, true, None),
+        ];
+        let plan = VfioResetPlan::new(&targets);
+        assert_eq!(
+            plan.steps,
+            vec![
+                VfioResetStep::FunctionReset { device: 3 },
+                VfioResetStep::HotReset {
+                    device: 0,
+                    devices: vec![0, 1, 2],
+                    groups: vec![1, 2],
+                },
+            ]
+        );
+        assert!(plan.unresettable.is_empty());
+
+        // Without the group of the third function, the bus can't be reset. Function 0 is
+        // reset last.
+        let bus1 = vec![dependent(1, 1, 0), dependent(1, 1, 1), dependent(2, 1, 2)];
+        let targets = vec![
+            target(1, 1, 0, true, Some(bus1.clone())),
+            target(1, 1, 1, true, Some(bus1)),
+        ];
+        let plan = VfioResetPlan::new(&targets);
+        assert_eq!(
+            plan.steps,
+            vec![
+                VfioResetStep::FunctionReset { device: 1 },
+                VfioResetStep::FunctionReset { device: 0 },
+            ]
+        );
+
+        // A single device is only hot reset when it can't be function reset.
+        let targets = vec![
+            target(1, 1, 0, true, Some(vec![dependent(1, 1, 0)])),
+            target(2, 2, 0, false, Some(vec![dependent(2, 2, 0)])),
+            target(3, 3, 0, false, None),
+            VfioResetTarget {
+                address: None,
+                group_id: 4,
+                function_reset: true,
+                hot_reset_dependents: Some(vec![dependent(4, 4, 0)]),
+            },
+        ];
+        let plan = VfioResetPlan::new(&targets);
+        assert_eq!(
+            plan.steps,
+            vec![
+                VfioResetStep::FunctionReset { device: 3 },
+                VfioResetStep::FunctionReset { device: 0 },
+                VfioResetStep::HotReset {
+                    device: 1,
+                    devices: vec![1],
+                    groups: vec![2],
+                },
+            ]
+        );
+        assert_eq!(plan.unresettable, vec![2]);
+    }
+
+    #[test]
+    fn test_vfio_device_group_reset() {
+        let container = Arc::new(create_vfio_container());
+        let mut dev0 = VfioDevice::new_in_group(
+            Path::new("/sys/bus/pci/devices/0000:01:00.0"),
+            1,
+            container.clone(),
+        )
+        .unwrap();
+        let dev1 =
+            VfioDevice::new_in_group(Path::new("/sys/bus/pci/devices/0000:01:00.1"), 2, container)
+                .unwrap();
+        assert_eq!(dev0.pci_address(), Some(address(1, 0)));
+
+        // Hot resets unsupported, and no function reset.
+        vfio_syscall::inject_hot_reset_info(&[]);
+        dev0.hot_reset_info().unwrap_err();
+        dev0.function_reset().unwrap_err();
+        let reset = VfioDeviceGroupReset::new(&[&dev0, &dev1]);
+        assert!(reset.plan().steps.is_empty());
+        assert_eq!(reset.plan().unresettable, vec![0, 1]);
+
+        // Resets drop the cached PCI IDs.
+        dev0.flags |= VFIO_DEVICE_FLAGS_RESET;
+        dev0.pci_ids.read().unwrap().set((0x8086, 0x153c)).unwrap();
+        dev0.function_reset().unwrap();
+        assert!(dev0.pci_ids.read().unwrap().get().is_none());
+
+        // A failed reset is reported, by reset() as well.
+        vfio_syscall::inject_reset_failure(true);
+        assert!(matches!(
+            dev0.function_reset(),
+            Err(VfioError::VfioDeviceReset(_))
+        ));
+        assert!(matches!(dev0.reset(), Err(VfioError::VfioDeviceReset(_))));
+        vfio_syscall::inject_reset_failure(false);
+        dev0.reset().unwrap();
+
+        let bus = [
+            vfio_pci_dependent_device {
+                group_id: 1,
+                segment: 0,
+                bus: 1,
+                devfn: 0,
+            },
+            vfio_pci_dependent_device {
+                group_id: 2,
+                segment: 0,
+                bus: 1,
+                devfn: 1,
+            },
+        ];
+        vfio_syscall::inject_hot_reset_info(&bus);
+        assert_eq!(
+            dev1.hot_reset_info().unwrap(),
+            vec![dependent(1, 1, 0), dependent(2, 1, 1)]
+        );
+
+        for dev in [&dev0, &dev1] {
+            dev.pci_ids.read().unwrap().set((0x8086, 0x153c)).unwrap();
+        }
+        let reset = VfioDeviceGroupReset::new(&[&dev0, &dev1]);
+        let results = reset.execute();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].0,
+            VfioResetStep::HotReset {
+                device: 0,
+                devices: vec![0, 1],
+                groups: vec![1, 2],
+            }
+        );
+        assert!(results[0].1.is_ok());
+        assert_eq!(vfio_syscall::take_hot_reset_log(), vec![2]);
+        assert!(dev0.pci_ids.read().unwrap().get().is_none());
+        assert!(dev1.pci_ids.read().unwrap().get().is_none());
+
+        // The group of the second function isn't owned.
+        let reset = VfioDeviceGroupReset::new(&[&dev0]);
+        assert_eq!(
+            reset.plan().steps,
+            vec![VfioResetStep::FunctionReset { device: 0 }]
+        );
+        vfio_syscall::inject_hot_reset_info(&[]);
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{VfioDevice, VfioRegionInfoCap};
+
+/// Version of the [`VfioTopology`] layout, bumped on incompatible changes.
+pub const VFIO_TOPOLOGY_VERSION: u32 = 1;
+
+/// Description of a device region in a [`VfioTopology`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VfioRegionTopology {
+    /// Region index.
+    pub index: u32,
+    /// `VFIO_REGION_INFO_FLAG_*` flags of the region.
+    pub flags: u32,
+    /// Region size.
+    pub size: u64,
+    /// Region capabilities.
+    pub caps: Vec<VfioRegionInfoCap>,
+}
+
+/// Description of a device irq index in a [`VfioTopology`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VfioIrqTopology {
+    /// Irq index.
+    pub index: u32,
+    /// `VFIO_IRQ_INFO_*` flags of the irq index.
+    pub flags: u32,
+    /// Number of vectors.
+    pub count: u32,
+}
+
+/// Regions and interrupts exposed by a VFIO device.
+///
+/// The migration source and destination can compare their topologies to check that device
+/// state may be transferred between them. Region offsets are left out as they only describe
+/// how the host exposes the region through the device file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VfioTopology {
+    /// Layout version, [`VFIO_TOPOLOGY_VERSION`] for topologies built by this crate.
+    pub version: u32,
+    /// `VFIO_DEVICE_FLAGS_*` flags of the device.
+    pub flags: u32,
+    /// Regions sorted by index.
+    pub regions: Vec<VfioRegionTopology>,
+    /// Irq indexes sorted by index.
+    pub irqs: Vec<VfioIrqTopology>,
+}
+
+impl VfioDevice {
+    /// Get the topology of the device.
+    pub fn topology(&self) -> VfioTopology {
+        let mut regions: Vec<VfioRegionTopology> = self
+            .regions()
+            .iter()
+            .map(|r| VfioRegionTopology {
+                index: r.index,
+                flags: r.flags,
+                size: r.size,
+                caps: r.caps.to_vec(),
+            })
+            .collect();
+        regions.sort_by_key(|r| r.index);
+
+        let mut irqs: Vec<VfioIrqTopology> = self
+            .irqs()
+            .values()
+            .map(|i| VfioIrqTopology {
+                index: i.index,
+                flags: i.flags,
+                count: i.count,
+            })
+            .collect();
+        irqs.sort_by_key(|i| i.index);
+
+        VfioTopology {
+            version: VFIO_TOPOLOGY_VERSION,
+            flags: self.flags,
+            regions,
+            irqs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::sync::Arc;
+    use vfio_bindings::bindings::vfio::{VFIO_DEVICE_FLAGS_PCI, VFIO_PCI_MSIX_IRQ_INDEX};
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_vfio_topology() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container.clone()).unwrap();
+
+        let topology = device.topology();
+        assert_eq!(topology.version, VFIO_TOPOLOGY_VERSION);
+        assert_eq!(topology.flags, VFIO_DEVICE_FLAGS_PCI);
+        assert_eq!(topology.regions.len(), 7);
+        assert_eq!(topology.regions[0].size, 0x1000);
+        assert_eq!(topology.regions[1].caps.len(), 3);
+        assert_eq!(
+            topology.irqs[2],
+            VfioIrqTopology {
+                index: VFIO_PCI_MSIX_IRQ_INDEX,
+                flags: device.irqs()[&VFIO_PCI_MSIX_IRQ_INDEX].flags,
+                count: 2048,
+            }
+        );
+
+        let tmp_file = TempFile::new().unwrap();
+        let other = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        assert_eq!(other.topology(), topology);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_vfio_topology_serde() {
+        let tmp_file = TempFile::new().unwrap();
+        let container = Arc::new(create_vfio_container());
+        let device = VfioDevice::new(tmp_file.as_path(), container).unwrap();
+        let topology = device.topology();
+
+        let json = serde_json::to_string(&topology).unwrap();
+        let restored: VfioTopology = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, topology);
+    }
+}
@@ -0,0 +1,642 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use log::error;
+
+use crate::{Result, VfioDevice, VfioError, VfioPaths};
+
+// Largest status line of the VGA arbiter.
+const VGA_ARB_STATUS_MAX: usize = 1024;
+
+/// Legacy VGA resources, the I/O ports and the memory range routed to a single card at a time.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VgaResources {
+    /// Legacy I/O ports (0x3b0-0x3df).
+    pub io: bool,
+    /// Legacy memory range (0xa0000-0xbffff).
+    pub mem: bool,
+}
+
+impl VgaResources {
+    /// No legacy resource.
+    pub const NONE: Self = VgaResources {
+        io: false,
+        mem: false,
+    };
+    /// The legacy I/O ports.
+    pub const IO: Self = VgaResources {
+        io: true,
+        mem: false,
+    };
+    /// The legacy memory range.
+    pub const MEM: Self = VgaResources {
+        io: false,
+        mem: true,
+    };
+    /// All the legacy resources.
+    pub const ALL: Self = VgaResources {
+        io: true,
+        mem: true,
+    };
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Self::NONE),
+            "io" => Some(Self::IO),
+            "mem" => Some(Self::MEM),
+            "io+mem" => Some(Self::ALL),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match (self.io, self.mem) {
+            (true, true) => "io+mem",
+            (true, false) => "io",
+            (false, true) => "mem",
+            (false, false) => "none",
+        }
+    }
+}
+
+/// Arbitration state of the target card of a VGA arbiter client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VgaCardStatus {
+    /// Number of cards decoding legacy resources.
+    pub vga_count: u32,
+    /// PCI address of the card, `0000:01:00.0` for instance.
+    pub device: String,
+    /// Legacy resources the card decodes.
+    pub decodes: VgaResources,
+    /// Legacy resources currently routed to the card.
+    pub owns: VgaResources,
+    /// Legacy resources locked on the card, by any client.
+    pub locks: VgaResources,
+    /// Number of locks of the I/O ports held by this client.
+    pub io_lock_count: u32,
+    /// Number of locks of the memory range held by this client.
+    pub mem_lock_count: u32,
+}
+
+/// Parse a status line read from the VGA arbiter.
+///
+/// Returns `None` if the client has no valid target, which the arbiter reports as `invalid`.
+///
+/// # Arguments
+/// * `status` - Status line, `count:2,PCI:0000:01:00.0,decodes=io+mem,owns=io+mem,locks=none(0:0)`
+///   for instance.
+pub fn parse_vga_arbiter_status(status: &str) -> Result<Option<VgaCardStatus>> {
+    let status = status.trim();
+    if status == "invalid" {
+        return Ok(None);
+    }
+
+    let invalid = || VfioError::InvalidVgaArbiterStatus(status.to_string());
+    let mut fields = status.split(',');
+    let mut field = |prefix: &str| {
+        fields
+            .next()
+            .and_then(|f| f.strip_prefix(prefix))
+            .ok_or_else(invalid)
+    };
+    let vga_count = field("count:")?.parse().map_err(|_| invalid())?;
+    let device = field("PCI:")?.to_string();
+    let decodes = VgaResources::parse(field("decodes=")?).ok_or_else(invalid)?;
+    let owns = VgaResources::parse(field("owns=")?).ok_or_else(invalid)?;
+    let (locks, counts) = field("locks=")?.split_once('(').ok_or_else(invalid)?;
+    let locks = VgaResources::parse(locks).ok_or_else(invalid)?;
+    let (io, mem) = counts
+        .strip_suffix(')')
+        .and_then(|c| c.split_once(':'))
+        .ok_or_else(invalid)?;
+
+    Ok(Some(VgaCardStatus {
+        vga_count,
+        device,
+        decodes,
+        owns,
+        locks,
+        io_lock_count: io.parse().map_err(|_| invalid())?,
+        mem_lock_count: mem.parse().map_err(|_| invalid())?,
+    }))
+}
+
+/// A VGA card found in sysfs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VgaCard {
+    /// PCI address of the card.
+    pub device: String,
+    /// The firmware used the card as the boot display, and routed the legacy resources to it.
+    pub boot_vga: bool,
+}
+
+/// List the VGA cards of the host, sorted by PCI address.
+///
+/// VGA class devices are the ones with a `boot_vga` sysfs attribute.
+///
+/// # Arguments
+/// * `paths` - Locations of the VFIO character devices and of the sysfs tree.
+pub fn vga_cards(paths: &VfioPaths) -> Result<Vec<VgaCard>> {
+    let dir = paths.sysfs_root.join("bus/pci/devices");
+    let read_sysfs = |e, path: &Path| VfioError::ReadSysfs(e, path.display().to_string());
+    let mut cards = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(|e| read_sysfs(e, &dir))? {
+        let entry = entry.map_err(|e| read_sysfs(e, &dir))?;
+        let boot_vga = entry.path().join("boot_vga");
+        match fs::read_to_string(&boot_vga) {
+            Ok(value) => cards.push(VgaCard {
+                device: entry.file_name().to_string_lossy().into_owned(),
+                boot_vga: value.trim() == "1",
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(read_sysfs(e, &boot_vga)),
+        }
+    }
+    cards.sort_by(|a, b| a.device.cmp(&b.device));
+
+    Ok(cards)
+}
+
+/// A client of the kernel VGA arbiter, routing the legacy VGA resources between the cards.
+///
+/// Passing through the primary GPU of a host with several cards needs the legacy resources
+/// routed to it while the guest firmware drives it. The client locks them on behalf of a card,
+/// and tracks its own lock counts: a card can't be locked while the client holds locks on
+/// another one, as the arbiter would wait for the client itself. The kernel releases the locks
+/// of a client when its file is closed.
+pub struct VgaArbiter<F = File> {
+    file: F,
+    // Card the arbiter commands apply to.
+    target: Option<String>,
+    // I/O ports and memory range lock counts of the cards locked by this client.
+    locks: HashMap<String, (u32, u32)>,
+}
+
+impl VgaArbiter<File> {
+    /// Open the VGA arbiter at `/dev/vga_arbiter`.
+    pub fn open() -> Result<Self> {
+        Self::open_path(Path::new("/dev/vga_arbiter"))
+    }
+
+    /// Open the VGA arbiter at a non-standard location.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the VGA arbiter device.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(VfioError::VgaArbiter)?;
+
+        Ok(Self::from_file(file))
+    }
+}
+
+impl<F: Read + Write> VgaArbiter<F> {
+    /// Create a client over an opened VGA arbiter file.
+    ///
+    /// # Arguments
+    /// * `file` - The VGA arbiter file.
+    pub fn from_file(file: F) -> Self {
+        VgaArbiter {
+            file,
+            target: None,
+            locks: HashMap::new(),
+        }
+    }
+
+    // Each command must be written at once, the arbiter doesn't accept partial commands.
+    fn command(&mut self, command: &str) -> Result<()> {
+        let written = self
+            .file
+            .write(command.as_bytes())
+            .map_err(VfioError::VgaArbiter)?;
+        if written != command.len() {
+            return Err(VfioError::VgaArbiter(io::Error::other(format!(
+                "short write of VGA arbiter command {:?}: {} of {} bytes",
+                command,
+                written,
+                command.len()
+            ))));
+        }
+
+        Ok(())
+    }
+
+    /// Make a card the target of the following commands and status reads.
+    ///
+    /// # Arguments
+    /// * `device` - PCI address of the card.
+    pub fn target(&mut self, device: &str) -> Result<()> {
+        if self.target.as_deref() != Some(device) {
+            self.command(&format!("target PCI:{}", device))?;
+            self.target = Some(device.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Read the arbitration state of the target card.
+    ///
+    /// Returns `None` if there is no valid target.
+    pub fn status(&mut self) -> Result<Option<VgaCardStatus>> {
+        let mut buf = [0u8; VGA_ARB_STATUS_MAX];
+        let len = self.file.read(&mut buf).map_err(VfioError::VgaArbiter)?;
+
+        parse_vga_arbiter_status(&String::from_utf8_lossy(&buf[..len]))
+    }
+
+    /// Get the I/O ports and memory range lock counts this client holds on a card.
+    ///
+    /// # Arguments
+    /// * `device` - PCI address of the card.
+    pub fn lock_counts(&self, device: &str) -> (u32, u32) {
+        self.locks.get(device).copied().unwrap_or_default()
+    }
+
+    fn check_conflict(&self, device: &str) -> Result<()> {
+        match self.locks.keys().find(|holder| *holder != device) {
+            Some(holder) => Err(VfioError::VgaArbiterConflict {
+                device: device.to_string(),
+                holder: holder.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn add_locks(&mut self, device: &str, resources: VgaResources) {
+        let counts = self.locks.entry(device.to_string()).or_default();
+        counts.0 += u32::from(resources.io);
+        counts.1 += u32::from(resources.mem);
+    }
+
+    /// Lock legacy resources on a card, waiting for other clients to release them.
+    ///
+    /// Locks are counted, each one must be released with `unlock()`.
+    ///
+    /// # Arguments
+    /// * `device` - PCI address of the card.
+    /// * `resources` - Legacy resources to route to the card.
+    pub fn lock(&mut self, device: &str, resources: VgaResources) -> Result<()> {
+        if resources == VgaResources::NONE {
+            return Ok(());
+        }
+        self.check_conflict(device)?;
+        self.target(device)?;
+        self.command(&format!("lock {}", resources.as_str()))?;
+        self.add_locks(device, resources);
+
+        Ok(())
+    }
+
+    /// Lock legacy resources on a card if no other client holds them.
+    ///
+    /// Returns whether the resources were locked.
+    ///
+    /// # Arguments
+    /// * `device` - PCI address of the card.
+    /// * `resources` - Legacy resources to route to the card.
+    pub fn trylock(&mut self, device: &str, resources: VgaResources) -> Result<bool> {
+        if resources == VgaResources::NONE {
+            return Ok(true);
+        }
+        self.check_conflict(device)?;
+        self.target(device)?;
+        match self.command(&format!("trylock {}", resources.as_str())) {
+            Ok(()) => {
+                self.add_locks(device, resources);
+                Ok(true)
+            }
+            Err(VfioError::VgaArbiter(e)) if e.raw_os_error() == Some(libc::EBUSY) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Release legacy resources locked on a card by `lock()` or `trylock()`.
+    ///
+    /// # Arguments
+    /// * `device` - PCI address of the card.
+    /// * `resources` - Legacy resources to release.
+    pub fn unlock(&mut self, device: &str, resources: VgaResources) -> Result<()> {
+        let (io, mem) = self.lock_counts(device);
+        if (resources.io && io == 0) || (resources.mem && mem == 0) {
+            return Err(VfioError::VgaResourcesNotLocked {
+                device: device.to_string(),
+            });
+        }
+        if resources == VgaResources::NONE {
+            return Ok(());
+        }
+        self.target(device)?;
+        self.command(&format!("unlock {}", resources.as_str()))?;
+
+        let counts = (io - u32::from(resources.io), mem - u32::from(resources.mem));
+        if counts == (0, 0) {
+            self.locks.remove(device);
+        } else {
+            self.locks.insert(device.to_string(), counts);
+        }
+
+        Ok(())
+    }
+
+    /// Release all the legacy resources locked by this client.
+    pub fn unlock_all(&mut self) -> Result<()> {
+        let devices: Vec<String> = self.locks.keys().cloned().collect();
+        for device in devices {
+            self.target(&device)?;
+            self.command("unlock all")?;
+            self.locks.remove(&device);
+        }
+
+        Ok(())
+    }
+}
+
+// Legacy resources locked by `with_vga_arbitration()`, released when dropped if `f` panics.
+struct VgaLockGuard<'a, A: Read + Write> {
+    arbiter: &'a mut VgaArbiter<A>,
+    device: &'a str,
+    resources: VgaResources,
+    locked: bool,
+}
+
+impl<A: Read + Write> VgaLockGuard<'_, A> {
+    fn unlock(mut self) -> Result<()> {
+        self.locked = false;
+        self.arbiter.unlock(self.device, self.resources)
+    }
+}
+
+impl<A: Read + Write> Drop for VgaLockGuard<'_, A> {
+    fn drop(&mut self) {
+        if self.locked {
+            if let Err(e) = self.arbiter.unlock(self.device, self.resources) {
+                error!(
+                    "Failed to release the VGA resources of {}: {}",
+                    self.device, e
+                );
+            }
+        }
+    }
+}
+
+impl VfioDevice {
+    /// Run `f` with legacy VGA resources routed to the device.
+    ///
+    /// The resources are locked on the device through the VGA arbiter before `f` runs, and
+    /// released after, so that accesses to the VGA region reach this device. The lock waits for
+    /// other clients holding the resources. The resources are released too if `f` panics.
+    ///
+    /// # Arguments
+    /// * `arbiter` - The VGA arbiter client.
+    /// * `resources` - Legacy resources the accesses need.
+    /// * `f` - The accesses to run.
+    pub fn with_vga_arbitration<A, F, T>(
+        &self,
+        arbiter: &mut VgaArbiter<A>,
+        resources: VgaResources,
+        f: F,
+    ) -> Result<T>
+    where
+        A: Read + Write,
+        F: FnOnce(&VfioDevice) -> T,
+    {
+        let name = self.name();
+        arbiter.lock(&name, resources)?;
+        let guard = VgaLockGuard {
+            arbiter,
+            device: &name,
+            resources,
+            locked: true,
+        };
+        let ret = f(self);
+        guard.unlock()?;
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfio_device::tests::create_vfio_container;
+    use std::sync::Arc;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Kernel side of a VGA arbiter client, answering the status of the target.
+    #[derive(Default)]
+    struct FakeArbiter {
+        commands: Vec<String>,
+        // Legacy resources locked by other clients.
+        busy: bool,
+        // Accept one byte less of each command.
+        short_write: bool,
+    }
+
+    impl Read for FakeArbiter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let target = self
+                .commands
+                .iter()
+                .rev()
+                .find_map(|c| c.strip_prefix("target PCI:"));
+            let status = match target {
+                Some(device) => format!(
+                    "count:2,PCI:{},decodes=io+mem,owns=none,locks=none(0:0)\n",
+                    device
+                ),
+                None => "invalid\n".to_string(),
+            };
+            let len = status.len().min(buf.len());
+            buf[..len].copy_from_slice(&status.as_bytes()[..len]);
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeArbiter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let command = String::from_utf8(buf.to_vec()).unwrap();
+            if self.busy && command.starts_with("trylock") {
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+            self.commands.push(command);
+            if self.short_write {
+                return Ok(buf.len() - 1);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_vga_arbiter_status() {
+        assert_eq!(
+            parse_vga_arbiter_status(
+                "count:2,PCI:0000:01:00.0,decodes=io+mem,owns=mem,locks=io(1:0)\n"
+            )
+            .unwrap(),
+            Some(VgaCardStatus {
+                vga_count: 2,
+                device: "0000:01:00.0".to_string(),
+                decodes: VgaResources::ALL,
+                owns: VgaResources::MEM,
+                locks: VgaResources::IO,
+                io_lock_count: 1,
+                mem_lock_count: 0,
+            })
+        );
+        assert_eq!(parse_vga_arbiter_status("invalid").unwrap(), None);
+        for status in [
+            "",
+            "count:2,PCI:0000:01:00.0",
+            "count:2,PCI:0000:01:00.0,decodes=vga,owns=mem,locks=io(1:0)",
+            "count:2,PCI:0000:01:00.0,decodes=io,owns=mem,locks=io(1)",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                parse_vga_arbiter_status(status),
+                Err(VfioError::InvalidVgaArbiterStatus(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_vga_arbiter_locks() {
+        let mut fake = FakeArbiter::default();
+        let mut arbiter = VgaArbiter::from_file(&mut fake);
+        assert_eq!(arbiter.status().unwrap(), None);
+
+        arbiter.lock("0000:01:00.0", VgaResources::ALL).unwrap();
+        arbiter.lock("0000:01:00.0", VgaResources::IO).unwrap();
+        assert_eq!(arbiter.lock_counts("0000:01:00.0"), (2, 1));
+        assert_eq!(
+            arbiter.status().unwrap().unwrap().device,
+            "0000:01:00.0".to_string()
+        );
+
+        // Locking another card would wait for this client.
+        assert!(matches!(
+            arbiter.trylock("0000:02:00.0", VgaResources::MEM),
+            Err(VfioError::VgaArbiterConflict { .. })
+        ));
+        assert!(matches!(
+            arbiter.unlock("0000:02:00.0", VgaResources::MEM),
+            Err(VfioError::VgaResourcesNotLocked { .. })
+        ));
+
+        arbiter.unlock("0000:01:00.0", VgaResources::ALL).unwrap();
+        assert_eq!(arbiter.lock_counts("0000:01:00.0"), (1, 0));
+        assert!(arbiter.unlock("0000:01:00.0", VgaResources::MEM).is_err());
+        arbiter.unlock("0000:01:00.0", VgaResources::IO).unwrap();
+        assert!(arbiter.trylock("0000:02:00.0", VgaResources::MEM).unwrap());
+        arbiter.unlock_all().unwrap();
+        assert_eq!(arbiter.lock_counts("0000:02:00.0"), (0, 0));
+        drop(arbiter);
+
+        assert_eq!(
+            fake.commands,
+            vec![
+                "target PCI:0000:01:00.0",
+                "lock io+mem",
+                "lock io",
+                "unlock io+mem",
+                "unlock io",
+                "target PCI:0000:02:00.0",
+                "trylock mem",
+                "unlock all",
+            ]
+        );
+
+        // Resources held by other clients.
+        fake.busy = true;
+        let mut arbiter = VgaArbiter::from_file(&mut fake);
+        assert!(!arbiter.trylock("0000:01:00.0", VgaResources::IO).unwrap());
+        assert_eq!(arbiter.lock_counts("0000:01:00.0"), (0, 0));
+
+        // A partially written command fails.
+        let mut fake = FakeArbiter {
+            short_write: true,
+            ..Default::default()
+        };
+        let mut arbiter = VgaArbiter::from_file(&mut fake);
+        assert!(matches!(
+            arbiter.lock("0000:01:00.0", VgaResources::IO),
+            Err(VfioError::VgaArbiter(_))
+        ));
+        assert_eq!(arbiter.lock_counts("0000:01:00.0"), (0, 0));
+    }
+
+    #[test]
+    fn test_vfio_device_with_vga_arbitration() {
+        let container = Arc::new(create_vfio_container());
+        let device =
+            VfioDevice::new(Path::new("/sys/bus/pci/devices/0000:03:00.0"), container).unwrap();
+        let mut fake = FakeArbiter::default();
+        let mut arbiter = VgaArbiter::from_file(&mut fake);
+
+        let name = device
+            .with_vga_arbitration(&mut arbiter, VgaResources::ALL, |d| d.name())
+            .unwrap();
+        assert_eq!(name, "0000:03:00.0");
+        assert_eq!(arbiter.lock_counts(&name), (0, 0));
+
+        // A panic doesn't leave the resources locked.
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            device.with_vga_arbitration(&mut arbiter, VgaResources::IO, |_| panic!("oops"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(arbiter.lock_counts(&name), (0, 0));
+        drop(arbiter);
+        assert_eq!(
+            fake.commands,
+            vec![
+                "target PCI:0000:03:00.0",
+                "lock io+mem",
+                "unlock io+mem",
+                "lock io",
+                "unlock io"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vga_cards() {
+        let root = TempDir::new().unwrap();
+        let devices = root.as_path().join("bus/pci/devices");
+        for (device, boot_vga) in [("0000:02:00.0", "0"), ("0000:01:00.0", "1")].iter() {
+            fs::create_dir_all(devices.join(device)).unwrap();
+            fs::write(devices.join(device).join("boot_vga"), boot_vga).unwrap();
+        }
+        fs::create_dir_all(devices.join("0000:00:1f.0")).unwrap();
+        let paths = VfioPaths {
+            sysfs_root: root.as_path().to_path_buf(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vga_cards(&paths).unwrap(),
+            vec![
+                VgaCard {
+                    device: "0000:01:00.0".to_string(),
+                    boot_vga: true,
+                },
+                VgaCard {
+                    device: "0000:02:00.0".to_string(),
+                    boot_vga: false,
+                },
+            ]
+        );
+    }
+}
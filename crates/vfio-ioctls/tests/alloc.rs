@@ -0,0 +1,134 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Heap usage tests of the VFIO device wrappers.
+//!
+//! The allocations are counted by a global allocator, which is why these tests are built as a
+//! test binary of their own. Like the hardware tests, they need a PCI device bound to the
+//! `vfio-pci` driver and are ignored by default:
+//!
+//! ```text
+//! VFIO_TEST_DEVICE=0000:03:00.0 cargo test --test alloc -- --ignored --test-threads=1
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use vfio_bindings::bindings::vfio::*;
+use vfio_ioctls::{VfioContainer, VfioDevice};
+
+const TEST_DEVICE_ENV: &str = "VFIO_TEST_DEVICE";
+
+// Heap bytes a device may hold once another device of the same kind is open. A PCI device has
+// about ten regions and five interrupt indexes, each taking a few dozen bytes.
+const DEVICE_HEAP_LIMIT: isize = 4096;
+
+// The device can only be opened by one container at a time.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+// Allocator tracking the heap bytes and allocations held by each thread.
+struct CountingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+thread_local! {
+    static HEAP_BYTES: Cell<isize> = const { Cell::new(0) };
+    static HEAP_ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_heap(bytes: isize, allocs: usize) {
+    // Allocations made while the thread is torn down aren't counted.
+    let _ = HEAP_BYTES.try_with(|c| c.set(c.get() + bytes));
+    let _ = HEAP_ALLOCS.try_with(|c| c.set(c.get() + allocs));
+}
+
+// SAFETY: the allocations are forwarded to the system allocator.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_heap(layout.size() as isize, 1);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_heap(-(layout.size() as isize), 0);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_heap(new_size as isize - layout.size() as isize, 1);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+// Run `f` and return its result with the heap bytes it left allocated and the number of
+// allocations it made.
+fn heap_usage<T>(f: impl FnOnce() -> T) -> (T, isize, usize) {
+    let (bytes, allocs) = (HEAP_BYTES.with(Cell::get), HEAP_ALLOCS.with(Cell::get));
+    let value = f();
+    (
+        value,
+        HEAP_BYTES.with(Cell::get) - bytes,
+        HEAP_ALLOCS.with(Cell::get) - allocs,
+    )
+}
+
+fn test_device_path() -> PathBuf {
+    let bdf = std::env::var(TEST_DEVICE_ENV).unwrap_or_else(|_| {
+        panic!(
+            "{} must be set to the address of a device bound to vfio-pci",
+            TEST_DEVICE_ENV
+        )
+    });
+
+    PathBuf::from("/sys/bus/pci/devices").join(bdf)
+}
+
+#[test]
+#[ignore]
+fn test_device_heap_footprint() {
+    let _guard = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let container = Arc::new(VfioContainer::new(None).unwrap());
+    let path = test_device_path();
+
+    let (device, bytes, _) = heap_usage(|| VfioDevice::new(&path, container.clone()).unwrap());
+    assert!(bytes > 0);
+
+    // Another device of the same kind shares the group of the first one, leaving only its
+    // regions, capabilities, interrupts and paths.
+    let (other, other_bytes, _) = heap_usage(|| VfioDevice::new(&path, container.clone()).unwrap());
+    assert!(
+        other_bytes < bytes,
+        "second device holds {} heap bytes, first one {}",
+        other_bytes,
+        bytes
+    );
+    assert!(
+        other_bytes < DEVICE_HEAP_LIMIT,
+        "second device holds {} heap bytes, more than {}",
+        other_bytes,
+        DEVICE_HEAP_LIMIT
+    );
+    drop(other);
+
+    // Looking up the regions and interrupts doesn't allocate.
+    let ((), _, allocs) = heap_usage(|| {
+        for index in 0..VFIO_PCI_NUM_REGIONS {
+            device.get_region_flags(index);
+            device.get_region_offset(index);
+            device.get_region_size(index);
+            device.region_class(index);
+        }
+        for index in 0..VFIO_PCI_NUM_IRQS {
+            device.irq_info(index);
+        }
+    });
+    assert_eq!(allocs, 0);
+
+    // The heap held by the device is released with it.
+    let ((), bytes, _) = heap_usage(|| drop(device));
+    assert!(bytes < 0);
+}
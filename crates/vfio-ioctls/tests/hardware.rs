@@ -0,0 +1,316 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Integration tests exercising the real VFIO kernel interfaces.
+//!
+//! These tests need a PCI device bound to the `vfio-pci` driver and permission to open
+//! `/dev/vfio`, so they are ignored by default. To run them, pass the device address:
+//!
+//! ```text
+//! VFIO_TEST_DEVICE=0000:03:00.0 cargo test --test hardware -- --ignored --test-threads=1
+//! ```
+//!
+//! Each test documents the sequence of VFIO ioctls it relies on.
+
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use vfio_bindings::bindings::vfio::*;
+use vfio_ioctls::{VfioContainer, VfioDevice};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+const TEST_DEVICE_ENV: &str = "VFIO_TEST_DEVICE";
+const PAGE_SIZE: u64 = 0x1000;
+
+// The device can only be opened by one container at a time.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+struct TestDevice {
+    _guard: MutexGuard<'static, ()>,
+    sysfs_path: PathBuf,
+}
+
+impl TestDevice {
+    fn get() -> Self {
+        let guard = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let bdf = std::env::var(TEST_DEVICE_ENV).unwrap_or_else(|_| {
+            panic!(
+                "{} must be set to the address of a device bound to vfio-pci",
+                TEST_DEVICE_ENV
+            )
+        });
+
+        TestDevice {
+            _guard: guard,
+            sysfs_path: PathBuf::from("/sys/bus/pci/devices").join(bdf),
+        }
+    }
+
+    fn sysfs_hex(&self, name: &str) -> u64 {
+        let content = fs::read_to_string(self.sysfs_path.join(name)).unwrap();
+        u64::from_str_radix(content.trim().trim_start_matches("0x"), 16).unwrap()
+    }
+
+    fn group_id(&self) -> u32 {
+        let group = fs::read_link(self.sysfs_path.join("iommu_group")).unwrap();
+        group
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    // VFIO_GET_API_VERSION, VFIO_CHECK_EXTENSION, VFIO_GROUP_GET_STATUS,
+    // VFIO_GROUP_SET_CONTAINER, VFIO_SET_IOMMU, VFIO_GROUP_GET_DEVICE_FD,
+    // VFIO_DEVICE_GET_INFO, VFIO_DEVICE_GET_REGION_INFO and VFIO_DEVICE_GET_IRQ_INFO.
+    fn open(&self) -> (Arc<VfioContainer>, VfioDevice) {
+        let container = Arc::new(VfioContainer::new(None).unwrap());
+        let device = VfioDevice::new(&self.sysfs_path, container.clone()).unwrap();
+
+        (container, device)
+    }
+}
+
+#[test]
+#[ignore]
+fn test_open_device() {
+    let test_device = TestDevice::get();
+    let (_container, device) = test_device.open();
+
+    assert!(device.max_interrupts() > 0);
+}
+
+#[test]
+#[ignore]
+fn test_region_info() {
+    let test_device = TestDevice::get();
+    let (_container, device) = test_device.open();
+
+    // Each line of the sysfs resource file is "start end flags" for BAR0 to BAR5 and the ROM,
+    // which is what lspci reports as the device regions.
+    let resources = fs::read_to_string(test_device.sysfs_path.join("resource")).unwrap();
+    for (index, line) in resources
+        .lines()
+        .take(VFIO_PCI_ROM_REGION_INDEX as usize + 1)
+        .enumerate()
+    {
+        let fields: Vec<u64> = line
+            .split_whitespace()
+            .map(|f| u64::from_str_radix(f.trim_start_matches("0x"), 16).unwrap())
+            .collect();
+        let expected = if fields[1] > fields[0] {
+            fields[1] - fields[0] + 1
+        } else {
+            0
+        };
+
+        assert_eq!(
+            device.get_region_size(index as u32),
+            expected,
+            "size of region {}",
+            index
+        );
+    }
+    assert!(device.get_region_size(VFIO_PCI_CONFIG_REGION_INDEX) >= 0x100);
+}
+
+#[test]
+#[ignore]
+fn test_config_space() {
+    let test_device = TestDevice::get();
+    let (_container, device) = test_device.open();
+
+    let mut buf = [0u8; 4];
+    device.read_config(0, &mut buf).unwrap();
+    assert_eq!(
+        u64::from(u16::from_le_bytes([buf[0], buf[1]])),
+        test_device.sysfs_hex("vendor")
+    );
+    assert_eq!(
+        u64::from(u16::from_le_bytes([buf[2], buf[3]])),
+        test_device.sysfs_hex("device")
+    );
+
+    let status = device.status_register().unwrap();
+    assert!(status.capabilities_list);
+}
+
+// VFIO_DEVICE_SET_IRQS with DATA_EVENTFD | ACTION_TRIGGER, then DATA_NONE | ACTION_TRIGGER
+// to fire the interrupt from the kernel, and DATA_NONE | ACTION_TRIGGER with a zero count to
+// disable it.
+#[test]
+#[ignore]
+fn test_msi() {
+    let test_device = TestDevice::get();
+    let (_container, device) = test_device.open();
+
+    match device.irq_info(VFIO_PCI_MSI_IRQ_INDEX) {
+        Some(irq) if irq.count > 0 => {}
+        _ => {
+            eprintln!("device doesn't support MSI, skipping");
+            return;
+        }
+    }
+
+    let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+    device.enable_msi(vec![&evt]).unwrap();
+    device.trigger_irq(VFIO_PCI_MSI_IRQ_INDEX, 0).unwrap();
+    assert_eq!(evt.read().unwrap(), 1);
+    device.disable_msi().unwrap();
+}
+
+// VFIO_DEVICE_SET_IRQS with DATA_EVENTFD | ACTION_TRIGGER for every MSI-X vector, then a single
+// DATA_NONE | ACTION_TRIGGER over the whole range to fire them all, timing the burst until
+// each eventfd is signaled. Run with --nocapture to see the delivery time.
+#[test]
+#[ignore]
+fn test_msix_burst() {
+    let test_device = TestDevice::get();
+    let (_container, device) = test_device.open();
+
+    let count = match device.irq_info(VFIO_PCI_MSIX_IRQ_INDEX) {
+        Some(irq) if irq.count > 0 => irq.count,
+        _ => {
+            eprintln!("device doesn't support MSI-X, skipping");
+            return;
+        }
+    };
+
+    let evts: Vec<EventFd> = (0..count)
+        .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+        .collect();
+    device.enable_msix(evts.iter().collect()).unwrap();
+    device
+        .trigger_irq_range(VFIO_PCI_MSIX_IRQ_INDEX, 0, count + 1)
+        .unwrap_err();
+
+    let start = Instant::now();
+    device
+        .trigger_irq_range(VFIO_PCI_MSIX_IRQ_INDEX, 0, count)
+        .unwrap();
+    for evt in evts.iter() {
+        assert_eq!(evt.read().unwrap(), 1);
+    }
+    let elapsed = start.elapsed();
+    eprintln!("{} MSI-X vectors delivered in {:?}", count, elapsed);
+
+    device.disable_msix().unwrap();
+}
+
+// pread() on the device fd against memcpy from a mapping of the largest mmap'able BAR, timing
+// both to show the gain of the mmap fast path. Run with --nocapture to see the throughputs, which
+// depend too much on the device and the host to be compared.
+#[test]
+#[ignore]
+fn test_region_mmap_fastpath() {
+    const ROUNDS: u32 = 16;
+    let test_device = TestDevice::get();
+    let (container, device) = test_device.open();
+
+    let msix_bar = device.msix_info().unwrap().map(|info| info.table_bir);
+    let bar = (VFIO_PCI_BAR0_REGION_INDEX..=VFIO_PCI_BAR5_REGION_INDEX)
+        .filter(|&index| device.get_region_flags(index) & VFIO_REGION_INFO_FLAG_MMAP != 0)
+        .filter(|&index| Some(index) != msix_bar && !device.region_requires_sparse_mmap(index))
+        .max_by_key(|&index| device.get_region_size(index));
+    let bar = match bar {
+        Some(bar) => bar,
+        None => {
+            eprintln!("device doesn't have a mmap'able BAR, skipping");
+            return;
+        }
+    };
+    let len = device.get_region_size(bar).min(0x10_0000) as usize;
+
+    let time_reads = |device: &VfioDevice| {
+        let mut buf = vec![0u8; len];
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            device.region_read(bar, &mut buf, 0);
+        }
+        start.elapsed()
+    };
+    let pread = time_reads(&device);
+    drop(device);
+    let device = VfioDevice::builder(&test_device.sysfs_path, container)
+        .enable_mmap_fastpath()
+        .build()
+        .unwrap();
+    let mmap = time_reads(&device);
+
+    let mib = (len as f64 * f64::from(ROUNDS)) / f64::from(0x10_0000);
+    eprintln!(
+        "BAR {} ({} KiB): pread {:.1} MiB/s, mmap {:.1} MiB/s",
+        bar,
+        len / 1024,
+        mib / pread.as_secs_f64(),
+        mib / mmap.as_secs_f64()
+    );
+}
+
+// VFIO_IOMMU_GET_INFO, VFIO_IOMMU_MAP_DMA and VFIO_IOMMU_UNMAP_DMA.
+#[test]
+#[ignore]
+fn test_dma_map() {
+    let test_device = TestDevice::get();
+    let (container, _device) = test_device.open();
+
+    // Pick the first page aligned IOVA, above page 0, fitting in an IOVA window.
+    let iova = container
+        .valid_iova_ranges()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            let start = (r.start.max(PAGE_SIZE) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            (start, r.end)
+        })
+        .find(|(start, end)| start < end && end - start >= PAGE_SIZE - 1)
+        .map(|(start, _)| start)
+        .unwrap_or(0x10_0000);
+
+    // SAFETY: anonymous private mapping, checked and unmapped below.
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            PAGE_SIZE as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(addr, libc::MAP_FAILED);
+
+    container
+        .vfio_dma_map(iova, PAGE_SIZE, addr as u64)
+        .unwrap();
+    assert_eq!(container.mappings().len(), 1);
+    container.vfio_dma_unmap(iova, PAGE_SIZE).unwrap();
+    assert!(container.mappings().is_empty());
+
+    // SAFETY: addr was returned by the mmap() above.
+    assert_eq!(unsafe { libc::munmap(addr, PAGE_SIZE as usize) }, 0);
+}
+
+// VFIO_GROUP_UNSET_CONTAINER when the last device of the group is dropped.
+#[test]
+#[ignore]
+fn test_teardown() {
+    let test_device = TestDevice::get();
+    let group_id = test_device.group_id();
+    let (container, device) = test_device.open();
+    drop(device);
+    drop(container);
+
+    // A group still attached to a container can't be opened again.
+    let group_path = PathBuf::from("/dev/vfio").join(group_id.to_string());
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(group_path)
+        .unwrap();
+}